@@ -22,12 +22,19 @@ extern int pea_core_beacon_frame(void* h, uint16_t listen_port, uint8_t* out_buf
 extern int pea_core_discovery_response_frame(void* h, uint16_t listen_port, uint8_t* out_buf, size_t out_buf_len);
 extern int pea_core_decode_discovery_frame(const uint8_t* bytes, size_t len,
     uint8_t* out_device_id_16, uint8_t* out_public_key_32, uint16_t* out_listen_port);
-extern int pea_core_handshake_bytes(void* h, uint8_t* out_buf, size_t out_buf_len);
-extern int pea_core_session_key(void* h, const uint8_t* peer_public_key_32, uint8_t* out_session_key_32);
-extern int pea_core_encrypt_wire(const uint8_t* session_key_32, uint64_t nonce,
-    const uint8_t* plain, size_t plain_len, uint8_t* out_buf, size_t out_buf_len);
-extern int pea_core_decrypt_wire(const uint8_t* session_key_32, uint64_t nonce,
-    const uint8_t* cipher, size_t cipher_len, uint8_t* out_buf, size_t out_buf_len);
+extern void* pea_core_handshake_initiate(uint8_t* out_buf, size_t out_buf_len, size_t* out_len);
+extern void* pea_core_handshake_respond(void);
+extern int pea_core_handshake_next_len(void* hs);
+extern int pea_core_handshake_read(void* h, void* hs, const uint8_t* msg, size_t msg_len,
+    uint8_t* out_buf, size_t out_buf_len, uint8_t* out_done);
+extern int pea_core_handshake_peer(void* hs, uint8_t* out_device_id_16, uint8_t* out_public_key_32);
+extern void pea_core_handshake_destroy(void* hs);
+extern void* pea_core_session_cipher(void* hs, uint64_t now_ms);
+extern int pea_core_session_seal(void* cipher, const uint8_t* plain, size_t plain_len,
+    uint64_t now_ms, uint8_t* out_buf, size_t out_buf_len);
+extern int pea_core_session_open(void* cipher, const uint8_t* frame, size_t frame_len,
+    uint64_t now_ms, uint8_t* out_buf, size_t out_buf_len);
+extern void pea_core_session_cipher_destroy(void* cipher);
 
 #define PEA_CORE_JNI "dev/peapod/android/PeaCore"
 
@@ -241,80 +248,134 @@ Java_dev_peapod_android_PeaCore_nativeDecodeDiscoveryFrame(JNIEnv *env, jclass c
     return (jint)r;
 }
 
-JNIEXPORT jint JNICALL
-Java_dev_peapod_android_PeaCore_nativeHandshakeBytes(JNIEnv *env, jclass clazz, jlong handle, jbyteArray outBuf) {
+JNIEXPORT jlong JNICALL
+Java_dev_peapod_android_PeaCore_nativeHandshakeInitiate(JNIEnv *env, jclass clazz,
+    jbyteArray outBuf, jintArray outLen) {
     (void)clazz;
-    if (!outBuf || (*env)->GetArrayLength(env, outBuf) < 49) return -1;
+    if (!outBuf || !outLen || (*env)->GetArrayLength(env, outLen) < 1) return 0;
     jbyte* out = (*env)->GetByteArrayElements(env, outBuf, NULL);
-    if (!out) return -1;
-    int r = pea_core_handshake_bytes((void*)(uintptr_t)handle, (uint8_t*)out, 49);
+    if (!out) return 0;
+    jsize out_len = (*env)->GetArrayLength(env, outBuf);
+    size_t written = 0;
+    void* hs = pea_core_handshake_initiate((uint8_t*)out, (size_t)out_len, &written);
     (*env)->ReleaseByteArrayElements(env, outBuf, out, 0);
-    return (jint)r;
+    jint len = (jint)written;
+    (*env)->SetIntArrayRegion(env, outLen, 0, 1, &len);
+    return (jlong)(uintptr_t)hs;
+}
+
+JNIEXPORT jlong JNICALL
+Java_dev_peapod_android_PeaCore_nativeHandshakeRespond(JNIEnv *env, jclass clazz) {
+    (void)env;
+    (void)clazz;
+    return (jlong)(uintptr_t)pea_core_handshake_respond();
 }
 
 JNIEXPORT jint JNICALL
-Java_dev_peapod_android_PeaCore_nativeSessionKey(JNIEnv *env, jclass clazz, jlong handle,
-    jbyteArray peerPublicKey, jbyteArray outSessionKey) {
+Java_dev_peapod_android_PeaCore_nativeHandshakeNextLen(JNIEnv *env, jclass clazz, jlong hs) {
+    (void)env;
     (void)clazz;
-    if (!peerPublicKey || (*env)->GetArrayLength(env, peerPublicKey) < 32) return -1;
-    if (!outSessionKey || (*env)->GetArrayLength(env, outSessionKey) < 32) return -1;
-    jbyte* pk = (*env)->GetByteArrayElements(env, peerPublicKey, NULL);
-    jbyte* out = (*env)->GetByteArrayElements(env, outSessionKey, NULL);
-    if (!pk || !out) {
-        if (pk) (*env)->ReleaseByteArrayElements(env, peerPublicKey, pk, JNI_ABORT);
-        if (out) (*env)->ReleaseByteArrayElements(env, outSessionKey, out, JNI_ABORT);
+    return (jint)pea_core_handshake_next_len((void*)(uintptr_t)hs);
+}
+
+JNIEXPORT jint JNICALL
+Java_dev_peapod_android_PeaCore_nativeHandshakeRead(JNIEnv *env, jclass clazz, jlong handle,
+    jlong hs, jbyteArray msg, jbyteArray outBuf, jintArray outDone) {
+    (void)clazz;
+    if (!msg || !outBuf || !outDone || (*env)->GetArrayLength(env, outDone) < 1) return -1;
+    jbyte* m = (*env)->GetByteArrayElements(env, msg, NULL);
+    jbyte* out = (*env)->GetByteArrayElements(env, outBuf, NULL);
+    if (!m || !out) {
+        if (m) (*env)->ReleaseByteArrayElements(env, msg, m, JNI_ABORT);
+        if (out) (*env)->ReleaseByteArrayElements(env, outBuf, out, JNI_ABORT);
         return -1;
     }
-    int r = pea_core_session_key((void*)(uintptr_t)handle, (const uint8_t*)pk, (uint8_t*)out);
-    (*env)->ReleaseByteArrayElements(env, peerPublicKey, pk, JNI_ABORT);
-    (*env)->ReleaseByteArrayElements(env, outSessionKey, out, 0);
+    jsize msg_len = (*env)->GetArrayLength(env, msg);
+    jsize out_len = (*env)->GetArrayLength(env, outBuf);
+    uint8_t done = 0;
+    int r = pea_core_handshake_read((void*)(uintptr_t)handle, (void*)(uintptr_t)hs,
+        (const uint8_t*)m, (size_t)msg_len, (uint8_t*)out, (size_t)out_len, &done);
+    (*env)->ReleaseByteArrayElements(env, msg, m, JNI_ABORT);
+    (*env)->ReleaseByteArrayElements(env, outBuf, out, 0);
+    jint d = (jint)done;
+    (*env)->SetIntArrayRegion(env, outDone, 0, 1, &d);
     return (jint)r;
 }
 
 JNIEXPORT jint JNICALL
-Java_dev_peapod_android_PeaCore_nativeEncryptWire(JNIEnv *env, jclass clazz,
-    jbyteArray sessionKey, jlong nonce, jbyteArray plain, jbyteArray outBuf) {
+Java_dev_peapod_android_PeaCore_nativeHandshakePeer(JNIEnv *env, jclass clazz, jlong hs,
+    jbyteArray outDeviceId, jbyteArray outPublicKey) {
+    (void)clazz;
+    if (!outDeviceId || (*env)->GetArrayLength(env, outDeviceId) < 16) return -1;
+    if (!outPublicKey || (*env)->GetArrayLength(env, outPublicKey) < 32) return -1;
+    uint8_t id[16];
+    uint8_t pk[32];
+    int r = pea_core_handshake_peer((void*)(uintptr_t)hs, id, pk);
+    if (r != 0) return (jint)r;
+    (*env)->SetByteArrayRegion(env, outDeviceId, 0, 16, (jbyte*)id);
+    (*env)->SetByteArrayRegion(env, outPublicKey, 0, 32, (jbyte*)pk);
+    return 0;
+}
+
+JNIEXPORT void JNICALL
+Java_dev_peapod_android_PeaCore_nativeHandshakeDestroy(JNIEnv *env, jclass clazz, jlong hs) {
+    (void)env;
+    (void)clazz;
+    pea_core_handshake_destroy((void*)(uintptr_t)hs);
+}
+
+JNIEXPORT jlong JNICALL
+Java_dev_peapod_android_PeaCore_nativeSessionCipher(JNIEnv *env, jclass clazz, jlong hs, jlong nowMs) {
+    (void)env;
+    (void)clazz;
+    return (jlong)(uintptr_t)pea_core_session_cipher((void*)(uintptr_t)hs, (uint64_t)nowMs);
+}
+
+JNIEXPORT jint JNICALL
+Java_dev_peapod_android_PeaCore_nativeSessionSeal(JNIEnv *env, jclass clazz, jlong cipher,
+    jbyteArray plain, jlong nowMs, jbyteArray outBuf) {
     (void)clazz;
-    if (!sessionKey || !plain || !outBuf) return -1;
-    jbyte* key = (*env)->GetByteArrayElements(env, sessionKey, NULL);
+    if (!plain || !outBuf) return -1;
     jbyte* p = (*env)->GetByteArrayElements(env, plain, NULL);
     jbyte* out = (*env)->GetByteArrayElements(env, outBuf, NULL);
-    if (!key || !p || !out) {
-        if (key) (*env)->ReleaseByteArrayElements(env, sessionKey, key, JNI_ABORT);
+    if (!p || !out) {
         if (p) (*env)->ReleaseByteArrayElements(env, plain, p, JNI_ABORT);
         if (out) (*env)->ReleaseByteArrayElements(env, outBuf, out, JNI_ABORT);
         return -1;
     }
     jsize plain_len = (*env)->GetArrayLength(env, plain);
     jsize out_len = (*env)->GetArrayLength(env, outBuf);
-    int r = pea_core_encrypt_wire((const uint8_t*)key, (uint64_t)nonce,
-        (const uint8_t*)p, (size_t)plain_len, (uint8_t*)out, (size_t)out_len);
-    (*env)->ReleaseByteArrayElements(env, sessionKey, key, JNI_ABORT);
+    int r = pea_core_session_seal((void*)(uintptr_t)cipher, (const uint8_t*)p, (size_t)plain_len,
+        (uint64_t)nowMs, (uint8_t*)out, (size_t)out_len);
     (*env)->ReleaseByteArrayElements(env, plain, p, JNI_ABORT);
     (*env)->ReleaseByteArrayElements(env, outBuf, out, 0);
     return (jint)r;
 }
 
 JNIEXPORT jint JNICALL
-Java_dev_peapod_android_PeaCore_nativeDecryptWire(JNIEnv *env, jclass clazz,
-    jbyteArray sessionKey, jlong nonce, jbyteArray cipher, jbyteArray outBuf) {
+Java_dev_peapod_android_PeaCore_nativeSessionOpen(JNIEnv *env, jclass clazz, jlong cipher,
+    jbyteArray frame, jlong nowMs, jbyteArray outBuf) {
     (void)clazz;
-    if (!sessionKey || !cipher || !outBuf) return -1;
-    jbyte* key = (*env)->GetByteArrayElements(env, sessionKey, NULL);
-    jbyte* c = (*env)->GetByteArrayElements(env, cipher, NULL);
+    if (!frame || !outBuf) return -1;
+    jbyte* f = (*env)->GetByteArrayElements(env, frame, NULL);
     jbyte* out = (*env)->GetByteArrayElements(env, outBuf, NULL);
-    if (!key || !c || !out) {
-        if (key) (*env)->ReleaseByteArrayElements(env, sessionKey, key, JNI_ABORT);
-        if (c) (*env)->ReleaseByteArrayElements(env, cipher, c, JNI_ABORT);
+    if (!f || !out) {
+        if (f) (*env)->ReleaseByteArrayElements(env, frame, f, JNI_ABORT);
         if (out) (*env)->ReleaseByteArrayElements(env, outBuf, out, JNI_ABORT);
         return -1;
     }
-    jsize cipher_len = (*env)->GetArrayLength(env, cipher);
+    jsize frame_len = (*env)->GetArrayLength(env, frame);
     jsize out_len = (*env)->GetArrayLength(env, outBuf);
-    int r = pea_core_decrypt_wire((const uint8_t*)key, (uint64_t)nonce,
-        (const uint8_t*)c, (size_t)cipher_len, (uint8_t*)out, (size_t)out_len);
-    (*env)->ReleaseByteArrayElements(env, sessionKey, key, JNI_ABORT);
-    (*env)->ReleaseByteArrayElements(env, cipher, c, JNI_ABORT);
+    int r = pea_core_session_open((void*)(uintptr_t)cipher, (const uint8_t*)f, (size_t)frame_len,
+        (uint64_t)nowMs, (uint8_t*)out, (size_t)out_len);
+    (*env)->ReleaseByteArrayElements(env, frame, f, JNI_ABORT);
     (*env)->ReleaseByteArrayElements(env, outBuf, out, 0);
     return (jint)r;
 }
+
+JNIEXPORT void JNICALL
+Java_dev_peapod_android_PeaCore_nativeSessionCipherDestroy(JNIEnv *env, jclass clazz, jlong cipher) {
+    (void)env;
+    (void)clazz;
+    pea_core_session_cipher_destroy((void*)(uintptr_t)cipher);
+}
@@ -15,7 +15,13 @@ int pea_core_tick(void* h, void* out_buf, size_t out_buf_len) { (void)h; (void)o
 int pea_core_beacon_frame(void* h, uint16_t listen_port, void* out_buf, size_t out_buf_len) { (void)h; (void)listen_port; (void)out_buf; (void)out_buf_len; return -1; }
 int pea_core_discovery_response_frame(void* h, uint16_t listen_port, void* out_buf, size_t out_buf_len) { (void)h; (void)listen_port; (void)out_buf; (void)out_buf_len; return -1; }
 int pea_core_decode_discovery_frame(const void* bytes, size_t len, void* out_device_id_16, void* out_public_key_32, uint16_t* out_listen_port) { (void)bytes; (void)len; (void)out_device_id_16; (void)out_public_key_32; (void)out_listen_port; return -1; }
-int pea_core_handshake_bytes(void* h, void* out_buf, size_t out_buf_len) { (void)h; (void)out_buf; (void)out_buf_len; return -1; }
-int pea_core_session_key(void* h, const void* peer_public_key_32, void* out_session_key_32) { (void)h; (void)peer_public_key_32; (void)out_session_key_32; return -1; }
-int pea_core_encrypt_wire(const void* session_key_32, uint64_t nonce, const void* plain, size_t plain_len, void* out_buf, size_t out_buf_len) { (void)session_key_32; (void)nonce; (void)plain; (void)plain_len; (void)out_buf; (void)out_buf_len; return -1; }
-int pea_core_decrypt_wire(const void* session_key_32, uint64_t nonce, const void* cipher, size_t cipher_len, void* out_buf, size_t out_buf_len) { (void)session_key_32; (void)nonce; (void)cipher; (void)cipher_len; (void)out_buf; (void)out_buf_len; return -1; }
+void* pea_core_handshake_initiate(void* out_buf, size_t out_buf_len, size_t* out_len) { (void)out_buf; (void)out_buf_len; (void)out_len; return NULL; }
+void* pea_core_handshake_respond(void) { return NULL; }
+int pea_core_handshake_next_len(void* hs) { (void)hs; return -1; }
+int pea_core_handshake_read(void* h, void* hs, const void* msg, size_t msg_len, void* out_buf, size_t out_buf_len, uint8_t* out_done) { (void)h; (void)hs; (void)msg; (void)msg_len; (void)out_buf; (void)out_buf_len; (void)out_done; return -1; }
+int pea_core_handshake_peer(void* hs, void* out_device_id_16, void* out_public_key_32) { (void)hs; (void)out_device_id_16; (void)out_public_key_32; return -1; }
+void pea_core_handshake_destroy(void* hs) { (void)hs; }
+void* pea_core_session_cipher(void* hs, uint64_t now_ms) { (void)hs; (void)now_ms; return NULL; }
+int pea_core_session_seal(void* cipher, const void* plain, size_t plain_len, uint64_t now_ms, void* out_buf, size_t out_buf_len) { (void)cipher; (void)plain; (void)plain_len; (void)now_ms; (void)out_buf; (void)out_buf_len; return -1; }
+int pea_core_session_open(void* cipher, const void* frame, size_t frame_len, uint64_t now_ms, void* out_buf, size_t out_buf_len) { (void)cipher; (void)frame; (void)frame_len; (void)now_ms; (void)out_buf; (void)out_buf_len; return -1; }
+void pea_core_session_cipher_destroy(void* cipher) { (void)cipher; }
@@ -19,7 +19,7 @@ object Discovery {
     private const val BEACON_INTERVAL_THROTTLE_MS = 12000L
     private const val PEER_TIMEOUT_MS = 16000L
     private const val TIMEOUT_CHECK_MS = 4000L
-    private const val BEACON_FRAME_MAX = 256
+    private const val BEACON_FRAME_MAX = 512
 
     @Volatile
     private var socket: MulticastSocket? = null
@@ -10,9 +10,6 @@ object PeaCore {
         System.loadLibrary("pea_jni")
     }
 
-    /** Protocol version for handshake (must match pea-core PROTOCOL_VERSION). */
-    const val PROTOCOL_VERSION: Int = 1
-
     /** Create core instance. Returns 0 if stub or failure. */
     @JvmStatic
     external fun nativeCreate(): Long
@@ -88,19 +85,53 @@ object PeaCore {
         outListenPort: IntArray
     ): Int
 
-    /** Handshake bytes (49: version + device_id + public_key). Returns 0 on success, -1 on error. */
+    /**
+     * Start a handshake on a connection we opened. Fills outBuf with the first message (its length in
+     * outLen[0]). Returns a handshake handle, or 0 on error.
+     */
+    @JvmStatic
+    external fun nativeHandshakeInitiate(outBuf: ByteArray, outLen: IntArray): Long
+
+    /** Start a handshake on a connection we accepted. Returns a handshake handle, or 0 on error. */
     @JvmStatic
-    external fun nativeHandshakeBytes(handle: Long, outBuf: ByteArray): Int
+    external fun nativeHandshakeRespond(): Long
 
-    /** Derive session key for peer. outSessionKey must be 32 bytes. Returns 0 on success, -1 on error. */
+    /** Bytes the next nativeHandshakeRead expects; 0 once finished, -1 on error. */
+    @JvmStatic
+    external fun nativeHandshakeNextLen(hs: Long): Int
+
+    /**
+     * Process the peer's next handshake message. Fills outBuf with the reply to send and sets
+     * outDone[0] to 1 once the peer is authenticated. Returns reply bytes written (0 if none), or -1
+     * on error (close the connection).
+     */
+    @JvmStatic
+    external fun nativeHandshakeRead(handle: Long, hs: Long, msg: ByteArray, outBuf: ByteArray, outDone: IntArray): Int
+
+    /** Authenticated peer of a finished handshake: outDeviceId (16), outPublicKey (32). Returns 0 on success, -1 on error. */
+    @JvmStatic
+    external fun nativeHandshakePeer(hs: Long, outDeviceId: ByteArray, outPublicKey: ByteArray): Int
+
+    /** Destroy a handshake handle. */
+    @JvmStatic
+    external fun nativeHandshakeDestroy(hs: Long)
+
+    /** Frame cipher for a finished handshake (nowMs: monotonic clock). Returns a cipher handle, or 0 on error. */
+    @JvmStatic
+    external fun nativeSessionCipher(hs: Long, nowMs: Long): Long
+
+    /**
+     * Encrypt a frame. Fills outBuf with the bytes to write to the socket (length-prefixed frames,
+     * plain.size + 64 is enough). Returns bytes written, or -1 on error.
+     */
     @JvmStatic
-    external fun nativeSessionKey(handle: Long, peerPublicKey: ByteArray, outSessionKey: ByteArray): Int
+    external fun nativeSessionSeal(cipher: Long, plain: ByteArray, nowMs: Long, outBuf: ByteArray): Int
 
-    /** Encrypt for wire. Output length = plain.size + 16. Returns bytes written, or -1 on error. */
+    /** Decrypt a frame (without its length prefix). Returns bytes written, 0 for a rekey frame, -1 on error (close the connection). */
     @JvmStatic
-    external fun nativeEncryptWire(sessionKey: ByteArray, nonce: Long, plain: ByteArray, outBuf: ByteArray): Int
+    external fun nativeSessionOpen(cipher: Long, frame: ByteArray, nowMs: Long, outBuf: ByteArray): Int
 
-    /** Decrypt from wire. Output length = cipher.size - 16. Returns bytes written, or -1 on error. */
+    /** Destroy a cipher handle. */
     @JvmStatic
-    external fun nativeDecryptWire(sessionKey: ByteArray, nonce: Long, cipher: ByteArray, outBuf: ByteArray): Int
+    external fun nativeSessionCipherDestroy(cipher: Long)
 }
@@ -9,17 +9,17 @@ import java.net.Socket
 import java.nio.ByteBuffer
 import java.nio.ByteOrder
 import java.util.concurrent.ConcurrentHashMap
-import java.util.concurrent.atomic.AtomicLong
 import kotlin.concurrent.thread
 
 /**
  * Local transport per .tasks/03-android §4: TCP server (45679), TCP client to discovered peers,
- * handshake (49 bytes: version + device_id + public_key), then length-prefixed encrypted frames.
- * Same wire format as Windows (pea-windows/transport.rs).
+ * authenticated handshake (pea-core Handshake), then length-prefixed frames sealed by the session
+ * cipher. Same wire format as Linux and Windows (pea-linux/transport.rs).
  */
 object Transport {
 
-    private const val HANDSHAKE_SIZE = 49
+    private const val HANDSHAKE_BUF_SIZE = 256
+    private const val SEAL_OVERHEAD = 64
     private const val LEN_SIZE = 4
     private const val MAX_FRAME_LEN = 16 * 1024 * 1024
     private const val TICK_INTERVAL_MS = 1000L
@@ -31,15 +31,17 @@ object Transport {
     @Volatile
     private var running = false
 
-    private data class PeerSender(
+    /** Writer side of a connection; cipher is used and destroyed under the output lock (0 once closed). */
+    private class PeerSender(
         val deviceId: ByteArray,
         val output: DataOutputStream,
-        val sessionKey: ByteArray,
-        val writeNonce: AtomicLong
-    ) {
-        override fun equals(other: Any?) = other is PeerSender && deviceId.contentEquals(other.deviceId)
-        override fun hashCode() = deviceId.contentHashCode()
-    }
+        var cipher: Long
+    )
+
+    private val clockStart = System.nanoTime()
+
+    /** Monotonic milliseconds for the session cipher's rekey timer. */
+    private fun nowMs() = (System.nanoTime() - clockStart) / 1_000_000
 
     private val peerSenders = ConcurrentHashMap<String, PeerSender>()
     private val peerSendersLock = Object()
@@ -84,11 +86,16 @@ object Transport {
                 val socket = Socket()
                 socket.soTimeout = 30000
                 socket.connect(InetSocketAddress(addr, port), 10000)
-                val (peerId, sessionKey) = handshakeConnect(socket, deviceId, publicKey) ?: run {
+                val (peerId, cipher) = handshake(socket, initiator = true) ?: run {
+                    socket.close()
+                    return@thread
+                }
+                if (!peerId.contentEquals(deviceId)) {
+                    PeaCore.nativeSessionCipherDestroy(cipher)
                     socket.close()
                     return@thread
                 }
-                addPeerAndRunReadLoop(socket, peerId, sessionKey)
+                addPeerAndRunReadLoop(socket, peerId, cipher)
             } catch (_: Exception) {}
         }
     }
@@ -101,11 +108,11 @@ object Transport {
                 socket.soTimeout = 30000
                 thread {
                     try {
-                        val (peerId, sessionKey) = handshakeAccept(socket) ?: run {
+                        val (peerId, cipher) = handshake(socket, initiator = false) ?: run {
                             socket.close()
                             return@thread
                         }
-                        addPeerAndRunReadLoop(socket, peerId, sessionKey)
+                        addPeerAndRunReadLoop(socket, peerId, cipher)
                     } catch (_: Exception) {
                         try { socket.close() } catch (_: Exception) {}
                     }
@@ -116,58 +123,79 @@ object Transport {
         }
     }
 
-    private fun handshakeAccept(socket: Socket): Pair<ByteArray, ByteArray>? {
-        val input = DataInputStream(socket.getInputStream())
-        val output = DataOutputStream(socket.getOutputStream())
-        val buf = ByteArray(HANDSHAKE_SIZE)
-        input.readFully(buf)
-        if (buf[0].toInt() and 0xFF != PeaCore.PROTOCOL_VERSION) return null
-        val peerId = buf.copyOfRange(1, 17)
-        val peerPublic = buf.copyOfRange(17, 49)
-        val sessionKey = ByteArray(32)
-        if (PeaCore.nativeSessionKey(coreHandle, peerPublic, sessionKey) != 0) return null
-        val ourHandshake = ByteArray(HANDSHAKE_SIZE)
-        if (PeaCore.nativeHandshakeBytes(coreHandle, ourHandshake) != 0) return null
-        output.write(ourHandshake)
-        output.flush()
-        return peerId to sessionKey
-    }
-
-    private fun handshakeConnect(socket: Socket, expectDeviceId: ByteArray, expectPublicKey: ByteArray): Pair<ByteArray, ByteArray>? {
+    /**
+     * Run the authenticated handshake (initiator if we opened the connection): both sides prove they
+     * hold the keys of the identities they present. Returns the peer's device ID and a session
+     * cipher handle, or null if the peer is on another handshake version, fails to prove its
+     * identity or is refused by the core.
+     */
+    private fun handshake(socket: Socket, initiator: Boolean): Pair<ByteArray, Long>? {
         val input = DataInputStream(socket.getInputStream())
         val output = DataOutputStream(socket.getOutputStream())
-        val ourHandshake = ByteArray(HANDSHAKE_SIZE)
-        if (PeaCore.nativeHandshakeBytes(coreHandle, ourHandshake) != 0) return null
-        output.write(ourHandshake)
-        output.flush()
-        val buf = ByteArray(HANDSHAKE_SIZE)
-        input.readFully(buf)
-        if (buf[0].toInt() and 0xFF != PeaCore.PROTOCOL_VERSION) return null
-        val peerId = buf.copyOfRange(1, 17)
-        val peerPublic = buf.copyOfRange(17, 49)
-        if (!peerId.contentEquals(expectDeviceId)) return null
-        val sessionKey = ByteArray(32)
-        if (PeaCore.nativeSessionKey(coreHandle, peerPublic, sessionKey) != 0) return null
-        return peerId to sessionKey
+        val out = ByteArray(HANDSHAKE_BUF_SIZE)
+        val hs = if (initiator) {
+            val outLen = IntArray(1)
+            val started = PeaCore.nativeHandshakeInitiate(out, outLen)
+            if (started == 0L) return null
+            try {
+                output.write(out, 0, outLen[0])
+                output.flush()
+            } catch (e: Exception) {
+                PeaCore.nativeHandshakeDestroy(started)
+                throw e
+            }
+            started
+        } else {
+            PeaCore.nativeHandshakeRespond()
+        }
+        if (hs == 0L) return null
+        try {
+            while (true) {
+                val need = PeaCore.nativeHandshakeNextLen(hs)
+                if (need <= 0) return null
+                val msg = ByteArray(need)
+                input.readFully(msg)
+                val done = IntArray(1)
+                val n = PeaCore.nativeHandshakeRead(coreHandle, hs, msg, out, done)
+                if (n < 0) return null
+                if (n > 0) {
+                    output.write(out, 0, n)
+                    output.flush()
+                }
+                if (done[0] == 1) {
+                    val peerId = ByteArray(16)
+                    val peerPublic = ByteArray(32)
+                    if (PeaCore.nativeHandshakePeer(hs, peerId, peerPublic) != 0) return null
+                    val cipher = PeaCore.nativeSessionCipher(hs, nowMs())
+                    if (cipher == 0L) return null
+                    return peerId to cipher
+                }
+            }
+        } finally {
+            PeaCore.nativeHandshakeDestroy(hs)
+        }
     }
 
-    private fun addPeerAndRunReadLoop(socket: Socket, peerId: ByteArray, sessionKey: ByteArray) {
+    private fun addPeerAndRunReadLoop(socket: Socket, peerId: ByteArray, cipher: Long) {
         val idKey = peerId.joinToString("") { "%02x".format(it) }
-        val sender = PeerSender(peerId, DataOutputStream(socket.getOutputStream()), sessionKey, AtomicLong(0))
+        val sender = PeerSender(peerId, DataOutputStream(socket.getOutputStream()), cipher)
         synchronized(peerSendersLock) {
             peerSenders[idKey]?.let { try { it.output.close() } catch (_: Exception) {} }
             peerSenders[idKey] = sender
         }
-        runReadLoop(socket, peerId, sessionKey)
-        synchronized(peerSendersLock) { peerSenders.remove(idKey) }
+        runReadLoop(socket, peerId, cipher)
+        synchronized(peerSendersLock) { peerSenders.remove(idKey, sender) }
         try { socket.close() } catch (_: Exception) {}
+        synchronized(sender.output) {
+            PeaCore.nativeSessionCipherDestroy(sender.cipher)
+            sender.cipher = 0L
+        }
         PeaCore.nativePeerLeft(coreHandle, peerId, null)
     }
 
-    private fun runReadLoop(socket: Socket, peerId: ByteArray, sessionKey: ByteArray) {
+    private fun runReadLoop(socket: Socket, peerId: ByteArray, cipher: Long) {
         val input = DataInputStream(socket.getInputStream())
         val outBuf = ByteArray(OUTBUF_SIZE)
-        var readNonce = 0L
         val idKey = peerId.joinToString("") { "%02x".format(it) }
         try {
             while (running) {
@@ -175,12 +203,12 @@ object Transport {
                 input.readFully(lenBuf)
                 val len = ByteBuffer.wrap(lenBuf).order(ByteOrder.LITTLE_ENDIAN).int and 0x7FFF_FFFF
                 if (len <= 0 || len > MAX_FRAME_LEN) break
-                val cipher = ByteArray(len)
-                input.readFully(cipher)
-                val plainBuf = ByteArray(len + 1024)
-                val plainLen = PeaCore.nativeDecryptWire(sessionKey, readNonce, cipher, plainBuf)
-                if (plainLen <= 0) break
-                readNonce++
+                val frame = ByteArray(len)
+                input.readFully(frame)
+                val plainBuf = ByteArray(len)
+                val plainLen = PeaCore.nativeSessionOpen(cipher, frame, nowMs(), plainBuf)
+                if (plainLen < 0) break
+                if (plainLen == 0) continue
                 val plain = plainBuf.copyOfRange(0, plainLen)
                 val resultLen = PeaCore.nativeOnMessageReceived(coreHandle, peerId, plain, outBuf)
                 if (resultLen < 0) continue
@@ -215,14 +243,14 @@ object Transport {
     private fun sendToPeer(peerId: ByteArray, plain: ByteArray) {
         val idKey = peerId.joinToString("") { "%02x".format(it) }
         val sender = peerSenders[idKey] ?: return
-        val cipherBuf = ByteArray(plain.size + 16)
-        val n = PeaCore.nativeEncryptWire(sender.sessionKey, sender.writeNonce.getAndIncrement(), plain, cipherBuf)
-        if (n <= 0) return
+        val sealed = ByteArray(plain.size + SEAL_OVERHEAD)
         try {
             synchronized(sender.output) {
-                val lenBuf = ByteBuffer.allocate(4).order(ByteOrder.LITTLE_ENDIAN).putInt(n).array()
-                sender.output.write(lenBuf)
-                sender.output.write(cipherBuf, 0, n)
+                if (sender.cipher == 0L) return
+                // Sealed under the output lock, so frames go out in nonce order.
+                val n = PeaCore.nativeSessionSeal(sender.cipher, plain, nowMs(), sealed)
+                if (n <= 0) return
+                sender.output.write(sealed, 0, n)
                 sender.output.flush()
             }
         } catch (_: Exception) {}
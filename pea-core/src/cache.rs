@@ -0,0 +1,127 @@
+//! Chunk cache: verified payloads keyed by URL, range and validator, evicted least recently used.
+
+use std::collections::VecDeque;
+
+/// Identifies cached bytes: the origin URL, the byte range `[start, end)` and the origin's
+/// validator (ETag or Last-Modified), so a changed resource never matches stale bytes.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    pub url: String,
+    pub start: u64,
+    pub end: u64,
+    pub validator: Option<String>,
+}
+
+/// Size-bounded LRU cache of chunk payloads.
+#[derive(Clone, Debug, Default)]
+pub struct ChunkCache {
+    capacity: u64,
+    used: u64,
+    /// Least recently used first.
+    entries: VecDeque<(CacheKey, Vec<u8>)>,
+}
+
+impl ChunkCache {
+    /// Cache holding at most `capacity` payload bytes (0 disables caching).
+    pub fn new(capacity: u64) -> Self {
+        Self {
+            capacity,
+            ..Self::default()
+        }
+    }
+
+    /// Change the capacity, evicting the least recently used entries if it shrank.
+    pub fn set_capacity(&mut self, capacity: u64) {
+        self.capacity = capacity;
+        self.evict();
+    }
+
+    /// Payload bytes currently held.
+    pub fn used_bytes(&self) -> u64 {
+        self.used
+    }
+
+    /// Number of cached chunks.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Whether nothing is cached.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Look up `key`, marking it most recently used.
+    pub fn get(&mut self, key: &CacheKey) -> Option<&[u8]> {
+        let pos = self.entries.iter().position(|(k, _)| k == key)?;
+        let entry = self.entries.remove(pos)?;
+        self.entries.push_back(entry);
+        self.entries.back().map(|(_, p)| p.as_slice())
+    }
+
+    /// Store `payload` under `key` (replacing any previous copy). Payloads larger than the whole
+    /// cache are not stored.
+    pub fn insert(&mut self, key: CacheKey, payload: Vec<u8>) {
+        if payload.len() as u64 > self.capacity {
+            return;
+        }
+        self.remove(&key);
+        self.used += payload.len() as u64;
+        self.entries.push_back((key, payload));
+        self.evict();
+    }
+
+    /// Drop `key` if cached (e.g. its bytes turned out to be wrong).
+    pub fn remove(&mut self, key: &CacheKey) {
+        if let Some(pos) = self.entries.iter().position(|(k, _)| k == key) {
+            if let Some((_, old)) = self.entries.remove(pos) {
+                self.used -= old.len() as u64;
+            }
+        }
+    }
+
+    /// Drop everything.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.used = 0;
+    }
+
+    fn evict(&mut self) {
+        while self.used > self.capacity {
+            match self.entries.pop_front() {
+                Some((_, p)) => self.used -= p.len() as u64,
+                None => break,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(start: u64, validator: Option<&str>) -> CacheKey {
+        CacheKey {
+            url: "http://example.com/f".into(),
+            start,
+            end: start + 4,
+            validator: validator.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn evicts_least_recently_used_and_respects_validator() {
+        let mut cache = ChunkCache::new(8);
+        cache.insert(key(0, Some("v1")), b"aaaa".to_vec());
+        cache.insert(key(4, Some("v1")), b"bbbb".to_vec());
+        assert!(cache.get(&key(0, Some("v2"))).is_none());
+        // Touch the first chunk so the second is evicted next.
+        assert_eq!(cache.get(&key(0, Some("v1"))), Some(&b"aaaa"[..]));
+        cache.insert(key(8, Some("v1")), b"cccc".to_vec());
+        assert!(cache.get(&key(4, Some("v1"))).is_none());
+        assert_eq!((cache.len(), cache.used_bytes()), (2, 8));
+        cache.set_capacity(4);
+        assert_eq!(cache.get(&key(8, Some("v1"))), Some(&b"cccc"[..]));
+        assert_eq!(cache.len(), 1);
+    }
+}
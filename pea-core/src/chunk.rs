@@ -1,15 +1,24 @@
 //! Chunk manager: split transfer into chunks, track state, reassemble.
 
-use std::collections::HashMap;
+use std::collections::BTreeMap;
+use std::io::{Seek, SeekFrom, Write};
+use std::sync::{Arc, Mutex};
 
-use crate::integrity;
+use serde::{Deserialize, Serialize};
+
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::ChaCha20Poly1305;
+use rand::RngCore;
+
+use crate::identity::{DeviceId, KeyAgreement, WireCryptoError};
+use crate::integrity::HashAlgorithm;
 use crate::protocol::Message;
 
 /// Default chunk size in bytes (constant for now).
 pub const DEFAULT_CHUNK_SIZE: u64 = 256 * 1024; // 256 KiB
 
 /// Chunk identifier: transfer ID + range (start, end).
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct ChunkId {
     pub transfer_id: [u8; 16],
     pub start: u64,
@@ -37,72 +46,502 @@ pub fn split_into_chunks(transfer_id: [u8; 16], total_len: u64, chunk_size: u64)
     out
 }
 
+/// In-order slice of a transfer's body (offset relative to the start of the transfer).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub offset: u64,
+    pub bytes: Vec<u8>,
+}
+
+/// Host-provided destination for verified chunk bytes (file, mmap, socket), so a transfer does
+/// not hold its body in memory. Offsets are relative to the start of the transfer. Implemented
+/// for any seekable writer (e.g. `std::fs::File`).
+pub trait ChunkSink: Send {
+    fn write_at(&mut self, offset: u64, bytes: &[u8]) -> std::io::Result<()>;
+}
+
+impl<W: Write + Seek + Send> ChunkSink for W {
+    fn write_at(&mut self, offset: u64, bytes: &[u8]) -> std::io::Result<()> {
+        self.seek(SeekFrom::Start(offset))?;
+        self.write_all(bytes)
+    }
+}
+
+/// A [`ChunkSink`] shared between the host and a transfer.
+pub type SharedSink = Arc<Mutex<dyn ChunkSink>>;
+
+/// One bit per chunk ordinal (64 chunks per word).
+#[derive(Clone, Default, Serialize, Deserialize)]
+struct ChunkBits(Vec<u64>);
+
+impl ChunkBits {
+    fn with_len(len: usize) -> Self {
+        Self(vec![0; len.div_ceil(64)])
+    }
+
+    fn get(&self, index: usize) -> bool {
+        self.0
+            .get(index / 64)
+            .is_some_and(|w| w & (1 << (index % 64)) != 0)
+    }
+
+    /// Set the bit; returns false if it was already set.
+    fn set(&mut self, index: usize) -> bool {
+        let word = &mut self.0[index / 64];
+        let bit = 1 << (index % 64);
+        let was_clear = *word & bit == 0;
+        *word |= bit;
+        was_clear
+    }
+}
+
+/// How often a chunk not yet received has been requested and has failed (timeout, integrity
+/// failure, Nack).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkAttempts {
+    pub requests: u32,
+    pub failures: u32,
+}
+
+/// When a chunk was last requested (in core ticks) and, once verified, who delivered it and how
+/// many ticks after the request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkTiming {
+    pub requested_tick: u64,
+    pub source: Option<DeviceId>,
+    pub ticks: Option<u64>,
+}
+
+/// Evidence of who served a chunk: the serving peer's attestation over the chunk's range and
+/// hash ([`crate::identity::DeviceKey::attest_chunk`]), kept while that peer's copy is the one received.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkAttestation {
+    pub peer: DeviceId,
+    pub hash: [u8; 32],
+    pub signature: [u8; 32],
+}
+
 /// Per-transfer state: which chunks are assigned, received, in flight; reassembly.
+/// Chunk status is a bitfield indexed by ordinal in `chunk_ids`, so very large transfers cost a
+/// bit per chunk rather than a map entry.
+#[derive(Clone, Serialize, Deserialize)]
 pub struct TransferState {
     pub transfer_id: [u8; 16],
+    /// Origin offset of the first byte (the start of the client's Range, or 0). Chunk ranges are
+    /// absolute origin offsets; segment offsets are relative to this.
+    base_offset: u64,
     pub total_length: u64,
+    /// In body order (ascending offsets); a chunk's ordinal is its index here.
     chunk_ids: Vec<ChunkId>,
-    /// Chunk payloads received and verified (ChunkId -> payload).
-    received: HashMap<ChunkId, Vec<u8>>,
+    /// Chunks received and verified, by ordinal (including those already handed out).
+    received: ChunkBits,
+    /// Number of set bits in `received`.
+    received_count: usize,
+    /// Payloads of chunks received and verified but not yet handed out, by ordinal. Chunks
+    /// written to the sink have no entry.
+    payloads: BTreeMap<usize, Vec<u8>>,
+    /// Number of leading chunks (in `chunk_ids` order) already handed out via `take_contiguous`;
+    /// their payloads are freed.
+    delivered: usize,
+    /// Sum of verified payload lengths, including chunks already handed out.
+    received_bytes: u64,
+    /// Payload bytes held in `payloads`.
+    held_bytes: u64,
+    /// Attempts per chunk not yet received, by ordinal.
+    attempts: BTreeMap<usize, ChunkAttempts>,
+    /// Request time and delivery per requested chunk, by ordinal.
+    timings: BTreeMap<usize, ChunkTiming>,
+    attestations: BTreeMap<usize, ChunkAttestation>,
+    /// Where verified payloads go instead of `payloads` (not persisted; attach again after a
+    /// restore).
+    #[serde(skip)]
+    sink: Option<SharedSink>,
 }
 
 impl TransferState {
     pub fn new(transfer_id: [u8; 16], total_length: u64, chunk_ids: Vec<ChunkId>) -> Self {
+        Self::new_at(transfer_id, 0, total_length, chunk_ids)
+    }
+
+    /// State for a transfer of `total_length` bytes starting at origin offset `base_offset`;
+    /// `chunk_ids` carry absolute ranges within `[base_offset, base_offset + total_length)`.
+    pub fn new_at(
+        transfer_id: [u8; 16],
+        base_offset: u64,
+        total_length: u64,
+        chunk_ids: Vec<ChunkId>,
+    ) -> Self {
         Self {
             transfer_id,
+            base_offset,
             total_length,
+            received: ChunkBits::with_len(chunk_ids.len()),
             chunk_ids,
-            received: HashMap::new(),
+            received_count: 0,
+            payloads: BTreeMap::new(),
+            delivered: 0,
+            received_bytes: 0,
+            held_bytes: 0,
+            attempts: BTreeMap::new(),
+            timings: BTreeMap::new(),
+            attestations: BTreeMap::new(),
+            sink: None,
+        }
+    }
+
+    /// Ordinal of the chunk in body order, or None if it is not part of this transfer.
+    pub fn chunk_index(&self, chunk_id: ChunkId) -> Option<usize> {
+        self.chunk_ids
+            .binary_search_by_key(&chunk_id.start, |c| c.start)
+            .ok()
+            .filter(|&i| self.chunk_ids[i] == chunk_id)
+    }
+
+    /// Chunks lying entirely within the origin range `[start, end)`, in order.
+    pub fn covered_chunks(&self, start: u64, end: u64) -> &[ChunkId] {
+        let first = self.chunk_ids.partition_point(|c| c.start < start);
+        let last = self.chunk_ids.partition_point(|c| c.end <= end);
+        &self.chunk_ids[first..last.max(first)]
+    }
+
+    /// Chunk at the given ordinal.
+    pub fn chunk_at(&self, index: usize) -> Option<ChunkId> {
+        self.chunk_ids.get(index).copied()
+    }
+
+    /// Count a request for a chunk not yet received, made at `tick`.
+    pub fn note_request(&mut self, chunk_id: ChunkId, tick: u64) {
+        if let Some(index) = self
+            .chunk_index(chunk_id)
+            .filter(|&i| !self.received.get(i))
+        {
+            let attempts = self.attempts.entry(index).or_default();
+            attempts.requests = attempts.requests.saturating_add(1);
+            self.timings.insert(
+                index,
+                ChunkTiming {
+                    requested_tick: tick,
+                    source: None,
+                    ticks: None,
+                },
+            );
+        }
+    }
+
+    /// Attribute a received chunk to the device that delivered it at `tick`. The first delivery
+    /// counts; chunks never requested (e.g. from the cache) are not timed.
+    pub fn note_delivery(&mut self, chunk_id: ChunkId, source: DeviceId, tick: u64) {
+        let Some(index) = self.chunk_index(chunk_id).filter(|&i| self.received.get(i)) else {
+            return;
+        };
+        if self
+            .attestations
+            .get(&index)
+            .is_some_and(|a| a.peer != source)
+        {
+            self.attestations.remove(&index);
+        }
+        if let Some(timing) = self.timings.get_mut(&index) {
+            if timing.source.is_none() {
+                timing.source = Some(source);
+                timing.ticks = Some(tick.saturating_sub(timing.requested_tick));
+            }
+        }
+    }
+
+    /// Keep a verified attestation for a chunk not yet received (replacing one from an earlier,
+    /// failed delivery). [`TransferState::note_delivery`] drops it if another device's copy wins.
+    pub fn note_attestation(&mut self, chunk_id: ChunkId, attestation: ChunkAttestation) {
+        if let Some(index) = self
+            .chunk_index(chunk_id)
+            .filter(|&i| !self.received.get(i))
+        {
+            self.attestations.insert(index, attestation);
         }
     }
 
+    /// Attestation from the device whose copy of the chunk was received, if it sent one.
+    pub fn attestation(&self, chunk_id: ChunkId) -> Option<ChunkAttestation> {
+        let index = self.chunk_index(chunk_id)?;
+        self.attestations.get(&index).copied()
+    }
+
+    /// Request time, source and delivery time of every chunk requested so far, in body order.
+    pub fn chunk_timings(&self) -> Vec<(ChunkId, ChunkTiming)> {
+        self.timings
+            .iter()
+            .map(|(&i, &timing)| (self.chunk_ids[i], timing))
+            .collect()
+    }
+
+    /// Count a failed attempt at a chunk not yet received; returns its failures so far (0 for a
+    /// chunk already received or not part of this transfer).
+    pub fn note_failure(&mut self, chunk_id: ChunkId) -> u32 {
+        let Some(index) = self
+            .chunk_index(chunk_id)
+            .filter(|&i| !self.received.get(i))
+        else {
+            return 0;
+        };
+        let attempts = self.attempts.entry(index).or_default();
+        attempts.failures = attempts.failures.saturating_add(1);
+        attempts.failures
+    }
+
+    /// Requests and failures so far for a chunk not yet received (zero once it is).
+    pub fn attempts(&self, chunk_id: ChunkId) -> ChunkAttempts {
+        self.chunk_index(chunk_id)
+            .and_then(|i| self.attempts.get(&i))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Write verified payloads to `sink` from now on instead of holding them, starting with those
+    /// held and not yet handed out. `take_contiguous`, the reassembly methods and
+    /// `verified_segments` then return no bytes for chunks in the sink; the host reads the body
+    /// from it. A payload the sink fails to write is held in memory as before.
+    pub fn set_sink(&mut self, sink: SharedSink) {
+        self.sink = Some(sink);
+        let held = std::mem::take(&mut self.payloads);
+        self.held_bytes = 0;
+        for (index, payload) in held {
+            self.hold(index, payload);
+        }
+    }
+
+    /// Write the payload to the sink if there is one; hold it otherwise (or on a write error).
+    fn hold(&mut self, index: usize, payload: Vec<u8>) {
+        if let Some(sink) = &self.sink {
+            let offset = self.chunk_ids[index].start - self.base_offset;
+            let written = match sink.lock() {
+                Ok(mut sink) => sink.write_at(offset, &payload).is_ok(),
+                Err(_) => false,
+            };
+            if written {
+                return;
+            }
+        }
+        self.held_bytes += payload.len() as u64;
+        self.payloads.insert(index, payload);
+    }
+
     /// Record that a chunk was received and verified. Returns true if transfer is now complete.
+    /// A chunk already received (or handed out via `take_contiguous`), or not part of this
+    /// transfer, is not stored again.
     pub fn mark_received(&mut self, chunk_id: ChunkId, payload: Vec<u8>) -> bool {
-        self.received.insert(chunk_id, payload);
+        let Some(index) = self.chunk_index(chunk_id) else {
+            return self.is_complete();
+        };
+        if !self.received.set(index) {
+            return self.is_complete();
+        }
+        self.received_count += 1;
+        self.received_bytes += payload.len() as u64;
+        self.attempts.remove(&index);
+        self.hold(index, payload);
         self.is_complete()
     }
 
     pub fn is_complete(&self) -> bool {
-        self.chunk_ids
-            .iter()
-            .all(|id| self.received.contains_key(id))
+        self.received_count == self.chunk_ids.len()
     }
 
-    /// Reassemble chunks in order into a single byte stream. Call only when `is_complete()`.
+    /// Reassemble chunks in order into a single byte stream. Call only when `is_complete()`;
+    /// chunks already handed out via `take_contiguous` are not included.
     pub fn reassemble_into_bytes(&self) -> Vec<u8> {
         let mut out = Vec::with_capacity(self.total_length as usize);
-        for id in &self.chunk_ids {
-            if let Some(payload) = self.received.get(id) {
-                out.extend_from_slice(payload);
+        for payload in self.payloads.values() {
+            out.extend_from_slice(payload);
+        }
+        out
+    }
+
+    /// Take the bytes of received chunks that extend the contiguous prefix past what was already
+    /// delivered, freeing their payloads. Returns None if the next chunk in order has not arrived
+    /// yet.
+    pub fn take_contiguous(&mut self) -> Option<Segment> {
+        let first = *self.chunk_ids.get(self.delivered)?;
+        let mut bytes = Vec::new();
+        while self.received.get(self.delivered) {
+            if let Some(payload) = self.payloads.remove(&self.delivered) {
+                self.held_bytes -= payload.len() as u64;
+                if bytes.is_empty() {
+                    bytes = payload;
+                } else {
+                    bytes.extend_from_slice(&payload);
+                }
             }
+            self.delivered += 1;
+        }
+        if bytes.is_empty() {
+            return None;
+        }
+        Some(Segment {
+            offset: first.start - self.base_offset,
+            bytes,
+        })
+    }
+
+    /// Bytes already handed out via `take_contiguous`.
+    pub fn delivered_bytes(&self) -> u64 {
+        self.chunk_ids
+            .get(self.delivered)
+            .map(|c| c.start - self.base_offset)
+            .unwrap_or(self.total_length)
+    }
+
+    /// Reassemble the chunks not yet handed out via `take_contiguous` (the whole body if none were).
+    pub fn reassemble_remaining(&self) -> Vec<u8> {
+        let remaining = self.total_length - self.delivered_bytes();
+        let mut out = Vec::with_capacity(remaining as usize);
+        for payload in self.payloads.range(self.delivered..).map(|(_, p)| p) {
+            out.extend_from_slice(payload);
         }
         out
     }
 
+    /// Verified chunks not yet handed out via `take_contiguous`, merged into contiguous runs in
+    /// order (for a partial fallback: the host fetches only the gaps). Chunks in the sink are not
+    /// held, so the host fetches them again.
+    pub fn verified_segments(&self) -> Vec<Segment> {
+        let mut segments: Vec<Segment> = Vec::new();
+        let mut next = None;
+        for (&index, payload) in self.payloads.range(self.delivered..) {
+            match segments.last_mut() {
+                Some(last) if next == Some(index) => last.bytes.extend_from_slice(payload),
+                _ => segments.push(Segment {
+                    offset: self.chunk_ids[index].start - self.base_offset,
+                    bytes: payload.clone(),
+                }),
+            }
+            next = Some(index + 1);
+        }
+        segments
+    }
+
+    /// Origin offset of the transfer's first byte.
+    pub fn base_offset(&self) -> u64 {
+        self.base_offset
+    }
+
     pub fn chunk_ids(&self) -> &[ChunkId] {
         &self.chunk_ids
     }
 
+    /// Number of chunks received and verified (including those already handed out).
+    pub fn received_count(&self) -> usize {
+        self.received_count
+    }
+
+    /// Total verified payload bytes received so far.
+    pub fn bytes_received(&self) -> u64 {
+        self.received_bytes
+    }
+
+    /// Verified payload bytes held in memory: received, not yet handed out and not in a sink.
+    pub fn held_bytes(&self) -> u64 {
+        self.held_bytes
+    }
+
     /// Whether the chunk has been received and verified.
     pub fn is_chunk_received(&self, chunk_id: ChunkId) -> bool {
-        self.received.contains_key(&chunk_id)
+        self.chunk_index(chunk_id)
+            .is_some_and(|i| self.received.get(i))
     }
 }
 
+/// zstd level for ChunkData payloads: fast, since the link being saved is a LAN.
+const COMPRESSION_LEVEL: i32 = 1;
+
+/// Compress a ChunkData payload with zstd; `None` unless that saves at least an eighth of it
+/// (media and archives are usually compressed already).
+pub fn compress_payload(payload: &[u8]) -> Option<Vec<u8>> {
+    let packed = zstd::bulk::compress(payload, COMPRESSION_LEVEL).ok()?;
+    (packed.len() <= payload.len() - payload.len() / 8).then_some(packed)
+}
+
+/// Decompress a zstd ChunkData payload of at most `max_len` bytes; `None` if it is malformed or
+/// larger.
+pub fn decompress_payload(packed: &[u8], max_len: u64) -> Option<Vec<u8>> {
+    zstd::bulk::decompress(packed, usize::try_from(max_len).ok()?).ok()
+}
+
+/// Seal a chunk payload for storage at rest (cache spill files, snapshots) with this device's
+/// [`KeyAgreement::storage_key`], so other users of the machine cannot read browsing content out of
+/// PeaPod's files. Output: random 96-bit nonce, then ChaCha20-Poly1305 ciphertext. `context`
+/// (e.g. URL and range) is authenticated too, so a sealed payload cannot be passed off as another
+/// entry's.
+pub fn seal_chunk(
+    keypair: &(impl KeyAgreement + ?Sized),
+    context: &[u8],
+    payload: &[u8],
+) -> Result<Vec<u8>, WireCryptoError> {
+    let cipher = ChaCha20Poly1305::new_from_slice(keypair.storage_key().as_slice())
+        .map_err(|_| WireCryptoError::Key)?;
+    let mut nonce = [0u8; 12];
+    rand::rngs::OsRng.fill_bytes(&mut nonce);
+    let sealed = cipher
+        .encrypt(
+            (&nonce).into(),
+            Payload {
+                msg: payload,
+                aad: context,
+            },
+        )
+        .map_err(|_| WireCryptoError::Encrypt)?;
+    Ok([&nonce[..], &sealed].concat())
+}
+
+/// Open a payload sealed by [`seal_chunk`] with the same keypair and context. Fails with
+/// [`WireCryptoError::Decrypt`] if it was sealed by another keypair, for another context, or
+/// altered.
+pub fn open_chunk(
+    keypair: &(impl KeyAgreement + ?Sized),
+    context: &[u8],
+    sealed: &[u8],
+) -> Result<Vec<u8>, WireCryptoError> {
+    let cipher = ChaCha20Poly1305::new_from_slice(keypair.storage_key().as_slice())
+        .map_err(|_| WireCryptoError::Key)?;
+    if sealed.len() < 12 {
+        return Err(WireCryptoError::Decrypt);
+    }
+    let (nonce, ciphertext) = sealed.split_at(12);
+    cipher
+        .decrypt(
+            nonce.into(),
+            Payload {
+                msg: ciphertext,
+                aad: context,
+            },
+        )
+        .map_err(|_| WireCryptoError::Decrypt)
+}
+
 /// Build a ChunkRequest message for the given chunk (to send to a peer).
-/// Pass url so the responder can fetch from WAN when serving the request.
-pub fn chunk_request_message(chunk_id: ChunkId, url: Option<String>) -> Message {
+/// Pass url so the responder can fetch from WAN when serving the request, and the origin's
+/// validator (if known) so it can answer from its chunk cache. Carries no request headers or
+/// deadline; [`PeaPodCore::chunk_request_for`](crate::PeaPodCore::chunk_request_for) builds one
+/// with the transfer's full request context.
+pub fn chunk_request_message(
+    chunk_id: ChunkId,
+    url: Option<String>,
+    validator: Option<String>,
+) -> Message {
     Message::ChunkRequest {
         transfer_id: chunk_id.transfer_id,
         start: chunk_id.start,
         end: chunk_id.end,
         url,
+        validator,
+        headers: Vec::new(),
+        deadline_ms: None,
     }
 }
 
 /// Result of processing received ChunkData: verified and stored, or error.
 pub enum ChunkReceiveResult {
-    /// Chunk stored; transfer is now complete and reassembled bytes are ready.
+    /// Chunk stored; transfer is now complete. Holds the reassembled bytes not already taken via
+    /// `TransferState::take_contiguous` (the whole body when streaming was not used).
     Complete(Vec<u8>),
     /// Chunk stored; transfer not yet complete.
     InProgress,
@@ -110,7 +549,9 @@ pub enum ChunkReceiveResult {
     IntegrityFailed,
 }
 
-/// Process ChunkData message: verify hash, store in state. Returns result for the transfer.
+/// Process ChunkData message: verify length and hash, store in state. Returns result for the
+/// transfer. A payload whose length differs from its declared range fails integrity, since it
+/// would shift every later byte on reassembly.
 pub fn on_chunk_data_received(
     state: &mut TransferState,
     transfer_id: [u8; 16],
@@ -118,6 +559,27 @@ pub fn on_chunk_data_received(
     end: u64,
     hash: [u8; 32],
     payload: Vec<u8>,
+) -> ChunkReceiveResult {
+    on_chunk_data_received_with(
+        state,
+        transfer_id,
+        start,
+        end,
+        HashAlgorithm::Sha256,
+        hash,
+        payload,
+    )
+}
+
+/// Same as [`on_chunk_data_received`], for a `hash` made with `algorithm`.
+pub fn on_chunk_data_received_with(
+    state: &mut TransferState,
+    transfer_id: [u8; 16],
+    start: u64,
+    end: u64,
+    algorithm: HashAlgorithm,
+    hash: [u8; 32],
+    payload: Vec<u8>,
 ) -> ChunkReceiveResult {
     if state.transfer_id != transfer_id {
         return ChunkReceiveResult::IntegrityFailed;
@@ -127,12 +589,12 @@ pub fn on_chunk_data_received(
         start,
         end,
     };
-    if !integrity::verify_chunk(&payload, &hash) {
+    if payload.len() as u64 != end.saturating_sub(start) || !algorithm.verify(&payload, &hash) {
         return ChunkReceiveResult::IntegrityFailed;
     }
     let complete = state.mark_received(chunk_id, payload);
     if complete {
-        ChunkReceiveResult::Complete(state.reassemble_into_bytes())
+        ChunkReceiveResult::Complete(state.reassemble_remaining())
     } else {
         ChunkReceiveResult::InProgress
     }
@@ -141,6 +603,7 @@ pub fn on_chunk_data_received(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::identity::Keypair;
     use crate::integrity;
 
     #[test]
@@ -207,4 +670,150 @@ mod tests {
         let r2 = on_chunk_data_received(&mut state, c.transfer_id, c.start, c.end, hash, payload);
         assert!(matches!(r2, ChunkReceiveResult::InProgress));
     }
+
+    #[test]
+    fn bitfield_tracks_chunks_by_ordinal() {
+        let id = [7u8; 16];
+        let chunks = split_into_chunks(id, 1300, 10);
+        let mut state = TransferState::new(id, 1300, chunks.clone());
+        assert_eq!(state.chunk_index(chunks[100]), Some(100));
+        assert_eq!(state.chunk_at(100), Some(chunks[100]));
+        let foreign = ChunkId {
+            end: 15,
+            ..chunks[0]
+        };
+        assert_eq!(state.chunk_index(foreign), None);
+        assert!(!state.mark_received(foreign, vec![0; 15]));
+        assert_eq!(state.received_count(), 0);
+        for (i, &c) in chunks.iter().enumerate().rev() {
+            assert_eq!(state.mark_received(c, vec![i as u8; 10]), i == 0);
+        }
+        assert!(state.is_chunk_received(chunks[129]) && !state.is_chunk_received(foreign));
+        assert_eq!(state.reassemble_into_bytes()[640], 64);
+    }
+
+    #[test]
+    fn compression_round_trips_and_skips_incompressible_payloads() {
+        let text = b"peapod ".repeat(200);
+        let packed = compress_payload(&text).unwrap();
+        assert!(packed.len() < text.len() / 4);
+        assert_eq!(
+            decompress_payload(&packed, 1400).as_deref(),
+            Some(&text[..])
+        );
+        // A payload inflating past its range is refused.
+        assert_eq!(decompress_payload(&packed, 1000), None);
+        let mut x = 0x9e37_79b9_7f4a_7c15u64;
+        let noise: Vec<u8> = (0..1400)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 7;
+                x ^= x << 17;
+                x as u8
+            })
+            .collect();
+        assert_eq!(compress_payload(&noise), None);
+    }
+
+    #[test]
+    fn sealed_chunks_open_only_with_the_same_key_and_context() {
+        let owner = Keypair::generate();
+        let sealed = seal_chunk(&owner, b"http://example.com/f 0-10", b"browsing").unwrap();
+        assert!(!sealed.windows(8).any(|w| w == b"browsing"));
+        assert_eq!(
+            open_chunk(&owner, b"http://example.com/f 0-10", &sealed).unwrap(),
+            b"browsing"
+        );
+        assert!(open_chunk(&owner, b"http://example.com/g 0-10", &sealed).is_err());
+        assert!(open_chunk(&Keypair::generate(), b"http://example.com/f 0-10", &sealed).is_err());
+        assert!(open_chunk(&owner, b"", &sealed[..8]).is_err());
+    }
+
+    #[test]
+    fn payload_length_must_match_range() {
+        let id = [8u8; 16];
+        let chunks = split_into_chunks(id, 20, 10);
+        let mut state = TransferState::new(id, 20, chunks);
+        for payload in [vec![1u8; 9], vec![1u8; 11]] {
+            let hash = integrity::hash_chunk(&payload);
+            let r = on_chunk_data_received(&mut state, id, 0, 10, hash, payload);
+            assert!(matches!(r, ChunkReceiveResult::IntegrityFailed));
+        }
+        assert_eq!(state.received_count(), 0);
+    }
+
+    #[test]
+    fn take_contiguous_streams_prefix_in_order() {
+        let id = [4u8; 16];
+        let chunks = split_into_chunks(id, 90, 30);
+        let mut state = TransferState::new(id, 90, chunks.clone());
+        let payload = |c: &ChunkId| -> Vec<u8> { (c.start..c.end).map(|i| i as u8).collect() };
+        state.mark_received(chunks[1], payload(&chunks[1]));
+        assert_eq!(state.take_contiguous(), None);
+        state.mark_received(chunks[0], payload(&chunks[0]));
+        let seg = state.take_contiguous().unwrap();
+        assert_eq!(seg.offset, 0);
+        assert_eq!(seg.bytes.len(), 60);
+        assert_eq!(state.take_contiguous(), None);
+        // Delivered payloads are freed but still count as received.
+        assert!(state.payloads.is_empty());
+        assert!(state.is_chunk_received(chunks[0]) && state.is_chunk_received(chunks[1]));
+        assert_eq!((state.received_count(), state.bytes_received()), (2, 60));
+        assert!(!state.mark_received(chunks[0], payload(&chunks[0])));
+        assert!(state.payloads.is_empty());
+
+        let c = chunks[2];
+        let p = payload(&c);
+        let hash = integrity::hash_chunk(&p);
+        match on_chunk_data_received(&mut state, id, c.start, c.end, hash, p.clone()) {
+            ChunkReceiveResult::Complete(rest) => assert_eq!(rest, p),
+            _ => panic!("expected Complete with remaining bytes"),
+        }
+    }
+
+    #[test]
+    fn sink_receives_payloads_instead_of_memory() {
+        let id = [6u8; 16];
+        let chunks = split_into_chunks(id, 30, 10);
+        let mut state = TransferState::new(id, 30, chunks.clone());
+        state.mark_received(chunks[1], vec![1; 10]);
+        assert_eq!(state.held_bytes(), 10);
+        let file = Arc::new(Mutex::new(std::io::Cursor::new(Vec::new())));
+        state.set_sink(file.clone());
+        // The held payload is flushed to the sink; later ones never stay in memory.
+        assert_eq!(state.held_bytes(), 0);
+        state.mark_received(chunks[2], vec![2; 10]);
+        assert!(state.mark_received(chunks[0], vec![0; 10]));
+        assert_eq!((state.held_bytes(), state.bytes_received()), (0, 30));
+        assert!(state.verified_segments().is_empty());
+        let body = file.lock().unwrap().get_ref().clone();
+        assert_eq!(body, [vec![0; 10], vec![1; 10], vec![2; 10]].concat());
+    }
+
+    #[test]
+    fn verified_segments_merge_runs_after_delivered_prefix() {
+        let id = [5u8; 16];
+        // Bytes 100..150 of the origin, in 10-byte chunks.
+        let chunks: Vec<ChunkId> = split_into_chunks(id, 50, 10)
+            .into_iter()
+            .map(|c| ChunkId {
+                start: c.start + 100,
+                end: c.end + 100,
+                ..c
+            })
+            .collect();
+        let mut state = TransferState::new_at(id, 100, 50, chunks.clone());
+        for i in [0, 2, 3] {
+            state.mark_received(chunks[i], vec![i as u8; 10]);
+        }
+        assert_eq!(state.take_contiguous().map(|s| s.bytes.len()), Some(10));
+        let segments = state.verified_segments();
+        assert_eq!(
+            segments,
+            vec![Segment {
+                offset: 20,
+                bytes: [vec![2u8; 10], vec![3u8; 10]].concat(),
+            }]
+        );
+    }
 }
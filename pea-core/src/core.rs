@@ -1,477 +1,10006 @@
 //! Host-driven API: PeaPodCore receives events from host, returns actions.
 
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::sync::Arc;
 
-use crate::chunk::{self, ChunkId, TransferState, DEFAULT_CHUNK_SIZE};
-use crate::identity::{derive_session_key, DeviceId, Keypair, PublicKey};
-use crate::protocol::{Message, PROTOCOL_VERSION};
+use serde::{Deserialize, Serialize};
+use zeroize::Zeroizing;
+
+use crate::cache::{CacheKey, ChunkCache};
+use crate::chunk::{
+    self, ChunkAttempts, ChunkAttestation, ChunkId, ChunkTiming, Segment, SharedSink,
+    TransferState, DEFAULT_CHUNK_SIZE,
+};
+use crate::error::{ChunkError, CoreError, HandshakeError, OnMessageError, SnapshotError};
+use crate::identity::{
+    derive_session_key, DeviceId, DeviceKey, Keypair, NameRecord, PublicKey, Revocation,
+};
+use crate::integrity::{HashAlgorithm, Manifest, OriginDigest, PeerTrust, PeerTrustTracker};
+use crate::pairing::{self, Fingerprint, PodKey, WrappedPodKey, POD_KEY_HISTORY};
+use crate::policy::{
+    ContentDecision, ContentFilter, Eligibility, EligibilityPolicy, RequestInfo, TransferRequest,
+};
+use crate::protocol::{
+    self, Capabilities, Message, FEATURE_BLAKE3, FEATURE_BUSY, FEATURE_CANCEL, FEATURE_COMPRESSION,
+    FEATURE_CONSTRAINTS, FEATURE_FRAME_COMPRESSION, MAX_PROTOCOL_VERSION, MIN_PROTOCOL_VERSION,
+    PROTOCOL_VERSION, SUPPORTED_FEATURES,
+};
 use crate::scheduler;
+use crate::scheduler::{AssignmentPlan, DefaultScheduler, Scheduler, Workers};
+pub use crate::scheduler::{Constraints, PeerMetrics};
 use crate::wire;
-use crate::wire::FrameDecodeError;
 
-const HEARTBEAT_TIMEOUT_TICKS: u64 = 5;
+/// Events kept for the host before the oldest are dropped (host should drain regularly).
+const MAX_PENDING_EVENTS: usize = 1024;
+
+/// Context the pod key is sealed under in [`TrustStore::pod_key`].
+const POD_KEY_CONTEXT: &[u8] = b"peapod-pod-key";
+
+/// Configuration for chunking, timeouts, and pod size (use `Config::default()` when not set).
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Config {
+    /// Chunk size in bytes for new transfers (0 means [`DEFAULT_CHUNK_SIZE`]).
+    pub chunk_size: u64,
+    /// Size each worker's chunks from its measured delivery rate (about
+    /// [`scheduler::ADAPTIVE_CHUNK_TARGET_SECS`] of work, within `min_chunk_size..=max_chunk_size`).
+    /// Workers without a measurement get `chunk_size`. When no worker has one, chunks are uniform.
+    pub adaptive_chunk_size: bool,
+    /// Smallest adaptive chunk size in bytes.
+    pub min_chunk_size: u64,
+    /// Largest adaptive chunk size in bytes.
+    pub max_chunk_size: u64,
+    /// Wall-clock length of one tick in milliseconds, used by [`PeaPodCore::tick_at`]; all
+    /// `*_ticks` settings are multiples of it.
+    pub tick_interval_ms: u64,
+    /// Ticks after which an outstanding chunk request is considered lost and reassigned.
+    pub chunk_timeout_ticks: u64,
+    /// Times a chunk is retried after a timeout, integrity failure, Nack or failed local fetch; one
+    /// more failure fails the chunk ([`CoreEvent::ChunkFailed`]) and its transfer falls back to
+    /// the origin ([`OutboundAction::AbortToFallback`]). 0 means unlimited.
+    pub chunk_retries: u32,
+    /// Ticks to wait before the first retry of a timed-out chunk, doubled for each further retry
+    /// (at most `chunk_timeout_ticks`). 0 retries immediately.
+    pub retry_backoff_ticks: u64,
+    /// Percentage of a transfer's chunks that may fail integrity or time out before the core gives
+    /// up on acceleration and hands the host the verified ranges
+    /// ([`OutboundAction::PartialFallback`]). 0 disables.
+    pub fallback_failure_percent: u64,
+    /// Ticks between Heartbeats to peers (0 is treated as 1). Keep it well below
+    /// `heartbeat_timeout_ticks` so peers do not time this device out.
+    pub heartbeat_interval_ticks: u64,
+    /// Ticks without a heartbeat after which a peer is treated as left.
+    pub heartbeat_timeout_ticks: u64,
+    /// Maximum number of peers in the pod; further joins are refused unless `peer_eviction`
+    /// makes room.
+    pub max_peers: usize,
+    /// Which peer (if any) a join evicts when the pod is full.
+    pub peer_eviction: PeerEviction,
+    /// Memory budget per transfer: buffered (not yet taken) plus in-flight chunk bytes. Further
+    /// ChunkRequests are held back until the host takes data or chunks arrive.
+    pub max_transfer_buffer_bytes: u64,
+    /// Memory budget across all transfers of this core (same accounting as the per-transfer cap).
+    pub max_buffer_bytes: u64,
+    /// Largest request (in bytes of its range) the core accelerates; bigger ones fall back so a
+    /// single huge download cannot pin host memory. Hosts that want them accelerated can issue
+    /// sequential Range sub-requests of at most this size. 0 means no cap.
+    pub max_transfer_bytes: u64,
+    /// Integrity failures after which a peer is isolated (excluded from assignment); 0 disables.
+    pub max_integrity_failures: u32,
+    /// When a peer's chunk fails verification, also request it from a second worker besides the
+    /// one it is reassigned to. If two other sources agree on the chunk and the sender's hash
+    /// claimed different content, the sender was poisoning rather than hit by corruption and is
+    /// isolated at once ([`CoreEvent::ChunkPoisoned`]).
+    pub dispute_quorum: bool,
+    /// Endgame: once this many chunks or fewer are outstanding (and all are requested), each is
+    /// also requested from every other worker; the first verified copy wins. 0 disables.
+    pub endgame_chunks: usize,
+    /// Streaming transfers (see [`PeaPodCore::set_streaming`]): this many missing chunks at the
+    /// play position are also requested from the fastest other worker; the first verified copy
+    /// wins. 0 disables the duplicates.
+    pub stream_head_chunks: usize,
+    /// Straggler racing: a chunk whose peer has taken more than this many times its expected
+    /// delivery time (chunk length over the peer's measured delivery rate) is also requested from
+    /// the fastest other worker. The first verified copy wins and a late peer that loses is
+    /// charged a failure. 0 disables.
+    pub straggler_factor: u64,
+    /// Ticks after which half of a device's counted chunk failures are forgiven (repeated while
+    /// it has no new failures), so old failures stop reducing its share or excluding it. 0 keeps
+    /// them forever.
+    pub failure_half_life_ticks: u64,
+    /// Maximum ChunkRequests in flight to one peer (across transfers); further requests are
+    /// issued as that peer's chunks verify. Chunks this device fetches itself are not windowed.
+    /// 0 means unlimited.
+    pub peer_window: usize,
+    /// Work stealing: on each tick, chunks still waiting for room in a peer's window may move to
+    /// an idle peer (see [`Scheduler::rebalance`]) and are requested from it.
+    pub work_stealing: bool,
+    /// Percent of each accelerated transfer's bytes this device fetches itself (0–100; larger
+    /// values count as 100). It takes the start of the body and the peers share the rest, so a
+    /// battery-constrained or slow device can leave most of the work to the pod. `None` treats
+    /// this device like any other worker.
+    pub self_share_percent: Option<u8>,
+    /// Fast start: this device always fetches the first chunk of an accelerated transfer itself,
+    /// so time to first byte never waits on a peer round trip. The other chunks are assigned as
+    /// usual (including [`self_share_percent`](Self::self_share_percent)).
+    pub self_first_chunk: bool,
+    /// Send the client request headers the host passed to [`PeaPodCore::set_transfer_headers`]
+    /// (cookies, authorization) with ChunkRequests, so peers fetch origins that need them as the
+    /// client would. Off by default: the headers are credentials.
+    pub forward_request_headers: bool,
+    /// zstd-compress ChunkData served to peers that advertise support when that saves at least an
+    /// eighth of the payload (text, JSON, uncompressed assets); other payloads go as they are.
+    pub compress_chunks: bool,
+    /// zstd-compress whole ChunkData and ChunkRequest frames for peers that advertise
+    /// [`FEATURE_FRAME_COMPRESSION`] when that saves at least an eighth of the frame.
+    pub compress_frames: bool,
+    /// Hash ChunkData served to peers that advertise [`FEATURE_BLAKE3`] with BLAKE3 instead of
+    /// SHA-256 (much cheaper on phones); other peers always get SHA-256.
+    pub blake3_hashes: bool,
+    /// ChunkRequests served per peer per tick; excess requests are answered with a Nack. 0 means
+    /// unlimited.
+    pub serve_requests_per_tick: u32,
+    /// Bytes served per peer per tick (same handling as the request quota). 0 means unlimited.
+    pub serve_bytes_per_tick: u64,
+    /// How to treat ChunkRequests from peers that take more than they give (see [`PeerCredit`]).
+    pub reciprocity: ReciprocityPolicy,
+    /// When this device serves peers at all; requests outside it are answered with Busy.
+    pub contribution: ContributionPolicy,
+    /// Bytes a peer may receive from this device beyond what it has contributed before
+    /// `reciprocity` applies.
+    pub freeloader_grace_bytes: u64,
+    /// Size of the LRU cache of verified chunks (own transfers and ranges fetched for peers),
+    /// keyed by URL, range and validator. Repeated requests are answered from it without another
+    /// WAN fetch. 0 disables the cache.
+    pub chunk_cache_bytes: u64,
+    /// Ticks a transfer or upload may go without progress (verified chunks, taken segments,
+    /// acknowledged ranges, pause or resume) before its state is reaped as abandoned
+    /// ([`CoreEvent::TransferReaped`]). Paused transfers are included. 0 disables.
+    pub stale_transfer_ticks: u64,
+    /// Finished transfers kept in [`PeaPodCore::history`], oldest dropped first. 0 keeps none.
+    pub history_len: usize,
+    /// Ticks a transfer may run before the core gives up on acceleration and tells the host to
+    /// finish the download directly ([`OutboundAction::AbortToFallback`]); time spent paused does
+    /// not count. 0 (the default) means no deadline, since a large download may rightly take long.
+    pub transfer_deadline_ticks: u64,
+    /// Hold JoinRequests from peers the host has not approved (see [`PeaPodCore::approve_peer`])
+    /// instead of admitting every authenticated neighbor.
+    pub require_approval: bool,
+    /// Admit only peers paired by comparing codes ([`PeaPodCore::confirm_pairing`]); pairing then
+    /// stands in for host approval.
+    pub require_pairing: bool,
+    /// Ticks a peer's old identity stays valid after it rotated its key
+    /// ([`PeaPodCore::rotate_keypair`]); then the old device ID is revoked.
+    pub key_rotation_grace_ticks: u64,
+    /// This device's human-readable name ("Ana's laptop"), signed into Beacons and Joins so
+    /// peers can show it ([`PeaPodCore::peer_name`]). Cut to
+    /// [`crate::identity::MAX_DEVICE_NAME_LEN`] bytes.
+    pub device_name: Option<String>,
+    /// Once this device shares a pod key with paired devices, send Beacons and
+    /// DiscoveryResponses sealed with it ([`Message::SealedDiscovery`]), so devices outside the
+    /// pod cannot list its members. Unpaired devices always send them in the clear.
+    pub confidential_discovery: bool,
+    /// Accept Beacons and DiscoveryResponses from devices of other pods, so pods on one LAN can
+    /// find each other (and merge, unless pairing or approval is required). Off by default: a
+    /// paired device ignores plain discovery frames naming a pod other than its own. Frames
+    /// sealed under [`Config::confidential_discovery`] by another pod still cannot be read.
+    pub open_pod: bool,
+    /// Which requests may be accelerated (domain lists, size and type filters, never-share
+    /// exclusions); see [`PeaPodCore::evaluate_request`].
+    pub eligibility: EligibilityPolicy,
+}
+
+/// Policy for serving peers whose credit balance is below `-freeloader_grace_bytes`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ReciprocityPolicy {
+    /// Serve everyone alike (within the serve quotas).
+    #[default]
+    Off,
+    /// Serve at most one ChunkRequest per tick; the rest are Nacked.
+    Deprioritize,
+    /// Nack every ChunkRequest until the peer contributes again.
+    Decline,
+}
+
+/// When this device serves peers' ChunkRequests. Besides these limits the host can pause serving
+/// with [`PeaPodCore::set_do_not_disturb`] (e.g. under high load or while gaming).
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ContributionPolicy {
+    /// Local-time windows `(start, end)`, in minutes since midnight, in which peers are served; a
+    /// window with `end <= start` wraps past midnight. Empty serves at any time. Enforced once the
+    /// host reports the time with [`PeaPodCore::set_local_time`].
+    pub serve_windows: Vec<(u16, u16)>,
+    /// Bytes served to peers per local day (0 means unlimited).
+    pub daily_byte_budget: u64,
+}
+
+impl ContributionPolicy {
+    /// Whether `minute` (since midnight) falls in a serve window.
+    pub fn in_window(&self, minute: u16) -> bool {
+        self.serve_windows.is_empty()
+            || self.serve_windows.iter().any(|&(start, end)| {
+                if start < end {
+                    (start..end).contains(&minute)
+                } else {
+                    minute >= start || minute < end
+                }
+            })
+    }
+}
+
+/// Minutes in a day, for [`ContributionPolicy`] windows.
+const MINUTES_PER_DAY: u64 = 24 * 60;
+
+/// Ticks a peer that answered Busy gets no new chunks.
+const BUSY_BACKOFF_TICKS: u64 = 30;
+
+/// What a join does when the pod already has `max_peers` peers. Peers still assigned chunks of an
+/// active transfer are never evicted.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PeerEviction {
+    /// Refuse the newcomer.
+    #[default]
+    Refuse,
+    /// Evict the peer heard from least recently (not one heard from in the current tick).
+    LeastRecentlySeen,
+    /// Evict the peer with the lowest scheduling weight ([`PeerMetrics::weight`]), if it scores
+    /// below a peer with no history; otherwise refuse.
+    LowestScore,
+}
+
+/// Bytes exchanged with one peer, kept across leave/rejoin.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerCredit {
+    /// Bytes this device fetched and served to the peer.
+    pub bytes_served: u64,
+    /// Verified bytes the peer delivered to this device.
+    pub bytes_received: u64,
+}
+
+impl PeerCredit {
+    /// Contribution balance from this device's view: positive when the peer gave more than it took.
+    pub fn balance(&self) -> i64 {
+        (self.bytes_received as i128 - self.bytes_served as i128)
+            .clamp(i64::MIN as i128, i64::MAX as i128) as i64
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            adaptive_chunk_size: true,
+            min_chunk_size: 128 * 1024,
+            max_chunk_size: 4 * 1024 * 1024,
+            tick_interval_ms: 1000,
+            chunk_timeout_ticks: 10,
+            chunk_retries: 5,
+            retry_backoff_ticks: 1,
+            fallback_failure_percent: 50,
+            heartbeat_interval_ticks: 1,
+            heartbeat_timeout_ticks: 5,
+            max_peers: 64,
+            peer_eviction: PeerEviction::Refuse,
+            max_transfer_buffer_bytes: 64 * 1024 * 1024,
+            max_buffer_bytes: 256 * 1024 * 1024,
+            max_transfer_bytes: 4 * 1024 * 1024 * 1024,
+            max_integrity_failures: 3,
+            dispute_quorum: false,
+            endgame_chunks: 4,
+            stream_head_chunks: 2,
+            straggler_factor: 3,
+            failure_half_life_ticks: 600,
+            peer_window: 4,
+            work_stealing: true,
+            self_share_percent: None,
+            self_first_chunk: false,
+            forward_request_headers: false,
+            compress_chunks: true,
+            compress_frames: true,
+            blake3_hashes: true,
+            serve_requests_per_tick: 64,
+            serve_bytes_per_tick: 16 * 1024 * 1024,
+            reciprocity: ReciprocityPolicy::Off,
+            contribution: ContributionPolicy::default(),
+            freeloader_grace_bytes: 64 * 1024 * 1024,
+            chunk_cache_bytes: 16 * 1024 * 1024,
+            stale_transfer_ticks: 600,
+            history_len: 100,
+            transfer_deadline_ticks: 0,
+            require_approval: false,
+            require_pairing: false,
+            key_rotation_grace_ticks: 86_400,
+            device_name: None,
+            confidential_discovery: true,
+            open_pod: false,
+            eligibility: EligibilityPolicy::default(),
+        }
+    }
+}
+
+/// A peer currently in the pod, as seen by the core.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PeerInfo {
+    pub device_id: DeviceId,
+    /// Public key the peer announced when joining (used for session-key derivation).
+    pub public_key: PublicKey,
+    /// Core tick at which the peer was last heard from (join or heartbeat).
+    pub last_seen: u64,
+    /// Metrics set by the host via [`PeaPodCore::set_peer_metrics`].
+    pub metrics: PeerMetrics,
+    /// Capabilities the peer advertised in its JoinRequest or JoinAccept (default until one arrives).
+    pub capabilities: Capabilities,
+    /// WAN uplink the host reported for the peer ([`PeaPodCore::set_peer_uplink`]).
+    #[serde(default)]
+    pub uplink: Option<String>,
+    /// Core tick until which the peer gets no new chunks because it answered Busy.
+    #[serde(default)]
+    pub busy_until: u64,
+}
+
+/// Where a device stands with this pod: seen on the LAN, invited by this device, admitted, or
+/// gone. Devices only get work and are only served once `Joined`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Membership {
+    /// Seen in discovery, or asked to join and awaiting the host's approval.
+    Discovered,
+    /// This device sent a JoinRequest and is waiting for the peer's JoinAccept.
+    Invited,
+    /// In the pod.
+    Joined,
+    /// Left (Leave, disconnect or heartbeat timeout).
+    Left,
+}
 
-/// Configuration for timeouts and peer trust (optional; use defaults when not set).
-#[derive(Clone, Debug, Default)]
-pub struct Config {}
+/// A device that is not (or no longer) in the pod.
+#[derive(Clone, Debug)]
+struct Candidate {
+    state: Membership,
+    public_key: PublicKey,
+    /// Capabilities from the peer's pending JoinRequest, if it sent one.
+    requested: Option<Capabilities>,
+}
 
-/// Optional per-peer metrics for scheduler weighting.
-#[derive(Clone, Debug, Default)]
-pub struct PeerMetrics {
-    /// Estimated bandwidth in bytes per second; higher gives more chunks.
-    pub bandwidth_bytes_per_sec: Option<u64>,
-    /// Latency in milliseconds (for future use).
-    pub latency_ms: Option<u32>,
+/// Commit-then-reveal of the nonces a pairing code is derived from (see [`crate::pairing`]). This
+/// device reveals its nonce only once it holds the peer's commitment.
+#[derive(Clone, Debug)]
+struct SasExchange {
+    nonce: [u8; 32],
+    revealed: bool,
+    peer_commitment: Option<[u8; 32]>,
+    peer_nonce: Option<[u8; 32]>,
 }
 
-/// Stub for upload path (split outbound into chunks; full impl later).
+/// Split an outbound body of `data_len` bytes into upload ranges (see [`PeaPodCore::start_upload`]).
 pub fn split_upload_chunks(transfer_id: [u8; 16], data_len: u64, chunk_size: u64) -> Vec<ChunkId> {
     chunk::split_into_chunks(transfer_id, data_len, chunk_size)
 }
 
+/// A chunk whose copy from `sender` failed verification (see [`Config::dispute_quorum`]).
+struct Dispute {
+    sender: DeviceId,
+    /// Algorithm and hash the sender claimed for the chunk.
+    algorithm: HashAlgorithm,
+    claimed: [u8; 32],
+    /// Verified copies from other sources: device and digest (with `algorithm`).
+    copies: Vec<(DeviceId, [u8; 32])>,
+    since: u64,
+}
+
+/// Format version of [`PeaPodCore::snapshot`]; bumped when the layout changes.
+const SNAPSHOT_VERSION: u8 = 1;
+
+/// Persistent part of the core (see [`PeaPodCore::snapshot`]). Events, serve bookkeeping, uploads
+/// and the chunk cache are not kept.
+/// Borrowed when writing so chunk payloads are not copied.
+#[derive(Serialize, Deserialize)]
+struct Snapshot<'a> {
+    version: u8,
+    config: Cow<'a, Config>,
+    peers: Cow<'a, [PeerInfo]>,
+    tick_count: u64,
+    transfers: Cow<'a, HashMap<[u8; 16], ActiveTransfer>>,
+    self_metrics: Cow<'a, PeerMetrics>,
+    capabilities: Cow<'a, Capabilities>,
+    trust: Cow<'a, PeerTrustTracker>,
+    ledger: Cow<'a, HashMap<DeviceId, PeerCredit>>,
+}
+
+/// A FetchChunk the host is working on, with every (peer, transfer) waiting for its bytes.
+struct PendingServe {
+    waiters: Vec<(DeviceId, [u8; 16])>,
+    /// Tick the fetch was issued; dropped after `chunk_timeout_ticks` if the host never reports.
+    since: u64,
+}
+
+/// An upload this device started (see [`PeaPodCore::start_upload`]).
+struct ActiveUpload {
+    url: String,
+    body: Vec<u8>,
+    /// Device uploading each range not yet acknowledged.
+    pending: HashMap<ChunkId, DeviceId>,
+    /// Tick of the latest acknowledged range (see [`Config::stale_transfer_ticks`]).
+    last_activity: u64,
+}
+
+/// A peer's [`Message::UploadOffer`]: where its ranges go.
+struct UploadOffer {
+    url: String,
+    total_length: u64,
+    /// Tick of the offer or its latest UploadData; dropped after `chunk_timeout_ticks` of silence.
+    last_used: u64,
+}
+
 /// Active transfer: state and assignment.
+#[derive(Clone, Serialize, Deserialize)]
 struct ActiveTransfer {
+    /// Origin URL; included in ChunkRequests so peers can fetch from WAN.
+    url: String,
+    /// Origin validator (ETag or Last-Modified); part of the chunk cache key.
+    validator: Option<String>,
+    /// Client request headers to forward with ChunkRequests (see
+    /// [`Config::forward_request_headers`]); not kept in snapshots.
+    #[serde(skip)]
+    headers: Vec<(String, String)>,
     state: TransferState,
     assignment: Vec<(ChunkId, DeviceId)>,
+    /// Tick at which each outstanding chunk was last requested (for chunk timeouts).
+    requested_at: HashMap<ChunkId, u64>,
+    /// Paused transfers keep received chunks but do not time out or emit ChunkRequests.
+    paused: bool,
+    /// Tick of the latest pause; resuming moves the deadline on by the time spent paused.
+    paused_at: u64,
+    /// Tick at which the transfer started (for elapsed time in progress reports).
+    started_at_tick: u64,
+    /// Tick of the latest progress (see [`Config::stale_transfer_ticks`]).
+    last_activity: u64,
+    /// Tick after which the transfer is aborted to fallback (`None`: no deadline).
+    deadline_tick: Option<u64>,
+    /// Verified bytes delivered per device (self or peer).
+    contributions: HashMap<DeviceId, u64>,
+    /// Chunks whose ChunkRequest is held back by the memory budget, keyed by start offset.
+    deferred: BTreeMap<u64, ChunkId>,
+    /// Chunks waiting for room in their peer's in-flight window, keyed by start offset.
+    queued: BTreeMap<u64, ChunkId>,
+    /// Whether the host has been told this transfer is throttled (see [`CoreEvent::Backpressure`]).
+    backpressured: bool,
+    /// Endgame duplicates: devices asked for a chunk in addition to its assigned peer.
+    endgame: HashMap<ChunkId, Vec<DeviceId>>,
+    /// Chunks raced against a second worker because their assigned peer was late.
+    raced: HashSet<ChunkId>,
+    /// Chunks left unrequested because the same URL range is in flight for another transfer,
+    /// with that transfer's chunk; its verified copy is shared when it arrives.
+    shared: HashMap<ChunkId, ChunkId>,
+    /// Timed-out chunks waiting out their backoff, with the tick their retry is issued.
+    retry_at: HashMap<ChunkId, u64>,
+    /// Chunks that failed integrity or timed out at least once (see
+    /// [`Config::fallback_failure_percent`]).
+    failed: HashSet<ChunkId>,
+    /// Trusted block hashes of the whole body, checked on completion (`None`: chunk hashes only).
+    manifest: Option<Manifest>,
+    /// Whole-body digests the origin published, checked on completion.
+    origin_digests: Vec<OriginDigest>,
+    /// Played as it downloads: chunks are ordered by play-position deadline (see
+    /// [`PeaPodCore::set_streaming`]).
+    streaming: bool,
+}
+
+impl ActiveTransfer {
+    fn peer_for(&self, chunk_id: ChunkId) -> Option<DeviceId> {
+        self.assignment
+            .iter()
+            .find(|(c, _)| *c == chunk_id)
+            .map(|(_, p)| *p)
+    }
+
+    /// Verified bytes delivered by each device, largest contributor first.
+    fn per_peer_bytes(&self) -> Vec<(DeviceId, u64)> {
+        let mut per_peer: Vec<(DeviceId, u64)> =
+            self.contributions.iter().map(|(&p, &b)| (p, b)).collect();
+        per_peer.sort_by_key(|&(_, b)| std::cmp::Reverse(b));
+        per_peer
+    }
+
+    /// Forget requests in flight (endgame duplicates, races, retries, shares with other transfers)
+    /// and queue every chunk not yet received to be requested again.
+    fn requeue_missing(&mut self) {
+        self.requested_at.clear();
+        self.endgame.clear();
+        self.raced.clear();
+        self.shared.clear();
+        self.retry_at.clear();
+        self.deferred.clear();
+        self.queued = self
+            .assignment
+            .iter()
+            .map(|&(c, _)| c)
+            .filter(|&c| !self.state.is_chunk_received(c))
+            .map(|c| (c.start, c))
+            .collect();
+    }
+
+    /// Verified bytes held in memory: not taken via `take_segment` and not in a sink.
+    fn buffered_bytes(&self) -> u64 {
+        self.state.held_bytes()
+    }
+
+    /// Bytes the transfer may hold: buffered payloads plus requests still in flight.
+    fn memory_bytes(&self) -> u64 {
+        let in_flight: u64 = self.requested_at.keys().map(|c| c.end - c.start).sum();
+        self.buffered_bytes() + in_flight
+    }
+}
+
+/// Snapshot of an active transfer's progress (see [`PeaPodCore::transfer_progress`]).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TransferProgress {
+    pub total_length: u64,
+    /// Verified bytes received so far.
+    pub bytes_received: u64,
+    pub chunks_total: usize,
+    /// Chunks not yet received and verified.
+    pub chunks_outstanding: usize,
+    /// Verified bytes delivered by each device (self included), largest contributor first.
+    pub per_peer_bytes: Vec<(DeviceId, u64)>,
+    /// Ticks since the transfer started.
+    pub elapsed_ticks: u64,
+    pub paused: bool,
+    /// Verified bytes held in memory and not yet taken by the host.
+    pub buffered_bytes: u64,
+    /// True while ChunkRequests are held back by the memory budget.
+    pub backpressured: bool,
+}
+
+/// How a transfer in [`PeaPodCore::history`] ended.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TransferOutcome {
+    /// The body was reassembled and verified.
+    Completed,
+    /// Acceleration was given up and the host told to fetch directly (deadline, failed chunks,
+    /// root mismatch, shutdown).
+    Fallback,
+    /// The host abandoned the transfer, or it made no progress for `stale_transfer_ticks`.
+    Abandoned,
+}
+
+/// A finished transfer (see [`PeaPodCore::history`]).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TransferRecord {
+    pub transfer_id: [u8; 16],
+    pub url: String,
+    pub total_length: u64,
+    /// Verified bytes received before the transfer ended.
+    pub bytes_received: u64,
+    /// Verified bytes delivered by each device (self included), largest contributor first.
+    pub per_peer_bytes: Vec<(DeviceId, u64)>,
+    /// Ticks from start to end.
+    pub elapsed_ticks: u64,
+    pub outcome: TransferOutcome,
+}
+
+/// Trust decisions for the host to persist across restarts (see [`PeaPodCore::export_trust`]).
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TrustStore {
+    /// Pinned public keys ([`PeaPodCore::known_peers`]).
+    pub known_peers: Vec<(DeviceId, PublicKey)>,
+    /// Devices whose pairing code both users confirmed.
+    pub paired: Vec<DeviceId>,
+    /// Devices the host revoked.
+    pub revoked: Vec<DeviceId>,
+    /// Integrity history and isolation ([`PeaPodCore::peer_trust`]).
+    pub trust: Vec<(DeviceId, PeerTrust)>,
+    /// Peers' key rotations still in their grace period.
+    #[serde(default)]
+    pub rotations: Vec<KeyRotation>,
+    /// Signed revocations ([`PeaPodCore::revocations`]), to share with the user's other devices.
+    #[serde(default)]
+    pub revocations: Vec<Revocation>,
+    /// The pod key, sealed with this device's storage key
+    /// ([`crate::identity::KeyAgreement::storage_key`]) so the store itself holds no secret.
+    #[serde(default)]
+    pub pod_key: Option<Vec<u8>>,
+}
+
+/// A peer moved from `previous` to `current` device ID ([`Message::RotateKey`]); both are
+/// accepted until the grace period ends, then `previous` is revoked.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyRotation {
+    pub previous: DeviceId,
+    pub current: DeviceId,
+    /// Ticks left in the grace period.
+    pub remaining_ticks: u64,
+}
+
+/// How [`PeaPodCore::import_trust`] settles saved records that conflict with the core's own.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TrustMerge {
+    /// The more cautious record wins: revocations and isolation from either side stand, the
+    /// larger integrity failure count is kept, and pins and pairings are added only for devices
+    /// not revoked. Existing pins are never replaced.
+    #[default]
+    Cautious,
+    /// Saved records replace the core's for every device the store lists (e.g. a store the user
+    /// edited to lift a revocation).
+    Replace,
+}
+
+/// Traffic counters since the core was created or [`PeaPodCore::reset_stats`] (see
+/// [`PeaPodCore::stats`]). Only verified chunks count.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TrafficStats {
+    /// Bytes of this device's transfers it fetched over its own WAN link.
+    pub bytes_fetched_locally: u64,
+    /// Bytes of this device's transfers answered from the chunk cache.
+    pub bytes_from_cache: u64,
+    /// Bytes of this device's transfers delivered by each peer.
+    pub bytes_from_peers: HashMap<DeviceId, u64>,
+    /// Bytes this device sent to each peer in ChunkData.
+    pub bytes_served: HashMap<DeviceId, u64>,
+}
+
+impl TrafficStats {
+    /// Bytes this device received without fetching them itself (from peers or the cache): the WAN
+    /// data saved.
+    pub fn bytes_saved(&self) -> u64 {
+        self.bytes_from_peers
+            .values()
+            .fold(self.bytes_from_cache, |sum, &b| sum.saturating_add(b))
+    }
+
+    /// Estimated speedup: bytes delivered to this device's transfers divided by the bytes it
+    /// fetched itself, i.e. how much longer the same downloads would have taken over this
+    /// device's link alone (assuming links of similar speed). `None` until this device fetched
+    /// something.
+    pub fn speedup(&self) -> Option<f64> {
+        if self.bytes_fetched_locally == 0 {
+            return None;
+        }
+        let total = self
+            .bytes_fetched_locally
+            .saturating_add(self.bytes_saved());
+        Some(total as f64 / self.bytes_fetched_locally as f64)
+    }
+}
+
+/// Expected completion time of a download with and without acceleration (see
+/// [`PeaPodCore::estimate_savings`]).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SavingsEstimate {
+    /// Milliseconds over this device's link alone (its bandwidth taken as the pod's mean while
+    /// unmeasured); `None` when no bandwidth is known.
+    pub direct_ms: Option<u64>,
+    /// Milliseconds when the pod shares the download; `None` when no worker's bandwidth is known.
+    pub accelerated_ms: Option<u64>,
+    /// Workers (this device included) expected to take part.
+    pub workers: usize,
+}
+
+impl SavingsEstimate {
+    /// Expected speedup (e.g. 2.4 for "2.4× faster"); `None` unless both times are known.
+    pub fn speedup(&self) -> Option<f64> {
+        match (self.direct_ms, self.accelerated_ms) {
+            (Some(direct), Some(accelerated)) if accelerated > 0 => {
+                Some(direct as f64 / accelerated as f64)
+            }
+            (Some(_), Some(_)) => Some(1.0),
+            _ => None,
+        }
+    }
+}
+
+/// Fold a rate sample into a smoothed rate (the previous estimate weighs three times the sample).
+fn smooth_rate(rate: u64, sample: u64) -> u64 {
+    (rate.saturating_mul(3).saturating_add(sample)) / 4
+}
+
+/// Milliseconds to move `bytes` at `rate` bytes per second (`None` for an unknown rate).
+fn transfer_ms(bytes: u64, rate: u64) -> Option<u64> {
+    (rate > 0).then(|| {
+        (bytes as u128 * 1000)
+            .div_ceil(rate as u128)
+            .min(u64::MAX as u128) as u64
+    })
 }
 
 /// Main coordinator. The host passes events (request metadata, peer join/leave, messages, chunk data);
 /// the core returns actions (chunk assignment, messages to send). No I/O inside the core.
 pub struct PeaPodCore {
-    keypair: Arc<Keypair>,
-    peers: Vec<DeviceId>,
-    peer_last_tick: HashMap<DeviceId, u64>,
+    keypair: Arc<dyn DeviceKey>,
+    config: Config,
+    peers: Vec<PeerInfo>,
     tick_count: u64,
-    active_transfer: Option<ActiveTransfer>,
-    /// Optional metrics per peer (and self) for weighted chunk assignment.
-    peer_metrics: HashMap<DeviceId, PeerMetrics>,
+    /// Host clock reading (ms) at tick 0, set by the first [`PeaPodCore::tick_at`].
+    clock_origin_ms: Option<u64>,
+    /// Tick at or after which the next round of Heartbeats is sent.
+    next_heartbeat_tick: u64,
+    transfers: HashMap<[u8; 16], ActiveTransfer>,
+    /// Optional metrics for this device; peer metrics live in [`PeerInfo`].
+    self_metrics: PeerMetrics,
+    /// This device's capabilities, advertised in [`PeaPodCore::join_frame`].
+    capabilities: Capabilities,
+    /// Integrity failures per peer; isolated peers get no chunks.
+    trust: PeerTrustTracker,
+    /// ChunkRequests and bytes served to each peer in the current tick (fair-share quotas).
+    serve_usage: HashMap<DeviceId, (u32, u64)>,
+    /// Fetches issued for peers' ChunkRequests, deduplicated by URL and range.
+    pending_serves: HashMap<CacheKey, PendingServe>,
+    /// Verified chunk payloads (bounded by `chunk_cache_bytes`).
+    cache: ChunkCache,
+    /// Bytes served to and received from each peer (reciprocity).
+    ledger: HashMap<DeviceId, PeerCredit>,
+    /// Peers that have been sent this device's current constraints.
+    constraints_told: HashSet<DeviceId>,
+    /// Protocol version settled with each connected peer by Hello (absent: [`PROTOCOL_VERSION`]).
+    peer_versions: HashMap<DeviceId, u8>,
+    /// Peers sent a Hello on their current connection.
+    hello_sent: HashSet<DeviceId>,
+    /// Peers that have been sent this device's current capabilities.
+    capabilities_told: HashSet<DeviceId>,
+    /// Capabilities announced by connected devices that are not members yet.
+    announced_capabilities: HashMap<DeviceId, Capabilities>,
+    /// Membership of devices not in `peers` (discovered, invited or left).
+    candidates: HashMap<DeviceId, Candidate>,
+    /// Chunks that failed verification, awaiting copies from other sources (see
+    /// [`Config::dispute_quorum`]).
+    disputes: HashMap<ChunkId, Dispute>,
+    /// Pairing code exchanges with unpaired peers, restarted on each connection.
+    sas: HashMap<DeviceId, SasExchange>,
+    /// Pairings in progress: (this device's user confirmed, peer's confirmation verified).
+    pairings: HashMap<DeviceId, (bool, bool)>,
+    /// Peers whose pairing code both users confirmed.
+    paired: HashSet<DeviceId>,
+    /// Known peers: public key pinned on first join, imported, or trusted by the host.
+    known_peers: HashMap<DeviceId, PublicKey>,
+    /// Verified names of devices seen in discovery or joins ([`PeaPodCore::peer_name`]).
+    names: HashMap<DeviceId, String>,
+    /// Metrics of peers that left or were imported; restored when they join.
+    known_metrics: HashMap<DeviceId, PeerMetrics>,
+    /// Devices the host revoked; refused until trusted again.
+    revoked: HashSet<DeviceId>,
+    /// Signed revocations by revoked device ID ([`PeaPodCore::revoke_device`]).
+    revocations: HashMap<DeviceId, Revocation>,
+    /// Peers' rotated keys by new device ID: (old device ID, tick the old one is revoked).
+    rotations: HashMap<DeviceId, (DeviceId, u64)>,
+    /// Key shared with paired devices that seals discovery frames.
+    pod_key: Option<PodKey>,
+    /// Replaced pod keys, newest first (at most [`POD_KEY_HISTORY`]); still open discovery frames.
+    previous_pod_keys: Vec<PodKey>,
+    /// Pod keys peers sent with their PairConfirm, settled when the pairing completes.
+    offered_pod_keys: HashMap<DeviceId, PodKey>,
+    /// WAN uplink of this device (see [`PeaPodCore::set_peer_uplink`]).
+    uplink: Option<String>,
+    /// Serving paused by the host ([`PeaPodCore::set_do_not_disturb`]).
+    do_not_disturb: bool,
+    /// Local time reported by the host, in minutes since the Unix epoch.
+    local_minutes: Option<u64>,
+    /// Bytes served to peers on the current local day (see [`ContributionPolicy::daily_byte_budget`]).
+    served_today: u64,
+    /// Traffic counters for host UIs.
+    stats: TrafficStats,
+    /// Uploads this device started, by upload ID.
+    uploads: HashMap<[u8; 16], ActiveUpload>,
+    /// Uploads peers offered this device, by (peer, upload ID).
+    upload_offers: HashMap<(DeviceId, [u8; 16]), UploadOffer>,
+    /// State changes not yet drained by the host (see [`PeaPodCore::drain_events`]).
+    events: VecDeque<CoreEvent>,
+    /// Recently finished transfers, oldest first (see [`Config::history_len`]).
+    history: VecDeque<TransferRecord>,
+    /// Host hook that vetoes or rewrites eligible requests (see [`PeaPodCore::set_content_filter`]).
+    content_filter: Option<Box<dyn ContentFilter>>,
+    /// Chunk placement policy (see [`PeaPodCore::with_scheduler`]).
+    scheduler: Box<dyn Scheduler>,
 }
 
 impl PeaPodCore {
     pub fn new() -> Self {
-        Self {
-            keypair: Arc::new(Keypair::generate()),
-            peers: Vec::new(),
-            peer_last_tick: HashMap::new(),
-            tick_count: 0,
-            active_transfer: None,
-            peer_metrics: HashMap::new(),
-        }
+        Self::with_keypair(Keypair::generate())
     }
 
     pub fn with_keypair(keypair: Keypair) -> Self {
-        Self {
-            keypair: Arc::new(keypair),
-            peers: Vec::new(),
-            peer_last_tick: HashMap::new(),
-            tick_count: 0,
-            active_transfer: None,
-            peer_metrics: HashMap::new(),
-        }
+        Self::with_keypair_arc(Arc::new(keypair))
+    }
+
+    /// Same as with_keypair but takes a shared key so the host can share it (e.g. with discovery);
+    /// an `Arc<Keypair>`, or a host [`DeviceKey`] backed by hardware.
+    pub fn with_keypair_arc(keypair: Arc<dyn DeviceKey>) -> Self {
+        Self::with_keypair_arc_and_config(keypair, Config::default())
     }
 
-    /// Same as with_keypair but takes Arc<Keypair> so the host can share the keypair (e.g. with discovery).
-    pub fn with_keypair_arc(keypair: Arc<Keypair>) -> Self {
+    /// Create a core with a fresh keypair and the given config.
+    pub fn with_config(config: Config) -> Self {
+        Self::with_keypair_arc_and_config(Arc::new(Keypair::generate()), config)
+    }
+
+    /// Create a core with a shared keypair (or other [`DeviceKey`]) and the given config.
+    pub fn with_keypair_arc_and_config(keypair: Arc<dyn DeviceKey>, config: Config) -> Self {
         Self {
             keypair,
+            trust: PeerTrustTracker::new(config.max_integrity_failures),
+            cache: ChunkCache::new(config.chunk_cache_bytes),
+            config,
             peers: Vec::new(),
-            peer_last_tick: HashMap::new(),
             tick_count: 0,
-            active_transfer: None,
-            peer_metrics: HashMap::new(),
+            clock_origin_ms: None,
+            next_heartbeat_tick: 0,
+            transfers: HashMap::new(),
+            self_metrics: PeerMetrics::default(),
+            capabilities: Capabilities {
+                features: SUPPORTED_FEATURES,
+                ..Capabilities::default()
+            },
+            serve_usage: HashMap::new(),
+            pending_serves: HashMap::new(),
+            ledger: HashMap::new(),
+            constraints_told: HashSet::new(),
+            peer_versions: HashMap::new(),
+            hello_sent: HashSet::new(),
+            capabilities_told: HashSet::new(),
+            announced_capabilities: HashMap::new(),
+            candidates: HashMap::new(),
+            disputes: HashMap::new(),
+            rotations: HashMap::new(),
+            revocations: HashMap::new(),
+            pod_key: None,
+            previous_pod_keys: Vec::new(),
+            offered_pod_keys: HashMap::new(),
+            sas: HashMap::new(),
+            pairings: HashMap::new(),
+            paired: HashSet::new(),
+            known_peers: HashMap::new(),
+            names: HashMap::new(),
+            known_metrics: HashMap::new(),
+            revoked: HashSet::new(),
+            uplink: None,
+            do_not_disturb: false,
+            local_minutes: None,
+            served_today: 0,
+            stats: TrafficStats::default(),
+            uploads: HashMap::new(),
+            upload_offers: HashMap::new(),
+            events: VecDeque::new(),
+            history: VecDeque::new(),
+            content_filter: None,
+            scheduler: Box::new(DefaultScheduler),
+        }
+    }
+
+    /// Place chunks with `scheduler` instead of [`DefaultScheduler`], e.g.
+    /// `PeaPodCore::with_config(config).with_scheduler(Box::new(MyScheduler))`. A core rebuilt by
+    /// [`restore`](Self::restore) uses the default until this is called again.
+    pub fn with_scheduler(mut self, scheduler: Box<dyn Scheduler>) -> Self {
+        self.scheduler = scheduler;
+        self
+    }
+
+    /// Serialize peers, active transfers (including verified chunks not yet taken), metrics, trust
+    /// and credit so a restarted host can carry on with [`PeaPodCore::restore`]. The keypair is not
+    /// included.
+    pub fn snapshot(&self) -> Vec<u8> {
+        let snapshot = Snapshot {
+            version: SNAPSHOT_VERSION,
+            config: Cow::Borrowed(&self.config),
+            peers: Cow::Borrowed(&self.peers),
+            tick_count: self.tick_count,
+            transfers: Cow::Borrowed(&self.transfers),
+            self_metrics: Cow::Borrowed(&self.self_metrics),
+            capabilities: Cow::Borrowed(&self.capabilities),
+            trust: Cow::Borrowed(&self.trust),
+            ledger: Cow::Borrowed(&self.ledger),
+        };
+        // Plain data with known sizes: bincode serialization cannot fail here.
+        bincode::serialize(&snapshot).unwrap_or_default()
+    }
+
+    /// Rebuild a core from [`PeaPodCore::snapshot`] bytes with the device's `keypair`. Requests in
+    /// flight were lost with the old process: every chunk of a restored transfer not yet verified
+    /// is requested again from its assigned device on the next tick (ChunkRequests addressed to
+    /// [`device_id`](Self::device_id) are for the host to fetch itself), so an interrupted download
+    /// resumes without fetching verified chunks again. Paused transfers wait for
+    /// [`resume_transfer`](Self::resume_transfer).
+    pub fn restore(bytes: &[u8], keypair: Arc<dyn DeviceKey>) -> Result<Self, CoreError> {
+        let snapshot: Snapshot = bincode::deserialize(bytes).map_err(SnapshotError::Decode)?;
+        if snapshot.version != SNAPSHOT_VERSION {
+            return Err(SnapshotError::Version(snapshot.version).into());
+        }
+        let mut core = Self::with_keypair_arc_and_config(keypair, snapshot.config.into_owned());
+        core.peers = snapshot.peers.into_owned();
+        core.tick_count = snapshot.tick_count;
+        core.transfers = snapshot.transfers.into_owned();
+        for t in core.transfers.values_mut() {
+            t.requeue_missing();
+        }
+        core.self_metrics = snapshot.self_metrics.into_owned();
+        core.capabilities = snapshot.capabilities.into_owned();
+        core.trust = snapshot.trust.into_owned();
+        core.ledger = snapshot.ledger.into_owned();
+        Ok(core)
+    }
+
+    /// Current configuration.
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Replace the configuration on a live core (e.g. tray settings change, SIGHUP reload).
+    /// Timeouts apply from the next tick, including to requests already outstanding. A new chunk
+    /// size applies to new transfers only; transfers in progress keep their existing chunk plan.
+    /// Lowering `max_peers` does not drop peers already in the pod; joins then refuse or evict
+    /// until the pod is below the limit. Lowering `max_integrity_failures` isolates peers already
+    /// past it at once; the returned messages request their chunks from other workers.
+    pub fn update_config(&mut self, config: Config) -> Vec<OutboundAction> {
+        let isolated = self.trust.set_threshold(config.max_integrity_failures);
+        self.cache.set_capacity(config.chunk_cache_bytes);
+        if config.tick_interval_ms != self.config.tick_interval_ms {
+            // Re-anchor the clock so ticks already counted keep their meaning.
+            self.clock_origin_ms = None;
+        }
+        self.config = config;
+        let mut actions = Vec::new();
+        for peer in isolated {
+            self.emit(CoreEvent::PeerIsolated(peer));
+            actions.extend(self.redistribute_peer_chunks(peer));
+        }
+        actions
+    }
+
+    /// Take all pending state-change events (oldest first). Hosts call this after feeding events
+    /// into the core (or on each tick) instead of inferring changes from unrelated return values.
+    pub fn drain_events(&mut self) -> Vec<CoreEvent> {
+        self.events.drain(..).collect()
+    }
+
+    fn emit(&mut self, event: CoreEvent) {
+        if self.events.len() >= MAX_PENDING_EVENTS {
+            self.events.pop_front();
         }
+        self.events.push_back(event);
     }
 
     /// Set or update metrics for a peer (or self) for weighted chunk assignment.
+    /// Metrics for a device that is not in the pod are ignored. Only bandwidth and latency are taken
+    /// from `metrics`; chunk outcome counters are maintained by the core.
     pub fn set_peer_metrics(&mut self, peer_id: DeviceId, metrics: PeerMetrics) {
-        self.peer_metrics.insert(peer_id, metrics);
+        if let Some(m) = self.metrics_mut(peer_id) {
+            m.bandwidth_bytes_per_sec = metrics.bandwidth_bytes_per_sec;
+            m.latency_ms = metrics.latency_ms;
+        }
     }
 
-    /// Build weights for the given workers (self first, then peers). Returns None only when
-    /// every participant has default weight 1, so that weighted scheduling is used whenever
-    /// any participant (including self) has a non-default bandwidth.
-    fn worker_weights(&self, workers: &[DeviceId]) -> Option<Vec<u64>> {
-        let weights: Vec<u64> = workers
-            .iter()
-            .map(|id| {
-                self.peer_metrics
-                    .get(id)
-                    .and_then(|m| m.bandwidth_bytes_per_sec)
-                    .unwrap_or(1)
-            })
-            .collect();
-        if weights.iter().all(|&w| w == 1) {
-            return None;
+    /// Feed a throughput the host observed for a peer (or this device), e.g. `bytes` of a chunk
+    /// delivered over `duration_ms`. Samples are smoothed into the peer's bandwidth estimate, so
+    /// later chunks are assigned in proportion to it. Ignored for devices not in the pod.
+    pub fn report_peer_throughput(&mut self, peer_id: DeviceId, bytes: u64, duration_ms: u64) {
+        let sample = (u128::from(bytes) * 1000 / u128::from(duration_ms.max(1)))
+            .min(u128::from(u64::MAX)) as u64;
+        if let Some(m) = self.metrics_mut(peer_id) {
+            m.bandwidth_bytes_per_sec = Some(match m.bandwidth_bytes_per_sec {
+                Some(rate) => smooth_rate(rate, sample),
+                None => sample,
+            });
         }
-        Some(weights)
     }
 
-    /// This device's 16-byte ID (used in discovery and as "self" in assignments).
-    pub fn device_id(&self) -> DeviceId {
-        self.keypair.device_id()
+    /// Feed a round-trip time the host measured to a peer (e.g. its transport handshake). Samples
+    /// are smoothed into [`PeerMetrics::latency_ms`]; the lowest-latency worker gets the first
+    /// chunks of each transfer. Ignored for devices not in the pod.
+    pub fn report_peer_rtt(&mut self, peer_id: DeviceId, rtt_ms: u32) {
+        if let Some(m) = self.metrics_mut(peer_id) {
+            m.latency_ms = Some(match m.latency_ms {
+                Some(prev) => smooth_rate(u64::from(prev), u64::from(rtt_ms)) as u32,
+                None => rtt_ms,
+            });
+        }
     }
 
-    /// Build discovery beacon frame (length-prefix + bincode Beacon) for the host to send via UDP. Same format as 07.
-    pub fn beacon_frame(&self, listen_port: u16) -> Result<Vec<u8>, wire::FrameEncodeError> {
-        let beacon = Message::Beacon {
-            protocol_version: PROTOCOL_VERSION,
-            device_id: self.keypair.device_id(),
-            public_key: self.keypair.public_key().clone(),
-            listen_port,
-        };
-        wire::encode_frame(&beacon)
+    /// Feed a WAN throughput this device observed (e.g. a range it fetched itself); see
+    /// [`report_peer_throughput`](Self::report_peer_throughput).
+    pub fn report_self_wan_throughput(&mut self, bytes: u64, duration_ms: u64) {
+        self.report_peer_throughput(self.keypair.device_id(), bytes, duration_ms);
     }
 
-    /// Build DiscoveryResponse frame (sent to beacon sender). Same wire shape, different variant.
-    pub fn discovery_response_frame(
-        &self,
-        listen_port: u16,
-    ) -> Result<Vec<u8>, wire::FrameEncodeError> {
-        let resp = Message::DiscoveryResponse {
-            protocol_version: PROTOCOL_VERSION,
-            device_id: self.keypair.device_id(),
-            public_key: self.keypair.public_key().clone(),
-            listen_port,
-        };
-        wire::encode_frame(&resp)
+    /// Record the WAN uplink a peer (or this device) uses, as an opaque identifier such as a hash
+    /// of its public IP or gateway MAC; `None` when unknown. Workers on the same uplink split one
+    /// uplink's share of chunks. Ignored for devices not in the pod.
+    pub fn set_peer_uplink(&mut self, peer_id: DeviceId, uplink: Option<String>) {
+        if peer_id == self.keypair.device_id() {
+            self.uplink = uplink;
+        } else if let Some(info) = self.peer_mut(&peer_id) {
+            info.uplink = uplink;
+        }
     }
 
-    /// Handshake bytes for local transport: 1 version + 16 device_id + 32 public_key.
-    pub fn handshake_bytes(&self) -> [u8; 49] {
-        let mut out = [0u8; 49];
-        out[0] = PROTOCOL_VERSION;
-        out[1..17].copy_from_slice(self.keypair.device_id().as_bytes());
-        out[17..49].copy_from_slice(self.keypair.public_key().as_bytes());
-        out
+    /// Pause (or resume) serving peers, e.g. while the device is under high load or gaming. Peers'
+    /// ChunkRequests are answered with Busy meanwhile; this device's own transfers continue.
+    pub fn set_do_not_disturb(&mut self, on: bool) {
+        self.do_not_disturb = on;
     }
 
-    /// Session key for a peer (from shared secret with peer's public key).
-    pub fn session_key(&self, peer_public: &PublicKey) -> [u8; 32] {
-        derive_session_key(&self.keypair.shared_secret(peer_public))
+    /// Report the local wall-clock time, in minutes since the Unix epoch in the user's time zone,
+    /// for [`ContributionPolicy`] serve windows and the daily byte budget (which restarts when
+    /// the day changes). Hosts call it periodically, e.g. once a minute.
+    pub fn set_local_time(&mut self, local_minutes: u64) {
+        let day = |m: u64| m / MINUTES_PER_DAY;
+        if self
+            .local_minutes
+            .is_some_and(|prev| day(prev) != day(local_minutes))
+        {
+            self.served_today = 0;
+        }
+        self.local_minutes = Some(local_minutes);
     }
 
-    /// Called when the host has an eligible request. Returns [`Action::Accelerate`] with chunk assignment
-    /// (host then fetches self chunks and sends ChunkRequest to peers) or [`Action::Fallback`].
-    pub fn on_incoming_request(&mut self, _url: &str, range: Option<(u64, u64)>) -> Action {
-        let total_length = range
-            .map(|(s, e)| e.saturating_sub(s).saturating_add(1))
-            .unwrap_or(0);
-        if total_length == 0 {
-            return Action::Fallback;
-        }
-        if self.peers.is_empty() {
-            return Action::Fallback;
+    /// Whether the contribution policy lets this device serve `len` more bytes now.
+    fn may_contribute(&self, len: u64) -> bool {
+        let policy = &self.config.contribution;
+        let in_window = self
+            .local_minutes
+            .is_none_or(|m| policy.in_window((m % MINUTES_PER_DAY) as u16));
+        let within_budget = policy.daily_byte_budget == 0
+            || self.served_today.saturating_add(len) <= policy.daily_byte_budget;
+        !self.do_not_disturb && in_window && within_budget
+    }
+
+    /// Uplink of each of `workers` (same order).
+    fn worker_uplinks(&self, workers: &[DeviceId]) -> Vec<Option<&str>> {
+        workers
+            .iter()
+            .map(|id| {
+                if *id == self.keypair.device_id() {
+                    self.uplink.as_deref()
+                } else {
+                    self.peers
+                        .iter()
+                        .find(|p| p.device_id == *id)
+                        .and_then(|p| p.uplink.as_deref())
+                }
+            })
+            .collect()
+    }
+
+    /// Capabilities this device advertises to peers.
+    pub fn capabilities(&self) -> &Capabilities {
+        &self.capabilities
+    }
+
+    /// Set the capabilities advertised to peers (platform, downlink estimate, chunk size limit):
+    /// in Join frames, when a connection comes up, and to members on the next tick. Also used for
+    /// this device when sizing chunks and weighting work.
+    pub fn set_capabilities(&mut self, capabilities: Capabilities) {
+        if capabilities != self.capabilities {
+            self.capabilities_told.clear();
         }
-        let transfer_id: [u8; 16] = uuid::Uuid::new_v4().into_bytes();
-        let chunk_ids = chunk::split_into_chunks(transfer_id, total_length, DEFAULT_CHUNK_SIZE);
-        let workers: Vec<DeviceId> = std::iter::once(self.keypair.device_id())
-            .chain(self.peers.iter().copied())
-            .collect();
-        let weights = self.worker_weights(&workers);
-        let assignment =
-            scheduler::assign_chunks_to_peers_weighted(&chunk_ids, &workers, weights.as_deref());
-        let state = TransferState::new(transfer_id, total_length, chunk_ids.clone());
-        self.active_transfer = Some(ActiveTransfer {
-            state,
-            assignment: assignment.clone(),
-        });
-        Action::Accelerate {
-            transfer_id,
-            total_length,
-            assignment,
+        self.capabilities = capabilities;
+    }
+
+    /// Latest capabilities `peer_id` advertised (Capabilities, JoinRequest or JoinAccept), or
+    /// `None` if it has not sent any. Optional features are only used with peers whose
+    /// capabilities list them.
+    pub fn peer_capabilities(&self, peer_id: DeviceId) -> Option<&Capabilities> {
+        self.peers
+            .iter()
+            .find(|p| p.device_id == peer_id)
+            .map(|p| &p.capabilities)
+            .or_else(|| self.announced_capabilities.get(&peer_id))
+    }
+
+    /// Whether `peer_id` advertised every bit in `feature`.
+    fn peer_supports(&self, peer_id: DeviceId, feature: u32) -> bool {
+        self.peer_capabilities(peer_id)
+            .is_some_and(|c| c.supports(feature))
+    }
+
+    fn capabilities_of(&self, id: &DeviceId) -> Option<&Capabilities> {
+        if *id == self.keypair.device_id() {
+            Some(&self.capabilities)
+        } else {
+            self.peer_capabilities(*id)
         }
     }
 
-    /// Process received chunk. Returns `Ok(Some(body))` when the transfer is complete and reassembled,
-    /// `Ok(None)` when still in progress, or `Err(ChunkError)` on integrity failure or unknown transfer.
-    pub fn on_chunk_received(
+    /// Chunk size for a transfer over `workers`: the configured size, lowered to the smallest
+    /// `max_chunk_size` any worker advertised.
+    fn chunk_size_for(&self, workers: &[DeviceId]) -> u64 {
+        workers
+            .iter()
+            .filter_map(|id| self.capabilities_of(id))
+            .map(|c| c.max_chunk_size)
+            .filter(|&max| max > 0)
+            .fold(self.config.chunk_size, u64::min)
+    }
+
+    /// Per-worker chunk sizes and assignment: from [`Scheduler::chunk_sizes`] if the scheduler
+    /// sizes chunks itself, else from measured delivery rates (see
+    /// [`Config::adaptive_chunk_size`]); `None` for a uniform plan. Sizes are capped by each
+    /// worker's advertised `max_chunk_size`.
+    fn plan_sized(
         &mut self,
         transfer_id: [u8; 16],
-        start: u64,
-        end: u64,
-        hash: [u8; 32],
-        payload: Vec<u8>,
-    ) -> Result<Option<Vec<u8>>, ChunkError> {
-        let active = match &mut self.active_transfer {
-            Some(a) if a.state.transfer_id == transfer_id => a,
-            _ => return Err(ChunkError::UnknownTransfer),
+        total_length: u64,
+        workers: &[DeviceId],
+    ) -> Option<Vec<(ChunkId, DeviceId)>> {
+        let metrics = self.worker_metrics(workers);
+        let uplinks = self.owned_uplinks(workers);
+        let uplinks: Vec<Option<&str>> = uplinks.iter().map(Option::as_deref).collect();
+        let outstanding_bytes = self.outstanding_bytes(workers);
+        let view = Workers {
+            ids: workers,
+            metrics: &metrics,
+            uplinks: &uplinks,
+            outstanding_bytes: &outstanding_bytes,
         };
-        match chunk::on_chunk_data_received(
-            &mut active.state,
-            transfer_id,
-            start,
-            end,
-            hash,
-            payload,
-        ) {
-            chunk::ChunkReceiveResult::Complete(bytes) => {
-                self.active_transfer = None;
-                Ok(Some(bytes))
+        let sizes = match self.scheduler.chunk_sizes(&view) {
+            Some(sizes) if sizes.len() == workers.len() => sizes,
+            _ if !self.config.adaptive_chunk_size
+                || metrics.iter().all(|m| m.delivery_rate_bps.is_none()) =>
+            {
+                return None;
             }
-            chunk::ChunkReceiveResult::InProgress => Ok(None),
-            chunk::ChunkReceiveResult::IntegrityFailed => Err(ChunkError::IntegrityFailed),
-        }
+            _ => metrics
+                .iter()
+                .map(|m| match m.delivery_rate_bps {
+                    Some(rate) => scheduler::adaptive_chunk_size(
+                        rate,
+                        scheduler::ADAPTIVE_CHUNK_TARGET_SECS,
+                        self.config.min_chunk_size,
+                        self.config.max_chunk_size,
+                    ),
+                    None => self.config.chunk_size,
+                })
+                .collect(),
+        };
+        let sizes: Vec<u64> = workers
+            .iter()
+            .zip(sizes)
+            .map(
+                |(id, size)| match self.capabilities_of(id).map_or(0, |c| c.max_chunk_size) {
+                    0 => size,
+                    cap => size.min(cap),
+                },
+            )
+            .collect();
+        let mut plan =
+            scheduler::plan_sized_chunks(transfer_id, total_length, workers, &metrics, &sizes);
+        scheduler::lead_with_low_latency(&mut plan, workers, &metrics);
+        Some(plan)
     }
 
-    /// Notify that a peer joined (from discovery). Updates peer list for chunk assignment.
-    pub fn on_peer_joined(&mut self, peer_id: DeviceId, _public_key: &PublicKey) {
-        if !self.peers.contains(&peer_id) {
-            self.peers.push(peer_id);
+    fn metrics_mut(&mut self, id: DeviceId) -> Option<&mut PeerMetrics> {
+        if id == self.keypair.device_id() {
+            Some(&mut self.self_metrics)
+        } else {
+            self.peer_mut(&id).map(|p| &mut p.metrics)
         }
-        self.peer_last_tick.insert(peer_id, self.tick_count);
     }
 
-    /// Notify that a peer left. Redistributes its chunks to remaining peers; returns actions to send ChunkRequests.
-    pub fn on_peer_left(&mut self, peer_id: DeviceId) -> Vec<OutboundAction> {
-        self.peers.retain(|p| *p != peer_id);
-        self.peer_last_tick.remove(&peer_id);
-        self.redistribute_peer_chunks(peer_id)
+    /// Fold one delivery (`len` bytes, `ticks` after the request) into `id`'s smoothed rate. A
+    /// chunk that arrives within the tick it was requested counts as taking one tick.
+    fn record_delivery(&mut self, id: DeviceId, len: u64, ticks: u64) {
+        let sample = len / ticks.saturating_add(1);
+        if let Some(m) = self.metrics_mut(id) {
+            m.delivery_rate_bps = Some(match m.delivery_rate_bps {
+                Some(rate) => smooth_rate(rate, sample),
+                None => sample,
+            });
+        }
     }
 
-    /// Call when host receives a heartbeat from peer (so we don't mark peer as left).
-    pub fn on_heartbeat_received(&mut self, peer_id: DeviceId) {
-        self.peer_last_tick.insert(peer_id, self.tick_count);
+    /// Count a verified (`ok`) or failed (integrity failure, Nack, timeout) chunk against `id`.
+    fn record_outcome(&mut self, id: DeviceId, ok: bool) {
+        let now = self.tick_count;
+        if let Some(m) = self.metrics_mut(id) {
+            if ok {
+                m.chunks_verified = m.chunks_verified.saturating_add(1);
+            } else {
+                m.chunks_failed = m.chunks_failed.saturating_add(1);
+                m.last_failure_tick = Some(now);
+            }
+        }
+        self.scheduler.on_result(id, ok);
     }
 
-    /// Periodic tick: check heartbeat timeouts (treat overdue peers as left), produce heartbeat messages.
-    /// Periodic tick (e.g. every 1 s). Returns outbound actions (e.g. heartbeats); host sends them to peers.
-    pub fn tick(&mut self) -> Vec<OutboundAction> {
-        self.tick_count = self.tick_count.saturating_add(1);
-        let mut actions = Vec::new();
-        let overdue: Vec<DeviceId> = self
-            .peer_last_tick
-            .iter()
-            .filter(|(_, &t)| self.tick_count.saturating_sub(t) > HEARTBEAT_TIMEOUT_TICKS)
-            .map(|(&p, _)| p)
-            .collect();
-        for peer_id in overdue {
-            self.peers.retain(|p| *p != peer_id);
-            self.peer_last_tick.remove(&peer_id);
-            actions.extend(self.redistribute_peer_chunks(peer_id));
+    /// Halve the failure counts of devices whose latest failure (or decay step) is at least
+    /// [`Config::failure_half_life_ticks`] old, once per elapsed half-life.
+    fn decay_failures(&mut self) {
+        let (now, half_life) = (self.tick_count, self.config.failure_half_life_ticks);
+        if half_life == 0 {
+            return;
         }
-        let self_id = self.keypair.device_id();
-        for &peer in &self.peers {
-            let msg = Message::Heartbeat { device_id: self_id };
-            if let Ok(bytes) = wire::encode_frame(&msg) {
-                actions.push(OutboundAction::SendMessage(peer, bytes));
+        let peers = self.peers.iter_mut().map(|p| &mut p.metrics);
+        for m in std::iter::once(&mut self.self_metrics).chain(peers) {
+            if m.chunks_failed == 0 {
+                continue;
             }
+            let since = m.last_failure_tick.unwrap_or(0);
+            let halvings = now.saturating_sub(since) / half_life;
+            if halvings == 0 {
+                continue;
+            }
+            let shift = u32::try_from(halvings).unwrap_or(u32::MAX);
+            m.chunks_failed = m.chunks_failed.checked_shr(shift).unwrap_or(0);
+            m.last_failure_tick = Some(since + halvings * half_life);
         }
-        actions
     }
 
-    fn redistribute_peer_chunks(&mut self, peer_left: DeviceId) -> Vec<OutboundAction> {
-        let active = match &mut self.active_transfer {
-            Some(a) => a,
-            None => return vec![],
-        };
-        let remaining: Vec<DeviceId> = std::iter::once(self.keypair.device_id())
-            .chain(self.peers.iter().copied())
-            .collect();
-        let new_assignments =
-            scheduler::reassign_after_peer_left(&active.assignment, peer_left, &remaining);
-        active.assignment.retain(|(_, p)| *p != peer_left);
-        let mut actions = Vec::new();
-        for (chunk_id, new_peer) in new_assignments {
-            active.assignment.push((chunk_id, new_peer));
-            let msg = chunk::chunk_request_message(chunk_id, None);
-            if let Ok(bytes) = wire::encode_frame(&msg) {
-                actions.push(OutboundAction::SendMessage(new_peer, bytes));
-            }
+    /// Peers currently in the pod, in join order.
+    pub fn peers(&self) -> &[PeerInfo] {
+        &self.peers
+    }
+
+    fn peer_mut(&mut self, peer_id: &DeviceId) -> Option<&mut PeerInfo> {
+        self.peers.iter_mut().find(|p| p.device_id == *peer_id)
+    }
+
+    fn has_peer(&self, peer_id: &DeviceId) -> bool {
+        self.peers.iter().any(|p| p.device_id == *peer_id)
+    }
+
+    /// Self first, then non-isolated peers in join order.
+    fn workers(&self) -> impl Iterator<Item = DeviceId> + '_ {
+        std::iter::once(self.keypair.device_id()).chain(
+            self.peers
+                .iter()
+                .filter(|p| p.busy_until <= self.tick_count)
+                .map(|p| p.device_id)
+                .filter(|p| !self.trust.is_isolated(p)),
+        )
+    }
+
+    /// Traffic counters (bytes fetched locally, from each peer and from the cache, bytes served to
+    /// each peer) for "data saved" and speedup displays.
+    pub fn stats(&self) -> TrafficStats {
+        self.stats.clone()
+    }
+
+    /// Estimate how long `content_length` bytes take over this device's link alone and shared
+    /// with the current pod, from each worker's measured (or advertised) bandwidth, contribution
+    /// cap, constraints and uplink. Hosts use it to decide whether acceleration is worthwhile and
+    /// for "estimated 2.4× faster" displays.
+    pub fn estimate_savings(&self, content_length: u64) -> SavingsEstimate {
+        let workers: Vec<DeviceId> = self.workers().collect();
+        let metrics = self.worker_metrics(&workers);
+        let uplinks = self.worker_uplinks(&workers);
+        let direct = metrics[0].bandwidth_bytes_per_sec.unwrap_or(0);
+        SavingsEstimate {
+            direct_ms: transfer_ms(content_length, direct),
+            accelerated_ms: transfer_ms(content_length, scheduler::pod_rate(&metrics, &uplinks)),
+            workers: metrics
+                .iter()
+                .filter(|m| scheduler::contribution_rate(m) > 0)
+                .count(),
         }
-        actions
     }
 
-    /// Get current assignment for the active transfer (for host to issue ChunkRequests). Returns (chunk_id, peer_id) list.
-    pub fn current_assignment(&self) -> Option<Vec<(ChunkId, DeviceId)>> {
-        self.active_transfer.as_ref().map(|a| a.assignment.clone())
+    /// What-if: how `strategy` would split a `content_length`-byte transfer over the current
+    /// workers, from the same metrics, uplinks and outstanding bytes a real transfer would see
+    /// (see [`scheduler::plan`]). Nothing is requested and no state changes.
+    pub fn dry_run(&self, content_length: u64, strategy: &mut dyn Scheduler) -> AssignmentPlan {
+        let workers: Vec<DeviceId> = self.workers().collect();
+        let metrics = self.worker_metrics(&workers);
+        let uplinks = self.worker_uplinks(&workers);
+        let outstanding_bytes = self.outstanding_bytes(&workers);
+        let chunk_ids =
+            chunk::split_into_chunks([0; 16], content_length, self.chunk_size_for(&workers));
+        let view = Workers {
+            ids: &workers,
+            metrics: &metrics,
+            uplinks: &uplinks,
+            outstanding_bytes: &outstanding_bytes,
+        };
+        scheduler::plan(&chunk_ids, &view, strategy)
     }
 
-    /// Process a received message (host decrypts and passes frame bytes).
-    /// Returns (outbound actions, optional completed transfer body when ChunkData completes the transfer).
-    #[allow(clippy::type_complexity)]
-    pub fn on_message_received(
+    /// Zero the [`stats`](Self::stats) counters. The credit ledger is not affected.
+    pub fn reset_stats(&mut self) {
+        self.stats = TrafficStats::default();
+    }
+
+    /// Credit ledger: bytes served to and received from each peer this core has exchanged data
+    /// with, best contributors first (for host UIs).
+    pub fn credit_ledger(&self) -> Vec<(DeviceId, PeerCredit)> {
+        let mut ledger: Vec<(DeviceId, PeerCredit)> =
+            self.ledger.iter().map(|(&p, &c)| (p, c)).collect();
+        ledger.sort_by_key(|(_, c)| std::cmp::Reverse(c.balance()));
+        ledger
+    }
+
+    /// Integrity record for a peer (None if it never sent a corrupt chunk).
+    pub fn peer_trust(&self, peer_id: DeviceId) -> Option<PeerTrust> {
+        self.trust.get(&peer_id)
+    }
+
+    /// Lift a peer's isolation and forget its integrity failures. It receives chunks again from the
+    /// next assignment on.
+    pub fn reset_peer_trust(&mut self, peer_id: DeviceId) {
+        self.trust.reset(&peer_id);
+    }
+
+    /// Metrics for the given workers (same order); unknown devices get default metrics.
+    /// Bandwidth the host has not set falls back to the measured delivery rate (converted from
+    /// bytes per tick), then to the advertised downlink. Workers with none of these get the mean of
+    /// the others, so a newcomer gets a fair share of chunks (and a measured rate) rather than the
+    /// minimum weight.
+    fn worker_metrics(&self, workers: &[DeviceId]) -> Vec<PeerMetrics> {
+        let per_sec = |per_tick: u64| {
+            let tick_ms = self.config.tick_interval_ms.max(1);
+            (u128::from(per_tick) * 1000 / u128::from(tick_ms)).min(u128::from(u64::MAX)) as u64
+        };
+        let mut metrics: Vec<PeerMetrics> = workers
+            .iter()
+            .map(|id| {
+                let mut metrics = if *id == self.keypair.device_id() {
+                    self.self_metrics.clone()
+                } else {
+                    self.peers
+                        .iter()
+                        .find(|p| p.device_id == *id)
+                        .map(|p| p.metrics.clone())
+                        .unwrap_or_default()
+                };
+                let downlink = self.capabilities_of(id).map_or(0, |c| c.downlink_bps);
+                if metrics.bandwidth_bytes_per_sec.is_none() {
+                    metrics.bandwidth_bytes_per_sec = metrics
+                        .delivery_rate_bps
+                        .map(per_sec)
+                        .or((downlink > 0).then_some(downlink));
+                }
+                metrics
+            })
+            .collect();
+        let known: Vec<u64> = metrics
+            .iter()
+            .filter_map(|m| m.bandwidth_bytes_per_sec)
+            .collect();
+        if !known.is_empty() && known.len() < metrics.len() {
+            let mean = known.iter().map(|&b| u128::from(b)).sum::<u128>() / known.len() as u128;
+            for m in metrics.iter_mut() {
+                m.bandwidth_bytes_per_sec.get_or_insert(mean as u64);
+            }
+        }
+        metrics
+    }
+
+    /// Assign chunks over `workers` with the scheduler.
+    fn assign(&mut self, chunk_ids: &[ChunkId], workers: &[DeviceId]) -> Vec<(ChunkId, DeviceId)> {
+        let metrics = self.worker_metrics(workers);
+        let uplinks = self.owned_uplinks(workers);
+        let uplinks: Vec<Option<&str>> = uplinks.iter().map(Option::as_deref).collect();
+        let outstanding_bytes = self.outstanding_bytes(workers);
+        let view = Workers {
+            ids: workers,
+            metrics: &metrics,
+            uplinks: &uplinks,
+            outstanding_bytes: &outstanding_bytes,
+        };
+        self.scheduler.assign(chunk_ids, &view)
+    }
+
+    /// With [`Config::self_share_percent`], give this device the chunks at the start of the body
+    /// up to its share of the bytes and assign the rest over the other workers.
+    fn apply_self_share(
         &mut self,
-        peer_id: DeviceId,
-        frame_bytes: &[u8],
-    ) -> Result<(Vec<OutboundAction>, Option<([u8; 16], Vec<u8>)>), OnMessageError> {
-        let (msg, _) = wire::decode_frame(frame_bytes).map_err(OnMessageError::Decode)?;
-        let mut actions = Vec::new();
-        let mut completed = None;
+        mut assignment: Vec<(ChunkId, DeviceId)>,
+        workers: &[DeviceId],
+    ) -> Vec<(ChunkId, DeviceId)> {
+        let Some(percent) = self.config.self_share_percent else {
+            return assignment;
+        };
+        let self_id = self.keypair.device_id();
+        let peers: Vec<DeviceId> = workers.iter().copied().filter(|&p| p != self_id).collect();
+        if peers.is_empty() {
+            return assignment;
+        }
+        assignment.sort_by_key(|(c, _)| c.start);
+        let total: u64 = assignment.iter().map(|(c, _)| c.end - c.start).sum();
+        let target = u128::from(total) * u128::from(percent.min(100)) / 100;
+        let mut own = Vec::new();
+        let mut filled = 0u128;
+        for &(c, _) in &assignment {
+            let len = u128::from(c.end - c.start);
+            // Stop once the next chunk would overshoot the share by more than it falls short.
+            if 2 * filled + len > 2 * target {
+                break;
+            }
+            own.push((c, self_id));
+            filled += len;
+        }
+        let rest: Vec<ChunkId> = assignment[own.len()..].iter().map(|&(c, _)| c).collect();
+        own.extend(self.assign(&rest, &peers));
+        own
+    }
+
+    /// [`worker_uplinks`](Self::worker_uplinks) detached from `self`, so the scheduler can be
+    /// called mutably while they are in use.
+    fn owned_uplinks(&self, workers: &[DeviceId]) -> Vec<Option<String>> {
+        self.worker_uplinks(workers)
+            .into_iter()
+            .map(|u| u.map(str::to_string))
+            .collect()
+    }
+
+    /// This device's 16-byte ID (used in discovery and as "self" in assignments).
+    pub fn device_id(&self) -> DeviceId {
+        self.keypair.device_id()
+    }
+
+    /// Build discovery beacon frame (length-prefix + bincode Beacon) for the host to send via UDP. Same format as 07.
+    /// A [`Message::DiscoveryName`] frame follows it when [`Config::device_name`] is set. All are
+    /// sealed with the pod key under [`Config::confidential_discovery`]; otherwise a paired device
+    /// also appends a [`Message::DiscoveryPod`] frame. Send the bytes as one datagram, and rebuild
+    /// them for each send, since the key can change.
+    pub fn beacon_frame(&self, listen_port: u16) -> Result<Vec<u8>, CoreError> {
+        let beacon = Message::Beacon {
+            protocol_version: PROTOCOL_VERSION,
+            device_id: self.keypair.device_id(),
+            public_key: self.keypair.public_key().clone(),
+            listen_port,
+        };
+        self.discovery_frame(beacon)
+    }
+
+    /// Build DiscoveryResponse frame (sent to beacon sender). Same wire shape, different variant.
+    pub fn discovery_response_frame(&self, listen_port: u16) -> Result<Vec<u8>, CoreError> {
+        let resp = Message::DiscoveryResponse {
+            protocol_version: PROTOCOL_VERSION,
+            device_id: self.keypair.device_id(),
+            public_key: self.keypair.public_key().clone(),
+            listen_port,
+        };
+        self.discovery_frame(resp)
+    }
+
+    fn discovery_frame(&self, msg: Message) -> Result<Vec<u8>, CoreError> {
+        // Extensions travel in frames of their own after the message, which older builds ignore.
+        let mut messages = vec![msg];
+        messages.extend(
+            self.name_record()
+                .map(|name| Message::DiscoveryName { name }),
+        );
+        let mut frames = Vec::new();
+        match &self.pod_key {
+            Some(key) if self.config.confidential_discovery => {
+                for msg in &messages {
+                    frames.extend(wire::encode_frame(&key.seal_discovery(msg)?)?);
+                }
+            }
+            pod_key => {
+                messages.extend(
+                    pod_key
+                        .as_ref()
+                        .map(|key| Message::DiscoveryPod { pod_id: key.id() }),
+                );
+                // Plain beacons cross the LAN unauthenticated: checksum them.
+                let plain = wire::FrameOptions {
+                    checksum: true,
+                    ..Default::default()
+                };
+                for msg in &messages {
+                    frames.extend(wire::encode_frame_with(msg, plain)?);
+                }
+            }
+        }
+        Ok(frames)
+    }
+
+    /// ID of this device's pod, sent after its plain Beacons and DiscoveryResponses
+    /// ([`Message::DiscoveryPod`]): the current pod key's [`PodKey::id`], so `None` until the
+    /// device has paired.
+    pub fn pod_id(&self) -> Option<[u8; 8]> {
+        self.pod_key.as_ref().map(PodKey::id)
+    }
+
+    /// Decode a packet received on the discovery socket: a Beacon or DiscoveryResponse, opened
+    /// with the current or a recent pod key if sealed, and the sender's name if a
+    /// [`Message::DiscoveryName`] follows it (pass it to [`on_peer_named`](Self::on_peer_named)).
+    /// `None` for anything else, including frames sealed by another pod and, unless
+    /// [`Config::open_pod`] is set, plain ones naming another pod. Devices that have not paired
+    /// yet are still let through so they can be paired.
+    pub fn open_discovery_frame(&self, bytes: &[u8]) -> Option<(Message, Option<NameRecord>)> {
+        let (first, mut used) = wire::decode_frame(bytes).ok()?;
+        let msg = match self.open_discovery_message(first)? {
+            msg @ (Message::Beacon { .. } | Message::DiscoveryResponse { .. }) => msg,
+            _ => return None,
+        };
+        // Older builds send the message alone, and unpaired devices send no pod ID.
+        let (mut name, mut pod_id) = (None, None);
+        while let Some(rest) = bytes.get(used..).filter(|rest| !rest.is_empty()) {
+            match wire::decode_frame(rest) {
+                Ok((frame, n)) => {
+                    used += n;
+                    match self.open_discovery_message(frame) {
+                        Some(Message::DiscoveryName { name: record }) => name = Some(record),
+                        Some(Message::DiscoveryPod { pod_id: id }) => pod_id = Some(id),
+                        _ => {}
+                    }
+                }
+                Err(wire::FrameDecodeError::Skipped { header }) => used += header.frame_len(),
+                Err(_) => break,
+            }
+        }
+        (!self.is_foreign_pod(pod_id)).then_some((msg, name))
+    }
+
+    /// A discovery frame as sent, or opened with the current or a recent pod key if sealed.
+    fn open_discovery_message(&self, msg: Message) -> Option<Message> {
         match msg {
-            Message::Heartbeat { .. } => {
-                self.on_heartbeat_received(peer_id);
+            Message::SealedDiscovery {
+                key_id,
+                nonce,
+                ciphertext,
+            } => self
+                .pod_key
+                .iter()
+                .chain(&self.previous_pod_keys)
+                .find(|key| key.id() == key_id)?
+                .open_discovery(&nonce, &ciphertext),
+            msg => Some(msg),
+        }
+    }
+
+    /// Whether plain discovery naming `pod_id` comes from another pod: this device has paired,
+    /// [`Config::open_pod`] is off and the ID is not that of its current or a recent pod key.
+    fn is_foreign_pod(&self, pod_id: Option<[u8; 8]>) -> bool {
+        match pod_id {
+            Some(theirs) if self.pod_key.is_some() && !self.config.open_pod => !self
+                .pod_key
+                .iter()
+                .chain(&self.previous_pod_keys)
+                .any(|key| key.id() == theirs),
+            _ => false,
+        }
+    }
+
+    /// Build an authenticated JoinRequest frame for the peer with `peer_public` (learned from
+    /// discovery).
+    pub fn join_frame(&self, peer_public: &PublicKey) -> Result<Vec<u8>, CoreError> {
+        Ok(wire::encode_frame(&self.join_message(peer_public, false))?)
+    }
+
+    /// JoinRequest (or JoinAccept when `accept`) proving this device's identity to `peer_public`.
+    fn join_message(&self, peer_public: &PublicKey, accept: bool) -> Message {
+        let (device_id, public_key) = (self.keypair.device_id(), self.keypair.public_key().clone());
+        let signature = self.keypair.join_signature(peer_public);
+        let capabilities = self.capabilities.clone();
+        let name = self.name_record();
+        if accept {
+            Message::JoinAccept {
+                device_id,
+                public_key,
+                signature,
+                capabilities,
+                name,
+            }
+        } else {
+            Message::JoinRequest {
+                device_id,
+                public_key,
+                signature,
+                capabilities,
+                name,
+            }
+        }
+    }
+
+    /// This device's signed name ([`Config::device_name`]) for discovery and Joins.
+    pub fn name_record(&self) -> Option<NameRecord> {
+        self.config
+            .device_name
+            .as_deref()
+            .and_then(|name| self.keypair.name_record(name))
+    }
+
+    /// Record the name a device announced in discovery ([`Message::DiscoveryName`]). False, and
+    /// nothing recorded, when the record does not verify for `public_key`, the key is not the one
+    /// pinned for the device, or another pinned device already goes by the name.
+    pub fn on_peer_named(
+        &mut self,
+        peer_id: DeviceId,
+        public_key: &PublicKey,
+        record: &NameRecord,
+    ) -> bool {
+        if !record.verify(&peer_id, public_key) || self.check_pin(peer_id, public_key).is_err() {
+            return false;
+        }
+        let taken = self.names.iter().any(|(id, name)| {
+            *id != peer_id
+                && self.known_peers.contains_key(id)
+                && name.to_lowercase() == record.name.to_lowercase()
+        });
+        if taken {
+            return false;
+        }
+        self.names.insert(peer_id, record.name.clone());
+        true
+    }
+
+    /// The verified name of a device, if it announced one. Show it with the device ID: names are
+    /// only unique among pinned devices.
+    pub fn peer_name(&self, peer_id: DeviceId) -> Option<&str> {
+        self.names.get(&peer_id).map(String::as_str)
+    }
+
+    fn send_join(
+        &self,
+        peer_id: DeviceId,
+        peer_public: &PublicKey,
+        accept: bool,
+    ) -> Vec<OutboundAction> {
+        let mut actions = match wire::encode_frame(&self.join_message(peer_public, accept)) {
+            Ok(bytes) => vec![OutboundAction::SendMessage(peer_id, bytes)],
+            Err(_) => return vec![],
+        };
+        if accept {
+            actions.extend(self.send_pod_key(peer_id));
+        }
+        actions
+    }
+
+    /// Membership of `peer_id`, or `None` if this core has never heard of it.
+    pub fn membership(&self, peer_id: DeviceId) -> Option<Membership> {
+        if self.has_peer(&peer_id) {
+            return Some(Membership::Joined);
+        }
+        self.candidates.get(&peer_id).map(|c| c.state)
+    }
+
+    /// Record a device seen in discovery (Beacon or DiscoveryResponse). It is not trusted with any
+    /// work until it joins; see [`PeaPodCore::on_peer_connected`].
+    pub fn on_peer_discovered(&mut self, peer_id: DeviceId, public_key: &PublicKey) {
+        if peer_id == self.keypair.device_id() || self.has_peer(&peer_id) {
+            return;
+        }
+        let candidate = self.candidates.entry(peer_id).or_insert(Candidate {
+            state: Membership::Discovered,
+            public_key: public_key.clone(),
+            requested: None,
+        });
+        if candidate.state == Membership::Left {
+            candidate.state = Membership::Discovered;
+            candidate.requested = None;
+        }
+        candidate.public_key = public_key.clone();
+    }
+
+    /// Call when the transport to a peer is up. Sends a Hello with this build's protocol
+    /// versions, resends a pending JoinRequest and, unless [`Config::require_approval`] is set,
+    /// invites a discovered peer.
+    pub fn on_peer_connected(
+        &mut self,
+        peer_id: DeviceId,
+        public_key: &PublicKey,
+    ) -> Vec<OutboundAction> {
+        self.on_peer_discovered(peer_id, public_key);
+        self.peer_versions.remove(&peer_id);
+        self.hello_sent.remove(&peer_id);
+        let mut actions = self.send_hello(peer_id);
+        actions.extend(self.tell_capabilities(vec![peer_id]));
+        actions.extend(self.start_pairing(peer_id));
+        actions.extend(match self.membership(peer_id) {
+            Some(Membership::Invited) => self.send_join(peer_id, public_key, false),
+            Some(Membership::Discovered)
+                if !self.config.require_approval
+                    && (!self.config.require_pairing || self.is_trusted(peer_id)) =>
+            {
+                self.approve_peer(peer_id)
+            }
+            _ => vec![],
+        });
+        actions
+    }
+
+    fn send_hello(&mut self, peer_id: DeviceId) -> Vec<OutboundAction> {
+        let hello = Message::Hello {
+            min_version: MIN_PROTOCOL_VERSION,
+            max_version: MAX_PROTOCOL_VERSION,
+        };
+        match wire::encode_frame(&hello) {
+            Ok(bytes) if self.hello_sent.insert(peer_id) => {
+                vec![OutboundAction::SendMessage(peer_id, bytes)]
+            }
+            _ => vec![],
+        }
+    }
+
+    /// Settle on the highest version both sides speak, answering with this device's own Hello if
+    /// the connection has not had one yet.
+    fn on_hello(
+        &mut self,
+        peer_id: DeviceId,
+        min: u8,
+        max: u8,
+    ) -> Result<Vec<OutboundAction>, OnMessageError> {
+        let ours = (MIN_PROTOCOL_VERSION, MAX_PROTOCOL_VERSION);
+        let version = protocol::negotiate_version(ours, (min, max))
+            .ok_or(OnMessageError::IncompatibleVersion { min, max })?;
+        self.peer_versions.insert(peer_id, version);
+        Ok(self.send_hello(peer_id))
+    }
+
+    /// Capabilities messages to `peers`, recording them as told.
+    fn tell_capabilities(&mut self, peers: Vec<DeviceId>) -> Vec<OutboundAction> {
+        let msg = Message::Capabilities {
+            capabilities: self.capabilities.clone(),
+        };
+        let Ok(bytes) = wire::encode_frame(&msg) else {
+            return vec![];
+        };
+        peers
+            .into_iter()
+            .map(|peer| {
+                self.capabilities_told.insert(peer);
+                OutboundAction::SendMessage(peer, bytes.clone())
+            })
+            .collect()
+    }
+
+    /// Record what `peer_id` can do: on its member entry, or until it joins.
+    fn on_capabilities(&mut self, peer_id: DeviceId, capabilities: Capabilities) {
+        match self.peer_mut(&peer_id) {
+            Some(info) => info.capabilities = capabilities,
+            None => {
+                self.announced_capabilities.insert(peer_id, capabilities);
             }
-            Message::Leave { device_id } => {
-                if device_id == peer_id {
-                    actions.extend(self.on_peer_left(peer_id));
+        }
+    }
+
+    /// Protocol version in use with `peer_id`: the one settled by Hello, or [`PROTOCOL_VERSION`]
+    /// for peers that have not sent one.
+    pub fn peer_protocol_version(&self, peer_id: DeviceId) -> u8 {
+        self.peer_versions
+            .get(&peer_id)
+            .copied()
+            .unwrap_or(PROTOCOL_VERSION)
+    }
+
+    /// Host approval: admit a peer whose JoinRequest is pending (answering with JoinAccept), or
+    /// invite a discovered peer with a JoinRequest. Does nothing for unknown or joined peers, or
+    /// for unpaired peers when [`Config::require_pairing`] is set.
+    pub fn approve_peer(&mut self, peer_id: DeviceId) -> Vec<OutboundAction> {
+        if self.config.require_pairing && !self.is_trusted(peer_id) {
+            return vec![];
+        }
+        let Some(candidate) = self.candidates.get_mut(&peer_id) else {
+            return vec![];
+        };
+        let public_key = candidate.public_key.clone();
+        match candidate.requested.take() {
+            Some(capabilities) => {
+                if self.admit(peer_id, &public_key, capabilities) {
+                    self.send_join(peer_id, &public_key, true)
+                } else {
+                    vec![]
                 }
             }
-            Message::ChunkData {
+            None => {
+                candidate.state = Membership::Invited;
+                self.send_join(peer_id, &public_key, false)
+            }
+        }
+    }
+
+    fn public_key_of(&self, peer_id: DeviceId) -> Option<PublicKey> {
+        self.peers
+            .iter()
+            .find(|p| p.device_id == peer_id)
+            .map(|p| p.public_key.clone())
+            .or_else(|| self.candidates.get(&peer_id).map(|c| c.public_key.clone()))
+    }
+
+    /// Pairing code to show the user for `peer_id` (e.g. `"042 917"`); the peer's user sees the
+    /// same code only if no one is in the middle. `None` until both devices revealed their nonces
+    /// on the current connection ([`CoreEvent::PairingCode`]), or once the peer is paired.
+    pub fn pairing_code(&self, peer_id: DeviceId) -> Option<String> {
+        self.sas_code(peer_id).map(pairing::format_code)
+    }
+
+    fn sas_code(&self, peer_id: DeviceId) -> Option<u32> {
+        let sas = self.sas.get(&peer_id)?;
+        let peer_public = self.public_key_of(peer_id)?;
+        Some(pairing::pairing_code(
+            self.keypair.public_key(),
+            &sas.nonce,
+            &peer_public,
+            sas.peer_nonce.as_ref()?,
+        ))
+    }
+
+    /// Start a pairing code exchange with an unpaired peer: commit to a fresh nonce. Confirmations
+    /// of an earlier exchange no longer count.
+    fn start_pairing(&mut self, peer_id: DeviceId) -> Vec<OutboundAction> {
+        if self.paired.contains(&peer_id) {
+            return vec![];
+        }
+        let nonce = pairing::pairing_nonce();
+        let commitment = pairing::nonce_commitment(self.keypair.public_key(), &nonce);
+        let Ok(bytes) = wire::encode_frame(&Message::PairCommit { commitment }) else {
+            return vec![];
+        };
+        self.pairings.remove(&peer_id);
+        self.sas.insert(
+            peer_id,
+            SasExchange {
+                nonce,
+                revealed: false,
+                peer_commitment: None,
+                peer_nonce: None,
+            },
+        );
+        vec![OutboundAction::SendMessage(peer_id, bytes)]
+    }
+
+    /// A peer committed to its nonce: reveal this device's. A nonce is revealed once only, so a
+    /// commitment after that (the peer started over) gets a new exchange first.
+    fn on_pair_commit(&mut self, peer_id: DeviceId, commitment: [u8; 32]) -> Vec<OutboundAction> {
+        if self.paired.contains(&peer_id) || self.public_key_of(peer_id).is_none() {
+            return vec![];
+        }
+        let mut actions = match self.sas.get(&peer_id) {
+            Some(sas) if !sas.revealed => vec![],
+            _ => self.start_pairing(peer_id),
+        };
+        let Some(sas) = self.sas.get_mut(&peer_id) else {
+            return actions;
+        };
+        sas.peer_commitment = Some(commitment);
+        sas.revealed = true;
+        if let Ok(bytes) = wire::encode_frame(&Message::PairNonce { nonce: sas.nonce }) {
+            actions.push(OutboundAction::SendMessage(peer_id, bytes));
+        }
+        actions
+    }
+
+    /// A peer revealed its nonce; it must match the commitment it sent before seeing this
+    /// device's nonce.
+    fn on_pair_nonce(&mut self, peer_id: DeviceId, nonce: [u8; 32]) -> Result<(), OnMessageError> {
+        let peer_public = self
+            .public_key_of(peer_id)
+            .ok_or(OnMessageError::PairingRejected)?;
+        let sas = self
+            .sas
+            .get_mut(&peer_id)
+            .filter(|sas| sas.peer_nonce.is_none())
+            .ok_or(OnMessageError::PairingRejected)?;
+        if sas.peer_commitment != Some(pairing::nonce_commitment(&peer_public, &nonce)) {
+            return Err(OnMessageError::PairingRejected);
+        }
+        sas.peer_nonce = Some(nonce);
+        self.emit(CoreEvent::PairingCode(peer_id));
+        Ok(())
+    }
+
+    /// This device's key fingerprint, for the host to show next to its device ID.
+    pub fn fingerprint(&self) -> Fingerprint {
+        Fingerprint::of(self.keypair.public_key())
+    }
+
+    /// Fingerprint of a known device's key (pinned, in the pod or discovered); `None` if unknown.
+    pub fn peer_fingerprint(&self, peer_id: DeviceId) -> Option<Fingerprint> {
+        self.public_key_of(peer_id)
+            .or_else(|| self.known_peers.get(&peer_id).cloned())
+            .map(|key| Fingerprint::of(&key))
+    }
+
+    /// Whether both users confirmed the pairing code for `peer_id`.
+    pub fn is_paired(&self, peer_id: DeviceId) -> bool {
+        self.paired.contains(&peer_id)
+    }
+
+    /// Paired, or pinned in the known-peers store (e.g. imported from a previous run).
+    fn is_trusted(&self, peer_id: DeviceId) -> bool {
+        self.paired.contains(&peer_id) || self.known_peers.contains_key(&peer_id)
+    }
+
+    /// Check the identity a peer presented in the transport handshake: its device ID must be
+    /// derived from its public key, it must not be revoked, and a known device must present the
+    /// key pinned for it. Hosts drop the connection on error.
+    pub fn check_handshake(
+        &self,
+        peer_id: DeviceId,
+        public_key: &PublicKey,
+    ) -> Result<(), CoreError> {
+        let checked = if DeviceId::from_public_key(public_key.as_bytes()) != peer_id {
+            Err(HandshakeError::IdentityMismatch)
+        } else {
+            self.check_pin(peer_id, public_key)
+        };
+        checked.map_err(|source| CoreError::Handshake {
+            peer: peer_id,
+            source,
+        })
+    }
+
+    fn check_pin(&self, peer_id: DeviceId, public_key: &PublicKey) -> Result<(), HandshakeError> {
+        if self.revoked.contains(&peer_id) {
+            return Err(HandshakeError::Revoked);
+        }
+        match self.known_peers.get(&peer_id) {
+            Some(pinned) if pinned != public_key => Err(HandshakeError::KeyChanged),
+            _ => Ok(()),
+        }
+    }
+
+    /// Known peers and their pinned public keys, for the host to persist across restarts.
+    pub fn known_peers(&self) -> Vec<(DeviceId, PublicKey)> {
+        self.known_peers
+            .iter()
+            .map(|(id, pk)| (*id, pk.clone()))
+            .collect()
+    }
+
+    /// Load known peers saved from [`PeaPodCore::known_peers`]. Entries whose device ID is not
+    /// derived from the key are skipped; existing pins are not overwritten. Returns the number
+    /// of entries added.
+    pub fn import_known_peers(
+        &mut self,
+        entries: impl IntoIterator<Item = (DeviceId, PublicKey)>,
+    ) -> usize {
+        let mut added = 0;
+        for (id, public_key) in entries {
+            if DeviceId::from_public_key(public_key.as_bytes()) != id
+                || self.known_peers.contains_key(&id)
+            {
+                continue;
+            }
+            self.known_peers.insert(id, public_key);
+            added += 1;
+        }
+        added
+    }
+
+    /// Pins, pairings, revocations and integrity history, for the host to persist across restarts
+    /// and load with [`PeaPodCore::import_trust`]. Entries are sorted by device ID.
+    pub fn export_trust(&self) -> TrustStore {
+        let by_id = |a: &DeviceId, b: &DeviceId| a.as_bytes().cmp(b.as_bytes());
+        let mut store = TrustStore {
+            known_peers: self.known_peers(),
+            paired: self.paired.iter().copied().collect(),
+            revoked: self.revoked.iter().copied().collect(),
+            trust: self.trust.records(),
+            rotations: self
+                .rotations
+                .iter()
+                .map(|(current, (previous, until))| KeyRotation {
+                    previous: *previous,
+                    current: *current,
+                    remaining_ticks: until.saturating_sub(self.tick_count),
+                })
+                .collect(),
+            revocations: self.revocations(),
+            pod_key: self.pod_key.as_ref().and_then(|key| {
+                let bytes = bincode::serialize(key).ok()?;
+                chunk::seal_chunk(self.keypair.as_ref(), POD_KEY_CONTEXT, &bytes).ok()
+            }),
+        };
+        store.known_peers.sort_by(|a, b| by_id(&a.0, &b.0));
+        store.paired.sort_by(by_id);
+        store.revoked.sort_by(by_id);
+        store.trust.sort_by(|a, b| by_id(&a.0, &b.0));
+        store
+            .rotations
+            .sort_by(|a, b| by_id(&a.current, &b.current));
+        store
+    }
+
+    /// Load a [`TrustStore`] saved from [`PeaPodCore::export_trust`], settling conflicts with
+    /// `merge`. Pins whose device ID is not derived from the key are skipped, and pairings only
+    /// count for pinned devices. Revoked peers in the pod are removed and newly isolated ones lose
+    /// their chunks; the returned messages reassign them.
+    pub fn import_trust(&mut self, store: TrustStore, merge: TrustMerge) -> Vec<OutboundAction> {
+        let replace = merge == TrustMerge::Replace;
+        let mut actions = Vec::new();
+        let revoked: HashSet<DeviceId> = store.revoked.iter().copied().collect();
+        for (id, public_key) in store.known_peers {
+            if DeviceId::from_public_key(public_key.as_bytes()) != id || revoked.contains(&id) {
+                continue;
+            }
+            if replace {
+                self.revoked.remove(&id);
+                self.known_peers.insert(id, public_key);
+                self.paired.remove(&id);
+            } else if !self.revoked.contains(&id) {
+                self.known_peers.entry(id).or_insert(public_key);
+            }
+        }
+        for id in store.paired {
+            if self.known_peers.contains_key(&id) && !revoked.contains(&id) {
+                self.paired.insert(id);
+            }
+        }
+        for id in store.revoked {
+            actions.extend(self.revoke_peer(id));
+        }
+        for (id, record) in store.trust {
+            if self.trust.merge(id, record, replace) {
+                self.emit(CoreEvent::PeerIsolated(id));
+                actions.extend(self.redistribute_peer_chunks(id));
+            }
+        }
+        // The host kept these records, so they apply whoever issued them.
+        for revocation in store.revocations {
+            if revocation.verify() && revocation.revoked != self.keypair.device_id() {
+                self.revocations
+                    .insert(revocation.revoked, revocation.clone());
+                actions.extend(self.revoke_peer(revocation.revoked));
+            }
+        }
+        for rotation in store.rotations {
+            if !self.revoked.contains(&rotation.current) {
+                let until = self.tick_count.saturating_add(rotation.remaining_ticks);
+                self.rotations
+                    .entry(rotation.current)
+                    .or_insert((rotation.previous, until));
+            }
+        }
+        // Sealed with this device's storage key, so a store from another device is ignored.
+        let saved = store.pod_key.and_then(|sealed| {
+            let bytes = chunk::open_chunk(self.keypair.as_ref(), POD_KEY_CONTEXT, &sealed).ok()?;
+            bincode::deserialize::<PodKey>(&bytes).ok()
+        });
+        if let Some(key) = saved {
+            if self.pod_key.as_ref().is_none_or(|own| key.supersedes(own)) {
+                actions.extend(self.adopt_pod_key(key, None));
+            }
+        }
+        actions
+    }
+
+    /// Move this device to a new long-term keypair: every pod member is sent a RotateKey proving
+    /// both keys, and the core uses `new` from now on (join proofs, attestations, pairing codes).
+    /// The host persists `new` (e.g. [`Keypair::to_bytes_encrypted`]) and uses it for new
+    /// connections; peers accept the old identity for their [`Config::key_rotation_grace_ticks`].
+    /// Devices offline now learn nothing and see a new device.
+    pub fn rotate_keypair(&mut self, new: Arc<dyn DeviceKey>) -> Vec<OutboundAction> {
+        let mut actions = Vec::new();
+        for peer in &self.peers {
+            let (old_proof, new_proof) =
+                self.keypair.rotation_proofs(new.as_ref(), &peer.public_key);
+            let msg = Message::RotateKey {
+                new_public_key: new.public_key().clone(),
+                old_proof,
+                new_proof,
+            };
+            if let Ok(bytes) = wire::encode_frame(&msg) {
+                actions.push(OutboundAction::SendMessage(peer.device_id, bytes));
+            }
+        }
+        self.keypair = new;
+        actions
+    }
+
+    /// The device ID `peer_id` rotated from, while the old one is in its grace period.
+    pub fn previous_identity(&self, peer_id: DeviceId) -> Option<DeviceId> {
+        self.rotations.get(&peer_id).map(|(previous, _)| *previous)
+    }
+
+    /// A verified RotateKey from `peer_id`: the new device ID inherits its pin, pairing and
+    /// integrity record, and the old one stays valid until the grace period ends.
+    fn on_rotate_key(
+        &mut self,
+        peer_id: DeviceId,
+        new_public: PublicKey,
+        old_proof: &[u8; 32],
+        new_proof: &[u8; 32],
+    ) -> Result<(), OnMessageError> {
+        let old_public = self
+            .public_key_of(peer_id)
+            .or_else(|| self.known_peers.get(&peer_id).cloned())
+            .ok_or(OnMessageError::RotationRejected)?;
+        let new_id = DeviceId::from_public_key(new_public.as_bytes());
+        if self.revoked.contains(&new_id)
+            || !self
+                .keypair
+                .verify_rotation(&old_public, &new_public, old_proof, new_proof)
+        {
+            return Err(OnMessageError::RotationRejected);
+        }
+        self.known_peers.insert(new_id, new_public);
+        if self.paired.contains(&peer_id) {
+            self.paired.insert(new_id);
+        }
+        if let Some(record) = self.trust.get(&peer_id) {
+            self.trust.merge(new_id, record, true);
+        }
+        let until = self
+            .tick_count
+            .saturating_add(self.config.key_rotation_grace_ticks);
+        self.rotations.insert(new_id, (peer_id, until));
+        self.emit(CoreEvent::PeerKeyRotated {
+            previous: peer_id,
+            current: new_id,
+        });
+        Ok(())
+    }
+
+    /// Revoke the old identities of rotations whose grace period ended.
+    fn retire_rotated_keys(&mut self) -> Vec<OutboundAction> {
+        let now = self.tick_count;
+        let expired: Vec<DeviceId> = self
+            .rotations
+            .iter()
+            .filter(|(_, (_, until))| *until <= now)
+            .map(|(current, _)| *current)
+            .collect();
+        let mut actions = Vec::new();
+        for current in expired {
+            if let Some((previous, _)) = self.rotations.remove(&current) {
+                actions.extend(self.revoke_peer(previous));
+            }
+        }
+        actions
+    }
+
+    /// Metrics of current and former peers, for the host to persist across restarts.
+    pub fn known_metrics(&self) -> Vec<(DeviceId, PeerMetrics)> {
+        let mut known: HashMap<DeviceId, PeerMetrics> = self.known_metrics.clone();
+        for p in &self.peers {
+            known.insert(p.device_id, p.metrics.clone());
+        }
+        known.into_iter().collect()
+    }
+
+    /// Load metrics saved from [`PeaPodCore::known_metrics`]; each applies when its peer joins.
+    /// Failure decay restarts from now, since ticks of an earlier run do not carry over. Peers
+    /// already in the pod keep their current metrics. Returns the number of entries added.
+    pub fn import_known_metrics(
+        &mut self,
+        entries: impl IntoIterator<Item = (DeviceId, PeerMetrics)>,
+    ) -> usize {
+        let now = self.tick_count;
+        let mut added = 0;
+        for (id, mut metrics) in entries {
+            if self.has_peer(&id) {
+                continue;
+            }
+            metrics.last_failure_tick = (metrics.chunks_failed > 0).then_some(now);
+            self.known_metrics.insert(id, metrics);
+            added += 1;
+        }
+        added
+    }
+
+    /// Trust a device with this public key: pin it (replacing any previous pin) and lift a
+    /// revocation. Returns false if the device ID is not derived from the key.
+    pub fn trust_peer(&mut self, peer_id: DeviceId, public_key: &PublicKey) -> bool {
+        if DeviceId::from_public_key(public_key.as_bytes()) != peer_id {
+            return false;
+        }
+        self.revoked.remove(&peer_id);
+        self.revocations.remove(&peer_id);
+        self.known_peers.insert(peer_id, public_key.clone());
+        true
+    }
+
+    /// Stop trusting a device: drop its pin and pairing, remove it from the pod (redistributing
+    /// its chunks) and refuse it until [`PeaPodCore::trust_peer`]. The revocation itself is not
+    /// part of [`PeaPodCore::known_peers`].
+    pub fn revoke_peer(&mut self, peer_id: DeviceId) -> Vec<OutboundAction> {
+        self.known_peers.remove(&peer_id);
+        self.paired.remove(&peer_id);
+        self.pairings.remove(&peer_id);
+        self.sas.remove(&peer_id);
+        self.offered_pod_keys.remove(&peer_id);
+        self.names.remove(&peer_id);
+        self.revoked.insert(peer_id);
+        self.on_peer_left(peer_id)
+    }
+
+    /// Revoke a lost or compromised device for all of the user's devices: [`PeaPodCore::revoke_peer`]
+    /// here, and a signed [`Message::Revoke`] to every pod member, which paired devices apply and
+    /// pass on. A device may revoke itself. Nothing happens if a signed revocation is already held.
+    pub fn revoke_device(&mut self, peer_id: DeviceId) -> Vec<OutboundAction> {
+        if self.revocations.contains_key(&peer_id) {
+            return vec![];
+        }
+        let revocation = self.keypair.revoke(peer_id);
+        self.apply_revocation(revocation, None)
+    }
+
+    /// Signed revocations this device holds, sorted by revoked device ID, for the host to persist
+    /// (also in [`PeaPodCore::export_trust`]) or hand to the user's other devices.
+    pub fn revocations(&self) -> Vec<Revocation> {
+        let mut out: Vec<Revocation> = self.revocations.values().cloned().collect();
+        out.sort_by(|a, b| a.revoked.as_bytes().cmp(b.revoked.as_bytes()));
+        out
+    }
+
+    /// A Revoke from `peer_id`: apply it if it verifies and its issuer is paired, or is the revoked
+    /// device itself. Revocations of this device, or already held, are dropped.
+    fn on_revoke(
+        &mut self,
+        peer_id: DeviceId,
+        revocation: Revocation,
+    ) -> Result<Vec<OutboundAction>, OnMessageError> {
+        let issuer = revocation.issuer;
+        if !revocation.verify()
+            || self.revoked.contains(&issuer)
+            || !(issuer == revocation.revoked || self.paired.contains(&issuer))
+        {
+            return Err(OnMessageError::RevocationRejected);
+        }
+        if revocation.revoked == self.keypair.device_id()
+            || self.revocations.contains_key(&revocation.revoked)
+        {
+            return Ok(vec![]);
+        }
+        Ok(self.apply_revocation(revocation, Some(peer_id)))
+    }
+
+    /// Record `revocation`, revoke the device and pass the record to every other pod member.
+    fn apply_revocation(
+        &mut self,
+        revocation: Revocation,
+        from: Option<DeviceId>,
+    ) -> Vec<OutboundAction> {
+        let revoked = revocation.revoked;
+        let mut actions = Vec::new();
+        if let Ok(bytes) = wire::encode_frame(&Message::Revoke {
+            revocation: revocation.clone(),
+        }) {
+            for peer in &self.peers {
+                if peer.device_id != revoked && Some(peer.device_id) != from {
+                    actions.push(OutboundAction::SendMessage(peer.device_id, bytes.clone()));
+                }
+            }
+        }
+        self.emit(CoreEvent::PeerRevoked {
+            peer: revoked,
+            issuer: revocation.issuer,
+        });
+        self.revocations.insert(revoked, revocation);
+        if revoked != self.keypair.device_id() {
+            let knew_pod_key = self.paired.contains(&revoked);
+            actions.extend(self.revoke_peer(revoked));
+            // The revoked device could still read the pod's beacons.
+            if let Some(epoch) = self
+                .pod_key
+                .as_ref()
+                .map(|k| k.epoch)
+                .filter(|_| knew_pod_key)
+            {
+                actions.extend(self.adopt_pod_key(PodKey::generate(epoch + 1), None));
+            }
+        }
+        actions
+    }
+
+    /// This device's user confirmed that `peer_id` shows the same pairing code: send the peer a
+    /// PairConfirm. Once the peer's confirmation is verified too, the peer is paired
+    /// ([`CoreEvent::PeerPaired`]) and approved.
+    pub fn confirm_pairing(&mut self, peer_id: DeviceId) -> Vec<OutboundAction> {
+        let (Some(peer_public), Some(code)) = (self.public_key_of(peer_id), self.sas_code(peer_id))
+        else {
+            return vec![];
+        };
+        if self.paired.contains(&peer_id) {
+            return vec![];
+        }
+        let msg = Message::PairConfirm {
+            confirmation: pairing::confirmation(self.keypair.as_ref(), &peer_public, code),
+            pod_key: self
+                .pod_key
+                .as_ref()
+                .and_then(|key| key.wrap(self.keypair.as_ref(), &peer_public).ok()),
+        };
+        let mut actions = match wire::encode_frame(&msg) {
+            Ok(bytes) => vec![OutboundAction::SendMessage(peer_id, bytes)],
+            Err(_) => return vec![],
+        };
+        self.pairings.entry(peer_id).or_default().0 = true;
+        actions.extend(self.finish_pairing(peer_id));
+        actions
+    }
+
+    /// Confirm the pairing of the known, unpaired device whose fingerprint matches `peer` (any form
+    /// [`Fingerprint::matches`] accepts) and whose code is `code` (digits; spaces and other
+    /// separators ignored), e.g. a pair the user typed into a CLI. Empty if no such device.
+    pub fn confirm_pairing_code(&mut self, peer: &str, code: &str) -> Vec<OutboundAction> {
+        let digits: String = code.chars().filter(char::is_ascii_digit).collect();
+        let Ok(code) = digits.parse::<u32>() else {
+            return vec![];
+        };
+        let matching: Vec<DeviceId> = self
+            .peers
+            .iter()
+            .map(|p| (p.device_id, p.public_key.clone()))
+            .chain(
+                self.candidates
+                    .iter()
+                    .filter(|(_, c)| c.state != Membership::Left)
+                    .map(|(id, c)| (*id, c.public_key.clone())),
+            )
+            .filter(|(id, key)| {
+                !self.paired.contains(id)
+                    && Fingerprint::of(key).matches(peer)
+                    && self.sas_code(*id) == Some(code)
+            })
+            .map(|(id, _)| id)
+            .collect();
+        matching
+            .into_iter()
+            .flat_map(|peer_id| self.confirm_pairing(peer_id))
+            .collect()
+    }
+
+    /// Verify a peer's PairConfirm; `None` if the peer is unknown, confirmed a different code or
+    /// sent a pod key that does not unwrap.
+    fn on_pair_confirm(
+        &mut self,
+        peer_id: DeviceId,
+        mac: &[u8; 32],
+        pod_key: Option<&WrappedPodKey>,
+    ) -> Option<Vec<OutboundAction>> {
+        let peer_public = self.public_key_of(peer_id)?;
+        let code = self.sas_code(peer_id)?;
+        if !pairing::verify_confirmation(self.keypair.as_ref(), &peer_public, code, mac) {
+            return None;
+        }
+        if let Some(wrapped) = pod_key {
+            let key = PodKey::unwrap_from(wrapped, self.keypair.as_ref(), &peer_public)?;
+            self.offered_pod_keys.insert(peer_id, key);
+        }
+        self.pairings.entry(peer_id).or_default().1 = true;
+        Some(self.finish_pairing(peer_id))
+    }
+
+    /// Mark `peer_id` paired once both confirmations are in, settle the pod key and approve it.
+    fn finish_pairing(&mut self, peer_id: DeviceId) -> Vec<OutboundAction> {
+        if self.pairings.get(&peer_id) != Some(&(true, true)) {
+            return vec![];
+        }
+        self.pairings.remove(&peer_id);
+        self.sas.remove(&peer_id);
+        self.paired.insert(peer_id);
+        self.emit(CoreEvent::PeerPaired(peer_id));
+        // Both devices see the same two offers (or none) and settle on the same key.
+        let offered = self.offered_pod_keys.remove(&peer_id);
+        let mut actions = match (self.pod_key.clone(), offered) {
+            (Some(own), Some(theirs)) if theirs.supersedes(&own) => {
+                self.adopt_pod_key(theirs, Some(peer_id))
+            }
+            (None, Some(theirs)) => self.adopt_pod_key(theirs, Some(peer_id)),
+            (None, None) => match self.public_key_of(peer_id) {
+                Some(peer_public) => self.adopt_pod_key(
+                    PodKey::derive(self.keypair.as_ref(), &peer_public),
+                    Some(peer_id),
+                ),
+                None => vec![],
+            },
+            (Some(_), _) => vec![],
+        };
+        actions.extend(self.approve_peer(peer_id));
+        actions
+    }
+
+    /// Make `key` the pod key, keeping the old one for a while to open frames from devices that
+    /// have not heard of the change, and send it to every paired pod member except `from`.
+    fn adopt_pod_key(&mut self, key: PodKey, from: Option<DeviceId>) -> Vec<OutboundAction> {
+        if let Some(old) = self.pod_key.replace(key) {
+            self.previous_pod_keys.insert(0, old);
+            self.previous_pod_keys.truncate(POD_KEY_HISTORY);
+        }
+        self.peers
+            .iter()
+            .map(|p| p.device_id)
+            .filter(|&id| Some(id) != from)
+            .collect::<Vec<_>>()
+            .into_iter()
+            .flat_map(|id| self.send_pod_key(id))
+            .collect()
+    }
+
+    /// PodKey message for `peer_id` if it is paired and this device has a pod key.
+    fn send_pod_key(&self, peer_id: DeviceId) -> Vec<OutboundAction> {
+        let (Some(key), Some(peer_public)) = (&self.pod_key, self.public_key_of(peer_id)) else {
+            return vec![];
+        };
+        if !self.paired.contains(&peer_id) {
+            return vec![];
+        }
+        key.wrap(self.keypair.as_ref(), &peer_public)
+            .ok()
+            .and_then(|key| wire::encode_frame(&Message::PodKey { key }).ok())
+            .map(|bytes| vec![OutboundAction::SendMessage(peer_id, bytes)])
+            .unwrap_or_default()
+    }
+
+    /// A paired peer's pod key: adopt it if it supersedes this device's, or answer with ours if
+    /// the peer is behind.
+    fn on_pod_key(
+        &mut self,
+        peer_id: DeviceId,
+        wrapped: &WrappedPodKey,
+    ) -> Result<Vec<OutboundAction>, OnMessageError> {
+        let key = self
+            .public_key_of(peer_id)
+            .filter(|_| self.paired.contains(&peer_id))
+            .and_then(|peer_public| {
+                PodKey::unwrap_from(wrapped, self.keypair.as_ref(), &peer_public)
+            })
+            .ok_or(OnMessageError::PodKeyRejected)?;
+        Ok(match &self.pod_key {
+            Some(own) if *own == key => vec![],
+            Some(own) if own.supersedes(&key) => self.send_pod_key(peer_id),
+            _ => self.adopt_pod_key(key, Some(peer_id)),
+        })
+    }
+
+    /// Add an authenticated peer to the pod with its advertised capabilities.
+    fn admit(
+        &mut self,
+        peer_id: DeviceId,
+        public_key: &PublicKey,
+        capabilities: Capabilities,
+    ) -> bool {
+        if !self.on_peer_joined(peer_id, public_key) {
+            return false;
+        }
+        if let Some(info) = self.peer_mut(&peer_id) {
+            info.capabilities = capabilities;
+        }
+        true
+    }
+
+    /// Handle a verified JoinRequest: admit and accept if this device invited the peer, it is
+    /// already a member, or approval is not required; otherwise hold it for the host.
+    fn on_join_request(
+        &mut self,
+        peer_id: DeviceId,
+        public_key: PublicKey,
+        capabilities: Capabilities,
+    ) -> Vec<OutboundAction> {
+        let state = self.membership(peer_id);
+        let admit = if self.config.require_pairing {
+            self.is_trusted(peer_id)
+        } else {
+            !self.config.require_approval
+                || matches!(state, Some(Membership::Invited | Membership::Joined))
+        };
+        if admit {
+            return if self.admit(peer_id, &public_key, capabilities) {
+                self.send_join(peer_id, &public_key, true)
+            } else {
+                vec![]
+            };
+        }
+        self.candidates.insert(
+            peer_id,
+            Candidate {
+                state: Membership::Discovered,
+                public_key,
+                requested: Some(capabilities),
+            },
+        );
+        self.emit(CoreEvent::JoinRequested(peer_id));
+        vec![]
+    }
+
+    /// This device's key, for driving a [`crate::Handshake`] from the FFI.
+    pub(crate) fn device_key(&self) -> &dyn DeviceKey {
+        self.keypair.as_ref()
+    }
+
+    /// Legacy handshake bytes for local transport: 1 version + 16 device_id + 32 public_key. The
+    /// key swap authenticates neither side; hosts should run [`crate::Handshake`] instead.
+    pub fn handshake_bytes(&self) -> [u8; 49] {
+        let mut out = [0u8; 49];
+        out[0] = PROTOCOL_VERSION;
+        out[1..17].copy_from_slice(self.keypair.device_id().as_bytes());
+        out[17..49].copy_from_slice(self.keypair.public_key().as_bytes());
+        out
+    }
+
+    /// Session key for a peer (from shared secret with peer's public key), for the legacy
+    /// handshake. Both directions share it; [`crate::Session`] has one key per direction.
+    pub fn session_key(&self, peer_public: &PublicKey) -> Zeroizing<[u8; 32]> {
+        derive_session_key(&self.keypair.shared_secret(peer_public))
+    }
+
+    /// Evaluate a request against [`Config::eligibility`] before calling
+    /// [`on_incoming_request`](Self::on_incoming_request); an ineligible result names the rule so
+    /// the host can log why the request took the normal path.
+    pub fn evaluate_request(&self, request: &RequestInfo) -> Eligibility {
+        self.config.eligibility.evaluate(request)
+    }
+
+    /// Install (or with `None` remove) a hook that reviews every eligible request before chunks are
+    /// assigned: it may allow it, deny it (the request falls back) or replace its URL, range and
+    /// validator. The hook runs synchronously inside
+    /// [`on_incoming_request`](Self::on_incoming_request).
+    pub fn set_content_filter(&mut self, filter: Option<Box<dyn ContentFilter>>) {
+        self.content_filter = filter;
+    }
+
+    /// Called when the host has an eligible request. Returns [`Action::Accelerate`] with chunk
+    /// assignment (host then fetches self chunks and sends ChunkRequest to peers),
+    /// [`Action::Cached`], [`Action::Attached`] (an identical request is already in flight) or
+    /// [`Action::Fallback`] (also for URLs the eligibility policy or content filter excludes).
+    /// `range` is the client's inclusive byte range; chunk ranges are absolute origin offsets
+    /// within it, and the body returned on completion is exactly that range (the host answers 206
+    /// Partial Content).
+    pub fn on_incoming_request(&mut self, url: &str, range: Option<(u64, u64)>) -> Action {
+        self.on_incoming_request_with_validator(url, range, None)
+    }
+
+    /// Same as [`PeaPodCore::on_incoming_request`], with the origin's validator (ETag or
+    /// Last-Modified) when the host knows it. Chunks cached under the same URL, range and
+    /// validator are not fetched again; when all of them are cached the result is [`Action::Cached`].
+    pub fn on_incoming_request_with_validator(
+        &mut self,
+        url: &str,
+        range: Option<(u64, u64)>,
+        validator: Option<&str>,
+    ) -> Action {
+        if !self.config.eligibility.evaluate_url(url).is_eligible() {
+            return self.fall_back(url);
+        }
+        let Some(filter) = self.content_filter.as_mut() else {
+            return self.plan_transfer(url, range, validator);
+        };
+        let request = TransferRequest {
+            url: url.to_string(),
+            range,
+            validator: validator.map(str::to_string),
+        };
+        match filter.review(&request) {
+            ContentDecision::Allow => self.plan_transfer(url, range, validator),
+            ContentDecision::Deny => self.fall_back(url),
+            ContentDecision::Modify(r) => {
+                if !self.config.eligibility.evaluate_url(&r.url).is_eligible() {
+                    return self.fall_back(&r.url);
+                }
+                self.plan_transfer(&r.url, r.range, r.validator.as_deref())
+            }
+        }
+    }
+
+    /// Tell the host (and its event log) that `url` goes the normal path.
+    fn fall_back(&mut self, url: &str) -> Action {
+        self.emit(CoreEvent::Fallback {
+            url: url.to_string(),
+        });
+        Action::Fallback
+    }
+
+    /// Plan and start a transfer for a request that passed eligibility and the content filter.
+    fn plan_transfer(
+        &mut self,
+        url: &str,
+        range: Option<(u64, u64)>,
+        validator: Option<&str>,
+    ) -> Action {
+        let total_length = range
+            .map(|(s, e)| e.saturating_sub(s).saturating_add(1))
+            .unwrap_or(0);
+        let base = range.map(|(s, _)| s).unwrap_or(0);
+        if self.config.max_transfer_bytes > 0 && total_length > self.config.max_transfer_bytes {
+            return self.fall_back(url);
+        }
+        if let Some(transfer_id) = self.in_flight(url, base, total_length, validator) {
+            return Action::Attached {
+                transfer_id,
+                total_length,
+            };
+        }
+        // Chunks are planned from 0, then moved to absolute origin offsets of the client's Range.
+        let at_base = |c: ChunkId| ChunkId {
+            start: c.start.saturating_add(base),
+            end: c.end.saturating_add(base),
+            ..c
+        };
+        let workers: Vec<DeviceId> = self.workers().collect();
+        let transfer_id: [u8; 16] = uuid::Uuid::new_v4().into_bytes();
+        let plan: Option<Vec<(ChunkId, DeviceId)>> = self
+            .plan_sized(transfer_id, total_length, &workers)
+            .map(|plan| plan.into_iter().map(|(c, d)| (at_base(c), d)).collect());
+        let chunk_ids: Vec<ChunkId> = match &plan {
+            Some(plan) => plan.iter().map(|&(c, _)| c).collect(),
+            None => {
+                let chunk_size = self.chunk_size_for(&workers);
+                chunk::split_into_chunks(transfer_id, total_length, chunk_size)
+                    .into_iter()
+                    .map(at_base)
+                    .collect()
+            }
+        };
+        let mut state = TransferState::new_at(transfer_id, base, total_length, chunk_ids.clone());
+        let mut missing = Vec::new();
+        let mut cached_bytes = 0u64;
+        for chunk_id in chunk_ids {
+            let key = CacheKey {
+                url: url.to_string(),
+                start: chunk_id.start,
+                end: chunk_id.end,
+                validator: validator.map(str::to_string),
+            };
+            match self.cache.get(&key) {
+                Some(payload) => {
+                    cached_bytes += payload.len() as u64;
+                    state.mark_received(chunk_id, payload.to_vec());
+                }
+                None => missing.push(chunk_id),
+            }
+        }
+        if total_length > 0 && missing.is_empty() {
+            self.stats.bytes_from_cache = self.stats.bytes_from_cache.saturating_add(cached_bytes);
+            return Action::Cached {
+                body: state.reassemble_into_bytes(),
+            };
+        }
+        // Fall back when no peer can help (none joined, or all isolated).
+        if total_length == 0 || workers.len() < 2 {
+            self.emit(CoreEvent::Fallback {
+                url: url.to_string(),
+            });
+            return Action::Fallback;
+        }
+        let assignment = match plan {
+            Some(plan) => plan
+                .into_iter()
+                .filter(|(c, _)| missing.contains(c))
+                .collect(),
+            None => self.assign(&missing, &workers),
+        };
+        let mut assignment = self.apply_self_share(assignment, &workers);
+        if self.config.self_first_chunk {
+            let self_id = self.keypair.device_id();
+            if let Some(first) = assignment.iter_mut().min_by_key(|(c, _)| c.start) {
+                first.1 = self_id;
+            }
+        }
+        let shared: HashMap<ChunkId, ChunkId> = missing
+            .iter()
+            .filter_map(|&c| Some((c, self.twin_in_flight(url, validator, c)?)))
+            .collect();
+        self.stats.bytes_from_cache = self.stats.bytes_from_cache.saturating_add(cached_bytes);
+        self.transfers.insert(
+            transfer_id,
+            ActiveTransfer {
+                url: url.to_string(),
+                validator: validator.map(str::to_string),
+                headers: Vec::new(),
+                state,
+                assignment: assignment.clone(),
+                requested_at: HashMap::new(),
+                paused: false,
+                paused_at: 0,
+                started_at_tick: self.tick_count,
+                last_activity: self.tick_count,
+                deadline_tick: (self.config.transfer_deadline_ticks > 0).then(|| {
+                    self.tick_count
+                        .saturating_add(self.config.transfer_deadline_ticks)
+                }),
+                contributions: HashMap::new(),
+                deferred: BTreeMap::new(),
+                queued: BTreeMap::new(),
+                backpressured: false,
+                endgame: HashMap::new(),
+                raced: HashSet::new(),
+                shared,
+                failed: HashSet::new(),
+                retry_at: HashMap::new(),
+                manifest: None,
+                origin_digests: Vec::new(),
+                streaming: false,
+            },
+        );
+        self.emit(CoreEvent::TransferStarted {
+            transfer_id,
+            total_length,
+        });
+        let assignment: Vec<(ChunkId, DeviceId)> = assignment
+            .into_iter()
+            .filter(|&(c, _)| self.admit_chunk(c))
+            .collect();
+        self.update_backpressure(transfer_id);
+        Action::Accelerate {
+            transfer_id,
+            total_length,
+            assignment,
+        }
+    }
+
+    /// Active transfer for the same URL, range and validator whose whole body is still to be
+    /// delivered (not paused, nothing taken via [`take_segment`](Self::take_segment)).
+    fn in_flight(
+        &self,
+        url: &str,
+        base: u64,
+        total_length: u64,
+        validator: Option<&str>,
+    ) -> Option<[u8; 16]> {
+        if total_length == 0 {
+            return None;
+        }
+        self.transfers
+            .iter()
+            .find(|(_, t)| {
+                t.url == url
+                    && t.validator.as_deref() == validator
+                    && t.state.base_offset() == base
+                    && t.state.total_length == total_length
+                    && !t.paused
+                    && t.state.delivered_bytes() == 0
+            })
+            .map(|(&id, _)| id)
+    }
+
+    /// The same URL and range as `chunk_id`, outstanding in another transfer that is not paused
+    /// and fetches it itself (not shared in turn).
+    fn twin_in_flight(
+        &self,
+        url: &str,
+        validator: Option<&str>,
+        chunk_id: ChunkId,
+    ) -> Option<ChunkId> {
+        self.transfers.iter().find_map(|(&transfer_id, t)| {
+            let twin = ChunkId {
+                transfer_id,
+                ..chunk_id
+            };
+            let outstanding = t.state.chunk_index(twin).is_some()
+                && !t.state.is_chunk_received(twin)
+                && !t.shared.contains_key(&twin);
+            (t.url == url && t.validator.as_deref() == validator && !t.paused && outstanding)
+                .then_some(twin)
+        })
+    }
+
+    /// Hand a verified chunk to the transfers sharing it (see [`ActiveTransfer::shared`]). Their
+    /// bytes count as reused; a transfer completed this way is delivered with
+    /// [`OutboundAction::TransferComplete`].
+    fn deliver_shared(
+        &mut self,
+        twin: ChunkId,
+        from: DeviceId,
+        payload: &[u8],
+    ) -> Vec<OutboundAction> {
+        let now = self.tick_count;
+        let sharers: Vec<ChunkId> = self
+            .transfers
+            .values()
+            .flat_map(|t| t.shared.iter())
+            .filter(|&(_, &c)| c == twin)
+            .map(|(&c, _)| c)
+            .collect();
+        let mut actions = Vec::new();
+        for chunk_id in sharers {
+            let transfer_id = chunk_id.transfer_id;
+            let Some(t) = self.transfers.get_mut(&transfer_id) else {
+                continue;
+            };
+            t.shared.remove(&chunk_id);
+            if t.state.is_chunk_received(chunk_id) {
+                continue;
+            }
+            t.last_activity = now;
+            let done = t.state.mark_received(chunk_id, payload.to_vec());
+            self.stats.bytes_from_cache = self
+                .stats
+                .bytes_from_cache
+                .saturating_add(payload.len() as u64);
+            self.emit(CoreEvent::ChunkVerified { chunk_id, from });
+            if !done {
+                continue;
+            }
+            let Some(t) = self.transfers.remove(&transfer_id) else {
+                continue;
+            };
+            let body = t.state.reassemble_remaining();
+            if let Some(abort) = self
+                .reject_root_mismatch(transfer_id, &t, &body)
+                .or_else(|| self.reject_digest_mismatch(transfer_id, &t, &body))
+            {
+                self.record_history(transfer_id, &t, TransferOutcome::Fallback);
+                actions.extend(abort);
+                continue;
+            }
+            self.record_history(transfer_id, &t, TransferOutcome::Completed);
+            self.emit(CoreEvent::TransferComplete { transfer_id });
+            actions.push(OutboundAction::TransferComplete { transfer_id, body });
+        }
+        actions
+    }
+
+    /// Request shared chunks whose twin can no longer deliver them: its transfer was dropped or
+    /// paused.
+    fn release_orphaned_shares(&mut self) -> Vec<OutboundAction> {
+        let orphaned: Vec<ChunkId> = self
+            .transfers
+            .values()
+            .filter(|t| !t.paused)
+            .flat_map(|t| t.shared.iter())
+            .filter(|&(_, twin)| {
+                self.transfers
+                    .get(&twin.transfer_id)
+                    .is_none_or(|t| t.paused)
+            })
+            .map(|(&c, _)| c)
+            .collect();
+        let mut actions = Vec::new();
+        for chunk_id in orphaned {
+            if let Some(t) = self.transfers.get_mut(&chunk_id.transfer_id) {
+                t.shared.remove(&chunk_id);
+            }
+            actions.extend(self.request_chunk(chunk_id));
+        }
+        actions
+    }
+
+    /// Process received chunk. Returns `Ok(Some(body))` when the transfer is complete and reassembled,
+    /// `Ok(None)` when still in progress, or [`CoreError::Chunk`] on integrity failure or unknown transfer.
+    /// If the host streamed the transfer via [`take_segment`](Self::take_segment), `body` holds only the
+    /// bytes after the last segment taken. Chunks passed here are attributed to this device (host
+    /// fetched them over WAN).
+    pub fn on_chunk_received(
+        &mut self,
+        transfer_id: [u8; 16],
+        start: u64,
+        end: u64,
+        hash: [u8; 32],
+        payload: Vec<u8>,
+    ) -> Result<Option<Vec<u8>>, CoreError> {
+        self.on_chunk_received_with_actions(transfer_id, start, end, hash, payload)
+            .result
+    }
+
+    /// Same as [`on_chunk_received`](Self::on_chunk_received), also returning the messages the
+    /// chunk triggered (endgame duplicate requests, Cancels for copies no longer needed).
+    pub fn on_chunk_received_with_actions(
+        &mut self,
+        transfer_id: [u8; 16],
+        start: u64,
+        end: u64,
+        hash: [u8; 32],
+        payload: Vec<u8>,
+    ) -> ChunkReceiveOutcome {
+        let self_id = self.keypair.device_id();
+        self.chunk_received_from(
+            self_id,
+            transfer_id,
+            start,
+            end,
+            HashAlgorithm::Sha256,
+            hash,
+            payload,
+        )
+    }
+
+    /// ChunkData whose range is not one of the transfer's chunks (e.g. a peer answered from its own
+    /// cache, cut differently): verify the payload as sent, then accept each chunk it fully covers
+    /// as if that chunk had arrived on its own. Chunks it only partly covers stay outstanding.
+    fn covering_chunk_received(
+        &mut self,
+        from: DeviceId,
+        range: ChunkId,
+        algorithm: HashAlgorithm,
+        hash: [u8; 32],
+        payload: Vec<u8>,
+    ) -> ChunkReceiveOutcome {
+        let transfer_id = range.transfer_id;
+        if payload.len() as u64 != range.end.saturating_sub(range.start)
+            || !algorithm.verify(&payload, &hash)
+        {
+            self.record_outcome(from, false);
+            self.emit(CoreEvent::IntegrityFailure {
+                chunk_id: range,
+                from,
+            });
+            return ChunkReceiveOutcome {
+                result: Err(CoreError::Chunk {
+                    transfer_id,
+                    source: ChunkError::IntegrityFailed,
+                }),
+                actions: Vec::new(),
+            };
+        }
+        let covered: Vec<ChunkId> = self
+            .transfers
+            .get(&transfer_id)
+            .map(|t| t.state.covered_chunks(range.start, range.end).to_vec())
+            .unwrap_or_default();
+        let mut outcome = ChunkReceiveOutcome {
+            result: Ok(None),
+            actions: Vec::new(),
+        };
+        for c in covered {
+            let offset = (c.start - range.start) as usize;
+            let slice = payload[offset..offset + (c.end - c.start) as usize].to_vec();
+            let hash = algorithm.hash(&slice);
+            let part =
+                self.chunk_received_from(from, transfer_id, c.start, c.end, algorithm, hash, slice);
+            outcome.actions.extend(part.actions);
+            outcome.result = part.result;
+            if !matches!(outcome.result, Ok(None)) {
+                break;
+            }
+        }
+        outcome
+    }
+
+    /// Open a dispute over `chunk_id`, whose copy from `sender` (claiming `claimed`) failed
+    /// verification: ask a worker other than the sender and the chunk's new peer for it too, so
+    /// two other sources can settle what the chunk holds.
+    fn open_dispute(
+        &mut self,
+        sender: DeviceId,
+        chunk_id: ChunkId,
+        algorithm: HashAlgorithm,
+        claimed: [u8; 32],
+    ) -> Vec<OutboundAction> {
+        let Some(t) = self.transfers.get(&chunk_id.transfer_id) else {
+            return vec![];
+        };
+        if self.disputes.contains_key(&chunk_id) || t.state.chunk_index(chunk_id).is_none() {
+            return vec![];
+        }
+        let assigned = t.peer_for(chunk_id);
+        let witness = self.workers().find(|&p| p != sender && Some(p) != assigned);
+        self.disputes.insert(
+            chunk_id,
+            Dispute {
+                sender,
+                algorithm,
+                claimed,
+                copies: Vec::new(),
+                since: self.tick_count,
+            },
+        );
+        witness
+            .and_then(|w| self.chunk_request_to(w, chunk_id))
+            .into_iter()
+            .collect()
+    }
+
+    /// Count a verified copy of a disputed chunk from `from`. Once two sources other than the
+    /// sender agree, the dispute is settled: if the sender's claimed hash differs from theirs, it
+    /// is isolated at once.
+    fn witness_dispute(
+        &mut self,
+        from: DeviceId,
+        chunk_id: ChunkId,
+        algorithm: HashAlgorithm,
+        hash: [u8; 32],
+        payload: &[u8],
+    ) -> Vec<OutboundAction> {
+        let Some(d) = self.disputes.get_mut(&chunk_id) else {
+            return vec![];
+        };
+        if from == d.sender
+            || d.copies.iter().any(|(p, _)| *p == from)
+            || payload.len() as u64 != chunk_id.end.saturating_sub(chunk_id.start)
+            || !algorithm.verify(payload, &hash)
+        {
+            return vec![];
+        }
+        let digest = if algorithm == d.algorithm {
+            hash
+        } else {
+            d.algorithm.hash(payload)
+        };
+        d.copies.push((from, digest));
+        if d.copies.iter().filter(|(_, h)| *h == digest).count() < 2 {
+            return vec![];
+        }
+        let Some(d) = self.disputes.remove(&chunk_id) else {
+            return vec![];
+        };
+        if d.claimed == digest {
+            // The sender claimed the right content; its payload was damaged on the way.
+            return vec![];
+        }
+        self.emit(CoreEvent::ChunkPoisoned {
+            chunk_id,
+            from: d.sender,
+        });
+        if !self.trust.isolate(d.sender) {
+            return vec![];
+        }
+        self.emit(CoreEvent::PeerIsolated(d.sender));
+        self.redistribute_peer_chunks(d.sender)
+    }
+
+    /// Process a chunk delivered by `from` (self or a peer) and record its contribution. `hash` was
+    /// made with `algorithm`.
+    #[allow(clippy::too_many_arguments)]
+    fn chunk_received_from(
+        &mut self,
+        from: DeviceId,
+        transfer_id: [u8; 16],
+        start: u64,
+        end: u64,
+        algorithm: HashAlgorithm,
+        hash: [u8; 32],
+        payload: Vec<u8>,
+    ) -> ChunkReceiveOutcome {
+        let mut actions = Vec::new();
+        let chunk_id = ChunkId {
+            transfer_id,
+            start,
+            end,
+        };
+        if self.disputes.contains_key(&chunk_id) {
+            actions.extend(self.witness_dispute(from, chunk_id, algorithm, hash, &payload));
+        }
+        let now = self.tick_count;
+        let has_sharers = self.transfers.values().any(|t| {
+            t.shared
+                .values()
+                .any(|c| c.transfer_id == transfer_id && c.start == start && c.end == end)
+        });
+        let active = match self.transfers.get_mut(&transfer_id) {
+            Some(a) => a,
+            None => {
+                return ChunkReceiveOutcome {
+                    result: Err(CoreError::Chunk {
+                        transfer_id,
+                        source: ChunkError::UnknownTransfer,
+                    }),
+                    actions,
+                }
+            }
+        };
+        if active.state.chunk_index(chunk_id).is_none() {
+            return self.covering_chunk_received(from, chunk_id, algorithm, hash, payload);
+        }
+        let is_new = !active.state.is_chunk_received(chunk_id);
+        if is_new {
+            active.last_activity = now;
+        }
+        // Everyone asked for this chunk, in case a copy arrives and the rest must be cancelled.
+        let mut asked: Vec<DeviceId> = active.endgame.get(&chunk_id).cloned().unwrap_or_default();
+        asked.extend(active.peer_for(chunk_id));
+        let len = payload.len() as u64;
+        let requested = active.requested_at.get(&chunk_id).copied();
+        let raced_peer = active
+            .raced
+            .contains(&chunk_id)
+            .then(|| active.peer_for(chunk_id))
+            .flatten();
+        let shared_payload = (is_new && has_sharers).then(|| payload.clone());
+        let cache_entry = (is_new && len <= self.config.chunk_cache_bytes).then(|| {
+            let key = CacheKey {
+                url: active.url.clone(),
+                start,
+                end,
+                validator: active.validator.clone(),
+            };
+            (key, payload.clone())
+        });
+        let result = match chunk::on_chunk_data_received_with(
+            &mut active.state,
+            transfer_id,
+            start,
+            end,
+            algorithm,
+            hash,
+            payload,
+        ) {
+            chunk::ChunkReceiveResult::Complete(bytes) => {
+                let mut done = self.transfers.remove(&transfer_id);
+                if let (true, Some(t)) = (is_new, done.as_mut()) {
+                    *t.contributions.entry(from).or_insert(0) += len;
+                    t.state.note_delivery(chunk_id, from, now);
+                }
+                let abort = done.as_ref().and_then(|t| {
+                    self.reject_root_mismatch(transfer_id, t, &bytes)
+                        .map(|a| (ChunkError::RootMismatch, a))
+                        .or_else(|| {
+                            self.reject_digest_mismatch(transfer_id, t, &bytes)
+                                .map(|a| (ChunkError::DigestMismatch, a))
+                        })
+                });
+                if let Some(t) = &done {
+                    let outcome = match abort {
+                        Some(_) => TransferOutcome::Fallback,
+                        None => TransferOutcome::Completed,
+                    };
+                    self.record_history(transfer_id, t, outcome);
+                }
+                if let Some((source, abort)) = abort {
+                    return ChunkReceiveOutcome {
+                        result: Err(CoreError::Chunk {
+                            transfer_id,
+                            source,
+                        }),
+                        actions: abort,
+                    };
+                }
+                Ok(Some(bytes))
+            }
+            chunk::ChunkReceiveResult::InProgress => {
+                active.requested_at.remove(&chunk_id);
+                if is_new {
+                    *active.contributions.entry(from).or_insert(0) += len;
+                    active.state.note_delivery(chunk_id, from, now);
+                    active.endgame.remove(&chunk_id);
+                    active.raced.remove(&chunk_id);
+                    active.retry_at.remove(&chunk_id);
+                }
+                Ok(None)
+            }
+            chunk::ChunkReceiveResult::IntegrityFailed => Err(CoreError::Chunk {
+                transfer_id,
+                source: ChunkError::IntegrityFailed,
+            }),
+        };
+        if is_new || result.is_err() {
+            self.record_outcome(from, result.is_ok());
+        }
+        if let (Ok(_), Some((key, payload))) = (&result, cache_entry) {
+            self.cache.insert(key, payload);
+        }
+        if let (true, Ok(_), Some(at)) = (is_new, &result, requested) {
+            self.record_delivery(from, len, self.tick_count.saturating_sub(at));
+        }
+        if is_new && result.is_ok() {
+            if from == self.keypair.device_id() {
+                self.stats.bytes_fetched_locally =
+                    self.stats.bytes_fetched_locally.saturating_add(len);
+            } else {
+                let credit = self.ledger.entry(from).or_default();
+                credit.bytes_received = credit.bytes_received.saturating_add(len);
+                let from_peer = self.stats.bytes_from_peers.entry(from).or_default();
+                *from_peer = from_peer.saturating_add(len);
+            }
+        }
+        match &result {
+            Ok(done) => {
+                if is_new {
+                    self.emit(CoreEvent::ChunkVerified { chunk_id, from });
+                }
+                if done.is_some() {
+                    self.emit(CoreEvent::TransferComplete { transfer_id });
+                }
+            }
+            Err(_) => self.emit(CoreEvent::IntegrityFailure { chunk_id, from }),
+        }
+        if matches!(
+            result,
+            Err(CoreError::Chunk {
+                source: ChunkError::IntegrityFailed,
+                ..
+            })
+        ) {
+            match self.charge_attempt(chunk_id) {
+                Ok(_) => actions.extend(self.note_chunk_failure(chunk_id)),
+                Err(abort) => actions.extend(abort),
+            }
+        }
+        if let (true, Ok(_), Some(late)) = (is_new, &result, raced_peer) {
+            if late != from {
+                self.record_outcome(late, false);
+            }
+        }
+        if is_new && result.is_ok() && asked.len() > 1 {
+            let self_id = self.keypair.device_id();
+            let cancel = Message::Cancel {
+                transfer_id,
+                start,
+                end,
+            };
+            if let Ok(bytes) = wire::encode_frame(&cancel) {
+                for peer in asked
+                    .into_iter()
+                    .filter(|&p| p != from && p != self_id && self.peer_supports(p, FEATURE_CANCEL))
+                {
+                    actions.push(OutboundAction::SendMessage(peer, bytes.clone()));
+                }
+            }
+        }
+        if let (Ok(_), Some(payload)) = (&result, shared_payload) {
+            actions.extend(self.deliver_shared(chunk_id, from, &payload));
+        }
+        if is_new && matches!(result, Ok(None)) {
+            actions.extend(self.release_deferred());
+            actions.extend(self.endgame_requests(transfer_id));
+        }
+        ChunkReceiveOutcome { result, actions }
+    }
+
+    /// Keep `peer`'s attestation for a chunk it is delivering, if it verifies against the key the
+    /// peer joined with.
+    fn note_attestation(
+        &mut self,
+        peer: DeviceId,
+        chunk_id: ChunkId,
+        hash: [u8; 32],
+        signature: [u8; 32],
+    ) {
+        let Some(public_key) = self.public_key_of(peer) else {
+            return;
+        };
+        let range = (chunk_id.start, chunk_id.end);
+        if !self.keypair.verify_chunk_attestation(
+            &public_key,
+            &chunk_id.transfer_id,
+            range,
+            &hash,
+            &signature,
+        ) {
+            return;
+        }
+        if let Some(t) = self.transfers.get_mut(&chunk_id.transfer_id) {
+            t.state.note_attestation(
+                chunk_id,
+                ChunkAttestation {
+                    peer,
+                    hash,
+                    signature,
+                },
+            );
+        }
+    }
+
+    /// Check a completed body against its transfer's manifest. On a mismatch, charge the peers
+    /// that delivered the bad blocks (the attesting peer where there is one, else the assigned
+    /// one; evicting those chunks from the cache) and abort to fallback. Attested chunks are
+    /// reported with their evidence ([`CoreEvent::AttestedCorruption`]). Returns `None` if there
+    /// is no manifest, the body is not held whole (streamed or in a sink) or the root matches.
+    fn reject_root_mismatch(
+        &mut self,
+        transfer_id: [u8; 16],
+        t: &ActiveTransfer,
+        body: &[u8],
+    ) -> Option<Vec<OutboundAction>> {
+        let manifest = t.manifest.as_ref()?;
+        if body.len() as u64 != t.state.total_length {
+            return None;
+        }
+        let bad = manifest.mismatched_blocks([body]);
+        if bad.is_empty() {
+            return None;
+        }
+        let block = manifest.block_size.max(1);
+        let base = t.state.base_offset();
+        let mut suspects: Vec<DeviceId> = Vec::new();
+        for (chunk_id, peer) in &t.assignment {
+            let tampered = bad.iter().any(|&i| {
+                let start = base.saturating_add((i as u64).saturating_mul(block));
+                chunk_id.start < start.saturating_add(block) && start < chunk_id.end
+            });
+            if !tampered {
+                continue;
+            }
+            self.cache.remove(&CacheKey {
+                url: t.url.clone(),
+                start: chunk_id.start,
+                end: chunk_id.end,
+                validator: t.validator.clone(),
+            });
+            let attestation = t.state.attestation(*chunk_id);
+            if let Some(attestation) = attestation {
+                self.emit(CoreEvent::AttestedCorruption {
+                    chunk_id: *chunk_id,
+                    attestation,
+                });
+            }
+            let culprit = attestation.map_or(*peer, |a| a.peer);
+            if !suspects.contains(&culprit) {
+                suspects.push(culprit);
+            }
+        }
+        let self_id = self.keypair.device_id();
+        let mut actions = Vec::new();
+        for peer in suspects {
+            self.record_outcome(peer, false);
+            if peer != self_id && self.trust.record_failure(peer) {
+                self.emit(CoreEvent::PeerIsolated(peer));
+                actions.extend(self.redistribute_peer_chunks(peer));
+            }
+        }
+        self.emit(CoreEvent::Fallback { url: t.url.clone() });
+        actions.push(OutboundAction::AbortToFallback(transfer_id));
+        Some(actions)
+    }
+
+    /// Check a completed body against the origin's digests. On a mismatch, evict the transfer's
+    /// chunks from the cache and abort to fallback (no chunk can be blamed); returns `None` if
+    /// there are no digests, the body is not held whole, or every digest matches.
+    fn reject_digest_mismatch(
+        &mut self,
+        transfer_id: [u8; 16],
+        t: &ActiveTransfer,
+        body: &[u8],
+    ) -> Option<Vec<OutboundAction>> {
+        if body.len() as u64 != t.state.total_length
+            || t.origin_digests.iter().all(|d| d.matches(body))
+        {
+            return None;
+        }
+        for chunk_id in t.state.chunk_ids() {
+            self.cache.remove(&CacheKey {
+                url: t.url.clone(),
+                start: chunk_id.start,
+                end: chunk_id.end,
+                validator: t.validator.clone(),
+            });
+        }
+        self.emit(CoreEvent::Fallback { url: t.url.clone() });
+        Some(vec![OutboundAction::AbortToFallback(transfer_id)])
+    }
+
+    /// Enter endgame for a transfer once few chunks remain: request each outstanding chunk from
+    /// every other worker (self included, as an [`OutboundAction::FetchChunk`] for this device).
+    /// Each chunk is duplicated once; nothing happens while paused or while the memory budget
+    /// defers requests.
+    fn endgame_requests(&mut self, transfer_id: [u8; 16]) -> Vec<OutboundAction> {
+        let threshold = self.config.endgame_chunks;
+        let workers: Vec<DeviceId> = self.workers().collect();
+        let Some(t) = self.transfers.get_mut(&transfer_id) else {
+            return vec![];
+        };
+        let outstanding = t.state.chunk_ids().len() - t.state.received_count();
+        if threshold == 0
+            || outstanding > threshold
+            || t.paused
+            || !t.deferred.is_empty()
+            || !t.queued.is_empty()
+        {
+            return vec![];
+        }
+        let missing: Vec<ChunkId> = t
+            .state
+            .chunk_ids()
+            .iter()
+            .copied()
+            .filter(|&c| {
+                !t.state.is_chunk_received(c)
+                    && !t.endgame.contains_key(&c)
+                    && !t.shared.contains_key(&c)
+            })
+            .collect();
+        let mut duplicates = Vec::new();
+        for chunk_id in missing {
+            let assigned = t.peer_for(chunk_id);
+            let extra: Vec<DeviceId> = workers
+                .iter()
+                .copied()
+                .filter(|&p| Some(p) != assigned)
+                .collect();
+            t.endgame.insert(chunk_id, extra.clone());
+            duplicates.push((chunk_id, extra));
+        }
+        duplicates
+            .into_iter()
+            .flat_map(|(chunk_id, extra)| extra.into_iter().map(move |peer| (chunk_id, peer)))
+            .filter_map(|(chunk_id, peer)| self.chunk_request_to(peer, chunk_id))
+            .collect()
+    }
+
+    /// Requests and failures so far of a chunk not yet received (see [`Config::chunk_retries`]);
+    /// `None` for an unknown transfer.
+    pub fn chunk_attempts(&self, chunk_id: ChunkId) -> Option<ChunkAttempts> {
+        let t = self.transfers.get(&chunk_id.transfer_id)?;
+        Some(t.state.attempts(chunk_id))
+    }
+
+    /// Per-chunk breakdown of an active transfer: when each requested chunk was last requested,
+    /// which device delivered it and how many ticks that took (for per-peer contribution UIs and
+    /// scheduler tuning). `None` for an unknown transfer.
+    pub fn chunk_timings(&self, transfer_id: [u8; 16]) -> Option<Vec<(ChunkId, ChunkTiming)>> {
+        Some(self.transfers.get(&transfer_id)?.state.chunk_timings())
+    }
+
+    /// Progress of an active transfer for host UIs (tray, CLI). Returns None if `transfer_id` is not
+    /// active (e.g. it already completed).
+    pub fn transfer_progress(&self, transfer_id: [u8; 16]) -> Option<TransferProgress> {
+        let active = self.transfers.get(&transfer_id)?;
+        let chunks_total = active.state.chunk_ids().len();
+        let chunks_received = active.state.received_count();
+        Some(TransferProgress {
+            total_length: active.state.total_length,
+            bytes_received: active.state.bytes_received(),
+            chunks_total,
+            chunks_outstanding: chunks_total - chunks_received,
+            per_peer_bytes: active.per_peer_bytes(),
+            elapsed_ticks: self.tick_count.saturating_sub(active.started_at_tick),
+            paused: active.paused,
+            buffered_bytes: active.buffered_bytes(),
+            backpressured: active.backpressured,
+        })
+    }
+
+    /// Recently finished transfers (completed, fallen back or abandoned), oldest first; at most
+    /// [`Config::history_len`] are kept.
+    pub fn history(&self) -> Vec<TransferRecord> {
+        self.history.iter().cloned().collect()
+    }
+
+    /// Append a finished transfer to the history, dropping the oldest beyond `history_len`.
+    fn record_history(
+        &mut self,
+        transfer_id: [u8; 16],
+        t: &ActiveTransfer,
+        outcome: TransferOutcome,
+    ) {
+        let limit = self.config.history_len;
+        if limit == 0 {
+            return;
+        }
+        self.history.push_back(TransferRecord {
+            transfer_id,
+            url: t.url.clone(),
+            total_length: t.state.total_length,
+            bytes_received: t.state.bytes_received(),
+            per_peer_bytes: t.per_peer_bytes(),
+            elapsed_ticks: self.tick_count.saturating_sub(t.started_at_tick),
+            outcome,
+        });
+        while self.history.len() > limit {
+            self.history.pop_front();
+        }
+    }
+
+    /// Streaming reassembly: take the in-order bytes that became contiguous since the last call, so the
+    /// host can start writing the response before every chunk has arrived. Call after each received
+    /// chunk or message; returns None when the next chunk in order is still missing or the transfer is
+    /// unknown. Once used, the completion body only carries the remainder.
+    pub fn take_segment(&mut self, transfer_id: [u8; 16]) -> Option<Segment> {
+        let now = self.tick_count;
+        let t = self.transfers.get_mut(&transfer_id)?;
+        let segment = t.state.take_contiguous()?;
+        t.last_activity = now;
+        Some(segment)
+    }
+
+    /// IDs of the transfers in progress (e.g. after [`restore`](Self::restore)), for
+    /// [`transfer_progress`](Self::transfer_progress) and [`resume_transfer`](Self::resume_transfer).
+    pub fn active_transfers(&self) -> Vec<[u8; 16]> {
+        let mut ids: Vec<[u8; 16]> = self.transfers.keys().copied().collect();
+        ids.sort_unstable();
+        ids
+    }
+
+    /// Pause a transfer (e.g. user disabled acceleration mid-download). Chunks already
+    /// received are kept and late ChunkData is still accepted, but chunk timeouts are suspended and
+    /// no ChunkRequests are emitted until [`resume_transfer`](Self::resume_transfer).
+    /// Returns false if `transfer_id` is not active.
+    pub fn pause_transfer(&mut self, transfer_id: [u8; 16]) -> bool {
+        match self.transfers.get_mut(&transfer_id) {
+            Some(a) => {
+                if !a.paused {
+                    a.paused = true;
+                    a.paused_at = self.tick_count;
+                }
+                a.last_activity = self.tick_count;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Resume a paused transfer. Returns ChunkRequests for every chunk not yet received, addressed to
+    /// its currently assigned peer (chunks assigned to this device are addressed to [`device_id`](Self::device_id);
+    /// the host fetches those itself). Returns no actions if the transfer is unknown or not paused.
+    pub fn resume_transfer(&mut self, transfer_id: [u8; 16]) -> Vec<OutboundAction> {
+        let missing: Vec<ChunkId> = match self.transfers.get_mut(&transfer_id) {
+            Some(a) if a.paused => {
+                a.paused = false;
+                let paused_for = self.tick_count.saturating_sub(a.paused_at);
+                if let Some(deadline) = a.deadline_tick.as_mut() {
+                    *deadline = deadline.saturating_add(paused_for);
+                }
+                a.last_activity = self.tick_count;
+                a.assignment
+                    .iter()
+                    .map(|&(c, _)| c)
+                    .filter(|&c| !a.state.is_chunk_received(c))
+                    .collect()
+            }
+            _ => return vec![],
+        };
+        let actions = missing
+            .into_iter()
+            .filter_map(|c| self.request_chunk(c))
+            .collect();
+        self.update_backpressure(transfer_id);
+        actions
+    }
+
+    /// Mark `chunk_id` as requested now. When its peer's in-flight window is full or the memory
+    /// budget is exhausted the chunk is held back instead (released as chunks verify and from
+    /// `tick`). Returns false while the transfer is paused, if the chunk was already received, or if
+    /// it was held back.
+    fn admit_chunk(&mut self, chunk_id: ChunkId) -> bool {
+        let transfer_id = chunk_id.transfer_id;
+        let Some(t) = self.transfers.get(&transfer_id) else {
+            return false;
+        };
+        if t.paused || t.state.is_chunk_received(chunk_id) || t.shared.contains_key(&chunk_id) {
+            return false;
+        }
+        // Re-requesting an in-flight chunk does not add memory or window use.
+        let in_flight = t.requested_at.contains_key(&chunk_id);
+        let window_ok = in_flight || self.window_allows(chunk_id);
+        let budget_ok = in_flight
+            || !window_ok
+            || self.budget_allows(transfer_id, chunk_id.end - chunk_id.start);
+        let now = self.tick_count;
+        let Some(t) = self.transfers.get_mut(&transfer_id) else {
+            return false;
+        };
+        if !window_ok {
+            t.deferred.remove(&chunk_id.start);
+            t.queued.insert(chunk_id.start, chunk_id);
+            return false;
+        }
+        if !budget_ok {
+            t.queued.remove(&chunk_id.start);
+            t.deferred.insert(chunk_id.start, chunk_id);
+            return false;
+        }
+        t.deferred.remove(&chunk_id.start);
+        t.queued.remove(&chunk_id.start);
+        t.requested_at.insert(chunk_id, now);
+        t.state.note_request(chunk_id, now);
+        true
+    }
+
+    /// Fair queuing across transfers: of the window-queued chunks whose peer has room (the first
+    /// by offset per transfer and peer), the one whose transfer has the fewest bytes in flight to
+    /// that peer; ties go to the transfer with fewer bytes left. A peer's window is thus shared
+    /// between concurrent transfers, and a large download cannot starve a small one. Chunks in
+    /// `tried` are skipped.
+    fn next_fair_queued(&self, tried: &HashSet<ChunkId>) -> Option<ChunkId> {
+        let mut best: Option<(u64, u64, [u8; 16], ChunkId)> = None;
+        for (&transfer_id, t) in self.transfers.iter().filter(|(_, t)| !t.paused) {
+            let mut seen: Vec<DeviceId> = Vec::new();
+            for &chunk_id in t.queued.values().filter(|c| !tried.contains(c)) {
+                let Some(peer) = t.peer_for(chunk_id) else {
+                    continue;
+                };
+                if seen.contains(&peer) {
+                    continue;
+                }
+                seen.push(peer);
+                if !self.window_allows(chunk_id) {
+                    continue;
+                }
+                let in_flight: u64 = t
+                    .requested_at
+                    .keys()
+                    .filter(|&&c| t.peer_for(c) == Some(peer))
+                    .map(|c| c.end - c.start)
+                    .sum();
+                let left = t
+                    .state
+                    .total_length
+                    .saturating_sub(t.state.bytes_received());
+                let key = (in_flight, left, transfer_id);
+                if best.is_none_or(|(n, l, id, _)| key < (n, l, id)) {
+                    best = Some((in_flight, left, transfer_id, chunk_id));
+                }
+            }
+        }
+        best.map(|(_, _, _, c)| c)
+    }
+
+    /// Whether the peer assigned `chunk_id` has room in its in-flight window.
+    fn window_allows(&self, chunk_id: ChunkId) -> bool {
+        let window = self.config.peer_window;
+        let peer = match self.transfers.get(&chunk_id.transfer_id) {
+            Some(t) => t.peer_for(chunk_id),
+            None => None,
+        };
+        let Some(peer) = peer else {
+            return true;
+        };
+        if window == 0 || peer == self.keypair.device_id() {
+            return true;
+        }
+        let in_flight: usize = self
+            .transfers
+            .values()
+            .map(|t| {
+                t.requested_at
+                    .keys()
+                    .filter(|&&c| t.peer_for(c) == Some(peer))
+                    .count()
+            })
+            .sum();
+        in_flight < window
+    }
+
+    /// Whether a new request of `len` bytes fits both memory budgets. A transfer with nothing in
+    /// flight may always request one chunk so it cannot stall.
+    fn budget_allows(&self, transfer_id: [u8; 16], len: u64) -> bool {
+        let Some(t) = self.transfers.get(&transfer_id) else {
+            return false;
+        };
+        if t.requested_at.is_empty() {
+            return true;
+        }
+        let core_used: u64 = self
+            .transfers
+            .values()
+            .map(ActiveTransfer::memory_bytes)
+            .sum();
+        t.memory_bytes() + len <= self.config.max_transfer_buffer_bytes
+            && core_used + len <= self.config.max_buffer_bytes
+    }
+
+    /// Admit `chunk_id` and build its ChunkRequest to the assigned peer (see `admit_chunk`).
+    fn request_chunk(&mut self, chunk_id: ChunkId) -> Option<OutboundAction> {
+        if !self.admit_chunk(chunk_id) {
+            return None;
+        }
+        let peer = self
+            .transfers
+            .get(&chunk_id.transfer_id)?
+            .peer_for(chunk_id)?;
+        self.chunk_request_to(peer, chunk_id)
+    }
+
+    /// Ask `peer` for `chunk_id`: a ChunkRequest framed for it or, when `peer` is this device, an
+    /// [`OutboundAction::FetchChunk`] the host runs as it would for a peer, handing the bytes to
+    /// [`on_chunk_fetched_for_peer`](Self::on_chunk_fetched_for_peer).
+    fn chunk_request_to(&self, peer: DeviceId, chunk_id: ChunkId) -> Option<OutboundAction> {
+        let msg = self.chunk_request_for(chunk_id)?;
+        if peer != self.keypair.device_id() {
+            let bytes = self.frame_for(peer, &msg)?;
+            return Some(OutboundAction::SendMessage(peer, bytes));
+        }
+        let Message::ChunkRequest {
+            url, deadline_ms, ..
+        } = msg
+        else {
+            return None;
+        };
+        // The client's own headers go on this device's fetches whatever the forwarding setting.
+        let headers = self.transfers.get(&chunk_id.transfer_id)?.headers.clone();
+        Some(OutboundAction::FetchChunk {
+            peer,
+            chunk_id,
+            url: url?,
+            headers,
+            deadline_ms,
+        })
+    }
+
+    /// ChunkRequest for one of a transfer's chunks with the transfer's request context: URL,
+    /// validator, request headers (with [`Config::forward_request_headers`]) and a deadline of
+    /// `chunk_timeout_ticks`, after which the chunk is reassigned. Hosts send it to the peers of an
+    /// [`Action::Accelerate`] assignment. `None` for an unknown transfer.
+    pub fn chunk_request_for(&self, chunk_id: ChunkId) -> Option<Message> {
+        let t = self.transfers.get(&chunk_id.transfer_id)?;
+        let headers = if self.config.forward_request_headers {
+            t.headers.clone()
+        } else {
+            Vec::new()
+        };
+        let deadline_ms = self
+            .config
+            .chunk_timeout_ticks
+            .saturating_mul(self.config.tick_interval_ms);
+        Some(Message::ChunkRequest {
+            transfer_id: chunk_id.transfer_id,
+            start: chunk_id.start,
+            end: chunk_id.end,
+            url: Some(t.url.clone()),
+            validator: t.validator.clone(),
+            headers,
+            deadline_ms: (deadline_ms > 0).then_some(deadline_ms),
+        })
+    }
+
+    /// Client request headers (e.g. `Cookie`, `Authorization`) for `transfer_id`, sent with its
+    /// ChunkRequests from now on when [`Config::forward_request_headers`] is set. Call right after
+    /// [`Action::Accelerate`], before requesting its peer chunks. Returns false for an unknown
+    /// transfer.
+    pub fn set_transfer_headers(
+        &mut self,
+        transfer_id: [u8; 16],
+        headers: Vec<(String, String)>,
+    ) -> bool {
+        match self.transfers.get_mut(&transfer_id) {
+            Some(t) => {
+                t.headers = headers;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Issue held-back ChunkRequests: window-queued chunks whose peer has room, shared fairly
+    /// across transfers (see `next_fair_queued`), then budget-deferred chunks in offset order while
+    /// the memory budget allows.
+    fn release_deferred(&mut self) -> Vec<OutboundAction> {
+        let mut actions = Vec::new();
+        let mut tried = HashSet::new();
+        while let Some(chunk_id) = self.next_fair_queued(&tried) {
+            tried.insert(chunk_id);
+            actions.extend(self.request_chunk(chunk_id));
+        }
+        let ids: Vec<[u8; 16]> = self.transfers.keys().copied().collect();
+        for transfer_id in ids {
+            loop {
+                let next = match self.transfers.get(&transfer_id) {
+                    Some(t) if !t.paused => t.deferred.values().next().copied(),
+                    _ => None,
+                };
+                let Some(chunk_id) = next else {
+                    break;
+                };
+                if !self.budget_allows(transfer_id, chunk_id.end - chunk_id.start) {
+                    break;
+                }
+                if let Some(t) = self.transfers.get_mut(&transfer_id) {
+                    t.deferred.remove(&chunk_id.start);
+                }
+                actions.extend(self.request_chunk(chunk_id));
+            }
+            self.update_backpressure(transfer_id);
+        }
+        actions
+    }
+
+    /// Emit a backpressure event when a transfer starts or stops holding back ChunkRequests.
+    fn update_backpressure(&mut self, transfer_id: [u8; 16]) {
+        let Some(t) = self.transfers.get_mut(&transfer_id) else {
+            return;
+        };
+        let now = !t.deferred.is_empty();
+        if now == t.backpressured {
+            return;
+        }
+        t.backpressured = now;
+        let buffered_bytes = t.buffered_bytes();
+        self.emit(if now {
+            CoreEvent::Backpressure {
+                transfer_id,
+                buffered_bytes,
+            }
+        } else {
+            CoreEvent::BackpressureReleased { transfer_id }
+        });
+    }
+
+    /// Notify that a peer joined (from discovery). Updates peer list for chunk assignment.
+    /// Returns false if the peer was refused because the pod already has `max_peers` peers (and
+    /// [`Config::peer_eviction`] frees no slot), it
+    /// was revoked, or its key differs from the one pinned for it. The first join pins the key
+    /// (trust on first use). A re-join refreshes the last-seen tick.
+    pub fn on_peer_joined(&mut self, peer_id: DeviceId, public_key: &PublicKey) -> bool {
+        if self.check_pin(peer_id, public_key).is_err() {
+            return false;
+        }
+        let now = self.tick_count;
+        if let Some(info) = self.peer_mut(&peer_id) {
+            info.public_key = public_key.clone();
+            info.last_seen = now;
+            return true;
+        }
+        while self.peers.len() >= self.config.max_peers {
+            if !self.evict_for_newcomer() {
+                return false;
+            }
+        }
+        self.peers.push(PeerInfo {
+            device_id: peer_id,
+            public_key: public_key.clone(),
+            last_seen: now,
+            metrics: self.known_metrics.remove(&peer_id).unwrap_or_default(),
+            capabilities: self
+                .announced_capabilities
+                .remove(&peer_id)
+                .unwrap_or_default(),
+            uplink: None,
+            busy_until: 0,
+        });
+        self.candidates.remove(&peer_id);
+        // Joins carry this device's capabilities; later changes go out on the tick.
+        self.capabilities_told.insert(peer_id);
+        self.known_peers
+            .entry(peer_id)
+            .or_insert_with(|| public_key.clone());
+        self.emit(CoreEvent::PeerJoined(peer_id));
+        true
+    }
+
+    /// Notify that a peer left. Redistributes its chunks to remaining peers; returns actions to send ChunkRequests.
+    pub fn on_peer_left(&mut self, peer_id: DeviceId) -> Vec<OutboundAction> {
+        if self.remove_peer(peer_id) {
+            self.emit(CoreEvent::PeerLeft(peer_id));
+        }
+        let mut actions = self.redistribute_peer_chunks(peer_id);
+        actions.extend(self.reclaim_uploads(peer_id));
+        actions
+    }
+
+    /// Leave the pod before the host exits: abort every transfer to fallback, drop uploads
+    /// ([`CoreEvent::UploadFailed`]) and peers, and return signed Leave messages so peers
+    /// reassign this device's chunks at once instead of waiting for its heartbeats to time out.
+    pub fn shutdown(&mut self) -> Vec<OutboundAction> {
+        let mut actions = Vec::new();
+        let transfer_ids: Vec<[u8; 16]> = self.transfers.keys().copied().collect();
+        for transfer_id in transfer_ids {
+            actions.extend(self.abort_transfer(transfer_id));
+        }
+        let upload_ids: Vec<[u8; 16]> = self.uploads.drain().map(|(id, _)| id).collect();
+        for upload_id in upload_ids {
+            self.emit(CoreEvent::UploadFailed { upload_id });
+        }
+        let self_id = self.keypair.device_id();
+        let peers: Vec<(DeviceId, PublicKey)> = self
+            .peers
+            .iter()
+            .map(|p| (p.device_id, p.public_key.clone()))
+            .collect();
+        for (peer, public_key) in peers {
+            self.remove_peer(peer);
+            let leave = Message::Leave {
+                device_id: self_id,
+                signature: self.keypair.leave_signature(&public_key),
+            };
+            if let Ok(bytes) = wire::encode_frame(&leave) {
+                actions.push(OutboundAction::SendMessage(peer, bytes));
+            }
+        }
+        actions
+    }
+
+    /// Drop `peer_id` from the pod (its membership becomes `Left`). Returns whether it was a member.
+    fn remove_peer(&mut self, peer_id: DeviceId) -> bool {
+        let was_member = match self.peers.iter().find(|p| p.device_id == peer_id) {
+            Some(info) => {
+                let public_key = info.public_key.clone();
+                self.candidates.insert(
+                    peer_id,
+                    Candidate {
+                        state: Membership::Left,
+                        public_key,
+                        requested: None,
+                    },
+                );
+                true
+            }
+            None => {
+                if let Some(candidate) = self.candidates.get_mut(&peer_id) {
+                    candidate.state = Membership::Left;
+                    candidate.requested = None;
+                }
+                false
+            }
+        };
+        if let Some(i) = self.peers.iter().position(|p| p.device_id == peer_id) {
+            let info = self.peers.remove(i);
+            self.known_metrics.insert(peer_id, info.metrics);
+        }
+        self.constraints_told.remove(&peer_id);
+        self.peer_versions.remove(&peer_id);
+        self.hello_sent.remove(&peer_id);
+        self.capabilities_told.remove(&peer_id);
+        self.announced_capabilities.remove(&peer_id);
+        was_member
+    }
+
+    /// Make room for a joining peer under [`Config::peer_eviction`]. Peers with chunks of an
+    /// active transfer still assigned are never evicted. Returns false if no peer may go.
+    fn evict_for_newcomer(&mut self) -> bool {
+        let now = self.tick_count;
+        let busy: HashSet<DeviceId> = self
+            .transfers
+            .values()
+            .flat_map(|t| {
+                t.assignment
+                    .iter()
+                    .filter(|(c, _)| !t.state.is_chunk_received(*c))
+                    .map(|&(_, d)| d)
+            })
+            .collect();
+        let idle = self.peers.iter().filter(|p| !busy.contains(&p.device_id));
+        let fresh = PeerMetrics::default().weight();
+        let victim = match self.config.peer_eviction {
+            PeerEviction::Refuse => None,
+            PeerEviction::LeastRecentlySeen => idle
+                .filter(|p| p.last_seen < now)
+                .min_by_key(|p| p.last_seen),
+            PeerEviction::LowestScore => idle
+                .filter(|p| p.metrics.weight() < fresh)
+                .min_by_key(|p| (p.metrics.weight(), p.last_seen)),
+        };
+        let Some(victim) = victim.map(|p| p.device_id) else {
+            return false;
+        };
+        self.remove_peer(victim);
+        self.emit(CoreEvent::PeerEvicted(victim));
+        true
+    }
+
+    /// Report this device's resource constraints (battery, metered WAN, contribution cap). The core
+    /// gives itself less work accordingly, caps the bytes it serves per tick at
+    /// `max_contribution_bps`, and returns Constraints messages for every peer; peers that join
+    /// later are told on the next tick.
+    pub fn set_local_constraints(
+        &mut self,
+        battery_low: bool,
+        metered: bool,
+        max_contribution_bps: Option<u64>,
+    ) -> Vec<OutboundAction> {
+        self.self_metrics.constraints = Constraints {
+            battery_low,
+            metered,
+            max_contribution_bps,
+        };
+        self.constraints_told.clear();
+        let peers: Vec<DeviceId> = self.peers.iter().map(|p| p.device_id).collect();
+        self.tell_constraints(peers)
+    }
+
+    /// Constraints messages to `peers`, recording them as told.
+    fn tell_constraints(&mut self, peers: Vec<DeviceId>) -> Vec<OutboundAction> {
+        let c = self.self_metrics.constraints;
+        let msg = Message::Constraints {
+            battery_low: c.battery_low,
+            metered: c.metered,
+            max_contribution_bps: c.max_contribution_bps,
+        };
+        let Ok(bytes) = wire::encode_frame(&msg) else {
+            return vec![];
+        };
+        peers
+            .into_iter()
+            .filter(|&peer| self.peer_supports(peer, FEATURE_CONSTRAINTS))
+            .collect::<Vec<_>>()
+            .into_iter()
+            .map(|peer| {
+                self.constraints_told.insert(peer);
+                OutboundAction::SendMessage(peer, bytes.clone())
+            })
+            .collect()
+    }
+
+    /// Call when host receives a heartbeat from peer (so we don't mark peer as left).
+    pub fn on_heartbeat_received(&mut self, peer_id: DeviceId) {
+        let now = self.tick_count;
+        if let Some(info) = self.peer_mut(&peer_id) {
+            info.last_seen = now;
+        }
+    }
+
+    /// Periodic tick: check heartbeat timeouts (treat overdue peers as left), produce heartbeat messages.
+    /// Periodic tick (every [`Config::tick_interval_ms`]). Returns outbound actions (e.g. heartbeats); host sends them to peers.
+    pub fn tick(&mut self) -> Vec<OutboundAction> {
+        self.advance_to(self.tick_count.saturating_add(1))
+    }
+
+    /// Tick from a monotonic host clock in milliseconds. Heartbeats, chunk timeouts and peer
+    /// expiry follow elapsed time ([`Config::tick_interval_ms`] per tick) however often the host
+    /// calls this; calls within the current interval return nothing. Use instead of
+    /// [`tick`](Self::tick), not alongside it.
+    pub fn tick_at(&mut self, now_ms: u64) -> Vec<OutboundAction> {
+        let interval = self.config.tick_interval_ms.max(1);
+        let elapsed_ms = self.tick_count.saturating_mul(interval);
+        let origin = *self
+            .clock_origin_ms
+            .get_or_insert_with(|| now_ms.saturating_sub(elapsed_ms));
+        let due = now_ms.saturating_sub(origin) / interval;
+        if due <= self.tick_count {
+            return Vec::new();
+        }
+        self.advance_to(due)
+    }
+
+    /// Timer settings in milliseconds, so hosts can schedule [`tick_at`](Self::tick_at) and show timeouts.
+    pub fn timer_intervals(&self) -> TimerIntervals {
+        let ms = |ticks: u64| ticks.saturating_mul(self.config.tick_interval_ms);
+        TimerIntervals {
+            tick_ms: self.config.tick_interval_ms,
+            heartbeat_ms: ms(self.config.heartbeat_interval_ticks.max(1)),
+            chunk_timeout_ms: ms(self.config.chunk_timeout_ticks),
+            peer_timeout_ms: ms(self.config.heartbeat_timeout_ticks),
+            transfer_deadline_ms: ms(self.config.transfer_deadline_ticks),
+        }
+    }
+
+    /// Housekeeping for reaching tick `tick` (one or more intervals after the last one).
+    fn advance_to(&mut self, tick: u64) -> Vec<OutboundAction> {
+        self.tick_count = tick;
+        self.serve_usage.clear();
+        let (now, timeout) = (self.tick_count, self.config.chunk_timeout_ticks);
+        self.pending_serves
+            .retain(|_, p| now.saturating_sub(p.since) <= timeout);
+        self.upload_offers
+            .retain(|_, o| now.saturating_sub(o.last_used) <= timeout);
+        let mut actions = Vec::new();
+        let overdue: Vec<DeviceId> = self
+            .peers
+            .iter()
+            .filter(|p| {
+                self.tick_count.saturating_sub(p.last_seen) > self.config.heartbeat_timeout_ticks
+            })
+            .map(|p| p.device_id)
+            .collect();
+        for peer_id in overdue {
+            actions.extend(self.on_peer_left(peer_id));
+        }
+        actions.extend(self.abort_overdue_transfers());
+        actions.extend(self.reap_stale_transfers());
+        actions.extend(self.retire_rotated_keys());
+        actions.extend(self.release_orphaned_shares());
+        self.decay_failures();
+        actions.extend(self.reassign_timed_out_chunks());
+        actions.extend(self.issue_due_retries());
+        actions.extend(self.race_stragglers());
+        actions.extend(self.duplicate_stream_heads());
+        self.steal_queued_work();
+        actions.extend(self.release_deferred());
+        if self.self_metrics.constraints != Constraints::default() {
+            let untold: Vec<DeviceId> = self
+                .peers
+                .iter()
+                .map(|p| p.device_id)
+                .filter(|p| !self.constraints_told.contains(p))
+                .collect();
+            actions.extend(self.tell_constraints(untold));
+        }
+        let untold: Vec<DeviceId> = self
+            .peers
+            .iter()
+            .map(|p| p.device_id)
+            .filter(|p| !self.capabilities_told.contains(p))
+            .collect();
+        actions.extend(self.tell_capabilities(untold));
+        if self.tick_count >= self.next_heartbeat_tick {
+            self.next_heartbeat_tick =
+                self.tick_count + self.config.heartbeat_interval_ticks.max(1);
+            let self_id = self.keypair.device_id();
+            for peer in self.peers.iter().map(|p| p.device_id) {
+                let msg = Message::Heartbeat { device_id: self_id };
+                if let Ok(bytes) = wire::encode_frame(&msg) {
+                    actions.push(OutboundAction::SendMessage(peer, bytes));
+                }
+            }
+        }
+        actions
+    }
+
+    /// Override the deadline of an active transfer: abort it to fallback `ticks` ticks from now
+    /// (0 removes the deadline). Returns false if the transfer is unknown.
+    pub fn set_transfer_deadline(&mut self, transfer_id: [u8; 16], ticks: u64) -> bool {
+        let now = self.tick_count;
+        match self.transfers.get_mut(&transfer_id) {
+            Some(t) => {
+                t.deadline_tick = (ticks > 0).then(|| now.saturating_add(ticks));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Flag an active transfer as streaming media (e.g. a video being played) or clear the flag.
+    /// Its chunks not yet requested are reassigned in deadline order: each, from the play position
+    /// on, goes to the worker that would finish it first given its bandwidth and backlog, so early
+    /// ranges land on the fastest workers and later ones on slower workers. On every tick the
+    /// first [`Config::stream_head_chunks`] missing chunks in flight are also requested from the
+    /// fastest other worker. Returns the resulting ChunkRequests (empty if the transfer is
+    /// unknown).
+    pub fn set_streaming(&mut self, transfer_id: [u8; 16], on: bool) -> Vec<OutboundAction> {
+        let Some(t) = self.transfers.get_mut(&transfer_id) else {
+            return vec![];
+        };
+        t.streaming = on;
+        if !on {
+            return vec![];
+        }
+        let waiting: Vec<(ChunkId, Option<DeviceId>)> = t
+            .queued
+            .values()
+            .chain(t.deferred.values())
+            .map(|&c| (c, t.peer_for(c)))
+            .collect();
+        let workers: Vec<DeviceId> = self.workers().collect();
+        let metrics = self.worker_metrics(&workers);
+        let owned = self.owned_uplinks(&workers);
+        let uplinks: Vec<Option<&str>> = owned.iter().map(Option::as_deref).collect();
+        // Backlogs without the chunks about to be placed again.
+        let mut outstanding_bytes = self.outstanding_bytes(&workers);
+        for &(c, peer) in &waiting {
+            if let Some(i) = workers.iter().position(|&w| Some(w) == peer) {
+                outstanding_bytes[i] = outstanding_bytes[i].saturating_sub(c.end - c.start);
+            }
+        }
+        let view = Workers {
+            ids: &workers,
+            metrics: &metrics,
+            uplinks: &uplinks,
+            outstanding_bytes: &outstanding_bytes,
+        };
+        let chunk_ids: Vec<ChunkId> = waiting.iter().map(|&(c, _)| c).collect();
+        let placed = scheduler::assign_by_backlog(&chunk_ids, &view);
+        if let Some(t) = self.transfers.get_mut(&transfer_id) {
+            for (chunk_id, peer) in placed {
+                t.assignment.retain(|(c, _)| *c != chunk_id);
+                t.assignment.push((chunk_id, peer));
+            }
+        }
+        let mut actions = self.release_deferred();
+        actions.extend(self.duplicate_stream_heads());
+        actions
+    }
+
+    /// For streaming transfers, request the first [`Config::stream_head_chunks`] missing chunks
+    /// that are in flight (by offset) from the fastest other worker too. Each chunk is duplicated
+    /// once; skipped while paused.
+    fn duplicate_stream_heads(&mut self) -> Vec<OutboundAction> {
+        let head = self.config.stream_head_chunks;
+        if head == 0 {
+            return vec![];
+        }
+        let workers: Vec<DeviceId> = self.workers().collect();
+        let metrics = self.worker_metrics(&workers);
+        let mut duplicates = Vec::new();
+        for t in self
+            .transfers
+            .values_mut()
+            .filter(|t| t.streaming && !t.paused)
+        {
+            let next: Vec<ChunkId> = t
+                .state
+                .chunk_ids()
+                .iter()
+                .copied()
+                .filter(|&c| !t.state.is_chunk_received(c))
+                .take(head)
+                .filter(|c| t.requested_at.contains_key(c) && !t.endgame.contains_key(c))
+                .collect();
+            for chunk_id in next {
+                let assigned = t.peer_for(chunk_id);
+                let Some(extra) = workers
+                    .iter()
+                    .zip(&metrics)
+                    .filter(|(&w, m)| Some(w) != assigned && m.weight() > 0)
+                    .max_by_key(|(_, m)| (m.delivery_rate_bps.unwrap_or(0), m.weight()))
+                    .map(|(&w, _)| w)
+                else {
+                    continue;
+                };
+                t.endgame.insert(chunk_id, vec![extra]);
+                duplicates.push((chunk_id, extra));
+            }
+        }
+        duplicates
+            .into_iter()
+            .filter_map(|(chunk_id, extra)| self.chunk_request_to(extra, chunk_id))
+            .collect()
+    }
+
+    /// Write an active transfer's verified chunks to `sink` (a file, mmap or socket the host
+    /// provides) instead of holding them, so multi-GB transfers need no proportional memory.
+    /// Chunks already held and not yet taken are written at once. The completion body and
+    /// [`take_segment`](Self::take_segment) then carry no bytes for chunks in the sink. Not kept
+    /// in snapshots: attach it again after [`restore`](Self::restore). Returns false if the
+    /// transfer is unknown.
+    pub fn set_transfer_sink(&mut self, transfer_id: [u8; 16], sink: SharedSink) -> bool {
+        match self.transfers.get_mut(&transfer_id) {
+            Some(t) => {
+                t.state.set_sink(sink);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Verify an active transfer's reassembled body against `manifest` (from a trusted origin
+    /// fetch) on completion. A mismatch aborts the transfer to fallback instead of delivering it.
+    /// Returns false if the transfer is unknown.
+    pub fn set_transfer_manifest(&mut self, transfer_id: [u8; 16], manifest: Manifest) -> bool {
+        match self.transfers.get_mut(&transfer_id) {
+            Some(t) => {
+                t.manifest = Some(manifest);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Verify an active transfer's reassembled body against digests the origin published (see
+    /// [`OriginDigest::from_headers`], e.g. from a HEAD probe of the range this transfer covers). A
+    /// mismatch aborts the transfer to fallback. Skipped for bodies the host took in segments or
+    /// into a sink, which the core no longer holds whole. Returns false if the transfer is unknown.
+    pub fn set_transfer_digests(
+        &mut self,
+        transfer_id: [u8; 16],
+        digests: Vec<OriginDigest>,
+    ) -> bool {
+        match self.transfers.get_mut(&transfer_id) {
+            Some(t) => {
+                t.origin_digests = digests;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Drop transfers past their deadline: Cancel their outstanding peer requests and tell the
+    /// host to fetch the rest directly.
+    fn abort_overdue_transfers(&mut self) -> Vec<OutboundAction> {
+        let now = self.tick_count;
+        let overdue: Vec<[u8; 16]> = self
+            .transfers
+            .iter()
+            .filter(|(_, t)| !t.paused && t.deadline_tick.is_some_and(|d| now > d))
+            .map(|(id, _)| *id)
+            .collect();
+        overdue
+            .into_iter()
+            .flat_map(|transfer_id| self.abort_transfer(transfer_id))
+            .collect()
+    }
+
+    /// Drop a transfer: Cancel its outstanding peer requests and tell the host to fetch the rest
+    /// directly.
+    fn abort_transfer(&mut self, transfer_id: [u8; 16]) -> Vec<OutboundAction> {
+        let Some((url, mut actions)) = self.drop_transfer(transfer_id, TransferOutcome::Fallback)
+        else {
+            return vec![];
+        };
+        self.emit(CoreEvent::Fallback { url });
+        actions.push(OutboundAction::AbortToFallback(transfer_id));
+        actions
+    }
+
+    /// Remove a transfer's state, recording it in the history with `outcome`. Returns its URL and
+    /// Cancels for its outstanding peer requests.
+    fn drop_transfer(
+        &mut self,
+        transfer_id: [u8; 16],
+        outcome: TransferOutcome,
+    ) -> Option<(String, Vec<OutboundAction>)> {
+        let self_id = self.keypair.device_id();
+        let mut actions = Vec::new();
+        let t = self.transfers.remove(&transfer_id)?;
+        self.record_history(transfer_id, &t, outcome);
+        for chunk_id in t.requested_at.keys() {
+            let mut asked: Vec<DeviceId> = t.endgame.get(chunk_id).cloned().unwrap_or_default();
+            asked.extend(t.peer_for(*chunk_id));
+            let cancel = Message::Cancel {
+                transfer_id,
+                start: chunk_id.start,
+                end: chunk_id.end,
+            };
+            let Ok(bytes) = wire::encode_frame(&cancel) else {
+                continue;
+            };
+            for peer in asked
+                .into_iter()
+                .filter(|&p| p != self_id && self.peer_supports(p, FEATURE_CANCEL))
+            {
+                actions.push(OutboundAction::SendMessage(peer, bytes.clone()));
+            }
+        }
+        Some((t.url, actions))
+    }
+
+    /// The host no longer needs a transfer or upload (e.g. the client disconnected): Cancel the
+    /// transfer's outstanding peer requests and drop its state, emitting
+    /// [`CoreEvent::TransferReaped`]. Late ChunkData or UploadAcks for it are ignored.
+    pub fn abandon_transfer(&mut self, transfer_id: [u8; 16]) -> Vec<OutboundAction> {
+        let actions = match self.drop_transfer(transfer_id, TransferOutcome::Abandoned) {
+            Some((_, actions)) => actions,
+            None if self.uploads.remove(&transfer_id).is_some() => vec![],
+            None => return vec![],
+        };
+        self.emit(CoreEvent::TransferReaped { transfer_id });
+        actions
+    }
+
+    /// Reap transfers and uploads without progress for `stale_transfer_ticks`: the host is told to
+    /// stop waiting ([`OutboundAction::AbortToFallback`]) in case it still is. Disputes unsettled
+    /// for as long are dropped.
+    fn reap_stale_transfers(&mut self) -> Vec<OutboundAction> {
+        let stale_after = self.config.stale_transfer_ticks;
+        if stale_after == 0 {
+            return vec![];
+        }
+        let now = self.tick_count;
+        let is_stale = |last: u64| now.saturating_sub(last) > stale_after;
+        self.disputes.retain(|_, d| !is_stale(d.since));
+        let mut stale: Vec<[u8; 16]> = self
+            .transfers
+            .iter()
+            .filter(|(_, t)| is_stale(t.last_activity))
+            .map(|(id, _)| *id)
+            .collect();
+        stale.extend(
+            self.uploads
+                .iter()
+                .filter(|(_, u)| is_stale(u.last_activity))
+                .map(|(id, _)| *id),
+        );
+        let mut actions = Vec::new();
+        for transfer_id in stale {
+            let was_transfer = self.transfers.contains_key(&transfer_id);
+            actions.extend(self.abandon_transfer(transfer_id));
+            if was_transfer {
+                actions.push(OutboundAction::AbortToFallback(transfer_id));
+            }
+        }
+        actions
+    }
+
+    /// Reassign chunks whose request is older than `chunk_timeout_ticks`, retrying after a backoff
+    /// (see [`Config::retry_backoff_ticks`]). A chunk out of retries fails its transfer. Skipped
+    /// while paused.
+    fn reassign_timed_out_chunks(&mut self) -> Vec<OutboundAction> {
+        let now = self.tick_count;
+        let timeout = self.config.chunk_timeout_ticks;
+        let timed_out: Vec<ChunkId> = self
+            .transfers
+            .values()
+            .filter(|t| !t.paused)
+            .flat_map(|t| t.requested_at.iter())
+            .filter(|(_, &t)| now.saturating_sub(t) > timeout)
+            .map(|(&c, _)| c)
+            .collect();
+        let base = self.config.retry_backoff_ticks;
+        let mut actions = Vec::new();
+        for chunk_id in timed_out {
+            let Some(late_peer) = self
+                .transfers
+                .get(&chunk_id.transfer_id)
+                .map(|t| t.peer_for(chunk_id))
+            else {
+                continue;
+            };
+            if let Some(peer) = late_peer {
+                self.record_outcome(peer, false);
+            }
+            let retries = match self.charge_attempt(chunk_id) {
+                Ok(retries) => retries.max(1),
+                Err(abort) => {
+                    actions.extend(abort);
+                    continue;
+                }
+            };
+            // Doubles per retry, capped at the chunk timeout.
+            let backoff = base
+                .saturating_mul(1 << (retries - 1).min(63))
+                .min(timeout.max(base));
+            // The retry's timeout runs from when it is issued.
+            if let Some(t) = self.transfers.get_mut(&chunk_id.transfer_id) {
+                t.requested_at.insert(chunk_id, now + backoff);
+            }
+            let degraded = self.note_chunk_failure(chunk_id);
+            if !degraded.is_empty() {
+                actions.extend(degraded);
+                continue;
+            }
+            if !self.move_chunk(chunk_id) {
+                continue;
+            }
+            if backoff == 0 {
+                actions.extend(self.request_chunk(chunk_id));
+            } else if let Some(t) = self.transfers.get_mut(&chunk_id.transfer_id) {
+                t.retry_at.insert(chunk_id, now + backoff);
+            }
+        }
+        actions
+    }
+
+    /// Count a failed attempt at `chunk_id` (timeout, integrity failure, Nack, failed local fetch)
+    /// in its transfer's
+    /// state and return the failures so far. Past `chunk_retries` the chunk fails for good
+    /// ([`CoreEvent::ChunkFailed`]) and `Err` holds the actions aborting its transfer, so a chunk
+    /// that keeps failing cannot cycle between peers forever.
+    fn charge_attempt(&mut self, chunk_id: ChunkId) -> Result<u32, Vec<OutboundAction>> {
+        let Some(t) = self.transfers.get_mut(&chunk_id.transfer_id) else {
+            return Ok(0);
+        };
+        let retries = t.state.note_failure(chunk_id);
+        let budget = self.config.chunk_retries;
+        if budget == 0 || retries <= budget {
+            return Ok(retries);
+        }
+        self.emit(CoreEvent::ChunkFailed { chunk_id, retries });
+        Err(self.abort_transfer(chunk_id.transfer_id))
+    }
+
+    /// Count a failed chunk against its transfer. Once more than `fallback_failure_percent` of the
+    /// transfer's chunks have failed, drop it and return a [`OutboundAction::PartialFallback`] with
+    /// the verified bytes so the host fetches only the gaps.
+    fn note_chunk_failure(&mut self, chunk_id: ChunkId) -> Vec<OutboundAction> {
+        let percent = self.config.fallback_failure_percent;
+        let transfer_id = chunk_id.transfer_id;
+        let Some(t) = self.transfers.get_mut(&transfer_id) else {
+            return vec![];
+        };
+        t.failed.insert(chunk_id);
+        let total = t.state.chunk_ids().len() as u64;
+        if percent == 0
+            || (t.failed.len() as u64).saturating_mul(100) <= percent.saturating_mul(total)
+        {
+            return vec![];
+        }
+        let verified = t.state.verified_segments();
+        let start = t.state.base_offset();
+        let range = (start, start + t.state.total_length);
+        let Some((url, mut actions)) = self.drop_transfer(transfer_id, TransferOutcome::Fallback)
+        else {
+            return vec![];
+        };
+        self.emit(CoreEvent::Fallback { url: url.clone() });
+        actions.push(OutboundAction::PartialFallback {
+            transfer_id,
+            url,
+            range,
+            verified,
+        });
+        actions
+    }
+
+    /// Issue retries whose backoff has elapsed. Skipped while paused.
+    fn issue_due_retries(&mut self) -> Vec<OutboundAction> {
+        let now = self.tick_count;
+        let mut due = Vec::new();
+        for t in self.transfers.values_mut().filter(|t| !t.paused) {
+            t.retry_at.retain(|&c, &mut at| {
+                if at <= now {
+                    due.push(c);
+                }
+                at > now
+            });
+        }
+        due.sort_by_key(|c| c.start);
+        due.into_iter()
+            .filter_map(|c| self.request_chunk(c))
+            .collect()
+    }
+
+    /// Also request each straggler chunk (see [`Config::straggler_factor`]) from the fastest other
+    /// worker, keeping the original request. Each chunk is raced once; skipped while paused and
+    /// for peers without a measured delivery rate.
+    fn race_stragglers(&mut self) -> Vec<OutboundAction> {
+        let factor = self.config.straggler_factor;
+        if factor == 0 {
+            return vec![];
+        }
+        let now = self.tick_count;
+        let workers: Vec<DeviceId> = self.workers().collect();
+        let metrics = self.worker_metrics(&workers);
+        let rate = |id: DeviceId| {
+            workers
+                .iter()
+                .position(|&w| w == id)
+                .and_then(|i| metrics[i].delivery_rate_bps)
+        };
+        let mut late = Vec::new();
+        for t in self.transfers.values().filter(|t| !t.paused) {
+            for (&chunk_id, &at) in &t.requested_at {
+                if t.endgame.contains_key(&chunk_id) {
+                    continue;
+                }
+                let Some(peer) = t.peer_for(chunk_id) else {
+                    continue;
+                };
+                let Some(bps) = rate(peer) else {
+                    continue;
+                };
+                let expected = (chunk_id.end - chunk_id.start).div_ceil(bps.max(1)).max(1);
+                if now.saturating_sub(at) > expected.saturating_mul(factor) {
+                    late.push((chunk_id, peer));
+                }
+            }
+        }
+        let mut races = Vec::new();
+        for (chunk_id, peer) in late {
+            let racer = workers
+                .iter()
+                .zip(&metrics)
+                .filter(|(&w, _)| w != peer)
+                .max_by_key(|(_, m)| (m.delivery_rate_bps.unwrap_or(0), m.weight()))
+                .map(|(&w, _)| w);
+            let (Some(racer), Some(t)) = (racer, self.transfers.get_mut(&chunk_id.transfer_id))
+            else {
+                continue;
+            };
+            t.endgame.insert(chunk_id, vec![racer]);
+            t.raced.insert(chunk_id);
+            races.push((chunk_id, racer));
+        }
+        races
+            .into_iter()
+            .filter_map(|(chunk_id, racer)| self.chunk_request_to(racer, chunk_id))
+            .collect()
+    }
+
+    fn redistribute_peer_chunks(&mut self, peer_left: DeviceId) -> Vec<OutboundAction> {
+        let remaining: Vec<DeviceId> = self.workers().collect();
+        let metrics = self.worker_metrics(&remaining);
+        let owned = self.owned_uplinks(&remaining);
+        let uplinks: Vec<Option<&str>> = owned.iter().map(Option::as_deref).collect();
+        let outstanding_bytes = self.outstanding_bytes(&remaining);
+        let view = Workers {
+            ids: &remaining,
+            metrics: &metrics,
+            uplinks: &uplinks,
+            outstanding_bytes: &outstanding_bytes,
+        };
+        let mut to_request = Vec::new();
+        for t in self.transfers.values_mut() {
+            let orphaned: Vec<ChunkId> = t
+                .assignment
+                .iter()
+                .filter(|(_, p)| *p == peer_left)
+                .map(|(c, _)| *c)
+                .collect();
+            let new_assignments = self.scheduler.assign(&orphaned, &view);
+            t.assignment.retain(|(_, p)| *p != peer_left);
+            for (chunk_id, new_peer) in new_assignments {
+                t.assignment.push((chunk_id, new_peer));
+                // Deferred chunks are requested from their new peer when released.
+                if !t.deferred.contains_key(&chunk_id.start)
+                    && !t.queued.contains_key(&chunk_id.start)
+                {
+                    to_request.push(chunk_id);
+                }
+            }
+        }
+        to_request
+            .into_iter()
+            .filter_map(|c| self.request_chunk(c))
+            .collect()
+    }
+
+    /// Host's WAN range request for `chunk_id` failed. `requester` is the peer whose ChunkRequest the
+    /// host was serving (it gets a Nack so it reassigns the chunk), or this device for a chunk of its
+    /// own transfer (the core reassigns it to a peer). Counts as a failure in this device's metrics.
+    pub fn on_chunk_fetch_failed(
+        &mut self,
+        requester: DeviceId,
+        chunk_id: ChunkId,
+        reason: FetchFailure,
+    ) -> Vec<OutboundAction> {
+        let self_id = self.keypair.device_id();
+        self.record_outcome(self_id, false);
+        self.emit(CoreEvent::FetchFailed { chunk_id, reason });
+        if requester == self_id {
+            return match self.charge_attempt(chunk_id) {
+                Ok(_) => self.reassign_single_chunk(chunk_id),
+                Err(abort) => abort,
+            };
+        }
+        let len = chunk_id.end.saturating_sub(chunk_id.start);
+        let mut actions = Vec::new();
+        let (_, waiters) = self.take_serve_waiters(requester, chunk_id);
+        for (peer, transfer_id) in waiters {
+            // Nothing was delivered; take the bytes back off the requester's tab.
+            if let Some(credit) = self.ledger.get_mut(&peer) {
+                credit.bytes_served = credit.bytes_served.saturating_sub(len);
+            }
+            let nack = Message::Nack {
+                transfer_id,
+                start: chunk_id.start,
+                end: chunk_id.end,
+            };
+            if let Ok(bytes) = wire::encode_frame(&nack) {
+                actions.push(OutboundAction::SendMessage(peer, bytes));
+            }
+        }
+        actions
+    }
+
+    /// Host fetched the range of a [`OutboundAction::FetchChunk`] for `requester`. Returns ChunkData
+    /// for every peer waiting on the same URL and range, and keeps the bytes for repeat requests.
+    /// When `requester` is this device, the chunk goes to its own transfer instead, as with
+    /// [`on_chunk_received_with_actions`](Self::on_chunk_received_with_actions); a transfer it
+    /// completes is delivered with [`OutboundAction::TransferComplete`].
+    pub fn on_chunk_fetched_for_peer(
+        &mut self,
+        requester: DeviceId,
+        chunk_id: ChunkId,
+        payload: Vec<u8>,
+    ) -> Vec<OutboundAction> {
+        let self_id = self.keypair.device_id();
+        self.record_outcome(self_id, true);
+        if requester == self_id {
+            let hash = crate::integrity::hash_chunk(&payload);
+            let ChunkId {
+                transfer_id,
+                start,
+                end,
+            } = chunk_id;
+            let outcome =
+                self.on_chunk_received_with_actions(transfer_id, start, end, hash, payload);
+            let mut actions = outcome.actions;
+            if let Ok(Some(body)) = outcome.result {
+                actions.push(OutboundAction::TransferComplete { transfer_id, body });
+            }
+            return actions;
+        }
+        let (key, waiters) = self.take_serve_waiters(requester, chunk_id);
+        let len = payload.len() as u64;
+        for (peer, _) in &waiters {
+            let served = self.stats.bytes_served.entry(*peer).or_default();
+            *served = served.saturating_add(len);
+        }
+        let packed = waiters
+            .iter()
+            .any(|&(peer, _)| self.accepts_compressed(peer))
+            .then(|| chunk::compress_payload(&payload))
+            .flatten();
+        let actions = waiters
+            .into_iter()
+            .filter_map(|(peer, transfer_id)| {
+                let chunk_id = ChunkId {
+                    transfer_id,
+                    ..chunk_id
+                };
+                let bytes = self.chunk_data_frame(peer, chunk_id, &payload, packed.as_deref())?;
+                Some(OutboundAction::SendMessage(peer, bytes))
+            })
+            .collect();
+        if let Some(key) = key {
+            self.cache.insert(key, payload);
+        }
+        actions
+    }
+
+    /// Whether ChunkData for `peer` may be compressed: [`Config::compress_chunks`] is on and the
+    /// peer advertised [`FEATURE_COMPRESSION`].
+    fn accepts_compressed(&self, peer: DeviceId) -> bool {
+        self.config.compress_chunks
+            && self
+                .capabilities_of(&peer)
+                .is_some_and(|c| c.supports(FEATURE_COMPRESSION))
+    }
+
+    /// Encode `msg` for `peer` in the encoding of the version settled with it, compressing the
+    /// frame when [`Config::compress_frames`] is on and the peer advertised
+    /// [`FEATURE_FRAME_COMPRESSION`].
+    fn frame_for(&self, peer: DeviceId, msg: &Message) -> Option<Vec<u8>> {
+        let compress = self.config.compress_frames
+            && self
+                .capabilities_of(&peer)
+                .is_some_and(|c| c.supports(FEATURE_FRAME_COMPRESSION));
+        let options = wire::FrameOptions {
+            encoding: wire::WireEncoding::for_protocol_version(self.peer_protocol_version(peer)),
+            compress,
+            ..Default::default()
+        };
+        wire::encode_frame_with(msg, options).ok()
+    }
+
+    /// Hash algorithm for ChunkData sent to `peer`: BLAKE3 when [`Config::blake3_hashes`] is on
+    /// and the peer advertised [`FEATURE_BLAKE3`], SHA-256 otherwise.
+    fn hash_algorithm_for(&self, peer: DeviceId) -> HashAlgorithm {
+        let blake3 = self.config.blake3_hashes
+            && self
+                .capabilities_of(&peer)
+                .is_some_and(|c| c.supports(FEATURE_BLAKE3));
+        if blake3 {
+            HashAlgorithm::Blake3
+        } else {
+            HashAlgorithm::Sha256
+        }
+    }
+
+    /// Encoded ChunkData for `peer`, hashed with the algorithm the peer understands, attested
+    /// with this device's key and carrying `packed` (the compressed payload, if compression paid off) when the peer accepts it and
+    /// `payload` otherwise.
+    fn chunk_data_frame(
+        &self,
+        peer: DeviceId,
+        chunk_id: ChunkId,
+        payload: &[u8],
+        packed: Option<&[u8]>,
+    ) -> Option<Vec<u8>> {
+        let packed = packed.filter(|_| self.accepts_compressed(peer));
+        let hash_algorithm = self.hash_algorithm_for(peer);
+        let hash = hash_algorithm.hash(payload);
+        let attestation = self.public_key_of(peer).map(|public_key| {
+            self.keypair.attest_chunk(
+                &public_key,
+                &chunk_id.transfer_id,
+                (chunk_id.start, chunk_id.end),
+                &hash,
+            )
+        });
+        let data = Message::ChunkData {
+            transfer_id: chunk_id.transfer_id,
+            start: chunk_id.start,
+            end: chunk_id.end,
+            hash,
+            payload: packed.unwrap_or(payload).to_vec(),
+            compressed: packed.is_some(),
+            hash_algorithm,
+            attestation,
+        };
+        self.frame_for(peer, &data)
+    }
+
+    /// Remove the pending fetch `requester` is waiting on and return its key and waiters (no key
+    /// and just the requester if the fetch already expired).
+    fn take_serve_waiters(
+        &mut self,
+        requester: DeviceId,
+        chunk_id: ChunkId,
+    ) -> (Option<CacheKey>, Vec<(DeviceId, [u8; 16])>) {
+        let waiter = (requester, chunk_id.transfer_id);
+        let key = self
+            .pending_serves
+            .iter()
+            .find(|(k, p)| {
+                k.start == chunk_id.start && k.end == chunk_id.end && p.waiters.contains(&waiter)
+            })
+            .map(|(k, _)| k.clone());
+        match key.and_then(|k| self.pending_serves.remove_entry(&k)) {
+            Some((k, pending)) => (Some(k), pending.waiters),
+            None => (None, vec![waiter]),
+        }
+    }
+
+    /// Get current assignment for an active transfer (for host to issue ChunkRequests). Returns (chunk_id, peer_id) list.
+    pub fn current_assignment(&self, transfer_id: [u8; 16]) -> Option<Vec<(ChunkId, DeviceId)>> {
+        self.transfers
+            .get(&transfer_id)
+            .map(|a| a.assignment.clone())
+    }
+
+    /// Process a received message (host decrypts and passes frame bytes).
+    /// Returns (outbound actions, optional completed transfer body when ChunkData completes the transfer).
+    #[allow(clippy::type_complexity)]
+    pub fn on_message_received(
+        &mut self,
+        peer_id: DeviceId,
+        frame_bytes: &[u8],
+    ) -> Result<(Vec<OutboundAction>, Option<([u8; 16], Vec<u8>)>), CoreError> {
+        self.handle_message(peer_id, frame_bytes)
+            .map_err(|source| CoreError::Message {
+                peer: peer_id,
+                source,
+            })
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn handle_message(
+        &mut self,
+        peer_id: DeviceId,
+        frame_bytes: &[u8],
+    ) -> Result<(Vec<OutboundAction>, Option<([u8; 16], Vec<u8>)>), OnMessageError> {
+        let msg = match wire::decode_frame(frame_bytes) {
+            Ok((msg, _)) => msg,
+            // A message type (or flag) from a newer peer: ignore it rather than fail the peer.
+            Err(wire::FrameDecodeError::Skipped { .. }) => return Ok((Vec::new(), None)),
+            Err(e) => return Err(e.into()),
+        };
+        let mut actions = Vec::new();
+        let mut completed = None;
+        match msg {
+            Message::Heartbeat { .. } => {
+                self.on_heartbeat_received(peer_id);
+            }
+            Message::Leave {
+                device_id,
+                signature,
+            } => {
+                let Some(public_key) = self.public_key_of(peer_id) else {
+                    return Err(OnMessageError::LeaveRejected);
+                };
+                if device_id != peer_id || !self.keypair.verify_leave(&public_key, &signature) {
+                    return Err(OnMessageError::LeaveRejected);
+                }
+                actions.extend(self.on_peer_left(peer_id));
+            }
+            Message::RotateKey {
+                new_public_key,
+                old_proof,
+                new_proof,
+            } => self.on_rotate_key(peer_id, new_public_key, &old_proof, &new_proof)?,
+            Message::Revoke { revocation } => actions.extend(self.on_revoke(peer_id, revocation)?),
+            // An isolated peer's chunks were reassigned; late copies from it are not trusted.
+            Message::ChunkData { .. } if self.trust.is_isolated(&peer_id) => {}
+            Message::ChunkData {
+                transfer_id,
+                start,
+                end,
+                hash,
+                payload,
+                compressed,
+                hash_algorithm,
+                attestation,
+            } => {
+                // An undecodable payload is empty and fails the length check like any corrupt one.
+                let payload = if compressed {
+                    chunk::decompress_payload(&payload, end.saturating_sub(start))
+                        .unwrap_or_default()
+                } else {
+                    payload
+                };
+                if let Some(signature) = attestation {
+                    let chunk_id = ChunkId {
+                        transfer_id,
+                        start,
+                        end,
+                    };
+                    self.note_attestation(peer_id, chunk_id, hash, signature);
+                }
+                let outcome = self.chunk_received_from(
+                    peer_id,
+                    transfer_id,
+                    start,
+                    end,
+                    hash_algorithm,
+                    hash,
+                    payload,
+                );
+                actions.extend(outcome.actions);
+                match outcome.result {
+                    Ok(Some(body)) => completed = Some((transfer_id, body)),
+                    Ok(None) => {}
+                    Err(CoreError::Chunk {
+                        source: ChunkError::IntegrityFailed,
+                        ..
+                    }) => {
+                        let chunk_id = ChunkId {
+                            transfer_id,
+                            start,
+                            end,
+                        };
+                        actions.extend(self.reassign_single_chunk(chunk_id));
+                        if self.config.dispute_quorum {
+                            actions.extend(self.open_dispute(
+                                peer_id,
+                                chunk_id,
+                                hash_algorithm,
+                                hash,
+                            ));
+                        }
+                        if self.trust.record_failure(peer_id) {
+                            self.emit(CoreEvent::PeerIsolated(peer_id));
+                            actions.extend(self.redistribute_peer_chunks(peer_id));
+                        }
+                    }
+                    Err(_) => {}
+                }
+            }
+            Message::Nack {
+                transfer_id,
+                start,
+                end,
+            } => {
+                let chunk_id = ChunkId {
+                    transfer_id,
+                    start,
+                    end,
+                };
+                self.record_outcome(peer_id, false);
+                match self.charge_attempt(chunk_id) {
+                    Ok(_) => actions.extend(self.reassign_single_chunk(chunk_id)),
+                    Err(abort) => actions.extend(abort),
+                }
+            }
+            Message::Busy {
+                transfer_id,
+                start,
+                end,
+            } => {
+                let chunk_id = ChunkId {
+                    transfer_id,
+                    start,
+                    end,
+                };
+                // Not the peer's fault: it gets no new work for a while but no failure is charged.
+                let until = self.tick_count.saturating_add(BUSY_BACKOFF_TICKS);
+                if let Some(info) = self.peer_mut(&peer_id) {
+                    info.busy_until = until;
+                }
+                actions.extend(self.reassign_single_chunk(chunk_id));
+            }
+            Message::JoinRequest {
+                device_id,
+                public_key,
+                signature,
+                capabilities,
+                name,
+            } => {
+                if device_id != peer_id
+                    || !self
+                        .keypair
+                        .verify_join(&device_id, &public_key, &signature)
+                    || self.check_pin(peer_id, &public_key).is_err()
+                    || name
+                        .as_ref()
+                        .is_some_and(|r| !r.verify(&device_id, &public_key))
+                {
+                    return Err(OnMessageError::JoinRejected);
+                }
+                if let Some(record) = &name {
+                    self.on_peer_named(peer_id, &public_key, record);
+                }
+                actions.extend(self.on_join_request(peer_id, public_key, capabilities));
+            }
+            Message::JoinAccept {
+                device_id,
+                public_key,
+                signature,
+                capabilities,
+                name,
+            } => {
+                if device_id != peer_id
+                    || !self
+                        .keypair
+                        .verify_join(&device_id, &public_key, &signature)
+                    || self.check_pin(peer_id, &public_key).is_err()
+                    || name
+                        .as_ref()
+                        .is_some_and(|r| !r.verify(&device_id, &public_key))
+                {
+                    return Err(OnMessageError::JoinRejected);
+                }
+                if let Some(record) = &name {
+                    self.on_peer_named(peer_id, &public_key, record);
+                }
+                // Only an invitation this device sent can be accepted.
+                if self.membership(peer_id) == Some(Membership::Invited)
+                    && self.admit(peer_id, &public_key, capabilities)
+                {
+                    actions.extend(self.send_pod_key(peer_id));
+                }
+            }
+            Message::PairConfirm {
+                confirmation,
+                pod_key,
+            } => match self.on_pair_confirm(peer_id, &confirmation, pod_key.as_ref()) {
+                Some(more) => actions.extend(more),
+                None => return Err(OnMessageError::PairingRejected),
+            },
+            Message::PairCommit { commitment } => {
+                actions.extend(self.on_pair_commit(peer_id, commitment))
+            }
+            Message::PairNonce { nonce } => self.on_pair_nonce(peer_id, nonce)?,
+            Message::ChunkRequest {
+                transfer_id,
+                start,
+                end,
+                url,
+                validator,
+                headers,
+                deadline_ms,
+            } => {
+                let chunk_id = ChunkId {
+                    transfer_id,
+                    start,
+                    end,
+                };
+                // Only pod members are served.
+                if self.has_peer(&peer_id) {
+                    actions.extend(self.serve_request(
+                        peer_id,
+                        chunk_id,
+                        url,
+                        validator,
+                        headers,
+                        deadline_ms,
+                    ));
+                }
+            }
+            Message::Constraints {
+                battery_low,
+                metered,
+                max_contribution_bps,
+            } => {
+                if let Some(info) = self.peer_mut(&peer_id) {
+                    info.metrics.constraints = Constraints {
+                        battery_low,
+                        metered,
+                        max_contribution_bps,
+                    };
+                }
+            }
+            Message::Cancel {
+                transfer_id,
+                start,
+                end,
+            } => {
+                for (key, pending) in self.pending_serves.iter_mut() {
+                    if key.start == start && key.end == end {
+                        pending.waiters.retain(|w| *w != (peer_id, transfer_id));
+                    }
+                }
+            }
+            Message::UploadOffer {
+                upload_id,
+                url,
+                total_length,
+            } => {
+                // Only pod members get uploads done on their behalf.
+                if self.has_peer(&peer_id) {
+                    let offer = UploadOffer {
+                        url,
+                        total_length,
+                        last_used: self.tick_count,
+                    };
+                    self.upload_offers.insert((peer_id, upload_id), offer);
+                }
+            }
+            Message::UploadData {
+                upload_id,
+                start,
+                end,
+                hash,
+                payload,
+            } => {
+                let chunk_id = ChunkId {
+                    transfer_id: upload_id,
+                    start,
+                    end,
+                };
+                actions.extend(self.on_upload_data(peer_id, chunk_id, hash, payload));
+            }
+            Message::UploadAck {
+                upload_id,
+                start,
+                end,
+                ok,
+            } => {
+                let chunk_id = ChunkId {
+                    transfer_id: upload_id,
+                    start,
+                    end,
+                };
+                actions.extend(self.upload_acked(peer_id, chunk_id, ok));
+            }
+            Message::PodKey { key } => actions.extend(self.on_pod_key(peer_id, &key)?),
+            Message::Hello {
+                min_version,
+                max_version,
+            } => actions.extend(self.on_hello(peer_id, min_version, max_version)?),
+            Message::Capabilities { capabilities } => self.on_capabilities(peer_id, capabilities),
+            Message::Beacon { .. }
+            | Message::DiscoveryResponse { .. }
+            | Message::SealedDiscovery { .. }
+            | Message::DiscoveryPod { .. }
+            | Message::DiscoveryName { .. }
+            | Message::Rekey { .. } => {}
+        }
+        Ok((actions, completed))
+    }
+
+    /// Split `body` into ranges and share them among this device and pod members that support
+    /// uploads ([`crate::protocol::FEATURE_UPLOAD`]), so each uploads its ranges to `url`.
+    /// Returns the upload ID and actions: an [`Message::UploadOffer`] and UploadData for each
+    /// helping peer, and an [`OutboundAction::UploadChunk`] for each range this device uploads.
+    /// Completion is reported as [`CoreEvent::UploadComplete`]; a range this device cannot
+    /// upload ends the upload with [`CoreEvent::UploadFailed`] and the host uploads directly.
+    pub fn start_upload(&mut self, url: &str, body: Vec<u8>) -> ([u8; 16], Vec<OutboundAction>) {
+        let upload_id: [u8; 16] = uuid::Uuid::new_v4().into_bytes();
+        let self_id = self.keypair.device_id();
+        let workers: Vec<DeviceId> = self
+            .workers()
+            .filter(|id| {
+                *id == self_id
+                    || self
+                        .capabilities_of(id)
+                        .is_some_and(|c| c.supports(crate::protocol::FEATURE_UPLOAD))
+            })
+            .collect();
+        let chunk_size = match self.chunk_size_for(&workers) {
+            0 => DEFAULT_CHUNK_SIZE,
+            n => n,
+        };
+        let chunks = split_upload_chunks(upload_id, body.len() as u64, chunk_size);
+        let assignment = self.assign(&chunks, &workers);
+        let total_length = body.len() as u64;
+        let mut upload = ActiveUpload {
+            url: url.to_string(),
+            body,
+            pending: HashMap::new(),
+            last_activity: self.tick_count,
+        };
+        let mut actions = Vec::new();
+        let mut offered = HashSet::new();
+        for (chunk_id, peer) in assignment {
+            upload.pending.insert(chunk_id, peer);
+            if peer != self_id && offered.insert(peer) {
+                let offer = Message::UploadOffer {
+                    upload_id,
+                    url: upload.url.clone(),
+                    total_length,
+                };
+                if let Ok(bytes) = wire::encode_frame(&offer) {
+                    actions.push(OutboundAction::SendMessage(peer, bytes));
+                }
+            }
+            actions.extend(upload_action(&upload, upload_id, chunk_id, peer, self_id));
+        }
+        if upload.pending.is_empty() {
+            self.emit(CoreEvent::UploadComplete { upload_id });
+        } else {
+            self.uploads.insert(upload_id, upload);
+        }
+        (upload_id, actions)
+    }
+
+    /// The host finished an [`OutboundAction::UploadChunk`] for `peer` (this device for its own
+    /// ranges). Returns the [`Message::UploadAck`] for a peer, or follow-up actions for this
+    /// device's upload.
+    pub fn on_upload_chunk_done(
+        &mut self,
+        peer: DeviceId,
+        chunk_id: ChunkId,
+        ok: bool,
+    ) -> Vec<OutboundAction> {
+        if peer == self.keypair.device_id() {
+            return self.upload_acked(peer, chunk_id, ok);
+        }
+        let ack = Message::UploadAck {
+            upload_id: chunk_id.transfer_id,
+            start: chunk_id.start,
+            end: chunk_id.end,
+            ok,
+        };
+        match wire::encode_frame(&ack) {
+            Ok(bytes) => vec![OutboundAction::SendMessage(peer, bytes)],
+            Err(_) => vec![],
+        }
+    }
+
+    /// A peer's UploadData: hand a verified range of an offered upload to the host, otherwise
+    /// answer with a failed UploadAck.
+    fn on_upload_data(
+        &mut self,
+        peer_id: DeviceId,
+        chunk_id: ChunkId,
+        hash: [u8; 32],
+        payload: Vec<u8>,
+    ) -> Vec<OutboundAction> {
+        let now = self.tick_count;
+        let member = self.has_peer(&peer_id);
+        if let Some(offer) = self
+            .upload_offers
+            .get_mut(&(peer_id, chunk_id.transfer_id))
+            .filter(|_| member)
+        {
+            let valid = chunk_id.start < chunk_id.end
+                && chunk_id.end <= offer.total_length
+                && payload.len() as u64 == chunk_id.end - chunk_id.start
+                && crate::integrity::hash_chunk(&payload) == hash;
+            if valid {
+                offer.last_used = now;
+                return vec![OutboundAction::UploadChunk {
+                    peer: peer_id,
+                    chunk_id,
+                    url: offer.url.clone(),
+                    total_length: offer.total_length,
+                    payload,
+                }];
+            }
+        }
+        self.on_upload_chunk_done(peer_id, chunk_id, false)
+    }
+
+    /// `from` reports a range of one of this device's uploads. A peer's failure moves the range to
+    /// this device; this device's own failure ends the upload.
+    fn upload_acked(&mut self, from: DeviceId, chunk_id: ChunkId, ok: bool) -> Vec<OutboundAction> {
+        let self_id = self.keypair.device_id();
+        let upload_id = chunk_id.transfer_id;
+        let Some(upload) = self.uploads.get_mut(&upload_id) else {
+            return vec![];
+        };
+        if upload.pending.get(&chunk_id) != Some(&from) {
+            return vec![];
+        }
+        if ok {
+            upload.last_activity = self.tick_count;
+            upload.pending.remove(&chunk_id);
+            let done = upload.pending.is_empty();
+            if from != self_id {
+                self.record_outcome(from, true);
+            }
+            if done {
+                self.uploads.remove(&upload_id);
+                self.emit(CoreEvent::UploadComplete { upload_id });
+            }
+            return vec![];
+        }
+        if from == self_id {
+            self.uploads.remove(&upload_id);
+            self.emit(CoreEvent::UploadFailed { upload_id });
+            return vec![];
+        }
+        upload.pending.insert(chunk_id, self_id);
+        let action = upload_action(upload, upload_id, chunk_id, self_id, self_id);
+        self.record_outcome(from, false);
+        action.into_iter().collect()
+    }
+
+    /// Take back the upload ranges `peer_left` had not acknowledged; this device uploads them.
+    fn reclaim_uploads(&mut self, peer_left: DeviceId) -> Vec<OutboundAction> {
+        let self_id = self.keypair.device_id();
+        self.upload_offers.retain(|(peer, _), _| *peer != peer_left);
+        let mut actions = Vec::new();
+        for (&upload_id, upload) in self.uploads.iter_mut() {
+            let orphaned: Vec<ChunkId> = upload
+                .pending
+                .iter()
+                .filter(|(_, p)| **p == peer_left)
+                .map(|(c, _)| *c)
+                .collect();
+            for chunk_id in orphaned {
+                upload.pending.insert(chunk_id, self_id);
+                actions.extend(upload_action(upload, upload_id, chunk_id, self_id, self_id));
+            }
+        }
+        actions
+    }
+
+    /// Answer a peer's ChunkRequest: ChunkData from the cache or a [`OutboundAction::FetchChunk`]
+    /// (with the request's headers and deadline) while the peer is within its per-tick serve
+    /// quotas, otherwise (or without a URL to fetch from) a Nack so it reassigns. While
+    /// [`Config::contribution`] or do-not-disturb forbids serving, the answer is Busy (a Nack for
+    /// peers that do not understand it).
+    fn serve_request(
+        &mut self,
+        peer_id: DeviceId,
+        chunk_id: ChunkId,
+        url: Option<String>,
+        validator: Option<String>,
+        headers: Vec<(String, String)>,
+        deadline_ms: Option<u64>,
+    ) -> Option<OutboundAction> {
+        let key = url.map(|url| CacheKey {
+            url,
+            start: chunk_id.start,
+            end: chunk_id.end,
+            validator,
+        });
+        // A retry of a request already being fetched needs no second fetch or answer.
+        let waiter = (peer_id, chunk_id.transfer_id);
+        if let Some(key) = &key {
+            if self
+                .pending_serves
+                .get(key)
+                .is_some_and(|p| p.waiters.contains(&waiter))
+            {
+                return None;
+            }
+        }
+        let len = chunk_id.end.saturating_sub(chunk_id.start);
+        if !self.may_contribute(len)
+            && self
+                .capabilities_of(&peer_id)
+                .is_some_and(|c| c.supports(FEATURE_BUSY))
+        {
+            let busy = Message::Busy {
+                transfer_id: chunk_id.transfer_id,
+                start: chunk_id.start,
+                end: chunk_id.end,
+            };
+            let bytes = wire::encode_frame(&busy).ok()?;
+            return Some(OutboundAction::SendMessage(peer_id, bytes));
+        }
+        let mut max_requests = self.config.serve_requests_per_tick;
+        let max_bytes = self.config.serve_bytes_per_tick;
+        let credit = self.ledger.get(&peer_id).copied().unwrap_or_default();
+        let freeloading = credit.bytes_served.saturating_add(len)
+            > credit
+                .bytes_received
+                .saturating_add(self.config.freeloader_grace_bytes);
+        let mut declined = !self.may_contribute(len);
+        if freeloading {
+            match self.config.reciprocity {
+                ReciprocityPolicy::Off => {}
+                ReciprocityPolicy::Deprioritize => max_requests = 1,
+                ReciprocityPolicy::Decline => declined = true,
+            }
+        }
+        if let Some(cap) = self.self_metrics.constraints.max_contribution_bps {
+            let served: u64 = self.serve_usage.values().map(|u| u.1).sum();
+            declined |= served.saturating_add(len) > cap;
+        }
+        let usage = self.serve_usage.entry(peer_id).or_default();
+        let within_quota = !declined
+            && (max_requests == 0 || usage.0 < max_requests)
+            && (max_bytes == 0 || usage.1.saturating_add(len) <= max_bytes);
+        if let (Some(key), true) = (key, within_quota) {
+            usage.0 += 1;
+            usage.1 = usage.1.saturating_add(len);
+            self.served_today = self.served_today.saturating_add(len);
+            let credit = self.ledger.entry(peer_id).or_default();
+            credit.bytes_served = credit.bytes_served.saturating_add(len);
+            if let Some(payload) = self.cache.get(&key).map(<[u8]>::to_vec) {
+                let served = self.stats.bytes_served.entry(peer_id).or_default();
+                *served = served.saturating_add(payload.len() as u64);
+                let packed = self
+                    .accepts_compressed(peer_id)
+                    .then(|| chunk::compress_payload(&payload))
+                    .flatten();
+                let bytes =
+                    self.chunk_data_frame(peer_id, chunk_id, &payload, packed.as_deref())?;
+                return Some(OutboundAction::SendMessage(peer_id, bytes));
+            }
+            let url = key.url.clone();
+            let fresh = !self.pending_serves.contains_key(&key);
+            self.pending_serves
+                .entry(key)
+                .or_insert(PendingServe {
+                    waiters: Vec::new(),
+                    since: self.tick_count,
+                })
+                .waiters
+                .push(waiter);
+            // Someone else's request for the same range is already being fetched.
+            if !fresh {
+                return None;
+            }
+            return Some(OutboundAction::FetchChunk {
+                peer: peer_id,
+                chunk_id,
+                url,
+                headers,
+                deadline_ms,
+            });
+        }
+        let nack = Message::Nack {
+            transfer_id: chunk_id.transfer_id,
+            start: chunk_id.start,
+            end: chunk_id.end,
+        };
+        let bytes = wire::encode_frame(&nack).ok()?;
+        Some(OutboundAction::SendMessage(peer_id, bytes))
+    }
+
+    /// Reassign one chunk (e.g. after Nack or integrity failure). Returns ChunkRequest(s) to new peer(s).
+    fn reassign_single_chunk(&mut self, chunk_id: ChunkId) -> Vec<OutboundAction> {
+        if !self.move_chunk(chunk_id) {
+            return vec![];
+        }
+        self.request_chunk(chunk_id).into_iter().collect()
+    }
+
+    /// Assign `chunk_id` to the worker the scheduler picks among those other than its current peer
+    /// (by default the least loaded, see [`scheduler::least_loaded_worker`]), without requesting
+    /// it. Returns false if there is no other worker or the scheduler keeps the chunk in place.
+    fn move_chunk(&mut self, chunk_id: ChunkId) -> bool {
+        let Some(peer_left) = self
+            .transfers
+            .get(&chunk_id.transfer_id)
+            .and_then(|t| t.peer_for(chunk_id))
+        else {
+            return false;
+        };
+        let remaining: Vec<DeviceId> = self.workers().filter(|&p| p != peer_left).collect();
+        if remaining.is_empty() {
+            return false;
+        }
+        let metrics = self.worker_metrics(&remaining);
+        let owned = self.owned_uplinks(&remaining);
+        let uplinks: Vec<Option<&str>> = owned.iter().map(Option::as_deref).collect();
+        let outstanding_bytes = self.outstanding_bytes(&remaining);
+        let view = Workers {
+            ids: &remaining,
+            metrics: &metrics,
+            uplinks: &uplinks,
+            outstanding_bytes: &outstanding_bytes,
+        };
+        let Some(new_peer) = self.scheduler.reassign(chunk_id, &view) else {
+            return false;
+        };
+        let Some(t) = self.transfers.get_mut(&chunk_id.transfer_id) else {
+            return false;
+        };
+        t.assignment.retain(|(c, _)| *c != chunk_id);
+        t.assignment.push((chunk_id, new_peer));
+        true
+    }
+
+    /// Move window-queued chunks of active transfers to peers the scheduler picks (see
+    /// [`Scheduler::rebalance`]); `release_deferred` then requests them from their new peer.
+    fn steal_queued_work(&mut self) {
+        if !self.config.work_stealing {
+            return;
+        }
+        let self_id = self.keypair.device_id();
+        let peers: Vec<DeviceId> = self.workers().filter(|&p| p != self_id).collect();
+        let queued: Vec<(ChunkId, DeviceId)> = self
+            .transfers
+            .values()
+            .filter(|t| !t.paused)
+            .flat_map(|t| t.queued.values().filter_map(|&c| Some((c, t.peer_for(c)?))))
+            .collect();
+        if peers.len() < 2 || queued.is_empty() {
+            return;
+        }
+        let metrics = self.worker_metrics(&peers);
+        let owned = self.owned_uplinks(&peers);
+        let uplinks: Vec<Option<&str>> = owned.iter().map(Option::as_deref).collect();
+        let outstanding_bytes = self.outstanding_bytes(&peers);
+        let view = Workers {
+            ids: &peers,
+            metrics: &metrics,
+            uplinks: &uplinks,
+            outstanding_bytes: &outstanding_bytes,
+        };
+        for (chunk_id, new_peer) in self.scheduler.rebalance(&queued, &view) {
+            let Some(t) = self.transfers.get_mut(&chunk_id.transfer_id) else {
+                continue;
+            };
+            if !t.queued.contains_key(&chunk_id.start) || !peers.contains(&new_peer) {
+                continue;
+            }
+            t.assignment.retain(|(c, _)| *c != chunk_id);
+            t.assignment.push((chunk_id, new_peer));
+        }
+    }
+
+    /// Bytes requested from or queued for each of `workers` across all transfers.
+    fn outstanding_bytes(&self, workers: &[DeviceId]) -> Vec<u64> {
+        let mut bytes = vec![0; workers.len()];
+        for t in self.transfers.values() {
+            for &c in t.requested_at.keys().chain(t.queued.values()) {
+                let Some(peer) = t.peer_for(c) else {
+                    continue;
+                };
+                if let Some(i) = workers.iter().position(|&w| w == peer) {
+                    bytes[i] += c.end.saturating_sub(c.start);
+                }
+            }
+        }
+        bytes
+    }
+}
+
+impl Default for PeaPodCore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The action that gets `chunk_id` of `upload` uploaded by `peer`: an UploadChunk for this device,
+/// UploadData for a peer.
+fn upload_action(
+    upload: &ActiveUpload,
+    upload_id: [u8; 16],
+    chunk_id: ChunkId,
+    peer: DeviceId,
+    self_id: DeviceId,
+) -> Option<OutboundAction> {
+    let payload = upload
+        .body
+        .get(chunk_id.start as usize..chunk_id.end as usize)?
+        .to_vec();
+    if peer == self_id {
+        return Some(OutboundAction::UploadChunk {
+            peer,
+            chunk_id,
+            url: upload.url.clone(),
+            total_length: upload.body.len() as u64,
+            payload,
+        });
+    }
+    let data = Message::UploadData {
+        upload_id,
+        start: chunk_id.start,
+        end: chunk_id.end,
+        hash: crate::integrity::hash_chunk(&payload),
+        payload,
+    };
+    let bytes = wire::encode_frame(&data).ok()?;
+    Some(OutboundAction::SendMessage(peer, bytes))
+}
+
+/// Why a host's WAN range request failed (see [`PeaPodCore::on_chunk_fetch_failed`]).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FetchFailure {
+    /// The origin did not answer in time.
+    Timeout,
+    /// The origin answered with a non-success HTTP status.
+    Status(u16),
+    /// Connection or other I/O error.
+    Network,
+}
+
+/// Outcome of processing a received chunk: result and any outbound actions (e.g. reassign on failure).
+#[derive(Debug)]
+pub struct ChunkReceiveOutcome {
+    pub result: Result<Option<Vec<u8>>, CoreError>,
+    pub actions: Vec<OutboundAction>,
+}
+
+/// Core timers in milliseconds (see [`PeaPodCore::timer_intervals`]).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimerIntervals {
+    /// How often the host should call [`PeaPodCore::tick_at`].
+    pub tick_ms: u64,
+    /// Heartbeats are sent this often (`heartbeat_interval_ticks`).
+    pub heartbeat_ms: u64,
+    /// An outstanding chunk request is reassigned after this long.
+    pub chunk_timeout_ms: u64,
+    /// A peer that has not been heard from for this long is treated as left.
+    pub peer_timeout_ms: u64,
+    /// A transfer is aborted to fallback after this long (0 = no deadline).
+    pub transfer_deadline_ms: u64,
+}
+
+/// Result of `on_incoming_request`: accelerate (with chunk assignment) or fall back to normal path.
+pub enum Action {
+    /// Core produced a chunk plan; host fetches self chunks via WAN and sends ChunkRequest to peers.
+    /// Chunk ranges are absolute origin offsets (within the client's Range).
+    /// `assignment` lists the chunks to request now; if the memory budget holds some back, they are
+    /// released later as ChunkRequests from `tick` (see [`CoreEvent::Backpressure`]).
+    Accelerate {
+        transfer_id: [u8; 16],
+        total_length: u64,
+        assignment: Vec<(ChunkId, DeviceId)>,
+    },
+    /// Every chunk was in the chunk cache; host responds with `body` without any fetch.
+    Cached { body: Vec<u8> },
+    /// The same URL, range and validator is already being accelerated as `transfer_id`; the host
+    /// waits for that transfer's body (delivering it to every requester) instead of fetching again.
+    Attached {
+        transfer_id: [u8; 16],
+        total_length: u64,
+    },
+    /// Do not accelerate; host forwards the request normally.
+    Fallback,
+}
+
+/// State change in the core, queued for the host (see [`PeaPodCore::drain_events`]).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CoreEvent {
+    /// A new peer was added to the pod.
+    PeerJoined(DeviceId),
+    /// An authenticated peer asked to join while [`Config::require_approval`] is set; call
+    /// [`PeaPodCore::approve_peer`] to admit it.
+    JoinRequested(DeviceId),
+    /// Both devices' pairing nonces are in: [`PeaPodCore::pairing_code`] now returns the code to
+    /// show for this peer.
+    PairingCode(DeviceId),
+    /// Both users confirmed the pairing code for this peer.
+    PeerPaired(DeviceId),
+    /// A peer left (Leave, host notification, or heartbeat timeout).
+    PeerLeft(DeviceId),
+    /// A peer was dropped to make room for a newcomer (see [`Config::peer_eviction`]).
+    PeerEvicted(DeviceId),
+    /// A request is being accelerated.
+    TransferStarted {
+        transfer_id: [u8; 16],
+        total_length: u64,
+    },
+    /// A chunk passed verification and was stored.
+    ChunkVerified { chunk_id: ChunkId, from: DeviceId },
+    /// A chunk failed verification; `from` supplied the bad payload.
+    IntegrityFailure { chunk_id: ChunkId, from: DeviceId },
+    /// A chunk in a body that failed its manifest check had been attested by the peer that served
+    /// it; `attestation` pins the bad chunk on that peer.
+    AttestedCorruption {
+        chunk_id: ChunkId,
+        attestation: ChunkAttestation,
+    },
+    /// All chunks received; the reassembled body was returned to the host.
+    TransferComplete { transfer_id: [u8; 16] },
+    /// A request was not accelerated; the host forwards it normally.
+    Fallback { url: String },
+    /// The memory budget is exhausted; further ChunkRequests for the transfer are held back until
+    /// the host takes data (`take_segment`) or outstanding chunks arrive.
+    Backpressure {
+        transfer_id: [u8; 16],
+        buffered_bytes: u64,
+    },
+    /// Held-back ChunkRequests for the transfer have all been issued.
+    BackpressureReleased { transfer_id: [u8; 16] },
+    /// A peer reached `max_integrity_failures` and no longer receives chunks (see
+    /// [`PeaPodCore::peer_trust`]).
+    PeerIsolated(DeviceId),
+    /// A peer moved to a new key ([`Message::RotateKey`]); `current` inherits its pin, pairing
+    /// and integrity record, and `previous` is revoked after [`Config::key_rotation_grace_ticks`].
+    PeerKeyRotated {
+        previous: DeviceId,
+        current: DeviceId,
+    },
+    /// `peer` was revoked by a signed revocation from `issuer` (this device for
+    /// [`PeaPodCore::revoke_device`]); it is out of the pod and refused at handshake.
+    PeerRevoked { peer: DeviceId, issuer: DeviceId },
+    /// Two other sources agreed on a disputed chunk and `from` had claimed different content: it
+    /// sent a poisoned chunk on purpose (see [`Config::dispute_quorum`]).
+    ChunkPoisoned { chunk_id: ChunkId, from: DeviceId },
+    /// A chunk failed (timeout, integrity failure or Nack) after its last retry
+    /// ([`Config::chunk_retries`]); its transfer was aborted to fallback.
+    ChunkFailed { chunk_id: ChunkId, retries: u32 },
+    /// Every range of an upload started with [`PeaPodCore::start_upload`] was uploaded.
+    UploadComplete { upload_id: [u8; 16] },
+    /// This device could not upload a range; the upload was dropped and the host should upload
+    /// the body directly.
+    UploadFailed { upload_id: [u8; 16] },
+    /// A transfer's or upload's state was dropped: abandoned by the host
+    /// ([`PeaPodCore::abandon_transfer`]) or without progress for [`Config::stale_transfer_ticks`].
+    TransferReaped { transfer_id: [u8; 16] },
+    /// The host reported a failed WAN fetch for a chunk.
+    FetchFailed {
+        chunk_id: ChunkId,
+        reason: FetchFailure,
+    },
+}
+
+/// Instruction for the host: send a message to a peer (e.g. ChunkRequest, Heartbeat, Leave) or
+/// serve a peer's chunk.
+#[derive(Debug)]
+pub enum OutboundAction {
+    /// Send the given bytes to the peer over the local transport (host encrypts if required).
+    SendMessage(DeviceId, Vec<u8>),
+    /// Fetch `chunk_id` from `url` over WAN for `peer` (this device for its own chunks requested
+    /// after [`Action::Accelerate`], e.g. once the memory budget allows), then pass the bytes to
+    /// [`PeaPodCore::on_chunk_fetched_for_peer`]. On failure, call [`PeaPodCore::on_chunk_fetch_failed`].
+    /// `headers` (the requester's forwarded client headers, usually empty) go on the origin
+    /// request; `deadline_ms` is how long the requester waits, so a longer fetch is wasted.
+    FetchChunk {
+        peer: DeviceId,
+        chunk_id: ChunkId,
+        url: String,
+        headers: Vec<(String, String)>,
+        deadline_ms: Option<u64>,
+    },
+    /// The transfer missed its deadline and was dropped; the host should finish the request
+    /// directly from the origin instead of waiting for the reassembled body.
+    AbortToFallback([u8; 16]),
+    /// A transfer was completed by a chunk verified for another transfer fetching the same URL
+    /// range, or by a [`OutboundAction::FetchChunk`] for this device; the host delivers `body` as
+    /// if [`PeaPodCore::on_chunk_received`] had returned it.
+    TransferComplete {
+        transfer_id: [u8; 16],
+        body: Vec<u8>,
+    },
+    /// Too many of the transfer's chunks failed (see [`Config::fallback_failure_percent`]) and it
+    /// was dropped. `verified` holds the verified bytes not yet taken with
+    /// [`PeaPodCore::take_segment`] (offsets relative to `range.0`); the host fetches the rest of
+    /// the origin bytes `range.0..range.1` from `url` directly.
+    PartialFallback {
+        transfer_id: [u8; 16],
+        url: String,
+        range: (u64, u64),
+        verified: Vec<Segment>,
+    },
+    /// Upload `payload` as bytes `chunk_id.start..chunk_id.end` of a `total_length`-byte body to
+    /// `url` (e.g. a PUT with Content-Range) for `peer` (this device for its own ranges), then
+    /// report with [`PeaPodCore::on_upload_chunk_done`].
+    UploadChunk {
+        peer: DeviceId,
+        chunk_id: ChunkId,
+        url: String,
+        total_length: u64,
+        payload: Vec<u8>,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk::split_into_chunks;
+    use crate::integrity;
+
+    #[test]
+    fn integration_request_then_receive_chunks() {
+        let kp = Keypair::generate();
+        let mut core = PeaPodCore::with_keypair(kp);
+        let peer_id = Keypair::generate().device_id();
+        core.on_peer_joined(peer_id, &Keypair::generate().public_key().clone());
+
+        let range = (0u64, 99u64);
+        let total = 100u64;
+        let action = core.on_incoming_request("http://example.com/file", Some(range));
+        let transfer_id = match &action {
+            Action::Accelerate {
+                transfer_id,
+                total_length,
+                assignment: _,
+            } => {
+                assert_eq!(*total_length, total);
+                *transfer_id
+            }
+            _ => panic!("expected Accelerate"),
+        };
+
+        let chunk_ids = split_into_chunks(transfer_id, total, crate::chunk::DEFAULT_CHUNK_SIZE);
+        for &chunk_id in &chunk_ids {
+            let payload: Vec<u8> = (chunk_id.start..chunk_id.end).map(|j| j as u8).collect();
+            let hash = integrity::hash_chunk(&payload);
+            let r =
+                core.on_chunk_received(transfer_id, chunk_id.start, chunk_id.end, hash, payload);
+            if let Ok(Some(bytes)) = r {
+                assert_eq!(bytes.len(), 100);
+                for (j, &b) in bytes.iter().enumerate() {
+                    assert_eq!(b, j as u8);
+                }
+                return;
+            }
+        }
+        panic!("transfer should complete after receiving all chunks");
+    }
+
+    /// Decoded messages among `actions` (FetchChunk actions are skipped).
+    fn messages(actions: &[OutboundAction]) -> Vec<(DeviceId, Message)> {
+        actions
+            .iter()
+            .filter_map(|a| match a {
+                OutboundAction::SendMessage(peer, bytes) => {
+                    Some((*peer, wire::decode_frame(bytes).ok()?.0))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn chunk_requests(actions: &[OutboundAction]) -> Vec<(DeviceId, Message)> {
+        messages(actions)
+            .into_iter()
+            .filter(|(_, m)| matches!(m, Message::ChunkRequest { .. }))
+            .collect()
+    }
+
+    /// Chunks asked for in `actions`: ChunkRequests to peers and fetches for `self_id` itself.
+    fn requested(actions: &[OutboundAction], self_id: DeviceId) -> Vec<(DeviceId, ChunkId)> {
+        actions
+            .iter()
+            .filter_map(|a| match a {
+                OutboundAction::FetchChunk { peer, chunk_id, .. } if *peer == self_id => {
+                    Some((*peer, *chunk_id))
+                }
+                OutboundAction::SendMessage(peer, bytes) => {
+                    match wire::decode_frame(bytes).ok()?.0 {
+                        Message::ChunkRequest {
+                            transfer_id,
+                            start,
+                            end,
+                            ..
+                        } => Some((
+                            *peer,
+                            ChunkId {
+                                transfer_id,
+                                start,
+                                end,
+                            },
+                        )),
+                        _ => None,
+                    }
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn pause_suspends_timeouts_and_resume_rerequests_missing() {
+        let mut core = PeaPodCore::with_keypair(Keypair::generate());
+        let peer_id = Keypair::generate().device_id();
+        core.on_peer_joined(peer_id, &Keypair::generate().public_key().clone());
+        let total = 3 * crate::chunk::DEFAULT_CHUNK_SIZE;
+        let transfer_id =
+            match core.on_incoming_request("http://example.com/f", Some((0, total - 1))) {
+                Action::Accelerate { transfer_id, .. } => transfer_id,
+                _ => panic!("expected Accelerate"),
+            };
+        assert!(core.pause_transfer(transfer_id));
+
+        let first = split_into_chunks(transfer_id, total, crate::chunk::DEFAULT_CHUNK_SIZE)[0];
+        let payload = vec![7u8; (first.end - first.start) as usize];
+        let hash = integrity::hash_chunk(&payload);
+        let r = core.on_chunk_received(transfer_id, first.start, first.end, hash, payload);
+        assert!(
+            matches!(r, Ok(None)),
+            "chunks are still accepted while paused"
+        );
+
+        for _ in 0..(core.config().chunk_timeout_ticks + 2) {
+            core.on_heartbeat_received(peer_id);
+            assert!(chunk_requests(&core.tick()).is_empty());
+        }
+
+        let self_id = core.device_id();
+        let resumed = requested(&core.resume_transfer(transfer_id), self_id);
+        assert_eq!(
+            resumed.len(),
+            2,
+            "only the two missing chunks are re-requested"
+        );
+        assert!(resumed.iter().all(|(_, c)| c.start != 0));
+        assert!(core.resume_transfer(transfer_id).is_empty());
+    }
+
+    #[test]
+    fn transfer_progress_tracks_bytes_and_contributors() {
+        let kp = Keypair::generate();
+        let self_id = kp.device_id();
+        let mut core = PeaPodCore::with_keypair(kp);
+        let peer_id = Keypair::generate().device_id();
+        core.on_peer_joined(peer_id, &Keypair::generate().public_key().clone());
+        let size = crate::chunk::DEFAULT_CHUNK_SIZE;
+        let total = 3 * size;
+        let transfer_id =
+            match core.on_incoming_request("http://example.com/f", Some((0, total - 1))) {
+                Action::Accelerate { transfer_id, .. } => transfer_id,
+                _ => panic!("expected Accelerate"),
+            };
+        core.tick();
+        let chunks = split_into_chunks(transfer_id, total, size);
+
+        let payload = vec![1u8; size as usize];
+        let hash = integrity::hash_chunk(&payload);
+        let c = chunks[0];
+        core.on_chunk_received(transfer_id, c.start, c.end, hash, payload.clone())
+            .unwrap();
+        let c = chunks[1];
+        let frame = wire::encode_frame(&Message::ChunkData {
+            transfer_id,
+            start: c.start,
+            end: c.end,
+            hash,
+            payload,
+            compressed: false,
+            hash_algorithm: HashAlgorithm::Sha256,
+            attestation: None,
+        })
+        .unwrap();
+        core.on_message_received(peer_id, &frame).unwrap();
+
+        let p = core.transfer_progress(transfer_id).unwrap();
+        assert_eq!(p.total_length, total);
+        assert_eq!(p.bytes_received, 2 * size);
+        assert_eq!(p.chunks_total, 3);
+        assert_eq!(p.chunks_outstanding, 1);
+        assert_eq!(p.elapsed_ticks, 1);
+        assert_eq!(p.per_peer_bytes.len(), 2);
+        assert!(p.per_peer_bytes.contains(&(self_id, size)));
+        assert!(p.per_peer_bytes.contains(&(peer_id, size)));
+        assert!(core.transfer_progress([0u8; 16]).is_none());
+    }
+
+    #[test]
+    fn history_keeps_recent_finished_transfers() {
+        let mut core = PeaPodCore::with_config(Config {
+            chunk_size: 10,
+            adaptive_chunk_size: false,
+            endgame_chunks: 0,
+            history_len: 2,
+            ..Config::default()
+        });
+        let peer = Keypair::generate();
+        core.on_peer_joined(peer.device_id(), peer.public_key());
+        let self_id = core.device_id();
+        let accelerate =
+            |core: &mut PeaPodCore, url: &str| match core.on_incoming_request(url, Some((0, 19))) {
+                Action::Accelerate {
+                    transfer_id,
+                    assignment,
+                    ..
+                } => (transfer_id, assignment),
+                _ => panic!("expected Accelerate"),
+            };
+        let (abandoned, _) = accelerate(&mut core, "http://example.com/a");
+        core.abandon_transfer(abandoned);
+        let (aborted, _) = accelerate(&mut core, "http://example.com/b");
+        assert!(core.set_transfer_deadline(aborted, 1));
+        core.tick();
+        core.tick();
+        let (completed, assignment) = accelerate(&mut core, "http://example.com/c");
+        core.tick();
+        for (c, p) in assignment {
+            let payload = vec![1u8; 10];
+            let hash = integrity::hash_chunk(&payload);
+            if p == self_id {
+                core.on_chunk_received(completed, c.start, c.end, hash, payload)
+                    .unwrap();
+            } else {
+                let frame = wire::encode_frame(&Message::ChunkData {
+                    transfer_id: completed,
+                    start: c.start,
+                    end: c.end,
+                    hash,
+                    payload,
+                    compressed: false,
+                    hash_algorithm: HashAlgorithm::Sha256,
+                    attestation: None,
+                })
+                .unwrap();
+                core.on_message_received(p, &frame).unwrap();
+            }
+        }
+        let history = core.history();
+        // Only the two most recent are kept.
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].transfer_id, aborted);
+        assert_eq!(history[0].outcome, TransferOutcome::Fallback);
+        let done = &history[1];
+        assert_eq!(done.transfer_id, completed);
+        assert_eq!(done.url, "http://example.com/c");
+        assert_eq!(done.outcome, TransferOutcome::Completed);
+        assert_eq!((done.total_length, done.bytes_received), (20, 20));
+        assert_eq!(done.elapsed_ticks, 1);
+        assert_eq!(done.per_peer_bytes.iter().map(|&(_, b)| b).sum::<u64>(), 20);
+    }
+
+    #[test]
+    fn events_are_queued_and_drained() {
+        // A single bad copy of the only chunk must not end the transfer here.
+        let mut core = PeaPodCore::with_keypair_arc_and_config(
+            Arc::new(Keypair::generate()),
+            Config {
+                fallback_failure_percent: 0,
+                ..Config::default()
+            },
+        );
+        assert!(matches!(
+            core.on_incoming_request("http://example.com/f", Some((0, 99))),
+            Action::Fallback
+        ));
+        let peer_id = Keypair::generate().device_id();
+        core.on_peer_joined(peer_id, &Keypair::generate().public_key().clone());
+        core.on_peer_joined(peer_id, &Keypair::generate().public_key().clone());
+        let transfer_id = match core.on_incoming_request("http://example.com/f", Some((0, 99))) {
+            Action::Accelerate { transfer_id, .. } => transfer_id,
+            _ => panic!("expected Accelerate"),
+        };
+        let payload = vec![3u8; 100];
+        let bad = core.on_chunk_received(transfer_id, 0, 100, [0u8; 32], payload.clone());
+        assert!(bad.is_err());
+        let hash = integrity::hash_chunk(&payload);
+        core.on_chunk_received(transfer_id, 0, 100, hash, payload)
+            .unwrap();
+        core.on_peer_left(peer_id);
+
+        let chunk_id = ChunkId {
+            transfer_id,
+            start: 0,
+            end: 100,
+        };
+        let self_id = core.device_id();
+        assert_eq!(
+            core.drain_events(),
+            vec![
+                CoreEvent::Fallback {
+                    url: "http://example.com/f".to_string()
+                },
+                CoreEvent::PeerJoined(peer_id),
+                CoreEvent::TransferStarted {
+                    transfer_id,
+                    total_length: 100
+                },
+                CoreEvent::IntegrityFailure {
+                    chunk_id,
+                    from: self_id
+                },
+                CoreEvent::ChunkVerified {
+                    chunk_id,
+                    from: self_id
+                },
+                CoreEvent::TransferComplete { transfer_id },
+                CoreEvent::PeerLeft(peer_id),
+            ]
+        );
+        assert!(core.drain_events().is_empty());
+    }
+
+    #[test]
+    fn config_controls_chunk_size_timeouts_and_max_peers() {
+        let mut core = PeaPodCore::with_config(Config {
+            chunk_size: 10,
+            chunk_timeout_ticks: 2,
+            heartbeat_timeout_ticks: 1,
+            max_peers: 1,
+            ..Config::default()
+        });
+        let a = Keypair::generate();
+        let b = Keypair::generate();
+        assert!(core.on_peer_joined(a.device_id(), a.public_key()));
+        assert!(!core.on_peer_joined(b.device_id(), b.public_key()));
+        match core.on_incoming_request("http://example.com/f", Some((0, 99))) {
+            Action::Accelerate { transfer_id, .. } => assert_eq!(
+                core.transfer_progress(transfer_id).unwrap().chunks_total,
+                10
+            ),
+            _ => panic!("expected Accelerate"),
+        }
+        core.tick();
+        core.tick();
+        assert!(core
+            .drain_events()
+            .contains(&CoreEvent::PeerLeft(a.device_id())));
+    }
+
+    #[test]
+    fn full_pod_evicts_by_policy() {
+        let mut core = PeaPodCore::with_config(Config {
+            max_peers: 2,
+            peer_eviction: PeerEviction::LeastRecentlySeen,
+            ..Config::default()
+        });
+        let [a, b, c, d] = [(); 4].map(|_| Keypair::generate());
+        core.on_peer_joined(a.device_id(), a.public_key());
+        core.on_peer_joined(b.device_id(), b.public_key());
+        core.tick();
+        core.on_heartbeat_received(b.device_id());
+        core.drain_events();
+        assert!(core.on_peer_joined(c.device_id(), c.public_key()));
+        assert_eq!(
+            core.drain_events(),
+            vec![
+                CoreEvent::PeerEvicted(a.device_id()),
+                CoreEvent::PeerJoined(c.device_id())
+            ]
+        );
+        // Everyone left was heard from this tick.
+        assert!(!core.on_peer_joined(d.device_id(), d.public_key()));
+
+        let mut config = core.config().clone();
+        config.peer_eviction = PeerEviction::LowestScore;
+        core.update_config(config);
+        assert!(!core.on_peer_joined(d.device_id(), d.public_key()));
+        for _ in 0..3 {
+            core.record_outcome(c.device_id(), false);
+        }
+        assert!(core.on_peer_joined(d.device_id(), d.public_key()));
+        assert_eq!(core.membership(c.device_id()), Some(Membership::Left));
+    }
+
+    #[test]
+    fn trust_store_round_trips_and_merges_cautiously() {
+        let mut core = PeaPodCore::with_config(Config {
+            max_integrity_failures: 3,
+            ..Config::default()
+        });
+        let (a, b, c) = (
+            Keypair::generate(),
+            Keypair::generate(),
+            Keypair::generate(),
+        );
+        core.trust_peer(a.device_id(), a.public_key());
+        core.trust_peer(b.device_id(), b.public_key());
+        core.revoke_peer(c.device_id());
+        core.trust.record_failure(a.device_id());
+        let saved = core.export_trust();
+        assert_eq!(saved.known_peers.len(), 2);
+        assert_eq!(saved.revoked, vec![c.device_id()]);
+
+        // A fresh core takes the store as it is.
+        let mut restarted = PeaPodCore::new();
+        restarted.import_trust(saved.clone(), TrustMerge::Cautious);
+        assert_eq!(restarted.export_trust(), saved);
+
+        // Conflicts: the other side trusts c, revoked b and isolated a.
+        let mut other = PeaPodCore::new();
+        other.trust_peer(c.device_id(), c.public_key());
+        other.revoke_peer(b.device_id());
+        other.trust.merge(
+            a.device_id(),
+            PeerTrust {
+                integrity_failures: 1,
+                isolated: true,
+            },
+            true,
+        );
+        let theirs = other.export_trust();
+        let mut cautious = PeaPodCore::new();
+        cautious.import_trust(saved.clone(), TrustMerge::Cautious);
+        cautious.import_trust(theirs.clone(), TrustMerge::Cautious);
+        let merged = cautious.export_trust();
+        let known: Vec<DeviceId> = merged.known_peers.iter().map(|(id, _)| *id).collect();
+        assert_eq!(known, vec![a.device_id()]);
+        assert_eq!(merged.revoked.len(), 2);
+        assert!(cautious.peer_trust(a.device_id()).unwrap().isolated);
+        assert!(cautious
+            .drain_events()
+            .contains(&CoreEvent::PeerIsolated(a.device_id())));
+
+        let mut replaced = PeaPodCore::new();
+        replaced.import_trust(saved, TrustMerge::Cautious);
+        replaced.import_trust(theirs, TrustMerge::Replace);
+        assert!(replaced
+            .known_peers()
+            .iter()
+            .any(|(id, _)| *id == c.device_id()));
+        assert!(replaced.check_pin(b.device_id(), b.public_key()).is_err());
+    }
+
+    #[test]
+    fn known_peers_pin_keys_and_revoke() {
+        let mut core = PeaPodCore::new();
+        let a = Keypair::generate();
+        let mallory = Keypair::generate();
+        assert!(matches!(
+            core.check_handshake(a.device_id(), mallory.public_key()),
+            Err(CoreError::Handshake {
+                source: HandshakeError::IdentityMismatch,
+                ..
+            })
+        ));
+        assert!(core.on_peer_joined(a.device_id(), a.public_key()));
+        let saved = core.known_peers();
+        assert_eq!(saved, vec![(a.device_id(), a.public_key().clone())]);
+
+        // A restarted core imports the pins; a forged or changed key for a known ID is refused.
+        let mut restarted = PeaPodCore::new();
+        let forged = (a.device_id(), mallory.public_key().clone());
+        assert_eq!(
+            restarted.import_known_peers(saved.into_iter().chain([forged])),
+            1
+        );
+        assert!(restarted
+            .check_handshake(a.device_id(), a.public_key())
+            .is_ok());
+        assert!(!restarted.on_peer_joined(a.device_id(), mallory.public_key()));
+
+        restarted.on_peer_joined(a.device_id(), a.public_key());
+        restarted.revoke_peer(a.device_id());
+        assert_eq!(restarted.membership(a.device_id()), Some(Membership::Left));
+        assert!(restarted.known_peers().is_empty());
+        assert!(matches!(
+            restarted.check_handshake(a.device_id(), a.public_key()),
+            Err(CoreError::Handshake {
+                source: HandshakeError::Revoked,
+                ..
+            })
+        ));
+        assert!(!restarted.on_peer_joined(a.device_id(), a.public_key()));
+        assert!(restarted.trust_peer(a.device_id(), a.public_key()));
+        assert!(restarted.on_peer_joined(a.device_id(), a.public_key()));
+    }
+
+    #[test]
+    fn rotated_keys_overlap_then_retire_the_old_identity() {
+        let old_keys = Arc::new(Keypair::generate());
+        let new_keys = Arc::new(Keypair::generate());
+        let b_keys = Arc::new(Keypair::generate());
+        let (old_id, new_id) = (old_keys.device_id(), new_keys.device_id());
+        let mut a = PeaPodCore::with_keypair_arc(old_keys.clone());
+        let mut b = PeaPodCore::with_keypair_arc_and_config(
+            b_keys.clone(),
+            Config {
+                key_rotation_grace_ticks: 3,
+                ..Config::default()
+            },
+        );
+        a.on_peer_joined(b_keys.device_id(), b_keys.public_key());
+        b.on_peer_joined(old_id, old_keys.public_key());
+        b.drain_events();
+
+        // A frame for another peer, or from a stranger, proves nothing.
+        let mallory = Keypair::generate();
+        let (old_proof, new_proof) =
+            mallory.rotation_proofs(new_keys.as_ref(), b_keys.public_key());
+        let forged = wire::encode_frame(&Message::RotateKey {
+            new_public_key: new_keys.public_key().clone(),
+            old_proof,
+            new_proof,
+        })
+        .unwrap();
+        assert!(matches!(
+            b.on_message_received(old_id, &forged),
+            Err(CoreError::Message {
+                source: OnMessageError::RotationRejected,
+                ..
+            })
+        ));
+
+        let rotate = match &a.rotate_keypair(new_keys.clone())[..] {
+            [OutboundAction::SendMessage(_, bytes)] => bytes.clone(),
+            _ => panic!("expected one RotateKey"),
+        };
+        assert_eq!(a.device_id(), new_id);
+        b.on_message_received(old_id, &rotate).unwrap();
+        assert_eq!(
+            b.drain_events(),
+            vec![CoreEvent::PeerKeyRotated {
+                previous: old_id,
+                current: new_id,
+            }]
+        );
+        assert_eq!(b.previous_identity(new_id), Some(old_id));
+        assert!(b.check_handshake(old_id, old_keys.public_key()).is_ok());
+        assert!(b.check_handshake(new_id, new_keys.public_key()).is_ok());
+        assert_eq!(
+            b.export_trust().rotations,
+            vec![KeyRotation {
+                previous: old_id,
+                current: new_id,
+                remaining_ticks: 3,
+            }]
+        );
+
+        for _ in 0..3 {
+            b.tick();
+        }
+        assert_eq!(b.previous_identity(new_id), None);
+        assert!(b.check_handshake(new_id, new_keys.public_key()).is_ok());
+        assert!(matches!(
+            b.check_handshake(old_id, old_keys.public_key()),
+            Err(CoreError::Handshake {
+                source: HandshakeError::Revoked,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn signed_revocations_spread_to_paired_devices() {
+        let (a_keys, b_keys, lost) = (
+            Arc::new(Keypair::generate()),
+            Arc::new(Keypair::generate()),
+            Keypair::generate(),
+        );
+        let (a_id, b_id, lost_id) = (a_keys.device_id(), b_keys.device_id(), lost.device_id());
+        let mut a = PeaPodCore::with_keypair_arc(a_keys.clone());
+        let mut b = PeaPodCore::with_keypair_arc(b_keys.clone());
+        a.on_peer_joined(b_id, b_keys.public_key());
+        a.on_peer_joined(lost_id, lost.public_key());
+        b.on_peer_joined(a_id, a_keys.public_key());
+        b.on_peer_joined(lost_id, lost.public_key());
+        b.import_trust(
+            TrustStore {
+                paired: vec![a_id],
+                ..TrustStore::default()
+            },
+            TrustMerge::Cautious,
+        );
+        b.drain_events();
+
+        let revoke = match &a.revoke_device(lost_id)[..] {
+            [OutboundAction::SendMessage(to, bytes)] if *to == b_id => bytes.clone(),
+            other => panic!("expected one Revoke to b, got {:?}", other),
+        };
+        assert!(a.revoke_device(lost_id).is_empty());
+        let (forwarded, _) = b.on_message_received(a_id, &revoke).unwrap();
+        assert!(forwarded.is_empty());
+        assert!(b.drain_events().contains(&CoreEvent::PeerRevoked {
+            peer: lost_id,
+            issuer: a_id,
+        }));
+        assert_eq!(b.membership(lost_id), Some(Membership::Left));
+        assert!(matches!(
+            b.check_handshake(lost_id, lost.public_key()),
+            Err(CoreError::Handshake {
+                source: HandshakeError::Revoked,
+                ..
+            })
+        ));
+        assert_eq!(b.revocations(), a.revocations());
+
+        // An unpaired issuer, a revoked one, or a forged record revokes nothing.
+        let mut c = PeaPodCore::new();
+        c.on_peer_joined(lost_id, lost.public_key());
+        let rejected = |core: &mut PeaPodCore, from, frame: &[u8]| {
+            matches!(
+                core.on_message_received(from, frame),
+                Err(CoreError::Message {
+                    source: OnMessageError::RevocationRejected,
+                    ..
+                })
+            )
+        };
+        assert!(rejected(&mut c, a_id, &revoke));
+        assert_eq!(c.membership(lost_id), Some(Membership::Joined));
+        let frame = |revocation| wire::encode_frame(&Message::Revoke { revocation }).unwrap();
+        assert!(rejected(&mut b, lost_id, &frame(lost.revoke(a_id))));
+        let mut forged = a_keys.revoke(b_id);
+        forged.revoked = lost_id;
+        assert!(rejected(&mut b, a_id, &frame(forged)));
+
+        // A device may revoke its own key; anyone accepts that.
+        let (actions, _) = c
+            .on_message_received(lost_id, &frame(lost.revoke(lost_id)))
+            .unwrap();
+        assert!(actions.is_empty());
+        assert_eq!(c.membership(lost_id), Some(Membership::Left));
+
+        // Persisted records revoke on import, and trusting the device again drops the record.
+        let mut restarted = PeaPodCore::new();
+        restarted.import_trust(b.export_trust(), TrustMerge::Cautious);
+        assert_eq!(restarted.revocations(), a.revocations());
+        assert!(restarted
+            .check_handshake(lost_id, lost.public_key())
+            .is_err());
+        assert!(restarted.trust_peer(lost_id, lost.public_key()));
+        assert!(restarted.revocations().is_empty());
+    }
+
+    #[test]
+    fn device_names_are_signed_and_bound_to_the_key() {
+        let ana_keys = Arc::new(Keypair::generate());
+        let ana_id = ana_keys.device_id();
+        let ana = PeaPodCore::with_keypair_arc_and_config(
+            ana_keys.clone(),
+            Config {
+                device_name: Some("Ana's laptop\n".into()),
+                ..Config::default()
+            },
+        );
+        let record = ana.name_record().unwrap();
+        assert_eq!(record.name, "Ana's laptop");
+        let b_keys = Arc::new(Keypair::generate());
+        let mut b = PeaPodCore::with_keypair_arc(b_keys.clone());
+        let join = wire::encode_frame(&ana.join_message(b_keys.public_key(), false)).unwrap();
+        b.on_message_received(ana_id, &join).unwrap();
+        assert_eq!(b.peer_name(ana_id), Some("Ana's laptop"));
+
+        // Mallory reusing the name: Ana's record does not verify for Mallory's key, and Mallory's
+        // own record for it is refused while Ana is pinned.
+        let mallory = Keypair::generate();
+        let m_id = mallory.device_id();
+        assert!(!b.on_peer_named(m_id, mallory.public_key(), &record));
+        let forged = mallory.name_record("ana's LAPTOP").unwrap();
+        assert!(!b.on_peer_named(ana_id, ana_keys.public_key(), &forged));
+        assert!(!b.on_peer_named(m_id, mallory.public_key(), &forged));
+        assert_eq!(b.peer_name(m_id), None);
+        assert!(b.on_peer_named(
+            m_id,
+            mallory.public_key(),
+            &mallory.name_record("Mallory").unwrap()
+        ));
+
+        // Discovery carries the record in a frame of its own after the Beacon, which older builds
+        // ignore.
+        let beacon = ana.beacon_frame(45679).unwrap();
+        let (_, used) = wire::decode_frame(&beacon).unwrap();
+        assert!(matches!(
+            wire::decode_frame(&beacon[used..]).unwrap().0,
+            Message::DiscoveryName { name } if name == record
+        ));
+        let (msg, name) = b.open_discovery_frame(&beacon).unwrap();
+        assert!(matches!(msg, Message::Beacon { device_id, .. } if device_id == ana_id));
+        assert_eq!(name, Some(record.clone()));
+        assert_eq!(b.open_discovery_frame(&beacon[..used]).unwrap().1, None);
+
+        // A join carrying someone else's record is refused outright.
+        let mut c = PeaPodCore::new();
+        let mut request = ana.join_message(c.keypair.public_key(), false);
+        if let Message::JoinRequest { name, .. } = &mut request {
+            *name = Some(forged);
+        }
+        assert!(matches!(
+            c.on_message_received(ana_id, &wire::encode_frame(&request).unwrap()),
+            Err(CoreError::Message {
+                source: OnMessageError::JoinRejected,
+                ..
+            })
+        ));
+        assert_eq!(c.peer_name(ana_id), None);
+        assert_eq!(
+            ana_keys.name_record(&"x".repeat(100)).unwrap().name.len(),
+            64
+        );
+        assert!(ana_keys.name_record(" \t").is_none());
+    }
+
+    #[test]
+    fn peers_expose_public_key_last_seen_and_metrics() {
+        let mut core = PeaPodCore::new();
+        let a = Keypair::generate();
+        let b = Keypair::generate();
+        core.on_peer_joined(a.device_id(), a.public_key());
+        core.tick();
+        core.on_heartbeat_received(a.device_id());
+        core.set_peer_metrics(
+            a.device_id(),
+            PeerMetrics {
+                bandwidth_bytes_per_sec: Some(1000),
+                ..PeerMetrics::default()
+            },
+        );
+        // The first key is pinned: a re-join with another key is refused.
+        assert!(!core.on_peer_joined(a.device_id(), b.public_key()));
+        assert!(core.on_peer_joined(a.device_id(), a.public_key()));
+        let peers = core.peers();
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].device_id, a.device_id());
+        assert_eq!(&peers[0].public_key, a.public_key());
+        assert_eq!(peers[0].last_seen, 1);
+        assert_eq!(peers[0].metrics.bandwidth_bytes_per_sec, Some(1000));
+        core.on_peer_left(a.device_id());
+        assert!(core.peers().is_empty());
+    }
+
+    #[test]
+    fn membership_waits_for_host_approval() {
+        let a_keys = Arc::new(Keypair::generate());
+        let b_keys = Arc::new(Keypair::generate());
+        let (a_id, b_id) = (a_keys.device_id(), b_keys.device_id());
+        let mut a = PeaPodCore::with_keypair_arc(a_keys.clone());
+        let mut b = PeaPodCore::with_keypair_arc_and_config(
+            b_keys.clone(),
+            Config {
+                require_approval: true,
+                ..Config::default()
+            },
+        );
+        let frame = |actions: Vec<OutboundAction>| match &actions[..] {
+            [OutboundAction::SendMessage(_, bytes)] => bytes.clone(),
+            _ => panic!("expected one message"),
+        };
+
+        let request = frame(skip_greeting(
+            a.on_peer_connected(b_id, b_keys.public_key()),
+        ));
+        assert_eq!(a.membership(b_id), Some(Membership::Invited));
+        assert!(skip_greeting(b.on_peer_connected(a_id, a_keys.public_key())).is_empty());
+        assert_eq!(b.membership(a_id), Some(Membership::Discovered));
+
+        let (actions, _) = b.on_message_received(a_id, &request).unwrap();
+        assert!(actions.is_empty());
+        assert_eq!(b.membership(a_id), Some(Membership::Discovered));
+        assert_eq!(b.drain_events(), vec![CoreEvent::JoinRequested(a_id)]);
+
+        let accept = frame(b.approve_peer(a_id));
+        assert_eq!(b.membership(a_id), Some(Membership::Joined));
+        a.on_message_received(b_id, &accept).unwrap();
+        assert_eq!(a.membership(b_id), Some(Membership::Joined));
+
+        // An accept nobody asked for admits nothing.
+        let mut c = PeaPodCore::new();
+        let unsolicited =
+            wire::encode_frame(&b.join_message(c.keypair.public_key(), true)).unwrap();
+        c.on_message_received(b_id, &unsolicited).unwrap();
+        assert_eq!(c.membership(b_id), None);
+
+        a.on_peer_left(b_id);
+        assert_eq!(a.membership(b_id), Some(Membership::Left));
+    }
+
+    /// Deliver `actions` from `from` to `to`, then the replies back, until nothing is left.
+    /// Join a peer that advertises every feature this build supports.
+    fn join_capable(core: &mut PeaPodCore, peer_id: DeviceId, public_key: &PublicKey) {
+        assert!(core.on_peer_joined(peer_id, public_key));
+        core.peer_mut(&peer_id).unwrap().capabilities.features = SUPPORTED_FEATURES;
+    }
+
+    /// `actions` without the Hello, Capabilities and PairCommit that open each connection.
+    fn skip_greeting(actions: Vec<OutboundAction>) -> Vec<OutboundAction> {
+        actions
+            .into_iter()
+            .filter(|a| {
+                !matches!(a, OutboundAction::SendMessage(_, f)
+                if matches!(
+                    wire::decode_frame(f),
+                    Ok((
+                        Message::Hello { .. }
+                            | Message::Capabilities { .. }
+                            | Message::PairCommit { .. },
+                        _
+                    ))
+                ))
+            })
+            .collect()
+    }
+
+    /// Feed the messages in `actions` from `from` to `to`, returning its replies.
+    fn deliver(
+        actions: Vec<OutboundAction>,
+        from: DeviceId,
+        to: &mut PeaPodCore,
+    ) -> Vec<OutboundAction> {
+        let mut replies = Vec::new();
+        for action in actions {
+            if let OutboundAction::SendMessage(_, bytes) = action {
+                replies.extend(to.on_message_received(from, &bytes).unwrap().0);
+            }
+        }
+        replies
+    }
+
+    /// Connect two devices as a transport would: what each side sends when the connection comes
+    /// up arrives before anything the other sends in reply.
+    fn connect(a: &mut PeaPodCore, b: &mut PeaPodCore) {
+        let (a_id, b_id) = (a.device_id(), b.device_id());
+        let (a_public, b_public) = (
+            a.keypair.public_key().clone(),
+            b.keypair.public_key().clone(),
+        );
+        let from_a = a.on_peer_connected(b_id, &b_public);
+        let from_b = b.on_peer_connected(a_id, &a_public);
+        let replies_b = deliver(from_a, a_id, b);
+        let replies_a = deliver(from_b, b_id, a);
+        pump(replies_b, (b, b_id), (a, a_id));
+        pump(replies_a, (a, a_id), (b, b_id));
+    }
+
+    fn pump<'a>(
+        mut actions: Vec<OutboundAction>,
+        mut from: (&'a mut PeaPodCore, DeviceId),
+        mut to: (&'a mut PeaPodCore, DeviceId),
+    ) {
+        while !actions.is_empty() {
+            actions = deliver(actions, from.1, to.0);
+            std::mem::swap(&mut from, &mut to);
+        }
+    }
+
+    /// Connect two devices and have both users confirm the pairing code.
+    fn pair(a: &mut PeaPodCore, b: &mut PeaPodCore) {
+        let (a_id, b_id) = (a.device_id(), b.device_id());
+        connect(a, b);
+        let actions = a.confirm_pairing(b_id);
+        pump(actions, (a, a_id), (b, b_id));
+        let actions = b.confirm_pairing(a_id);
+        pump(actions, (b, b_id), (a, a_id));
+        assert!(a.is_paired(b_id) && b.is_paired(a_id));
+    }
+
+    #[test]
+    fn pod_key_seals_discovery_from_devices_outside_the_pod() {
+        let config = Config {
+            require_pairing: true,
+            ..Config::default()
+        };
+        let a_keys = Arc::new(Keypair::generate());
+        let mut a = PeaPodCore::with_keypair_arc_and_config(a_keys.clone(), config.clone());
+        let mut b =
+            PeaPodCore::with_keypair_arc_and_config(Arc::new(Keypair::generate()), config.clone());
+        let stranger = PeaPodCore::new();
+        let sealed = |frame: &[u8]| {
+            matches!(
+                wire::decode_frame(frame).unwrap().0,
+                Message::SealedDiscovery { .. }
+            )
+        };
+        let sender = |opened: Option<(Message, Option<NameRecord>)>| match opened {
+            Some((Message::Beacon { device_id, .. }, _))
+            | Some((Message::DiscoveryResponse { device_id, .. }, _)) => Some(device_id),
+            _ => None,
+        };
+
+        // Unpaired devices beacon in the clear, so they can be found and paired.
+        let beacon = a.beacon_frame(45679).unwrap();
+        assert!(!sealed(&beacon));
+        assert_eq!(
+            sender(stranger.open_discovery_frame(&beacon)),
+            Some(a.device_id())
+        );
+
+        // Pairing derives the same key on both sides; only pod members read the beacons.
+        pair(&mut a, &mut b);
+        assert!(a.pod_key.is_some() && a.pod_key == b.pod_key);
+        let beacon = a.beacon_frame(45679).unwrap();
+        assert!(sealed(&beacon));
+        assert_eq!(sender(b.open_discovery_frame(&beacon)), Some(a.device_id()));
+        assert!(stranger.open_discovery_frame(&beacon).is_none());
+        // The name frame after it is sealed too.
+        a.config.device_name = Some("kitchen".into());
+        let beacon = a.beacon_frame(45679).unwrap();
+        let (_, used) = wire::decode_frame(&beacon).unwrap();
+        assert!(sealed(&beacon[used..]));
+        assert_eq!(b.open_discovery_frame(&beacon).unwrap().1, a.name_record());
+        assert!(stranger.open_discovery_frame(&beacon).is_none());
+        let response = b.discovery_response_frame(45679).unwrap();
+        assert_eq!(
+            sender(a.open_discovery_frame(&response)),
+            Some(b.device_id())
+        );
+
+        // A device paired later receives the pod's key rather than deriving a new one.
+        let mut d = PeaPodCore::with_keypair_arc_and_config(Arc::new(Keypair::generate()), config);
+        let d_id = d.device_id();
+        pair(&mut a, &mut d);
+        assert_eq!(d.pod_key, a.pod_key);
+        let old_beacon = d.beacon_frame(45679).unwrap();
+        assert_eq!(sender(b.open_discovery_frame(&old_beacon)), Some(d_id));
+
+        // Revoking a paired device moves the pod to a new key it does not hold.
+        let b_id = b.device_id();
+        let actions = a.revoke_device(d_id);
+        pump(actions, (&mut a, a_keys.device_id()), (&mut b, b_id));
+        assert_eq!(a.pod_key.as_ref().map(|k| k.epoch), Some(1));
+        assert_eq!(a.pod_key, b.pod_key);
+        let beacon = a.beacon_frame(45679).unwrap();
+        assert_eq!(sender(b.open_discovery_frame(&beacon)), Some(a.device_id()));
+        assert!(d.open_discovery_frame(&beacon).is_none());
+        // Frames under the previous key still open, so stragglers can be brought up to date.
+        assert_eq!(sender(b.open_discovery_frame(&old_beacon)), Some(d_id));
+
+        // The key survives a restart through the trust store, sealed for this device only.
+        let store = a.export_trust();
+        let mut restarted = PeaPodCore::with_keypair_arc(a_keys);
+        restarted.import_trust(store.clone(), TrustMerge::Cautious);
+        assert_eq!(restarted.pod_key, a.pod_key);
+        let mut other = PeaPodCore::new();
+        other.import_trust(store, TrustMerge::Cautious);
+        assert_eq!(other.pod_key, None);
+    }
+
+    #[test]
+    fn paired_devices_ignore_discovery_from_other_pods() {
+        let config = Config {
+            require_pairing: true,
+            confidential_discovery: false,
+            ..Config::default()
+        };
+        let device = || {
+            PeaPodCore::with_keypair_arc_and_config(Arc::new(Keypair::generate()), config.clone())
+        };
+        let (mut a, mut b, mut c, mut e) = (device(), device(), device(), device());
+        let stranger = PeaPodCore::new();
+        let sender = |opened: Option<(Message, Option<NameRecord>)>| match opened {
+            Some((Message::Beacon { device_id, .. }, _))
+            | Some((Message::DiscoveryResponse { device_id, .. }, _)) => Some(device_id),
+            _ => None,
+        };
+        assert_eq!(a.pod_id(), None);
+
+        // Two households on one LAN, each paired into its own pod.
+        pair(&mut a, &mut b);
+        pair(&mut c, &mut e);
+        assert!(a.pod_id().is_some() && a.pod_id() == b.pod_id());
+        assert_ne!(a.pod_id(), c.pod_id());
+        let beacon = a.beacon_frame(45679).unwrap();
+        let (_, used) = wire::decode_frame(&beacon).unwrap();
+        assert!(matches!(
+            wire::decode_frame(&beacon[used..]).unwrap().0,
+            Message::DiscoveryPod { pod_id } if Some(pod_id) == a.pod_id()
+        ));
+        assert_eq!(sender(b.open_discovery_frame(&beacon)), Some(a.device_id()));
+        assert!(c.open_discovery_frame(&beacon).is_none());
+        let response = c.discovery_response_frame(45679).unwrap();
+        assert!(a.open_discovery_frame(&response).is_none());
+        assert_eq!(
+            sender(e.open_discovery_frame(&response)),
+            Some(c.device_id())
+        );
+
+        // Devices outside any pod still see and are seen by everyone, so they can be paired.
+        assert_eq!(
+            sender(stranger.open_discovery_frame(&beacon)),
+            Some(a.device_id())
+        );
+        let lone = stranger.beacon_frame(45679).unwrap();
+        assert_eq!(
+            sender(a.open_discovery_frame(&lone)),
+            Some(stranger.device_id())
+        );
+
+        // Builds without pod IDs send the Beacon alone; it is let through like an unpaired one.
+        assert_eq!(
+            sender(c.open_discovery_frame(&beacon[..used])),
+            Some(a.device_id())
+        );
+
+        // An open pod hears beacons from every pod.
+        c.config.open_pod = true;
+        assert_eq!(sender(c.open_discovery_frame(&beacon)), Some(a.device_id()));
+    }
+
+    #[test]
+    fn pairing_codes_gate_membership() {
+        let config = Config {
+            require_pairing: true,
+            ..Config::default()
+        };
+        let (a_keys, b_keys) = (Arc::new(Keypair::generate()), Arc::new(Keypair::generate()));
+        let (a_id, b_id) = (a_keys.device_id(), b_keys.device_id());
+        let mut a = PeaPodCore::with_keypair_arc_and_config(a_keys.clone(), config.clone());
+        let mut b = PeaPodCore::with_keypair_arc_and_config(b_keys.clone(), config);
+        let from_a = a.on_peer_connected(b_id, b_keys.public_key());
+        let from_b = b.on_peer_connected(a_id, a_keys.public_key());
+        // Only commitments go out at first: no code until both nonces are revealed.
+        let sends = |actions: &[OutboundAction], kind| {
+            actions.iter().any(|action| {
+                matches!(action, OutboundAction::SendMessage(_, f)
+                    if wire::peek_frame(f).is_ok_and(|h| h.message_type() == Some(kind)))
+            })
+        };
+        assert!(sends(&from_a, protocol::MessageType::PairCommit));
+        assert!(!sends(&from_a, protocol::MessageType::PairNonce));
+        assert_eq!(a.pairing_code(b_id), None);
+        let replies_b = deliver(from_a, a_id, &mut b);
+        let replies_a = deliver(from_b, b_id, &mut a);
+        assert_eq!(a.pairing_code(b_id), None);
+        pump(replies_b, (&mut b, b_id), (&mut a, a_id));
+        pump(replies_a, (&mut a, a_id), (&mut b, b_id));
+        let code = a.pairing_code(b_id).unwrap();
+        assert_eq!(b.pairing_code(a_id), Some(code.clone()));
+        assert!(a.drain_events().contains(&CoreEvent::PairingCode(b_id)));
+        assert!(a.approve_peer(b_id).is_empty());
+
+        // A's user confirms first; the pairing is not complete until B's user does too. The code
+        // only confirms the device it was shown for.
+        let b_fingerprint = a.peer_fingerprint(b_id).unwrap();
+        let a_fingerprint = b.peer_fingerprint(a_id).unwrap();
+        assert!(a
+            .confirm_pairing_code(&a_fingerprint.to_words(), &code)
+            .is_empty());
+        let actions = a.confirm_pairing_code(&b_fingerprint.to_words(), &code);
+        pump(actions, (&mut a, a_id), (&mut b, b_id));
+        assert!(!a.is_paired(b_id) && !b.is_paired(a_id));
+        let actions = b.confirm_pairing(a_id);
+        pump(actions, (&mut b, b_id), (&mut a, a_id));
+        assert!(a.is_paired(b_id) && b.is_paired(a_id));
+        assert_eq!(a.membership(b_id), Some(Membership::Joined));
+        assert_eq!(b.membership(a_id), Some(Membership::Joined));
+
+        // A confirmation for someone else's code is rejected, and so is a nonce that was not
+        // committed to first or does not match its commitment.
+        let c_keys = Keypair::generate();
+        let c_id = c_keys.device_id();
+        let mut c = PeaPodCore::with_keypair(Keypair::generate());
+        c.on_peer_connected(c_id, c_keys.public_key());
+        let rejected = |result| {
+            matches!(
+                result,
+                Err(CoreError::Message {
+                    source: OnMessageError::PairingRejected,
+                    ..
+                })
+            )
+        };
+        let forged = wire::encode_frame(&Message::PairConfirm {
+            confirmation: crate::pairing::confirmation(&c_keys, a_keys.public_key(), 0),
+            pod_key: None,
+        })
+        .unwrap();
+        assert!(rejected(c.on_message_received(c_id, &forged)));
+        let nonce = crate::pairing::pairing_nonce();
+        let reveal = wire::encode_frame(&Message::PairNonce { nonce }).unwrap();
+        assert!(rejected(c.on_message_received(c_id, &reveal)));
+        let commit = wire::encode_frame(&Message::PairCommit {
+            commitment: crate::pairing::nonce_commitment(c_keys.public_key(), &[0; 32]),
+        })
+        .unwrap();
+        let (answer, _) = c.on_message_received(c_id, &commit).unwrap();
+        assert!(matches!(
+            &answer[..],
+            [OutboundAction::SendMessage(_, f)]
+                if matches!(wire::decode_frame(f), Ok((Message::PairNonce { .. }, _)))
+        ));
+        assert!(rejected(c.on_message_received(c_id, &reveal)));
+        assert_eq!(c.pairing_code(c_id), None);
+
+        // A revealed nonce is never reused: a new commitment gets a new exchange.
+        let (answer, _) = c.on_message_received(c_id, &commit).unwrap();
+        let kinds: Vec<_> = answer
+            .iter()
+            .filter_map(|action| match action {
+                OutboundAction::SendMessage(_, f) => wire::decode_frame(f).ok(),
+                _ => None,
+            })
+            .map(|(msg, _)| msg.message_type())
+            .collect();
+        assert_eq!(
+            kinds,
+            [
+                protocol::MessageType::PairCommit,
+                protocol::MessageType::PairNonce
+            ]
+        );
+    }
+
+    #[test]
+    fn join_is_authenticated() {
+        let mut core = PeaPodCore::new();
+        let core_pk = core.keypair.public_key().clone();
+        let peer = PeaPodCore::new();
+        let frame = peer.join_frame(&core_pk).unwrap();
+        // Replayed from a different connection: rejected.
+        let other = Keypair::generate();
+        assert!(matches!(
+            core.on_message_received(other.device_id(), &frame),
+            Err(CoreError::Message {
+                peer,
+                source: OnMessageError::JoinRejected,
+            }) if peer == other.device_id()
+        ));
+        assert!(core.peers().is_empty());
+        core.on_message_received(peer.device_id(), &frame).unwrap();
+        assert_eq!(core.peers()[0].public_key, *peer.keypair.public_key());
+        // Signature made for someone else does not verify here.
+        let forged = peer.join_frame(other.public_key()).unwrap();
+        let mut fresh = PeaPodCore::new();
+        assert!(fresh
+            .on_message_received(peer.device_id(), &forged)
+            .is_err());
+    }
+
+    #[test]
+    fn shutdown_sends_signed_leave_and_falls_back() {
+        let mut core = PeaPodCore::with_config(Config {
+            chunk_size: 10,
+            ..Config::default()
+        });
+        let mut peer = PeaPodCore::new();
+        let (core_id, peer_id) = (core.device_id(), peer.device_id());
+        core.on_peer_joined(peer_id, peer.keypair.public_key());
+        peer.on_peer_joined(core_id, core.keypair.public_key());
+        let transfer_id = match core.on_incoming_request("http://example.com/f", Some((0, 39))) {
+            Action::Accelerate { transfer_id, .. } => transfer_id,
+            _ => panic!("expected Accelerate"),
+        };
+        let actions = core.shutdown();
+        assert!(actions
+            .iter()
+            .any(|a| matches!(a, OutboundAction::AbortToFallback(t) if *t == transfer_id)));
+        assert!(core.peers().is_empty());
+        let leave = actions
+            .iter()
+            .filter_map(|a| match a {
+                OutboundAction::SendMessage(p, bytes) if *p == peer_id => Some(bytes.clone()),
+                _ => None,
+            })
+            .find(|bytes| matches!(wire::decode_frame(bytes), Ok((Message::Leave { .. }, _))))
+            .expect("Leave for the peer");
+        // A third device cannot replay it as its own.
+        let other = Keypair::generate();
+        peer.on_peer_joined(other.device_id(), other.public_key());
+        assert!(matches!(
+            peer.on_message_received(other.device_id(), &leave),
+            Err(CoreError::Message {
+                source: OnMessageError::LeaveRejected,
+                ..
+            })
+        ));
+        peer.on_message_received(core_id, &leave).unwrap();
+        assert_eq!(peer.membership(core_id), Some(Membership::Left));
+    }
+
+    #[test]
+    fn join_capabilities_shape_chunk_size_and_assignment() {
+        let mut core = PeaPodCore::with_config(Config {
+            chunk_size: 100,
+            ..Config::default()
+        });
+        let core_pk = core.keypair.public_key().clone();
+        let mut slow = PeaPodCore::new();
+        slow.set_capabilities(Capabilities {
+            max_chunk_size: 10,
+            downlink_bps: 1_000,
+            features: SUPPORTED_FEATURES,
+            platform: "android".into(),
+        });
+        let mut fast = PeaPodCore::new();
+        fast.set_capabilities(Capabilities {
+            downlink_bps: 100_000,
+            ..fast.capabilities().clone()
+        });
+        for peer in [&slow, &fast] {
+            let frame = peer.join_frame(&core_pk).unwrap();
+            core.on_message_received(peer.device_id(), &frame).unwrap();
+        }
+        assert_eq!(core.peers()[0].capabilities.platform, "android");
+        assert!(core.peers()[1].capabilities.supports(SUPPORTED_FEATURES));
+        let assignment = match core.on_incoming_request("http://example.com/f", Some((0, 199))) {
+            Action::Accelerate { assignment, .. } => assignment,
+            _ => panic!("expected Accelerate"),
+        };
+        // Chunks shrink to the slow peer's limit; the fast peer's downlink wins it more of them.
+        assert!(assignment.iter().all(|(c, _)| c.end - c.start <= 10));
+        let count = |id: DeviceId| assignment.iter().filter(|(_, d)| *d == id).count();
+        assert!(count(fast.device_id()) > count(slow.device_id()));
+    }
+
+    #[test]
+    fn peer_assisted_upload_completes_after_a_peer_failure() {
+        let mut sender = PeaPodCore::with_config(Config {
+            chunk_size: 10,
+            ..Config::default()
+        });
+        let mut helper = PeaPodCore::new();
+        let (sender_id, helper_id) = (sender.device_id(), helper.device_id());
+        let frame = helper.join_frame(sender.keypair.public_key()).unwrap();
+        sender.on_message_received(helper_id, &frame).unwrap();
+        helper.on_peer_joined(sender_id, &sender.keypair.public_key().clone());
+
+        let body: Vec<u8> = (0..40).collect();
+        let (upload_id, actions) = sender.start_upload("http://example.com/up", body.clone());
+        let mut own = Vec::new();
+        let mut helped = Vec::new();
+        for action in actions {
+            match action {
+                OutboundAction::UploadChunk {
+                    peer,
+                    chunk_id,
+                    payload,
+                    ..
+                } if peer == sender_id => own.push((chunk_id, payload)),
+                OutboundAction::SendMessage(peer, bytes) if peer == helper_id => {
+                    let (more, _) = helper.on_message_received(sender_id, &bytes).unwrap();
+                    helped.extend(more);
+                }
+                _ => panic!("unexpected action"),
+            }
+        }
+        assert!(!own.is_empty() && !helped.is_empty());
+        // The helper fails its first range, which comes back to the sender.
+        for (i, action) in helped.into_iter().enumerate() {
+            let OutboundAction::UploadChunk {
+                peer,
+                chunk_id,
+                payload,
+                total_length,
+                ..
+            } = action
+            else {
+                panic!("expected UploadChunk");
+            };
+            assert_eq!((peer, total_length), (sender_id, 40));
+            assert_eq!(
+                payload,
+                body[chunk_id.start as usize..chunk_id.end as usize]
+            );
+            for ack in helper.on_upload_chunk_done(peer, chunk_id, i > 0) {
+                let OutboundAction::SendMessage(to, bytes) = ack else {
+                    panic!("expected UploadAck");
+                };
+                assert_eq!(to, sender_id);
+                let (retry, _) = sender.on_message_received(helper_id, &bytes).unwrap();
+                for action in retry {
+                    if let OutboundAction::UploadChunk {
+                        chunk_id, payload, ..
+                    } = action
+                    {
+                        own.push((chunk_id, payload));
+                    }
+                }
+            }
+        }
+        let completed = |core: &mut PeaPodCore| {
+            core.drain_events().iter().any(
+                |e| matches!(e, CoreEvent::UploadComplete { upload_id: id } if *id == upload_id),
+            )
+        };
+        assert!(!completed(&mut sender));
+        for (chunk_id, _) in own {
+            assert!(sender
+                .on_upload_chunk_done(sender_id, chunk_id, true)
+                .is_empty());
+        }
+        assert!(completed(&mut sender));
+    }
+
+    #[test]
+    fn failing_peer_stops_receiving_work() {
+        let mut core = PeaPodCore::with_config(Config {
+            chunk_size: 10,
+            ..Config::default()
+        });
+        let a = Keypair::generate();
+        let b = Keypair::generate();
+        core.on_peer_joined(a.device_id(), a.public_key());
+        core.on_peer_joined(b.device_id(), b.public_key());
+        let transfer_id = match core.on_incoming_request("http://example.com/f", Some((0, 99))) {
+            Action::Accelerate { transfer_id, .. } => transfer_id,
+            _ => panic!("expected Accelerate"),
+        };
+        for i in 0..3u64 {
+            let bad = Message::ChunkData {
+                transfer_id,
+                start: i * 10,
+                end: i * 10 + 10,
+                hash: [0; 32],
+                payload: vec![1; 10],
+                compressed: false,
+                hash_algorithm: HashAlgorithm::Sha256,
+                attestation: None,
+            };
+            let frame = wire::encode_frame(&bad).unwrap();
+            core.on_message_received(a.device_id(), &frame).unwrap();
+        }
+        assert_eq!(core.peers()[0].metrics.chunks_failed, 3);
+        match core.on_incoming_request("http://example.com/g", Some((0, 99))) {
+            Action::Accelerate { assignment, .. } => {
+                assert!(assignment.iter().all(|(_, p)| *p != a.device_id()));
+            }
+            _ => panic!("expected Accelerate"),
+        }
+    }
+
+    #[test]
+    fn disputed_chunk_settled_by_two_sources_exposes_poisoner() {
+        let mut core = PeaPodCore::with_config(Config {
+            chunk_size: 10,
+            adaptive_chunk_size: false,
+            endgame_chunks: 0,
+            dispute_quorum: true,
+            ..Config::default()
+        });
+        let (a, b) = (Keypair::generate(), Keypair::generate());
+        core.on_peer_joined(a.device_id(), a.public_key());
+        core.on_peer_joined(b.device_id(), b.public_key());
+        let transfer_id = match core.on_incoming_request("http://example.com/f", Some((0, 59))) {
+            Action::Accelerate { transfer_id, .. } => transfer_id,
+            _ => panic!("expected Accelerate"),
+        };
+        let frame = |c: ChunkId, payload: &[u8], hash: [u8; 32]| {
+            wire::encode_frame(&Message::ChunkData {
+                transfer_id,
+                start: c.start,
+                end: c.end,
+                hash,
+                payload: payload.to_vec(),
+                compressed: false,
+                hash_algorithm: HashAlgorithm::Sha256,
+                attestation: None,
+            })
+            .unwrap()
+        };
+        let mut theirs = core
+            .current_assignment(transfer_id)
+            .unwrap()
+            .into_iter()
+            .filter(|(_, p)| *p == a.device_id())
+            .map(|(c, _)| c);
+        // `a` claims `claimed` for a chunk of `truth` but sends damaged bytes; `b` and this device
+        // then deliver the real chunk.
+        let dispute = |core: &mut PeaPodCore, c: ChunkId, claimed: &[u8]| {
+            let truth = vec![c.start as u8; 10];
+            let damaged = frame(c, &[0xee; 10], integrity::hash_chunk(claimed));
+            let (actions, _) = core.on_message_received(a.device_id(), &damaged).unwrap();
+            let self_id = core.device_id();
+            assert_eq!(
+                requested(&actions, self_id).len(),
+                2,
+                "reassigned and witness"
+            );
+            let good = frame(c, &truth, integrity::hash_chunk(&truth));
+            core.on_message_received(b.device_id(), &good).unwrap();
+            let _ = core.on_chunk_received(
+                transfer_id,
+                c.start,
+                c.end,
+                integrity::hash_chunk(&truth),
+                truth.clone(),
+            );
+            core.drain_events()
+        };
+        // Right hash, damaged payload: corruption, not poisoning.
+        let c = theirs.next().unwrap();
+        let events = dispute(&mut core, c, &[c.start as u8; 10]);
+        assert!(!events
+            .iter()
+            .any(|e| matches!(e, CoreEvent::ChunkPoisoned { .. })));
+        assert!(!core.peer_trust(a.device_id()).unwrap().isolated);
+
+        let c = theirs.next().unwrap();
+        let events = dispute(&mut core, c, b"not the chunk");
+        assert!(events.contains(&CoreEvent::ChunkPoisoned {
+            chunk_id: c,
+            from: a.device_id()
+        }));
+        assert!(events.contains(&CoreEvent::PeerIsolated(a.device_id())));
+        assert!(core.peer_trust(a.device_id()).unwrap().isolated);
+    }
+
+    #[test]
+    fn lowered_integrity_threshold_isolates_at_once() {
+        let config = Config {
+            chunk_size: 10,
+            adaptive_chunk_size: false,
+            endgame_chunks: 0,
+            max_integrity_failures: 5,
+            ..Config::default()
+        };
+        let mut core = PeaPodCore::with_config(config.clone());
+        let a = Keypair::generate();
+        core.on_peer_joined(a.device_id(), a.public_key());
+        let transfer_id = match core.on_incoming_request("http://example.com/f", Some((0, 99))) {
+            Action::Accelerate { transfer_id, .. } => transfer_id,
+            _ => panic!("expected Accelerate"),
+        };
+        let chunk_data = |c: ChunkId, payload: Vec<u8>, hash: [u8; 32]| {
+            wire::encode_frame(&Message::ChunkData {
+                transfer_id,
+                start: c.start,
+                end: c.end,
+                hash,
+                payload,
+                compressed: false,
+                hash_algorithm: HashAlgorithm::Sha256,
+                attestation: None,
+            })
+            .unwrap()
+        };
+        let theirs: Vec<ChunkId> = core
+            .current_assignment(transfer_id)
+            .unwrap()
+            .into_iter()
+            .filter(|(_, p)| *p == a.device_id())
+            .map(|(c, _)| c)
+            .collect();
+        for &c in &theirs[..2] {
+            let frame = chunk_data(c, vec![1; 10], [0; 32]);
+            core.on_message_received(a.device_id(), &frame).unwrap();
+        }
+        assert!(!core.peer_trust(a.device_id()).unwrap().isolated);
+        core.drain_events();
+
+        let actions = core.update_config(Config {
+            max_integrity_failures: 2,
+            ..config
+        });
+        assert!(!actions.is_empty());
+        assert!(core
+            .drain_events()
+            .contains(&CoreEvent::PeerIsolated(a.device_id())));
+        let assignment = core.current_assignment(transfer_id).unwrap();
+        assert!(assignment.iter().all(|(_, p)| *p != a.device_id()));
+        // A late, valid copy from the isolated peer is dropped.
+        let c = theirs[2];
+        let payload = vec![2; 10];
+        let frame = chunk_data(c, payload.clone(), integrity::hash_chunk(&payload));
+        core.on_message_received(a.device_id(), &frame).unwrap();
+        assert!(!core.transfers[&transfer_id].state.is_chunk_received(c));
+    }
+
+    #[test]
+    fn corrupt_chunks_isolate_peer() {
+        let mut core = PeaPodCore::with_config(Config {
+            chunk_size: 10,
+            max_integrity_failures: 2,
+            ..Config::default()
+        });
+        let a = Keypair::generate();
+        core.on_peer_joined(a.device_id(), a.public_key());
+        let transfer_id = match core.on_incoming_request("http://example.com/f", Some((0, 99))) {
+            Action::Accelerate { transfer_id, .. } => transfer_id,
+            _ => panic!("expected Accelerate"),
+        };
+        for i in 0..2u64 {
+            let bad = Message::ChunkData {
+                transfer_id,
+                start: i * 10,
+                end: i * 10 + 10,
+                hash: [0; 32],
+                payload: vec![1; 10],
+                compressed: false,
+                hash_algorithm: HashAlgorithm::Sha256,
+                attestation: None,
+            };
+            let frame = wire::encode_frame(&bad).unwrap();
+            core.on_message_received(a.device_id(), &frame).unwrap();
+        }
+        assert!(core
+            .drain_events()
+            .contains(&CoreEvent::PeerIsolated(a.device_id())));
+        assert!(core.peer_trust(a.device_id()).unwrap().isolated);
+        let assignment = core.current_assignment(transfer_id).unwrap();
+        assert!(assignment.iter().all(|(_, p)| *p != a.device_id()));
+        // The only peer is isolated: new requests fall back until trust is reset.
+        assert!(matches!(
+            core.on_incoming_request("http://example.com/g", Some((0, 99))),
+            Action::Fallback
+        ));
+        core.reset_peer_trust(a.device_id());
+        assert!(matches!(
+            core.on_incoming_request("http://example.com/g", Some((0, 99))),
+            Action::Accelerate { .. }
+        ));
+    }
+
+    #[test]
+    fn fetch_failure_nacks_requester_or_reassigns_own_chunk() {
+        let mut core = PeaPodCore::with_config(Config {
+            chunk_size: 50,
+            ..Config::default()
+        });
+        let peer = Keypair::generate();
+        core.on_peer_joined(peer.device_id(), peer.public_key());
+        let self_id = core.device_id();
+        let (transfer_id, own) =
+            match core.on_incoming_request("http://example.com/f", Some((0, 99))) {
+                Action::Accelerate {
+                    transfer_id,
+                    assignment,
+                    ..
+                } => (
+                    transfer_id,
+                    assignment
+                        .into_iter()
+                        .find(|&(_, p)| p == self_id)
+                        .map(|(c, _)| c)
+                        .unwrap(),
+                ),
+                _ => panic!("expected Accelerate"),
+            };
+        let actions = core.on_chunk_fetch_failed(self_id, own, FetchFailure::Status(503));
+        assert_eq!(chunk_requests(&actions)[0].0, peer.device_id());
+        let assignment = core.current_assignment(transfer_id).unwrap();
+        assert!(assignment.contains(&(own, peer.device_id())));
+
+        let served = ChunkId {
+            transfer_id: [7; 16],
+            start: 0,
+            end: 10,
+        };
+        let actions = core.on_chunk_fetch_failed(peer.device_id(), served, FetchFailure::Timeout);
+        assert_eq!(actions.len(), 1);
+        let sent = messages(&actions);
+        assert_eq!(sent[0].0, peer.device_id());
+        assert!(matches!(
+            sent[0].1,
+            Message::Nack {
+                start: 0,
+                end: 10,
+                ..
+            }
+        ));
+        assert_eq!(core.self_metrics.chunks_failed, 2);
+    }
+
+    #[test]
+    fn overlapping_chunk_data_fills_covered_chunks() {
+        let mut core = PeaPodCore::with_config(Config {
+            chunk_size: 10,
+            adaptive_chunk_size: false,
+            endgame_chunks: 0,
+            ..Config::default()
+        });
+        let peer = Keypair::generate();
+        core.on_peer_joined(peer.device_id(), peer.public_key());
+        let transfer_id = match core.on_incoming_request("http://example.com/f", Some((0, 39))) {
+            Action::Accelerate { transfer_id, .. } => transfer_id,
+            _ => panic!("expected Accelerate"),
+        };
+        let data = |start: u64, end: u64, hash: Option<[u8; 32]>| {
+            let payload: Vec<u8> = (start..end).map(|i| i as u8).collect();
+            let frame = wire::encode_frame(&Message::ChunkData {
+                transfer_id,
+                start,
+                end,
+                hash: hash.unwrap_or_else(|| integrity::hash_chunk(&payload)),
+                payload,
+                compressed: false,
+                hash_algorithm: HashAlgorithm::Sha256,
+                attestation: None,
+            });
+            frame.unwrap()
+        };
+        // Bytes 5..30 cover chunks 10..20 and 20..30; 0..10 is only partly covered.
+        core.on_message_received(peer.device_id(), &data(5, 30, None))
+            .unwrap();
+        let chunk = |start| ChunkId {
+            transfer_id,
+            start,
+            end: start + 10,
+        };
+        let state = &core.transfers[&transfer_id].state;
+        assert!(state.is_chunk_received(chunk(10)) && state.is_chunk_received(chunk(20)));
+        assert!(!state.is_chunk_received(chunk(0)));
+        assert_eq!(state.bytes_received(), 20);
+
+        // A payload shorter than its declared range counts against the sender.
+        let short = wire::encode_frame(&Message::ChunkData {
+            transfer_id,
+            start: 30,
+            end: 40,
+            hash: integrity::hash_chunk(&[0; 9]),
+            payload: vec![0; 9],
+            compressed: false,
+            hash_algorithm: HashAlgorithm::Sha256,
+            attestation: None,
+        })
+        .unwrap();
+        core.on_message_received(peer.device_id(), &short).unwrap();
+        assert_eq!(
+            core.peer_trust(peer.device_id())
+                .map(|t| t.integrity_failures),
+            Some(1)
+        );
+
+        // A corrupt overlapping payload is rejected as a whole.
+        core.drain_events();
+        core.on_message_received(peer.device_id(), &data(25, 40, Some([0; 32])))
+            .unwrap();
+        assert!(!core.transfers[&transfer_id]
+            .state
+            .is_chunk_received(chunk(30)));
+        assert!(matches!(
+            core.drain_events()[..],
+            [CoreEvent::IntegrityFailure { .. }]
+        ));
+    }
+
+    #[test]
+    fn chunk_timings_attribute_each_delivery() {
+        let mut core = PeaPodCore::with_config(Config {
+            chunk_size: 10,
+            adaptive_chunk_size: false,
+            endgame_chunks: 0,
+            heartbeat_timeout_ticks: 100,
+            ..Config::default()
+        });
+        let peer = Keypair::generate();
+        core.on_peer_joined(peer.device_id(), peer.public_key());
+        let (transfer_id, assignment) =
+            match core.on_incoming_request("http://example.com/f", Some((0, 39))) {
+                Action::Accelerate {
+                    transfer_id,
+                    assignment,
+                    ..
+                } => (transfer_id, assignment),
+                _ => panic!("expected Accelerate"),
+            };
+        let (chunk_id, _) = *assignment
+            .iter()
+            .find(|(_, p)| *p == peer.device_id())
+            .unwrap();
+        core.tick_count = 3;
+        let payload = vec![1; 10];
+        let data = Message::ChunkData {
+            transfer_id,
+            start: chunk_id.start,
+            end: chunk_id.end,
+            hash: integrity::hash_chunk(&payload),
+            payload,
+            compressed: false,
+            hash_algorithm: HashAlgorithm::Sha256,
+            attestation: None,
+        };
+        core.on_message_received(peer.device_id(), &wire::encode_frame(&data).unwrap())
+            .unwrap();
+        let timings = core.chunk_timings(transfer_id).unwrap();
+        assert_eq!(timings.len(), assignment.len());
+        for (c, timing) in timings {
+            assert_eq!(timing.requested_tick, 0);
+            let expected = (c == chunk_id).then_some((peer.device_id(), 3));
+            assert_eq!(timing.source.zip(timing.ticks), expected);
+        }
+    }
+
+    #[test]
+    fn nacked_chunk_fails_after_its_retry_budget() {
+        let mut core = PeaPodCore::with_config(Config {
+            chunk_size: 10,
+            adaptive_chunk_size: false,
+            endgame_chunks: 0,
+            chunk_retries: 2,
+            fallback_failure_percent: 0,
+            ..Config::default()
+        });
+        let (a, b) = (Keypair::generate(), Keypair::generate());
+        core.on_peer_joined(a.device_id(), a.public_key());
+        core.on_peer_joined(b.device_id(), b.public_key());
+        let (transfer_id, assignment) =
+            match core.on_incoming_request("http://example.com/f", Some((0, 29))) {
+                Action::Accelerate {
+                    transfer_id,
+                    assignment,
+                    ..
+                } => (transfer_id, assignment),
+                _ => panic!("expected Accelerate"),
+            };
+        let (chunk_id, mut peer) = *assignment
+            .iter()
+            .find(|(_, p)| *p != core.device_id())
+            .unwrap();
+        let nack = wire::encode_frame(&Message::Nack {
+            transfer_id,
+            start: chunk_id.start,
+            end: chunk_id.end,
+        })
+        .unwrap();
+        // Every peer refuses the chunk: it moves on twice, then the transfer gives up.
+        let self_id = core.device_id();
+        for failures in 1..=3 {
+            let actions = if peer == self_id {
+                core.on_chunk_fetch_failed(peer, chunk_id, FetchFailure::Network)
+            } else {
+                core.on_message_received(peer, &nack).unwrap().0
+            };
+            if failures <= 2 {
+                let attempts = core.chunk_attempts(chunk_id).unwrap();
+                assert_eq!(attempts.failures, failures);
+                assert_eq!(attempts.requests, failures + 1);
+                peer = requested(&actions, self_id)[0].0;
+            } else {
+                assert!(actions
+                    .iter()
+                    .any(|a| matches!(a, OutboundAction::AbortToFallback(t) if *t == transfer_id)));
+            }
+        }
+        assert!(core
+            .drain_events()
+            .iter()
+            .any(|e| matches!(e, CoreEvent::ChunkFailed { retries: 3, .. })));
+        assert!(core.chunk_attempts(chunk_id).is_none());
+    }
+
+    #[test]
+    fn timed_out_chunks_back_off_then_fail_the_transfer() {
+        let mut core = PeaPodCore::with_config(Config {
+            chunk_size: 10,
+            adaptive_chunk_size: false,
+            endgame_chunks: 0,
+            chunk_timeout_ticks: 2,
+            chunk_retries: 2,
+            retry_backoff_ticks: 1,
+            fallback_failure_percent: 0,
+            heartbeat_timeout_ticks: 100,
+            ..Config::default()
+        });
+        let (a, b) = (Keypair::generate(), Keypair::generate());
+        core.on_peer_joined(a.device_id(), a.public_key());
+        core.on_peer_joined(b.device_id(), b.public_key());
+        let transfer_id = match core.on_incoming_request("http://example.com/f", Some((0, 29))) {
+            Action::Accelerate { transfer_id, .. } => transfer_id,
+            _ => panic!("expected Accelerate"),
+        };
+        // Requested at tick 0; first timeout at tick 3, retried after 1 tick; second timeout at
+        // tick 7, retried after 2 ticks; the third timeout (tick 12) is out of retries.
+        let mut retried_at = Vec::new();
+        for tick in 1..12 {
+            if !chunk_requests(&core.tick()).is_empty() {
+                retried_at.push(tick);
+            }
+        }
+        assert_eq!(retried_at, vec![4, 9]);
+        let actions = core.tick();
+        assert!(actions
+            .iter()
+            .any(|a| matches!(a, OutboundAction::AbortToFallback(id) if *id == transfer_id)));
+        assert!(core
+            .drain_events()
+            .iter()
+            .any(|e| matches!(e, CoreEvent::ChunkFailed { retries: 3, .. })));
+        assert!(core.transfer_progress(transfer_id).is_none());
+    }
+
+    #[test]
+    fn late_chunks_race_a_second_peer_and_charge_the_loser() {
+        let mut core = PeaPodCore::with_config(Config {
+            chunk_size: 10,
+            adaptive_chunk_size: false,
+            endgame_chunks: 0,
+            straggler_factor: 2,
+            heartbeat_timeout_ticks: 100,
+            ..Config::default()
+        });
+        let (slow, fast) = (Keypair::generate(), Keypair::generate());
+        let (slow, fast) = (slow.device_id(), fast.device_id());
+        join_capable(&mut core, slow, &Keypair::generate().public_key().clone());
+        join_capable(&mut core, fast, &Keypair::generate().public_key().clone());
+        let (transfer_id, assignment) =
+            match core.on_incoming_request("http://example.com/f", Some((0, 59))) {
+                Action::Accelerate {
+                    transfer_id,
+                    assignment,
+                    ..
+                } => (transfer_id, assignment),
+                _ => panic!("expected Accelerate"),
+            };
+        core.peer_mut(&slow).unwrap().metrics.delivery_rate_bps = Some(5);
+        core.peer_mut(&fast).unwrap().metrics.delivery_rate_bps = Some(1000);
+        let payload = |c: ChunkId| vec![c.start as u8; 10];
+        for &(c, _) in assignment.iter().filter(|&&(_, d)| d != slow) {
+            let hash = crate::integrity::hash_chunk(&payload(c));
+            core.on_chunk_received(transfer_id, c.start, c.end, hash, payload(c))
+                .unwrap();
+        }
+        let late: Vec<ChunkId> = assignment
+            .iter()
+            .filter(|&&(_, d)| d == slow)
+            .map(|&(c, _)| c)
+            .collect();
+        // Expected 2 ticks at 5 bytes per tick: raced once more than 4 ticks have passed.
+        for _ in 0..4 {
+            assert!(chunk_requests(&core.tick()).is_empty());
+        }
+        let raced = chunk_requests(&core.tick());
+        assert_eq!(raced.len(), late.len());
+        assert!(raced.iter().all(|(p, _)| *p == fast));
+        assert!(chunk_requests(&core.tick()).is_empty());
+
+        let c = late[0];
+        let frame = wire::encode_frame(&Message::ChunkData {
+            transfer_id,
+            start: c.start,
+            end: c.end,
+            hash: crate::integrity::hash_chunk(&payload(c)),
+            payload: payload(c),
+            compressed: false,
+            hash_algorithm: HashAlgorithm::Sha256,
+            attestation: None,
+        })
+        .unwrap();
+        let (actions, _) = core.on_message_received(fast, &frame).unwrap();
+        assert!(messages(&actions)
+            .iter()
+            .any(|(p, m)| *p == slow && matches!(m, Message::Cancel { .. })));
+        assert_eq!(core.peer_mut(&slow).unwrap().metrics.chunks_failed, 1);
+    }
+
+    #[test]
+    fn endgame_duplicates_last_chunks_and_cancels_losers() {
+        let mut core = PeaPodCore::with_config(Config {
+            chunk_size: 10,
+            endgame_chunks: 2,
+            ..Config::default()
+        });
+        let a = Keypair::generate();
+        let b = Keypair::generate();
+        join_capable(&mut core, a.device_id(), a.public_key());
+        join_capable(&mut core, b.device_id(), b.public_key());
+        let (transfer_id, assignment) =
+            match core.on_incoming_request("http://example.com/f", Some((0, 99))) {
+                Action::Accelerate {
+                    transfer_id,
+                    assignment,
+                    ..
+                } => (transfer_id, assignment),
+                _ => panic!("expected Accelerate"),
+            };
+        let mut last = Vec::new();
+        for &(c, _) in &assignment[..8] {
+            let payload = vec![c.start as u8; 10];
+            let hash = crate::integrity::hash_chunk(&payload);
+            last = core
+                .on_chunk_received_with_actions(transfer_id, c.start, c.end, hash, payload)
+                .actions;
+        }
+        // Two chunks left, each duplicated to the two workers it is not assigned to. This
+        // device's copies are fetches for it; no message is ever addressed to itself.
+        let self_id = core.device_id();
+        assert_eq!(requested(&last, self_id).len(), 4);
+        assert!(last
+            .iter()
+            .any(|a| matches!(a, OutboundAction::FetchChunk { peer, .. } if *peer == self_id)));
+        assert!(messages(&last).iter().all(|(p, _)| *p != self_id));
+
+        let (c, assigned) = assignment[8];
+        let payload = vec![8; 10];
+        let data = Message::ChunkData {
+            transfer_id,
+            start: c.start,
+            end: c.end,
+            hash: crate::integrity::hash_chunk(&payload),
+            payload,
+            compressed: false,
+            hash_algorithm: HashAlgorithm::Sha256,
+            attestation: None,
+        };
+        let winner = if assigned == a.device_id() {
+            b.device_id()
+        } else {
+            a.device_id()
+        };
+        let frame = wire::encode_frame(&data).unwrap();
+        let (actions, _) = core.on_message_received(winner, &frame).unwrap();
+        let cancels: Vec<DeviceId> = messages(&actions)
+            .into_iter()
+            .filter(|(_, m)| matches!(m, Message::Cancel { .. }))
+            .map(|(p, _)| p)
+            .collect();
+        let expected: Vec<DeviceId> = [a.device_id(), b.device_id(), core.device_id()]
+            .into_iter()
+            .filter(|&p| p != winner && p != core.device_id())
+            .collect();
+        assert_eq!(cancels, expected);
+    }
+
+    /// Gives every chunk to the last worker and counts reported outcomes.
+    struct LastWorker(Arc<std::sync::Mutex<(u32, u32)>>);
+
+    impl Scheduler for LastWorker {
+        fn assign(&mut self, chunk_ids: &[ChunkId], workers: &Workers) -> Vec<(ChunkId, DeviceId)> {
+            let last = *workers.ids.last().unwrap();
+            chunk_ids.iter().map(|&c| (c, last)).collect()
+        }
+
+        fn reassign(&mut self, _chunk_id: ChunkId, workers: &Workers) -> Option<DeviceId> {
+            workers.ids.last().copied()
+        }
+
+        fn on_result(&mut self, _peer: DeviceId, ok: bool) {
+            let mut counts = self.0.lock().unwrap();
+            if ok {
+                counts.0 += 1;
+            } else {
+                counts.1 += 1;
+            }
+        }
+    }
+
+    #[test]
+    fn old_failures_decay_and_metrics_outlive_the_peer() {
+        let mut core = PeaPodCore::with_config(Config {
+            failure_half_life_ticks: 10,
+            heartbeat_timeout_ticks: 1_000,
+            ..Config::default()
+        });
+        let peer = Keypair::generate();
+        core.on_peer_joined(peer.device_id(), peer.public_key());
+        for _ in 0..4 {
+            core.record_outcome(peer.device_id(), false);
+        }
+        core.report_peer_rtt(peer.device_id(), 20);
+        assert_eq!(core.peers()[0].metrics.weight(), 0);
+        core.tick_count = 9;
+        core.tick();
+        assert_eq!(core.peers()[0].metrics.chunks_failed, 2);
+        assert!(core.peers()[0].metrics.weight() > 0);
+        core.tick_count = 29;
+        core.tick();
+        assert_eq!(core.peers()[0].metrics.chunks_failed, 0);
+
+        // Metrics survive the peer leaving and a restart of the host.
+        core.record_outcome(peer.device_id(), false);
+        core.on_peer_left(peer.device_id());
+        let saved = bincode::serialize(&core.known_metrics()).unwrap();
+        let mut restarted = PeaPodCore::new();
+        let entries: Vec<(DeviceId, PeerMetrics)> = bincode::deserialize(&saved).unwrap();
+        assert_eq!(restarted.import_known_metrics(entries), 1);
+        restarted.on_peer_joined(peer.device_id(), peer.public_key());
+        let m = &restarted.peers()[0].metrics;
+        assert_eq!((m.chunks_failed, m.latency_ms), (1, Some(20)));
+        assert_eq!(m.last_failure_tick, Some(0));
+    }
+
+    #[test]
+    fn streaming_orders_chunks_by_deadline_and_duplicates_the_head() {
+        let mut core = PeaPodCore::with_config(Config {
+            chunk_size: 10,
+            adaptive_chunk_size: false,
+            endgame_chunks: 0,
+            peer_window: 1,
+            work_stealing: false,
+            ..Config::default()
+        });
+        let fast = Keypair::generate();
+        let slow = Keypair::generate();
+        core.on_peer_joined(fast.device_id(), fast.public_key());
+        core.on_peer_joined(slow.device_id(), slow.public_key());
+        let rate = |bps| PeerMetrics {
+            bandwidth_bytes_per_sec: Some(bps),
+            ..PeerMetrics::default()
+        };
+        core.set_peer_metrics(core.device_id(), rate(100));
+        core.set_peer_metrics(fast.device_id(), rate(400));
+        core.set_peer_metrics(slow.device_id(), rate(100));
+        let transfer_id = match core.on_incoming_request("http://example.com/v", Some((0, 199))) {
+            Action::Accelerate { transfer_id, .. } => transfer_id,
+            _ => panic!("expected Accelerate"),
+        };
+        let actions = core.set_streaming(transfer_id, true);
+        let t = &core.transfers[&transfer_id];
+        // The earliest chunks still waiting go to the fastest peer; the slow one only gets
+        // later ranges.
+        let waiting: Vec<DeviceId> = t.queued.values().filter_map(|&c| t.peer_for(c)).collect();
+        assert!(waiting[..3].iter().all(|&p| p == fast.device_id()));
+        assert!(waiting.contains(&slow.device_id()));
+        // The chunk at the play position is in flight to the fast peer; it is also requested
+        // from the best other worker. The next one is still queued and is not duplicated.
+        let head: Vec<(DeviceId, u64)> = chunk_requests(&actions)
+            .into_iter()
+            .filter_map(|(p, m)| match m {
+                Message::ChunkRequest { start, .. } if start < 20 => Some((p, start)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(head.len(), 1);
+        assert_eq!(head[0].1, 0);
+        assert_ne!(head[0].0, fast.device_id());
+        // A chunk is duplicated once.
+        assert!(chunk_requests(&core.tick()).is_empty());
+    }
+
+    #[test]
+    fn dry_run_compares_strategies_without_changing_state() {
+        let mut core = PeaPodCore::with_config(Config {
+            chunk_size: 100,
+            ..Config::default()
+        });
+        let peer = Keypair::generate();
+        core.on_peer_joined(peer.device_id(), peer.public_key());
+        let rate = |bps| PeerMetrics {
+            bandwidth_bytes_per_sec: Some(bps),
+            ..PeerMetrics::default()
+        };
+        core.set_peer_metrics(core.device_id(), rate(1_000));
+        core.set_peer_metrics(peer.device_id(), rate(1_000));
+        core.drain_events();
+        let even = core.dry_run(1_000, &mut DefaultScheduler);
+        assert_eq!(even.bytes_per_worker, [500, 500]);
+        assert_eq!((even.unassigned, even.estimated_ms), (0, Some(500)));
+        let counts = Arc::new(std::sync::Mutex::new((0, 0)));
+        let lopsided = core.dry_run(1_000, &mut LastWorker(counts));
+        assert_eq!(lopsided.bytes_per_worker, [0, 1_000]);
+        assert_eq!(lopsided.estimated_ms, Some(1_000));
+        assert!(core.active_transfers().is_empty());
+        assert!(core.drain_events().is_empty());
+    }
+
+    #[test]
+    fn injected_scheduler_places_chunks_and_sees_results() {
+        let counts = Arc::new(std::sync::Mutex::new((0, 0)));
+        let mut core = PeaPodCore::with_config(Config {
+            chunk_size: 10,
+            adaptive_chunk_size: false,
+            endgame_chunks: 0,
+            peer_window: 0,
+            ..Config::default()
+        })
+        .with_scheduler(Box::new(LastWorker(counts.clone())));
+        let a = Keypair::generate();
+        let b = Keypair::generate();
+        core.on_peer_joined(a.device_id(), a.public_key());
+        core.on_peer_joined(b.device_id(), b.public_key());
+        let (transfer_id, assignment) =
+            match core.on_incoming_request("http://example.com/f", Some((0, 29))) {
+                Action::Accelerate {
+                    transfer_id,
+                    assignment,
+                    ..
+                } => (transfer_id, assignment),
+                _ => panic!("expected Accelerate"),
+            };
+        assert!(assignment.iter().all(|(_, d)| *d == b.device_id()));
+        let (chunk, _) = assignment[0];
+        let nack = wire::encode_frame(&Message::Nack {
+            transfer_id,
+            start: chunk.start,
+            end: chunk.end,
+        })
+        .unwrap();
+        core.on_message_received(b.device_id(), &nack).unwrap();
+        // The last worker other than b.
+        assert_eq!(
+            core.transfers[&transfer_id].peer_for(chunk),
+            Some(a.device_id())
+        );
+        let payload = vec![1; 10];
+        let data = Message::ChunkData {
+            transfer_id,
+            start: assignment[1].0.start,
+            end: assignment[1].0.end,
+            hash: integrity::hash_chunk(&payload),
+            payload,
+            compressed: false,
+            hash_algorithm: HashAlgorithm::Sha256,
+            attestation: None,
+        };
+        core.on_message_received(b.device_id(), &wire::encode_frame(&data).unwrap())
+            .unwrap();
+        assert_eq!(*counts.lock().unwrap(), (1, 1));
+    }
+
+    #[test]
+    fn content_filter_allows_denies_or_rewrites_requests() {
+        let mut core = PeaPodCore::with_config(Config {
+            chunk_size: 10,
+            eligibility: EligibilityPolicy {
+                never_share: vec!["/private/".into()],
+                ..EligibilityPolicy::default()
+            },
+            ..Config::default()
+        });
+        let peer = Keypair::generate();
+        core.on_peer_joined(peer.device_id(), peer.public_key());
+        core.set_content_filter(Some(Box::new(|r: &TransferRequest| {
+            if r.url.contains("blocked") {
+                ContentDecision::Deny
+            } else if r.url.contains("mirror") {
+                ContentDecision::Modify(TransferRequest {
+                    url: r.url.replace("mirror", "private"),
+                    ..r.clone()
+                })
+            } else if r.url.contains("old") {
+                ContentDecision::Modify(TransferRequest {
+                    url: "http://cdn.example.com/new".into(),
+                    range: Some((0, 9)),
+                    validator: None,
+                })
+            } else {
+                ContentDecision::Allow
+            }
+        })));
+        let range = Some((0, 39));
+        assert!(matches!(
+            core.on_incoming_request("http://example.com/f", range),
+            Action::Accelerate {
+                total_length: 40,
+                ..
+            }
+        ));
+        assert!(matches!(
+            core.on_incoming_request("http://example.com/blocked", range),
+            Action::Fallback
+        ));
+        // A rewrite cannot get around the never-share rule.
+        assert!(matches!(
+            core.on_incoming_request("http://example.com/mirror/f", range),
+            Action::Fallback
+        ));
+        let transfer_id = match core.on_incoming_request("http://example.com/old", range) {
+            Action::Accelerate {
+                transfer_id,
+                total_length: 10,
+                ..
+            } => transfer_id,
+            _ => panic!("expected the rewritten request"),
+        };
+        assert_eq!(
+            core.transfers[&transfer_id].url,
+            "http://cdn.example.com/new"
+        );
+        core.set_content_filter(None);
+        assert!(matches!(
+            core.on_incoming_request("http://example.com/blocked", range),
+            Action::Accelerate { .. }
+        ));
+    }
+
+    #[test]
+    fn restored_transfer_requests_only_missing_chunks() {
+        let keypair = Arc::new(Keypair::generate());
+        let mut core = PeaPodCore::with_keypair_arc_and_config(
+            keypair.clone(),
+            Config {
+                chunk_size: 10,
+                adaptive_chunk_size: false,
+                endgame_chunks: 0,
+                peer_window: 0,
+                ..Config::default()
+            },
+        );
+        let peer = Keypair::generate();
+        core.on_peer_joined(peer.device_id(), peer.public_key());
+        let transfer_id = match core.on_incoming_request("http://example.com/f", Some((0, 39))) {
+            Action::Accelerate { transfer_id, .. } => transfer_id,
+            _ => panic!("expected Accelerate"),
+        };
+        let first = vec![7; 10];
+        core.on_chunk_received(transfer_id, 20, 30, integrity::hash_chunk(&first), first)
+            .unwrap();
+
+        let mut restored = PeaPodCore::restore(&core.snapshot(), keypair).unwrap();
+        assert_eq!(restored.active_transfers(), vec![transfer_id]);
+        let self_id = restored.device_id();
+        let mut starts: Vec<u64> = requested(&restored.tick(), self_id)
+            .into_iter()
+            .map(|(_, c)| c.start)
+            .collect();
+        starts.sort_unstable();
+        assert_eq!(starts, vec![0, 10, 30]);
+        // Nothing is requested twice.
+        assert!(requested(&restored.tick(), self_id).is_empty());
+    }
+
+    #[test]
+    fn snapshot_restores_transfer_in_flight() {
+        let keypair = Arc::new(Keypair::generate());
+        let mut core = PeaPodCore::with_keypair_arc_and_config(
+            keypair.clone(),
+            Config {
+                chunk_size: 10,
+                ..Config::default()
+            },
+        );
+        let peer = Keypair::generate();
+        core.on_peer_joined(peer.device_id(), peer.public_key());
+        let body: Vec<u8> = (0..20).collect();
+        let transfer_id = match core.on_incoming_request("http://example.com/f", Some((0, 19))) {
+            Action::Accelerate { transfer_id, .. } => transfer_id,
+            _ => panic!("expected Accelerate"),
+        };
+        let first = body[..10].to_vec();
+        core.on_chunk_received(transfer_id, 0, 10, integrity::hash_chunk(&first), first)
+            .unwrap();
+
+        let mut restored = PeaPodCore::restore(&core.snapshot(), keypair).unwrap();
+        assert_eq!(restored.device_id(), core.device_id());
+        assert_eq!(restored.peers()[0].device_id, peer.device_id());
+        assert_eq!(
+            restored
+                .transfer_progress(transfer_id)
+                .unwrap()
+                .bytes_received,
+            10
+        );
+        let second = body[10..].to_vec();
+        let done = restored
+            .on_chunk_received(transfer_id, 10, 20, integrity::hash_chunk(&second), second)
+            .unwrap();
+        assert_eq!(done, Some(body));
+        assert!(PeaPodCore::restore(b"garbage", Arc::new(Keypair::generate())).is_err());
+    }
+
+    #[test]
+    fn client_range_uses_absolute_chunk_offsets() {
+        let mut core = PeaPodCore::with_config(Config {
+            chunk_size: 10,
+            endgame_chunks: 0,
+            ..Config::default()
+        });
+        let peer = Keypair::generate();
+        core.on_peer_joined(peer.device_id(), peer.public_key());
+        let (transfer_id, total_length, assignment) =
+            match core.on_incoming_request("http://example.com/f", Some((1000, 1039))) {
+                Action::Accelerate {
+                    transfer_id,
+                    total_length,
+                    assignment,
+                } => (transfer_id, total_length, assignment),
+                _ => panic!("expected Accelerate"),
+            };
+        assert_eq!(total_length, 40);
+        let mut starts: Vec<u64> = assignment.iter().map(|(c, _)| c.start).collect();
+        starts.sort_unstable();
+        assert_eq!(starts, vec![1000, 1010, 1020, 1030]);
+        let origin = |c: ChunkId| (c.start..c.end).map(|i| i as u8).collect::<Vec<u8>>();
+        let first = assignment.iter().find(|(c, _)| c.start == 1000).unwrap().0;
+        let payload = origin(first);
+        let hash = crate::integrity::hash_chunk(&payload);
+        core.on_chunk_received(transfer_id, first.start, first.end, hash, payload)
+            .unwrap();
+        let segment = core.take_segment(transfer_id).unwrap();
+        assert_eq!((segment.offset, segment.bytes.len()), (0, 10));
+        let mut body = segment.bytes;
+        for &(c, _) in assignment.iter().filter(|(c, _)| c.start != 1000) {
+            let payload = origin(c);
+            let hash = crate::integrity::hash_chunk(&payload);
+            if let Some(rest) = core
+                .on_chunk_received(transfer_id, c.start, c.end, hash, payload)
+                .unwrap()
+            {
+                body.extend(rest);
+            }
+        }
+        let expected: Vec<u8> = (1000..1040u64).map(|i| i as u8).collect();
+        assert_eq!(body, expected);
+    }
+
+    #[test]
+    fn identical_requests_attach_to_the_transfer_in_flight() {
+        let mut core = PeaPodCore::with_config(Config {
+            chunk_size: 10,
+            ..Config::default()
+        });
+        let peer = Keypair::generate();
+        core.on_peer_joined(peer.device_id(), peer.public_key());
+        let url = "http://example.com/f";
+        let transfer_id = match core.on_incoming_request(url, Some((0, 39))) {
+            Action::Accelerate { transfer_id, .. } => transfer_id,
+            _ => panic!("expected Accelerate"),
+        };
+        assert!(matches!(
+            core.on_incoming_request(url, Some((0, 39))),
+            Action::Attached { transfer_id: t, total_length: 40 } if t == transfer_id
+        ));
+        // A different range, validator or URL is a separate transfer.
+        assert!(matches!(
+            core.on_incoming_request(url, Some((10, 49))),
+            Action::Accelerate { .. }
+        ));
+        assert!(matches!(
+            core.on_incoming_request_with_validator(url, Some((0, 39)), Some("\"v2\"")),
+            Action::Accelerate { .. }
+        ));
+        assert!(matches!(
+            core.on_incoming_request("http://example.com/g", Some((0, 39))),
+            Action::Accelerate { .. }
+        ));
+        // Once the first requester streamed part of the body, a new request starts afresh.
+        let payload = vec![7u8; 10];
+        let hash = crate::integrity::hash_chunk(&payload);
+        core.on_chunk_received(transfer_id, 0, 10, hash, payload)
+            .unwrap();
+        assert!(core.take_segment(transfer_id).is_some());
+        assert!(matches!(
+            core.on_incoming_request(url, Some((0, 39))),
+            Action::Accelerate { transfer_id: t, .. } if t != transfer_id
+        ));
+    }
+
+    #[test]
+    fn never_share_urls_fall_back() {
+        let mut core = PeaPodCore::with_config(Config {
+            eligibility: EligibilityPolicy {
+                never_share: vec!["/private/".into()],
+                ..EligibilityPolicy::default()
+            },
+            ..Config::default()
+        });
+        let peer = Keypair::generate();
+        core.on_peer_joined(peer.device_id(), peer.public_key());
+        let request = RequestInfo {
+            method: "GET",
+            url: "http://example.com/private/f",
+            content_length: None,
+            content_type: None,
+        };
+        assert_eq!(
+            core.evaluate_request(&request),
+            Eligibility::Ineligible(crate::policy::PolicyRule::NeverShare("/private/".into()))
+        );
+        assert!(matches!(
+            core.on_incoming_request(request.url, Some((0, 99))),
+            Action::Fallback
+        ));
+        assert!(matches!(
+            core.on_incoming_request("http://example.com/public/f", Some((0, 99))),
+            Action::Accelerate { .. }
+        ));
+    }
+
+    #[test]
+    fn heartbeats_follow_their_own_interval() {
+        let mut core = PeaPodCore::with_config(Config {
+            tick_interval_ms: 100,
+            heartbeat_interval_ticks: 10,
+            heartbeat_timeout_ticks: 50,
+            ..Config::default()
+        });
+        assert_eq!(core.timer_intervals().heartbeat_ms, 1000);
+        let peer = Keypair::generate();
+        core.on_peer_joined(peer.device_id(), peer.public_key());
+        let sent: Vec<usize> = (1..=25)
+            .filter(|_| {
+                messages(&core.tick())
+                    .iter()
+                    .any(|(_, m)| matches!(m, Message::Heartbeat { .. }))
+            })
+            .collect();
+        assert_eq!(sent, vec![1, 11, 21]);
+    }
+
+    #[test]
+    fn hello_settles_the_highest_common_version() {
+        let mut a = PeaPodCore::new();
+        let mut b = PeaPodCore::new();
+        let (a_id, b_id) = (a.device_id(), b.device_id());
+        let b_public = b.keypair.public_key().clone();
+        let hello = |actions: &[OutboundAction]| {
+            actions
+                .iter()
+                .filter_map(|a| match a {
+                    OutboundAction::SendMessage(_, f) => Some(f.clone()),
+                    _ => None,
+                })
+                .find(|f| matches!(wire::decode_frame(f), Ok((Message::Hello { .. }, _))))
+        };
+        let opening = hello(&a.on_peer_connected(b_id, &b_public)).expect("hello sent");
+        assert_eq!(b.peer_protocol_version(a_id), PROTOCOL_VERSION);
+        let (reply, _) = b.on_message_received(a_id, &opening).unwrap();
+        assert_eq!(b.peer_protocol_version(a_id), MAX_PROTOCOL_VERSION);
+        let reply = hello(&reply).expect("hello answered");
+        let (actions, _) = a.on_message_received(b_id, &reply).unwrap();
+        assert!(hello(&actions).is_none());
+        assert_eq!(a.peer_protocol_version(b_id), MAX_PROTOCOL_VERSION);
+
+        // No overlap: refused, and the peer stays at the base version.
+        let mut c = PeaPodCore::new();
+        let future = wire::encode_frame(&Message::Hello {
+            min_version: MAX_PROTOCOL_VERSION + 1,
+            max_version: MAX_PROTOCOL_VERSION + 3,
+        })
+        .unwrap();
+        assert!(matches!(
+            c.on_message_received(a_id, &future),
+            Err(CoreError::Message {
+                source: OnMessageError::IncompatibleVersion { .. },
+                ..
+            })
+        ));
+        assert_eq!(c.peer_protocol_version(a_id), PROTOCOL_VERSION);
+        assert_eq!(protocol::negotiate_version((1, 2), (2, 5)), Some(2));
+        assert_eq!(protocol::negotiate_version((1, 1), (1, 2)), Some(1));
+    }
+
+    #[test]
+    fn capabilities_are_announced_on_connect_and_on_change() {
+        let mut a = PeaPodCore::new();
+        let mut b = PeaPodCore::new();
+        let (a_id, b_id) = (a.device_id(), b.device_id());
+        a.set_capabilities(Capabilities {
+            max_chunk_size: 4096,
+            features: FEATURE_CANCEL,
+            platform: "android".into(),
+            ..Capabilities::default()
+        });
+        let actions = a.on_peer_connected(b_id, &b.keypair.public_key().clone());
+        let announced = messages(&actions)
+            .into_iter()
+            .find(|(_, m)| matches!(m, Message::Capabilities { .. }))
+            .expect("capabilities sent");
+        let frame = wire::encode_frame(&announced.1).unwrap();
+        b.on_message_received(a_id, &frame).unwrap();
+        let known = b.peer_capabilities(a_id).unwrap();
+        assert_eq!(
+            (known.max_chunk_size, known.platform.as_str()),
+            (4096, "android")
+        );
+
+        // Joining keeps what was announced; a later change reaches members on the next tick.
+        assert!(b.on_peer_joined(a_id, a.keypair.public_key()));
+        assert!(b.peer_supports(a_id, FEATURE_CANCEL));
+        assert!(a.on_peer_joined(b_id, &b.keypair.public_key().clone()));
+        assert!(!messages(&a.tick())
+            .iter()
+            .any(|(_, m)| matches!(m, Message::Capabilities { .. })));
+        a.set_capabilities(Capabilities {
+            features: FEATURE_CANCEL | FEATURE_CONSTRAINTS,
+            ..a.capabilities().clone()
+        });
+        let update = messages(&a.tick())
+            .into_iter()
+            .find_map(|(p, m)| match m {
+                Message::Capabilities { capabilities } if p == b_id => Some(capabilities),
+                _ => None,
+            })
+            .expect("update sent");
+        b.on_message_received(
+            a_id,
+            &wire::encode_frame(&Message::Capabilities {
+                capabilities: update,
+            })
+            .unwrap(),
+        )
+        .unwrap();
+        assert!(b.peer_supports(a_id, FEATURE_CONSTRAINTS));
+
+        // Optional messages wait for the feature: B advertised nothing, so A keeps its
+        // constraints to itself.
+        assert_eq!(a.peer_capabilities(b_id).unwrap().features, 0);
+        assert!(a.set_local_constraints(true, false, None).is_empty());
+    }
+
+    #[test]
+    fn frames_of_unknown_types_are_ignored() {
+        let mut core = PeaPodCore::new();
+        let peer = Keypair::generate();
+        core.on_peer_joined(peer.device_id(), peer.public_key());
+        let mut frame = wire::encode_frame(&Message::Heartbeat {
+            device_id: peer.device_id(),
+        })
+        .unwrap();
+        frame[4] = 0xee;
+        let (actions, completed) = core.on_message_received(peer.device_id(), &frame).unwrap();
+        assert!(actions.is_empty() && completed.is_none());
+        frame.truncate(5);
+        assert!(core.on_message_received(peer.device_id(), &frame).is_err());
+    }
+
+    #[test]
+    fn tick_at_expires_peers_by_elapsed_time() {
+        let mut core = PeaPodCore::with_config(Config {
+            tick_interval_ms: 500,
+            heartbeat_timeout_ticks: 4,
+            ..Config::default()
+        });
+        assert_eq!(core.timer_intervals().peer_timeout_ms, 2000);
+        let peer = Keypair::generate();
+        core.on_peer_joined(peer.device_id(), peer.public_key());
+        core.tick_at(10_000);
+        // Called more often than the interval: only one heartbeat per interval.
+        assert_eq!(core.tick_at(10_100).len(), 0);
+        assert_eq!(core.tick_at(10_500).len(), 1);
+        // A late call catches up: 2.5 s of silence exceeds the 2 s peer timeout.
+        core.tick_at(13_000);
+        assert!(core.peers().is_empty());
+    }
+
+    #[test]
+    fn chunk_sizes_adapt_to_measured_delivery_rate() {
+        let mut core = PeaPodCore::with_config(Config {
+            chunk_size: 10,
+            min_chunk_size: 10,
+            max_chunk_size: 1000,
+            ..Config::default()
+        });
+        let peer = Keypair::generate().device_id();
+        core.on_peer_joined(peer, Keypair::generate().public_key());
+        let assignment = match core.on_incoming_request("http://example.com/f", Some((0, 39))) {
+            Action::Accelerate { assignment, .. } => assignment,
+            _ => panic!("expected Accelerate"),
+        };
+        // Nothing measured yet: uniform chunks.
+        assert!(assignment.iter().all(|(c, _)| c.end - c.start == 10));
+        for (c, _) in assignment.iter().filter(|(_, p)| *p == peer) {
+            let payload = vec![7u8; 10];
+            let data = Message::ChunkData {
+                transfer_id: c.transfer_id,
+                start: c.start,
+                end: c.end,
+                hash: integrity::hash_chunk(&payload),
+                payload,
+                compressed: false,
+                hash_algorithm: HashAlgorithm::Sha256,
+                attestation: None,
+            };
+            core.on_message_received(peer, &wire::encode_frame(&data).unwrap())
+                .unwrap();
+        }
+        assert_eq!(core.peers()[0].metrics.delivery_rate_bps, Some(10));
+        let assignment = match core.on_incoming_request("http://example.com/g", Some((0, 99))) {
+            Action::Accelerate { assignment, .. } => assignment,
+            _ => panic!("expected Accelerate"),
+        };
+        // The peer now gets ADAPTIVE_CHUNK_TARGET_SECS of work per chunk; self keeps chunk_size.
+        for (c, p) in &assignment {
+            let expected = if *p == peer { 20 } else { 10 };
+            assert!(c.end - c.start <= expected);
+        }
+        assert!(assignment
+            .iter()
+            .any(|(c, p)| *p == peer && c.end - c.start == 20));
+    }
+
+    /// Sizes chunks per worker and otherwise behaves like the default scheduler.
+    struct SizedChunks(Vec<u64>);
+
+    impl Scheduler for SizedChunks {
+        fn assign(&mut self, chunk_ids: &[ChunkId], workers: &Workers) -> Vec<(ChunkId, DeviceId)> {
+            DefaultScheduler.assign(chunk_ids, workers)
+        }
+
+        fn reassign(&mut self, chunk_id: ChunkId, workers: &Workers) -> Option<DeviceId> {
+            DefaultScheduler.reassign(chunk_id, workers)
+        }
+
+        fn chunk_sizes(&mut self, _workers: &Workers) -> Option<Vec<u64>> {
+            Some(self.0.clone())
+        }
+    }
+
+    #[test]
+    fn scheduler_sizes_chunks_per_worker() {
+        let mut core = PeaPodCore::with_config(Config {
+            adaptive_chunk_size: false,
+            endgame_chunks: 0,
+            ..Config::default()
+        })
+        .with_scheduler(Box::new(SizedChunks(vec![10, 30])));
+        let peer = Keypair::generate().device_id();
+        core.on_peer_joined(peer, Keypair::generate().public_key());
+        let (transfer_id, assignment) =
+            match core.on_incoming_request("http://example.com/f", Some((0, 99))) {
+                Action::Accelerate {
+                    transfer_id,
+                    assignment,
+                    ..
+                } => (transfer_id, assignment),
+                _ => panic!("expected Accelerate"),
+            };
+        for (c, p) in &assignment {
+            let size = if *p == peer { 30 } else { 10 };
+            assert!(c.end - c.start <= size);
+        }
+        assert!(assignment
+            .iter()
+            .any(|(c, p)| *p == peer && c.end - c.start == 30));
+
+        // Heterogeneous ranges reassemble in order; a duplicate is ignored.
+        let payload = |c: &ChunkId| -> Vec<u8> { (c.start..c.end).map(|i| i as u8).collect() };
+        let mut body = None;
+        for (c, p) in &assignment {
+            let data = payload(c);
+            let hash = integrity::hash_chunk(&data);
+            if *p == peer {
+                let frame = wire::encode_frame(&Message::ChunkData {
+                    transfer_id,
+                    start: c.start,
+                    end: c.end,
+                    hash,
+                    payload: data,
+                    compressed: false,
+                    hash_algorithm: HashAlgorithm::Sha256,
+                    attestation: None,
+                })
+                .unwrap();
+                for _ in 0..2 {
+                    let (_, done) = core.on_message_received(peer, &frame).unwrap();
+                    body = done.map(|(_, b)| b).or(body);
+                }
+            } else {
+                body = core
+                    .on_chunk_received(transfer_id, c.start, c.end, hash, data)
+                    .unwrap()
+                    .or(body);
+            }
+        }
+        assert_eq!(body, Some((0..100).map(|i| i as u8).collect()));
+    }
+
+    #[test]
+    fn chunk_requests_carry_request_context() {
+        let mut requester = PeaPodCore::with_config(Config {
+            forward_request_headers: true,
+            ..Config::default()
+        });
+        let mut server = PeaPodCore::new();
+        requester.on_peer_joined(server.device_id(), server.keypair.public_key());
+        server.on_peer_joined(requester.device_id(), requester.keypair.public_key());
+        let (transfer_id, assignment) =
+            match requester.on_incoming_request("http://example.com/f", Some((0, 999_999))) {
+                Action::Accelerate {
+                    transfer_id,
+                    assignment,
+                    ..
+                } => (transfer_id, assignment),
+                _ => panic!("expected Accelerate"),
+            };
+        let cookie = vec![("Cookie".to_string(), "session=1".to_string())];
+        assert!(!requester.set_transfer_headers([9; 16], cookie.clone()));
+        assert!(requester.set_transfer_headers(transfer_id, cookie.clone()));
+        let (chunk_id, _) = assignment[0];
+        let request = requester.chunk_request_for(chunk_id).unwrap();
+        let (actions, _) = server
+            .on_message_received(
+                requester.device_id(),
+                &wire::encode_frame(&request).unwrap(),
+            )
+            .unwrap();
+        match &actions[..] {
+            [OutboundAction::FetchChunk {
+                url,
+                headers,
+                deadline_ms,
+                ..
+            }] => {
+                assert_eq!(url, "http://example.com/f");
+                assert_eq!(headers, &cookie);
+                assert_eq!(*deadline_ms, Some(10_000));
+            }
+            _ => panic!("expected FetchChunk"),
+        }
+
+        // Headers stay home unless forwarding is on.
+        requester.config.forward_request_headers = false;
+        assert!(matches!(
+            requester.chunk_request_for(chunk_id),
+            Some(Message::ChunkRequest { headers, .. }) if headers.is_empty()
+        ));
+    }
+
+    #[test]
+    fn duplicate_requests_carry_request_context() {
+        let mut core = PeaPodCore::with_config(Config {
+            chunk_size: 10,
+            endgame_chunks: 2,
+            forward_request_headers: true,
+            ..Config::default()
+        });
+        let (a, b) = (Keypair::generate(), Keypair::generate());
+        join_capable(&mut core, a.device_id(), a.public_key());
+        join_capable(&mut core, b.device_id(), b.public_key());
+        let (transfer_id, assignment) =
+            match core.on_incoming_request("http://example.com/f", Some((0, 99))) {
+                Action::Accelerate {
+                    transfer_id,
+                    assignment,
+                    ..
+                } => (transfer_id, assignment),
+                _ => panic!("expected Accelerate"),
+            };
+        let cookie = vec![("Cookie".to_string(), "session=1".to_string())];
+        core.set_transfer_headers(transfer_id, cookie.clone());
+        let mut last = Vec::new();
+        for &(c, _) in &assignment[..8] {
+            let payload = vec![c.start as u8; 10];
+            let hash = integrity::hash_chunk(&payload);
+            last = core
+                .on_chunk_received_with_actions(transfer_id, c.start, c.end, hash, payload)
+                .actions;
+        }
+        let duplicates = chunk_requests(&last);
+        assert!(!duplicates.is_empty());
+        for (_, m) in duplicates {
+            assert!(matches!(
+                m,
+                Message::ChunkRequest { headers, deadline_ms: Some(_), .. } if headers == cookie
+            ));
+        }
+    }
+
+    #[test]
+    fn oversized_transfers_fall_back() {
+        let mut core = PeaPodCore::with_config(Config {
+            max_transfer_bytes: 1000,
+            ..Config::default()
+        });
+        let peer = Keypair::generate();
+        core.on_peer_joined(
+            DeviceId::from_public_key(peer.public_key().as_bytes()),
+            peer.public_key(),
+        );
+        assert!(matches!(
+            core.on_incoming_request("http://example.com/a.bin", Some((0, 999))),
+            Action::Accelerate { .. }
+        ));
+        assert!(matches!(
+            core.on_incoming_request("http://example.com/b.bin", Some((0, 1000))),
+            Action::Fallback
+        ));
+        assert!(core.drain_events().contains(&CoreEvent::Fallback {
+            url: "http://example.com/b.bin".to_string()
+        }));
+    }
+
+    #[test]
+    fn blake3_hashes_are_negotiated_per_peer() {
+        let mut requester = PeaPodCore::with_config(Config {
+            chunk_size: 100,
+            adaptive_chunk_size: false,
+            endgame_chunks: 0,
+            ..Config::default()
+        });
+        let mut server = PeaPodCore::new();
+        requester.on_peer_joined(server.device_id(), server.keypair.public_key());
+        server.on_peer_joined(requester.device_id(), requester.keypair.public_key());
+        let (transfer_id, assignment) =
+            match requester.on_incoming_request("http://example.com/f.bin", Some((0, 399))) {
+                Action::Accelerate {
+                    transfer_id,
+                    assignment,
+                    ..
+                } => (transfer_id, assignment),
+                _ => panic!("expected Accelerate"),
+            };
+        let served: Vec<ChunkId> = assignment
+            .iter()
+            .filter(|(_, p)| *p == server.device_id())
+            .map(|&(c, _)| c)
+            .collect();
+        let payload = vec![7u8; 100];
+        let requester_id = requester.device_id();
+        let mut serve = |server: &mut PeaPodCore, chunk_id: ChunkId| {
+            let request = requester.chunk_request_for(chunk_id).unwrap();
+            let frame = wire::encode_frame(&request).unwrap();
+            server
+                .on_message_received(requester.device_id(), &frame)
+                .unwrap();
+            let actions =
+                server.on_chunk_fetched_for_peer(requester.device_id(), chunk_id, payload.clone());
+            let [OutboundAction::SendMessage(_, frame)] = &actions[..] else {
+                panic!("expected ChunkData");
+            };
+            let (msg, _) = wire::decode_frame(frame).unwrap();
+            requester
+                .on_message_received(server.device_id(), frame)
+                .unwrap();
+            assert!(requester.transfers[&transfer_id]
+                .state
+                .is_chunk_received(chunk_id));
+            match msg {
+                Message::ChunkData {
+                    hash,
+                    hash_algorithm,
+                    ..
+                } => (hash, hash_algorithm),
+                _ => panic!("expected ChunkData"),
+            }
+        };
+        // SHA-256 until the requester advertises BLAKE3.
+        assert_eq!(
+            serve(&mut server, served[0]),
+            (integrity::hash_chunk(&payload), HashAlgorithm::Sha256)
+        );
+        server
+            .peer_mut(&requester_id)
+            .unwrap()
+            .capabilities
+            .features = FEATURE_BLAKE3;
+        assert_eq!(
+            serve(&mut server, served[1]),
+            (HashAlgorithm::Blake3.hash(&payload), HashAlgorithm::Blake3)
+        );
+        assert_ne!(
+            HashAlgorithm::Blake3.hash(&payload),
+            integrity::hash_chunk(&payload)
+        );
+    }
+
+    #[test]
+    fn compressible_chunks_travel_compressed_to_capable_peers() {
+        let config = Config {
+            chunk_size: 2000,
+            adaptive_chunk_size: false,
+            endgame_chunks: 0,
+            ..Config::default()
+        };
+        let mut requester = PeaPodCore::with_config(config.clone());
+        let mut server = PeaPodCore::with_config(config);
+        requester.on_peer_joined(server.device_id(), server.keypair.public_key());
+        server.on_peer_joined(requester.device_id(), requester.keypair.public_key());
+        let (transfer_id, assignment) =
+            match requester.on_incoming_request("http://example.com/f.txt", Some((0, 3999))) {
+                Action::Accelerate {
+                    transfer_id,
+                    assignment,
+                    ..
+                } => (transfer_id, assignment),
+                _ => panic!("expected Accelerate"),
+            };
+        let (chunk_id, _) = *assignment
+            .iter()
+            .find(|(_, p)| *p == server.device_id())
+            .unwrap();
+        let text = b"0123456789".repeat(200);
+        let serve = |server: &mut PeaPodCore| {
+            let request = requester.chunk_request_for(chunk_id).unwrap();
+            let frame = wire::encode_frame(&request).unwrap();
+            server
+                .on_message_received(requester.device_id(), &frame)
+                .unwrap();
+            let actions =
+                server.on_chunk_fetched_for_peer(requester.device_id(), chunk_id, text.clone());
+            match &actions[..] {
+                [OutboundAction::SendMessage(p, frame)] if *p == requester.device_id() => {
+                    frame.clone()
+                }
+                _ => panic!("expected ChunkData"),
+            }
+        };
+        // Without the feature bit the payload goes as it is.
+        let frame = serve(&mut server);
+        let (msg, _) = wire::decode_frame(&frame).unwrap();
+        assert!(matches!(
+            msg,
+            Message::ChunkData {
+                compressed: false,
+                hash_algorithm: HashAlgorithm::Sha256,
+                ..
+            }
+        ));
+
+        server
+            .peer_mut(&requester.device_id())
+            .unwrap()
+            .capabilities
+            .features = SUPPORTED_FEATURES;
+        let frame = serve(&mut server);
+        match wire::decode_frame(&frame).unwrap().0 {
+            Message::ChunkData {
+                compressed: true,
+                payload,
+                ..
+            } => assert!(payload.len() < text.len() / 4),
+            _ => panic!("expected compressed ChunkData"),
+        }
+
+        // Frame compression alone: the whole frame shrinks, the payload inside is plain.
+        server
+            .peer_mut(&requester.device_id())
+            .unwrap()
+            .capabilities
+            .features = FEATURE_FRAME_COMPRESSION;
+        let packed = serve(&mut server);
+        assert_ne!(
+            wire::peek_frame(&packed).unwrap().flags & wire::FLAG_COMPRESSED,
+            0
+        );
+        assert!(packed.len() < text.len() / 4);
+        match wire::decode_frame(&packed).unwrap().0 {
+            Message::ChunkData {
+                compressed: false,
+                payload,
+                ..
+            } => assert_eq!(payload, text),
+            _ => panic!("expected plain ChunkData"),
+        }
+
+        requester
+            .on_message_received(server.device_id(), &frame)
+            .unwrap();
+        assert!(requester.transfers[&transfer_id]
+            .state
+            .is_chunk_received(chunk_id));
+    }
+
+    #[test]
+    fn overdue_transfer_aborts_to_fallback() {
+        let mut core = PeaPodCore::with_config(Config {
+            chunk_size: 10,
+            transfer_deadline_ticks: 3,
+            heartbeat_timeout_ticks: 100,
+            chunk_timeout_ticks: 100,
+            ..Config::default()
+        });
+        let peer = Keypair::generate();
+        join_capable(&mut core, peer.device_id(), peer.public_key());
+        let transfer_id = match core.on_incoming_request("http://example.com/f", Some((0, 39))) {
+            Action::Accelerate { transfer_id, .. } => transfer_id,
+            _ => panic!("expected Accelerate"),
+        };
+        core.drain_events();
+        let aborted = |actions: &[OutboundAction]| {
+            actions
+                .iter()
+                .any(|a| matches!(a, OutboundAction::AbortToFallback(t) if *t == transfer_id))
+        };
+        for _ in 0..3 {
+            assert!(!aborted(&core.tick()));
+        }
+        let actions = core.tick();
+        assert!(aborted(&actions));
+        // The peer is told to drop the chunks it was asked for.
+        assert!(messages(&actions)
+            .iter()
+            .any(|(p, m)| *p == peer.device_id() && matches!(m, Message::Cancel { .. })));
+        assert!(core.transfer_progress(transfer_id).is_none());
+        assert!(matches!(
+            core.drain_events()[..],
+            [CoreEvent::Fallback { .. }]
+        ));
+    }
+
+    #[test]
+    fn paused_time_does_not_count_toward_deadline() {
+        let mut core = PeaPodCore::with_config(Config {
+            chunk_size: 10,
+            transfer_deadline_ticks: 3,
+            heartbeat_timeout_ticks: 100,
+            chunk_timeout_ticks: 100,
+            ..Config::default()
+        });
+        let peer = Keypair::generate();
+        join_capable(&mut core, peer.device_id(), peer.public_key());
+        let transfer_id = match core.on_incoming_request("http://example.com/f", Some((0, 39))) {
+            Action::Accelerate { transfer_id, .. } => transfer_id,
+            _ => panic!("expected Accelerate"),
+        };
+        let aborted = |actions: &[OutboundAction]| {
+            actions
+                .iter()
+                .any(|a| matches!(a, OutboundAction::AbortToFallback(t) if *t == transfer_id))
+        };
+        assert!(!aborted(&core.tick()));
+        assert!(core.pause_transfer(transfer_id));
+        for _ in 0..10 {
+            assert!(!aborted(&core.tick()));
+        }
+        core.resume_transfer(transfer_id);
+        // One tick ran before the pause, so two more are left.
+        for _ in 0..2 {
+            assert!(!aborted(&core.tick()));
+        }
+        assert!(aborted(&core.tick()));
+    }
+
+    #[test]
+    fn overlapping_transfers_share_in_flight_chunks() {
+        let mut core = PeaPodCore::with_config(Config {
+            chunk_size: 10,
+            adaptive_chunk_size: false,
+            endgame_chunks: 0,
+            ..Config::default()
+        });
+        let peer = Keypair::generate();
+        core.on_peer_joined(peer.device_id(), peer.public_key());
+        let first = match core.on_incoming_request("http://example.com/f", Some((0, 39))) {
+            Action::Accelerate { transfer_id, .. } => transfer_id,
+            _ => panic!("expected Accelerate"),
+        };
+        let second = match core.on_incoming_request("http://example.com/f", Some((0, 19))) {
+            Action::Accelerate {
+                transfer_id,
+                assignment,
+                ..
+            } => {
+                // Both chunks are already in flight for the first transfer.
+                assert!(assignment.is_empty());
+                transfer_id
+            }
+            _ => panic!("expected Accelerate"),
+        };
+        let mut completed = Vec::new();
+        for start in [0, 10] {
+            let payload = vec![start as u8; 10];
+            let hash = integrity::hash_chunk(&payload);
+            let outcome =
+                core.on_chunk_received_with_actions(first, start, start + 10, hash, payload);
+            completed.extend(outcome.actions.into_iter().filter_map(|a| match a {
+                OutboundAction::TransferComplete { transfer_id, body } => Some((transfer_id, body)),
+                _ => None,
+            }));
+        }
+        let mut body = vec![0u8; 10];
+        body.extend(vec![10u8; 10]);
+        assert_eq!(completed, vec![(second, body)]);
+        assert!(core.transfer_progress(second).is_none());
+        assert_eq!(core.stats().bytes_from_cache, 20);
+        assert_eq!(core.transfer_progress(first).unwrap().bytes_received, 20);
+    }
+
+    #[test]
+    fn shared_chunks_are_requested_once_their_twin_is_gone() {
+        let mut core = PeaPodCore::with_config(Config {
+            chunk_size: 10,
+            adaptive_chunk_size: false,
+            endgame_chunks: 0,
+            ..Config::default()
+        });
+        let peer = Keypair::generate();
+        core.on_peer_joined(peer.device_id(), peer.public_key());
+        let first = match core.on_incoming_request("http://example.com/f", Some((0, 39))) {
+            Action::Accelerate { transfer_id, .. } => transfer_id,
+            _ => panic!("expected Accelerate"),
+        };
+        let Action::Accelerate { transfer_id, .. } =
+            core.on_incoming_request("http://example.com/f", Some((0, 19)))
+        else {
+            panic!("expected Accelerate");
+        };
+        core.abandon_transfer(first);
+        let self_id = core.device_id();
+        let mut asked: Vec<(u64, u64)> = requested(&core.tick(), self_id)
+            .into_iter()
+            .filter(|(_, c)| c.transfer_id == transfer_id)
+            .map(|(_, c)| (c.start, c.end))
+            .collect();
+        asked.sort();
+        assert_eq!(asked, vec![(0, 10), (10, 20)]);
+    }
+
+    #[test]
+    fn failing_transfer_falls_back_with_verified_ranges() {
+        let mut core = PeaPodCore::with_config(Config {
+            chunk_size: 10,
+            adaptive_chunk_size: false,
+            endgame_chunks: 0,
+            fallback_failure_percent: 50,
+            ..Config::default()
+        });
+        let peer = Keypair::generate();
+        core.on_peer_joined(peer.device_id(), peer.public_key());
+        let transfer_id = match core.on_incoming_request("http://example.com/f", Some((100, 139))) {
+            Action::Accelerate { transfer_id, .. } => transfer_id,
+            _ => panic!("expected Accelerate"),
+        };
+        let good = vec![1u8; 10];
+        let hash = integrity::hash_chunk(&good);
+        core.on_chunk_received_with_actions(transfer_id, 100, 110, hash, good.clone())
+            .result
+            .unwrap();
+        let mut fallbacks = Vec::new();
+        for start in [110, 120, 130] {
+            let bad = wire::encode_frame(&Message::ChunkData {
+                transfer_id,
+                start,
+                end: start + 10,
+                hash: [0u8; 32],
+                payload: vec![2u8; 10],
+                compressed: false,
+                hash_algorithm: HashAlgorithm::Sha256,
+                attestation: None,
+            })
+            .unwrap();
+            let (actions, _) = core.on_message_received(peer.device_id(), &bad).unwrap();
+            fallbacks.extend(actions.into_iter().filter_map(|a| match a {
+                OutboundAction::PartialFallback {
+                    transfer_id: t,
+                    range,
+                    verified,
+                    ..
+                } if t == transfer_id => Some((start, range, verified)),
+                _ => None,
+            }));
+        }
+        // Two of four chunks failing is still tolerated; the third is not.
+        assert_eq!(
+            fallbacks,
+            vec![(
+                130,
+                (100, 140),
+                vec![Segment {
+                    offset: 0,
+                    bytes: good,
+                }]
+            )]
+        );
+        assert!(core.transfer_progress(transfer_id).is_none());
+    }
+
+    #[test]
+    fn stale_paused_transfer_is_reaped() {
+        let mut core = PeaPodCore::with_config(Config {
+            chunk_size: 10,
+            stale_transfer_ticks: 3,
+            transfer_deadline_ticks: 0,
+            heartbeat_timeout_ticks: 100,
+            chunk_timeout_ticks: 100,
+            ..Config::default()
+        });
+        let peer = Keypair::generate();
+        core.on_peer_joined(peer.device_id(), peer.public_key());
+        let transfer_id = match core.on_incoming_request("http://example.com/f", Some((0, 39))) {
+            Action::Accelerate { transfer_id, .. } => transfer_id,
+            _ => panic!("expected Accelerate"),
+        };
+        assert!(core.pause_transfer(transfer_id));
+        core.drain_events();
+        for _ in 0..3 {
+            core.tick();
+        }
+        assert!(core.transfer_progress(transfer_id).is_some());
+        let actions = core.tick();
+        assert!(actions
+            .iter()
+            .any(|a| matches!(a, OutboundAction::AbortToFallback(t) if *t == transfer_id)));
+        assert!(core.transfer_progress(transfer_id).is_none());
+        assert!(matches!(
+            core.drain_events()[..],
+            [CoreEvent::TransferReaped { transfer_id: t }] if t == transfer_id
+        ));
+    }
+
+    #[test]
+    fn abandon_transfer_cancels_peer_requests() {
+        let mut core = PeaPodCore::with_config(Config {
+            chunk_size: 10,
+            ..Config::default()
+        });
+        let peer = Keypair::generate();
+        join_capable(&mut core, peer.device_id(), peer.public_key());
+        let transfer_id = match core.on_incoming_request("http://example.com/f", Some((0, 39))) {
+            Action::Accelerate { transfer_id, .. } => transfer_id,
+            _ => panic!("expected Accelerate"),
+        };
+        core.drain_events();
+        let actions = core.abandon_transfer(transfer_id);
+        assert!(messages(&actions)
+            .iter()
+            .any(|(p, m)| *p == peer.device_id() && matches!(m, Message::Cancel { .. })));
+        assert!(!actions
+            .iter()
+            .any(|a| matches!(a, OutboundAction::AbortToFallback(_))));
+        assert!(core.transfer_progress(transfer_id).is_none());
+        assert!(matches!(
+            core.drain_events()[..],
+            [CoreEvent::TransferReaped { .. }]
+        ));
+        assert!(core.abandon_transfer(transfer_id).is_empty());
+    }
+
+    #[test]
+    fn root_mismatch_rejects_self_consistent_wrong_chunks() {
+        let mut core = PeaPodCore::with_config(Config {
+            chunk_size: 10,
+            endgame_chunks: 0,
+            ..Config::default()
+        });
+        let peer = Keypair::generate();
+        core.on_peer_joined(peer.device_id(), peer.public_key());
+        let (transfer_id, assignment) =
+            match core.on_incoming_request("http://example.com/f", Some((0, 39))) {
+                Action::Accelerate {
+                    transfer_id,
+                    assignment,
+                    ..
+                } => (transfer_id, assignment),
+                _ => panic!("expected Accelerate"),
+            };
+        let body: Vec<u8> = (0..40).collect();
+        assert!(core.set_transfer_manifest(transfer_id, Manifest::from_body(&body, 16)));
+        let mut last = None;
+        for (c, device) in assignment {
+            let mut payload = body[c.start as usize..c.end as usize].to_vec();
+            if device == peer.device_id() {
+                // The peer's chunk hash matches its payload, but the payload is wrong.
+                payload[0] ^= 0xff;
+            }
+            let hash = crate::integrity::hash_chunk(&payload);
+            last = Some(core.on_chunk_received_with_actions(
+                transfer_id,
+                c.start,
+                c.end,
+                hash,
+                payload,
+            ));
+        }
+        let outcome = last.unwrap();
+        assert_eq!(
+            outcome.result.unwrap_err().chunk_error(),
+            Some(&ChunkError::RootMismatch)
+        );
+        assert!(outcome
+            .actions
+            .iter()
+            .any(|a| matches!(a, OutboundAction::AbortToFallback(t) if *t == transfer_id)));
+        assert!(core.transfer_progress(transfer_id).is_none());
+        assert_eq!(core.peers()[0].metrics.chunks_failed, 1);
+        assert!(core
+            .drain_events()
+            .iter()
+            .any(|e| matches!(e, CoreEvent::Fallback { .. })));
+    }
+
+    #[test]
+    fn origin_digest_mismatch_aborts_transfer() {
+        let mut core = PeaPodCore::with_config(Config {
+            chunk_size: 10,
+            endgame_chunks: 0,
+            ..Config::default()
+        });
+        let peer = Keypair::generate();
+        core.on_peer_joined(peer.device_id(), peer.public_key());
+        let body: Vec<u8> = (0..40).collect();
+        let mut run = |url: &str, digest: OriginDigest| {
+            let (transfer_id, assignment) = match core.on_incoming_request(url, Some((0, 39))) {
+                Action::Accelerate {
+                    transfer_id,
+                    assignment,
+                    ..
+                } => (transfer_id, assignment),
+                _ => panic!("expected Accelerate"),
+            };
+            assert!(core.set_transfer_digests(transfer_id, vec![digest]));
+            let mut last = None;
+            for (c, _) in assignment {
+                let payload = body[c.start as usize..c.end as usize].to_vec();
+                let hash = integrity::hash_chunk(&payload);
+                last = Some(core.on_chunk_received_with_actions(
+                    transfer_id,
+                    c.start,
+                    c.end,
+                    hash,
+                    payload,
+                ));
+            }
+            last.unwrap()
+        };
+        let good = run(
+            "http://example.com/f",
+            OriginDigest::Sha256(integrity::hash_chunk(&body)),
+        );
+        assert_eq!(good.result.unwrap(), Some(body.clone()));
+        let bad = run(
+            "http://example.com/g",
+            OriginDigest::Sha256(integrity::hash_chunk(b"other")),
+        );
+        assert_eq!(
+            bad.result.unwrap_err().chunk_error(),
+            Some(&ChunkError::DigestMismatch)
+        );
+        assert!(bad
+            .actions
+            .iter()
+            .any(|a| matches!(a, OutboundAction::AbortToFallback(_))));
+    }
+
+    #[test]
+    fn corrupt_attested_chunk_is_pinned_on_its_server() {
+        let mut core = PeaPodCore::with_config(Config {
+            chunk_size: 10,
+            endgame_chunks: 0,
+            ..Config::default()
+        });
+        let peer = Keypair::generate();
+        core.on_peer_joined(peer.device_id(), peer.public_key());
+        let (transfer_id, assignment) =
+            match core.on_incoming_request("http://example.com/f", Some((0, 39))) {
+                Action::Accelerate {
+                    transfer_id,
+                    assignment,
+                    ..
+                } => (transfer_id, assignment),
+                _ => panic!("expected Accelerate"),
+            };
+        let body: Vec<u8> = (0..40).collect();
+        assert!(core.set_transfer_manifest(transfer_id, Manifest::from_body(&body, 16)));
+        let mut bad = None;
+        for (c, device) in assignment {
+            let mut payload = body[c.start as usize..c.end as usize].to_vec();
+            if device != peer.device_id() {
+                let hash = integrity::hash_chunk(&payload);
+                let _ = core.on_chunk_received(transfer_id, c.start, c.end, hash, payload);
+                continue;
+            }
+            if bad.is_none() {
+                payload[0] ^= 0xff;
+                bad = Some(c);
+            }
+            let hash = integrity::hash_chunk(&payload);
+            let signature = peer.attest_chunk(
+                core.keypair.public_key(),
+                &transfer_id,
+                (c.start, c.end),
+                &hash,
+            );
+            let frame = wire::encode_frame(&Message::ChunkData {
                 transfer_id,
-                start,
-                end,
+                start: c.start,
+                end: c.end,
                 hash,
                 payload,
-            } => match self.on_chunk_received(transfer_id, start, end, hash, payload) {
-                Ok(Some(body)) => completed = Some((transfer_id, body)),
-                Ok(None) => {}
-                Err(ChunkError::IntegrityFailed) => {
-                    let chunk_id = ChunkId {
-                        transfer_id,
-                        start,
-                        end,
-                    };
-                    actions.extend(self.reassign_single_chunk(chunk_id));
-                }
-                Err(ChunkError::UnknownTransfer) => {}
-            },
-            Message::Nack {
-                transfer_id,
+                compressed: false,
+                hash_algorithm: HashAlgorithm::Sha256,
+                attestation: Some(signature),
+            })
+            .unwrap();
+            core.on_message_received(peer.device_id(), &frame).unwrap();
+        }
+        let bad = bad.unwrap();
+        let evidence = core.drain_events().into_iter().find_map(|e| match e {
+            CoreEvent::AttestedCorruption {
+                chunk_id,
+                attestation,
+            } if chunk_id == bad => Some(attestation),
+            _ => None,
+        });
+        let evidence = evidence.expect("attested corruption reported");
+        assert_eq!(evidence.peer, peer.device_id());
+        assert!(core.keypair.verify_chunk_attestation(
+            peer.public_key(),
+            &transfer_id,
+            (bad.start, bad.end),
+            &evidence.hash,
+            &evidence.signature,
+        ));
+        assert_eq!(
+            core.peer_trust(peer.device_id())
+                .unwrap()
+                .integrity_failures,
+            1
+        );
+    }
+
+    #[test]
+    fn peer_window_releases_requests_as_chunks_verify() {
+        let mut core = PeaPodCore::with_config(Config {
+            chunk_size: 10,
+            peer_window: 2,
+            endgame_chunks: 0,
+            ..Config::default()
+        });
+        let peer = Keypair::generate();
+        core.on_peer_joined(peer.device_id(), peer.public_key());
+        let (transfer_id, assignment) =
+            match core.on_incoming_request("http://example.com/f", Some((0, 99))) {
+                Action::Accelerate {
+                    transfer_id,
+                    assignment,
+                    ..
+                } => (transfer_id, assignment),
+                _ => panic!("expected Accelerate"),
+            };
+        let to_peer: Vec<ChunkId> = assignment
+            .iter()
+            .filter(|(_, p)| *p == peer.device_id())
+            .map(|(c, _)| *c)
+            .collect();
+        // Self keeps all 5 of its chunks; the peer only gets its window.
+        assert_eq!(assignment.len(), 7);
+        assert_eq!(to_peer.len(), 2);
+        let first = to_peer[0];
+        let payload = vec![1; 10];
+        let data = Message::ChunkData {
+            transfer_id,
+            start: first.start,
+            end: first.end,
+            hash: crate::integrity::hash_chunk(&payload),
+            payload,
+            compressed: false,
+            hash_algorithm: HashAlgorithm::Sha256,
+            attestation: None,
+        };
+        let frame = wire::encode_frame(&data).unwrap();
+        let (actions, _) = core.on_message_received(peer.device_id(), &frame).unwrap();
+        let released = chunk_requests(&actions);
+        assert_eq!(released.len(), 1);
+        assert_eq!(released[0].0, peer.device_id());
+    }
+
+    #[test]
+    fn peer_window_is_shared_fairly_between_transfers() {
+        let mut core = PeaPodCore::with_config(Config {
+            chunk_size: 10,
+            adaptive_chunk_size: false,
+            peer_window: 2,
+            endgame_chunks: 0,
+            ..Config::default()
+        });
+        let peer = Keypair::generate();
+        core.on_peer_joined(peer.device_id(), peer.public_key());
+        let (large, assignment) =
+            match core.on_incoming_request("http://example.com/large", Some((0, 199))) {
+                Action::Accelerate {
+                    transfer_id,
+                    assignment,
+                    ..
+                } => (transfer_id, assignment),
+                _ => panic!("expected Accelerate"),
+            };
+        let small = match core.on_incoming_request("http://example.com/small", Some((0, 39))) {
+            Action::Accelerate { transfer_id, .. } => transfer_id,
+            _ => panic!("expected Accelerate"),
+        };
+        // The large transfer holds the peer's whole window; the small one waits behind it.
+        assert_eq!(core.transfers[&small].queued.len(), 2);
+        let first = assignment
+            .iter()
+            .find(|(_, p)| *p == peer.device_id())
+            .map(|(c, _)| *c)
+            .unwrap();
+        let payload = vec![1; 10];
+        let data = Message::ChunkData {
+            transfer_id: large,
+            start: first.start,
+            end: first.end,
+            hash: crate::integrity::hash_chunk(&payload),
+            payload,
+            compressed: false,
+            hash_algorithm: HashAlgorithm::Sha256,
+            attestation: None,
+        };
+        let frame = wire::encode_frame(&data).unwrap();
+        let (actions, _) = core.on_message_received(peer.device_id(), &frame).unwrap();
+        // The freed slot goes to the small transfer, not to the large one's long queue.
+        let released = chunk_requests(&actions);
+        assert_eq!(released.len(), 1);
+        assert!(matches!(
+            released[0].1,
+            Message::ChunkRequest { transfer_id, .. } if transfer_id == small
+        ));
+    }
+
+    #[test]
+    fn transfer_sink_takes_verified_chunks() {
+        let mut core = PeaPodCore::with_config(Config {
+            chunk_size: 10,
+            adaptive_chunk_size: false,
+            endgame_chunks: 0,
+            ..Config::default()
+        });
+        let peer = Keypair::generate();
+        core.on_peer_joined(peer.device_id(), peer.public_key());
+        let (transfer_id, assignment) =
+            match core.on_incoming_request("http://example.com/f", Some((100, 139))) {
+                Action::Accelerate {
+                    transfer_id,
+                    assignment,
+                    ..
+                } => (transfer_id, assignment),
+                _ => panic!("expected Accelerate"),
+            };
+        let file = Arc::new(std::sync::Mutex::new(std::io::Cursor::new(Vec::new())));
+        assert!(!core.set_transfer_sink([9; 16], file.clone()));
+        assert!(core.set_transfer_sink(transfer_id, file.clone()));
+        let (chunk_id, from) = *assignment
+            .iter()
+            .find(|(_, p)| *p == peer.device_id())
+            .unwrap();
+        let payload = vec![7; 10];
+        let data = Message::ChunkData {
+            transfer_id,
+            start: chunk_id.start,
+            end: chunk_id.end,
+            hash: crate::integrity::hash_chunk(&payload),
+            payload,
+            compressed: false,
+            hash_algorithm: HashAlgorithm::Sha256,
+            attestation: None,
+        };
+        core.on_message_received(from, &wire::encode_frame(&data).unwrap())
+            .unwrap();
+        let t = &core.transfers[&transfer_id];
+        assert_eq!((t.state.bytes_received(), t.buffered_bytes()), (10, 0));
+        let written = file.lock().unwrap().get_ref().clone();
+        let offset = (chunk_id.start - 100) as usize;
+        assert_eq!(written[offset..offset + 10], [7; 10]);
+    }
+
+    #[test]
+    fn serve_quota_nacks_excess_chunk_requests() {
+        let mut core = PeaPodCore::with_config(Config {
+            serve_requests_per_tick: 2,
+            serve_bytes_per_tick: 0,
+            ..Config::default()
+        });
+        let keys = Keypair::generate();
+        let peer = keys.device_id();
+        core.on_peer_joined(peer, keys.public_key());
+        let request = |start: u64| {
+            wire::encode_frame(&Message::ChunkRequest {
+                transfer_id: [1; 16],
                 start,
-                end,
-            } => {
-                let chunk_id = ChunkId {
+                end: start + 10,
+                url: Some("http://example.com/f".into()),
+                validator: None,
+                headers: Vec::new(),
+                deadline_ms: None,
+            })
+            .unwrap()
+        };
+        let mut fetches = 0;
+        let mut nacks = 0;
+        for i in 0..3 {
+            let (actions, _) = core.on_message_received(peer, &request(i * 10)).unwrap();
+            fetches += actions
+                .iter()
+                .filter(|a| matches!(a, OutboundAction::FetchChunk { .. }))
+                .count();
+            nacks += messages(&actions)
+                .iter()
+                .filter(|(p, m)| *p == peer && matches!(m, Message::Nack { .. }))
+                .count();
+        }
+        assert_eq!((fetches, nacks), (2, 1));
+        // Quota refills on the next tick.
+        core.tick();
+        let (actions, _) = core.on_message_received(peer, &request(30)).unwrap();
+        assert!(matches!(actions[0], OutboundAction::FetchChunk { .. }));
+    }
+
+    #[test]
+    fn repeated_request_is_answered_from_chunk_cache() {
+        let mut core = PeaPodCore::with_config(Config {
+            chunk_size: 10,
+            ..Config::default()
+        });
+        let peer = Keypair::generate();
+        core.on_peer_joined(peer.device_id(), peer.public_key());
+        let url = "http://example.com/f";
+        let body: Vec<u8> = (0..20).collect();
+        let transfer_id =
+            match core.on_incoming_request_with_validator(url, Some((0, 19)), Some("\"v1\"")) {
+                Action::Accelerate { transfer_id, .. } => transfer_id,
+                _ => panic!("expected Accelerate"),
+            };
+        for start in [0u64, 10] {
+            let payload = body[start as usize..start as usize + 10].to_vec();
+            let hash = integrity::hash_chunk(&payload);
+            core.on_chunk_received(transfer_id, start, start + 10, hash, payload)
+                .unwrap();
+        }
+        match core.on_incoming_request_with_validator(url, Some((0, 19)), Some("\"v1\"")) {
+            Action::Cached { body: cached } => assert_eq!(cached, body),
+            _ => panic!("expected Cached"),
+        }
+        // The resource changed: its old chunks must not be reused.
+        assert!(matches!(
+            core.on_incoming_request_with_validator(url, Some((0, 19)), Some("\"v2\"")),
+            Action::Accelerate { .. }
+        ));
+    }
+
+    #[test]
+    fn identical_chunk_requests_share_one_fetch_and_cache() {
+        let mut core = PeaPodCore::new();
+        let keys = [
+            Keypair::generate(),
+            Keypair::generate(),
+            Keypair::generate(),
+        ];
+        for k in &keys {
+            core.on_peer_joined(k.device_id(), k.public_key());
+        }
+        let [a, b, c] = keys.map(|k| k.device_id());
+        let request = |transfer_id: [u8; 16]| {
+            wire::encode_frame(&Message::ChunkRequest {
+                transfer_id,
+                start: 0,
+                end: 4,
+                url: Some("http://example.com/f".into()),
+                validator: None,
+                headers: Vec::new(),
+                deadline_ms: None,
+            })
+            .unwrap()
+        };
+        let (first, _) = core.on_message_received(a, &request([1; 16])).unwrap();
+        assert!(matches!(first[..], [OutboundAction::FetchChunk { .. }]));
+        // Retry from `a` and the same range from `b` wait on the fetch already issued.
+        let (retry, _) = core.on_message_received(a, &request([1; 16])).unwrap();
+        let (joined, _) = core.on_message_received(b, &request([2; 16])).unwrap();
+        assert!(retry.is_empty() && joined.is_empty());
+        let chunk_id = ChunkId {
+            transfer_id: [1; 16],
+            start: 0,
+            end: 4,
+        };
+        let served = core.on_chunk_fetched_for_peer(a, chunk_id, b"data".to_vec());
+        let answered: Vec<(DeviceId, [u8; 16])> = messages(&served)
+            .into_iter()
+            .filter_map(|(p, m)| match m {
+                Message::ChunkData { transfer_id, .. } => Some((p, transfer_id)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(answered, vec![(a, [1; 16]), (b, [2; 16])]);
+        // A later request for the range is answered from the cache.
+        let (cached, _) = core.on_message_received(c, &request([3; 16])).unwrap();
+        match &messages(&cached)[..] {
+            [(
+                p,
+                Message::ChunkData {
                     transfer_id,
-                    start,
-                    end,
-                };
-                actions.extend(self.reassign_single_chunk(chunk_id));
-            }
-            Message::Beacon { .. }
-            | Message::DiscoveryResponse { .. }
-            | Message::Join { .. }
-            | Message::ChunkRequest { .. } => {}
+                    payload,
+                    ..
+                },
+            )] => assert_eq!((*p, *transfer_id, &payload[..]), (c, [3; 16], &b"data"[..])),
+            other => panic!("expected cached ChunkData, got {other:?}"),
         }
-        Ok((actions, completed))
     }
 
-    /// Reassign one chunk (e.g. after Nack or integrity failure). Returns ChunkRequest(s) to new peer(s).
-    fn reassign_single_chunk(&mut self, chunk_id: ChunkId) -> Vec<OutboundAction> {
-        let mut actions = Vec::new();
-        let active = match &mut self.active_transfer {
-            Some(a) => a,
-            None => return actions,
+    #[test]
+    fn reported_throughput_weights_assignments() {
+        let mut core = PeaPodCore::with_config(Config {
+            chunk_size: 10,
+            adaptive_chunk_size: false,
+            endgame_chunks: 0,
+            peer_window: 0,
+            ..Config::default()
+        });
+        let peer = Keypair::generate();
+        core.on_peer_joined(peer.device_id(), peer.public_key());
+        core.report_self_wan_throughput(1000, 1000);
+        core.report_peer_throughput(peer.device_id(), 3000, 1000);
+        core.report_peer_throughput(peer.device_id(), 3000, 0);
+        assert_eq!(core.self_metrics.bandwidth_bytes_per_sec, Some(1000));
+        assert_eq!(
+            core.peers()[0].metrics.bandwidth_bytes_per_sec,
+            Some((3000 * 3 + 3_000_000) / 4)
+        );
+        // A fresh sample for the peer's bandwidth: three times this device's.
+        core.peer_mut(&peer.device_id())
+            .unwrap()
+            .metrics
+            .bandwidth_bytes_per_sec = None;
+        core.report_peer_throughput(peer.device_id(), 30_000, 10_000);
+        let assignment = match core.on_incoming_request("http://example.com/f", Some((0, 79))) {
+            Action::Accelerate { assignment, .. } => assignment,
+            _ => panic!("expected Accelerate"),
         };
-        let old_peer = active
-            .assignment
+        let to_peer = assignment
             .iter()
-            .find(|(c, _)| *c == chunk_id)
-            .map(|(_, p)| *p);
-        let Some(peer_left) = old_peer else {
-            return actions;
+            .filter(|(_, d)| *d == peer.device_id())
+            .count();
+        assert_eq!(to_peer, 3 * (assignment.len() - to_peer), "{assignment:?}");
+    }
+
+    #[test]
+    fn chunks_follow_measured_throughput_and_newcomers_get_the_mean() {
+        let mut core = PeaPodCore::with_config(Config {
+            chunk_size: 10,
+            adaptive_chunk_size: false,
+            endgame_chunks: 0,
+            peer_window: 0,
+            tick_interval_ms: 500,
+            ..Config::default()
+        });
+        let fiber = Keypair::generate();
+        let newcomer = Keypair::generate();
+        core.on_peer_joined(fiber.device_id(), fiber.public_key());
+        core.on_peer_joined(newcomer.device_id(), newcomer.public_key());
+        // 1000 bytes per 500 ms tick.
+        core.self_metrics.delivery_rate_bps = Some(1000);
+        core.report_peer_throughput(fiber.device_id(), 6000, 1000);
+        let workers = [core.device_id(), fiber.device_id(), newcomer.device_id()];
+        let bandwidth: Vec<Option<u64>> = core
+            .worker_metrics(&workers)
+            .iter()
+            .map(|m| m.bandwidth_bytes_per_sec)
+            .collect();
+        assert_eq!(bandwidth, vec![Some(2000), Some(6000), Some(4000)]);
+        let assignment = match core.on_incoming_request("http://example.com/f", Some((0, 119))) {
+            Action::Accelerate { assignment, .. } => assignment,
+            _ => panic!("expected Accelerate"),
+        };
+        let count = |id: DeviceId| assignment.iter().filter(|(_, d)| *d == id).count();
+        assert_eq!(
+            (count(workers[0]), count(workers[1]), count(workers[2])),
+            (2, 6, 4)
+        );
+    }
+
+    #[test]
+    fn first_chunks_go_to_the_lowest_rtt_peer() {
+        let mut core = PeaPodCore::with_config(Config {
+            chunk_size: 10,
+            adaptive_chunk_size: false,
+            endgame_chunks: 0,
+            peer_window: 0,
+            ..Config::default()
+        });
+        let far = Keypair::generate();
+        let near = Keypair::generate();
+        core.on_peer_joined(far.device_id(), far.public_key());
+        core.on_peer_joined(near.device_id(), near.public_key());
+        core.report_peer_rtt(far.device_id(), 80);
+        core.report_peer_rtt(near.device_id(), 4);
+        core.report_peer_rtt(near.device_id(), 8);
+        assert_eq!(core.peers()[1].metrics.latency_ms, Some(5));
+        let mut assignment = match core.on_incoming_request("http://example.com/f", Some((0, 59))) {
+            Action::Accelerate { assignment, .. } => assignment,
+            _ => panic!("expected Accelerate"),
+        };
+        assignment.sort_by_key(|(c, _)| c.start);
+        assert_eq!(assignment[0].1, near.device_id());
+        assert_eq!(assignment[1].1, near.device_id());
+        let count = |id: DeviceId| assignment.iter().filter(|(_, d)| *d == id).count();
+        assert_eq!(count(far.device_id()), 2);
+    }
+
+    #[test]
+    fn idle_peer_steals_queued_chunks_on_tick() {
+        let mut core = PeaPodCore::with_config(Config {
+            chunk_size: 10,
+            adaptive_chunk_size: false,
+            endgame_chunks: 0,
+            peer_window: 1,
+            ..Config::default()
+        });
+        let busy = Keypair::generate();
+        let idle = Keypair::generate();
+        core.on_peer_joined(busy.device_id(), busy.public_key());
+        let transfer_id = match core.on_incoming_request("http://example.com/f", Some((0, 79))) {
+            Action::Accelerate { transfer_id, .. } => transfer_id,
+            _ => panic!("expected Accelerate"),
         };
-        let remaining: Vec<DeviceId> = std::iter::once(self.keypair.device_id())
-            .chain(self.peers.iter().copied())
-            .filter(|&p| p != peer_left)
+        // Four chunks for the peer: one in flight, three waiting for its window.
+        assert_eq!(core.transfers[&transfer_id].queued.len(), 3);
+        core.on_peer_joined(idle.device_id(), idle.public_key());
+        let requests = chunk_requests(&core.tick());
+        // The idle peer takes the last two queued chunks, leaving both peers 20 bytes; its window
+        // admits the first of them now.
+        let ends: Vec<u64> = requests
+            .iter()
+            .map(|(peer, m)| match m {
+                Message::ChunkRequest { end, .. } if *peer == idle.device_id() => *end,
+                _ => panic!("unexpected request {m:?} to {peer:?}"),
+            })
             .collect();
-        if remaining.is_empty() {
-            return actions;
+        assert_eq!(ends, [70]);
+        let t = &core.transfers[&transfer_id];
+        let queued: Vec<(u64, Option<DeviceId>)> = t
+            .queued
+            .values()
+            .map(|&c| (c.start, t.peer_for(c)))
+            .collect();
+        assert_eq!(
+            queued,
+            [(50, Some(busy.device_id())), (70, Some(idle.device_id()))]
+        );
+
+        // Without work stealing the queue stays with its peer.
+        let mut core = PeaPodCore::with_config(Config {
+            chunk_size: 10,
+            adaptive_chunk_size: false,
+            endgame_chunks: 0,
+            peer_window: 1,
+            work_stealing: false,
+            ..Config::default()
+        });
+        core.on_peer_joined(busy.device_id(), busy.public_key());
+        core.on_incoming_request("http://example.com/f", Some((0, 79)));
+        core.on_peer_joined(idle.device_id(), idle.public_key());
+        assert!(chunk_requests(&core.tick()).is_empty());
+    }
+
+    #[test]
+    fn self_share_percent_sets_how_much_this_device_fetches() {
+        let assign = |percent| {
+            let mut core = PeaPodCore::with_config(Config {
+                chunk_size: 10,
+                adaptive_chunk_size: false,
+                endgame_chunks: 0,
+                peer_window: 0,
+                self_share_percent: Some(percent),
+                ..Config::default()
+            });
+            let a = Keypair::generate();
+            let b = Keypair::generate();
+            core.on_peer_joined(a.device_id(), a.public_key());
+            core.on_peer_joined(b.device_id(), b.public_key());
+            let assignment = match core.on_incoming_request("http://example.com/f", Some((0, 99))) {
+                Action::Accelerate { assignment, .. } => assignment,
+                _ => panic!("expected Accelerate"),
+            };
+            let own: Vec<u64> = assignment
+                .iter()
+                .filter(|(_, p)| *p == core.device_id())
+                .map(|(c, _)| c.start)
+                .collect();
+            let count = |id: DeviceId| assignment.iter().filter(|(_, p)| *p == id).count();
+            (own, count(a.device_id()), count(b.device_id()))
+        };
+        // Self takes the start of the body; the peers split the rest.
+        assert_eq!(assign(20), (vec![0, 10], 4, 4));
+        assert_eq!(assign(0), (vec![], 5, 5));
+        assert_eq!(assign(100).0.len(), 10);
+        assert_eq!(assign(200).0.len(), 10);
+    }
+
+    #[test]
+    fn self_first_chunk_pins_the_start_to_this_device() {
+        let mut core = PeaPodCore::with_config(Config {
+            chunk_size: 10,
+            adaptive_chunk_size: false,
+            endgame_chunks: 0,
+            peer_window: 0,
+            self_first_chunk: true,
+            ..Config::default()
+        });
+        let peer = Keypair::generate();
+        core.on_peer_joined(peer.device_id(), peer.public_key());
+        core.report_peer_rtt(peer.device_id(), 1);
+        let mut assignment = match core.on_incoming_request("http://example.com/f", Some((0, 59))) {
+            Action::Accelerate { assignment, .. } => assignment,
+            _ => panic!("expected Accelerate"),
+        };
+        assignment.sort_by_key(|(c, _)| c.start);
+        assert_eq!(assignment[0].1, core.device_id());
+        // The rest is still shared with the peer.
+        assert!(assignment[1..].iter().any(|(_, p)| *p == peer.device_id()));
+    }
+
+    #[test]
+    fn savings_estimate_uses_worker_bandwidth_and_uplinks() {
+        let mut core = PeaPodCore::new();
+        let rate = |bps| PeerMetrics {
+            bandwidth_bytes_per_sec: Some(bps),
+            ..PeerMetrics::default()
+        };
+        let est = core.estimate_savings(1_000_000);
+        assert_eq!(est.direct_ms, None);
+        assert_eq!(est.speedup(), None);
+        core.set_peer_metrics(core.device_id(), rate(1_000_000));
+        let est = core.estimate_savings(1_000_000);
+        assert_eq!(
+            (est.direct_ms, est.accelerated_ms, est.workers),
+            (Some(1000), Some(1000), 1)
+        );
+        let a = Keypair::generate();
+        let b = Keypair::generate();
+        core.on_peer_joined(a.device_id(), a.public_key());
+        core.on_peer_joined(b.device_id(), b.public_key());
+        core.set_peer_metrics(a.device_id(), rate(1_000_000));
+        core.set_peer_metrics(b.device_id(), rate(2_000_000));
+        let est = core.estimate_savings(1_000_000);
+        assert_eq!((est.accelerated_ms, est.workers), (Some(250), 3));
+        assert_eq!(est.speedup(), Some(4.0));
+        // Peers behind this device's uplink add nothing beyond the fastest of them.
+        for id in [core.device_id(), a.device_id(), b.device_id()] {
+            core.set_peer_uplink(id, Some("home".into()));
         }
-        let to_reassign = [chunk_id];
-        let new_assignments = scheduler::assign_chunks_to_peers(&to_reassign, &remaining);
-        active.assignment.retain(|(c, _)| *c != chunk_id);
-        for (c, new_peer) in new_assignments {
-            active.assignment.push((c, new_peer));
-            let msg = chunk::chunk_request_message(c, None);
-            if let Ok(bytes) = wire::encode_frame(&msg) {
-                actions.push(OutboundAction::SendMessage(new_peer, bytes));
+        assert_eq!(core.estimate_savings(1_000_000).accelerated_ms, Some(500));
+    }
+
+    #[test]
+    fn stats_count_local_peer_cached_and_served_bytes() {
+        let mut core = PeaPodCore::with_config(Config {
+            chunk_size: 10,
+            adaptive_chunk_size: false,
+            endgame_chunks: 0,
+            ..Config::default()
+        });
+        let peer = Keypair::generate();
+        let peer_id = peer.device_id();
+        core.on_peer_joined(peer_id, peer.public_key());
+        assert_eq!(core.stats().speedup(), None);
+        let url = "http://example.com/f";
+        let (transfer_id, assignment) = match core.on_incoming_request(url, Some((0, 39))) {
+            Action::Accelerate {
+                transfer_id,
+                assignment,
+                ..
+            } => (transfer_id, assignment),
+            _ => panic!("expected Accelerate"),
+        };
+        for (c, d) in assignment {
+            let payload = vec![c.start as u8; 10];
+            let hash = crate::integrity::hash_chunk(&payload);
+            if d == core.device_id() {
+                core.on_chunk_received(transfer_id, c.start, c.end, hash, payload)
+                    .unwrap();
+            } else {
+                let frame = wire::encode_frame(&Message::ChunkData {
+                    transfer_id,
+                    start: c.start,
+                    end: c.end,
+                    hash,
+                    payload,
+                    compressed: false,
+                    hash_algorithm: HashAlgorithm::Sha256,
+                    attestation: None,
+                })
+                .unwrap();
+                core.on_message_received(d, &frame).unwrap();
             }
         }
-        actions
+        let stats = core.stats();
+        assert_eq!(stats.bytes_fetched_locally, 20);
+        assert_eq!(stats.bytes_from_peers.get(&peer_id), Some(&20));
+        assert_eq!(stats.speedup(), Some(2.0));
+
+        // A repeat comes from the cache; serving a peer from the cache counts as served.
+        assert!(matches!(
+            core.on_incoming_request(url, Some((0, 39))),
+            Action::Cached { .. }
+        ));
+        let request = wire::encode_frame(&Message::ChunkRequest {
+            transfer_id: [9; 16],
+            start: 0,
+            end: 10,
+            url: Some(url.into()),
+            validator: None,
+            headers: Vec::new(),
+            deadline_ms: None,
+        })
+        .unwrap();
+        core.on_message_received(peer_id, &request).unwrap();
+        let stats = core.stats();
+        assert_eq!((stats.bytes_from_cache, stats.bytes_saved()), (40, 60));
+        assert_eq!(stats.bytes_served.get(&peer_id), Some(&10));
+        core.reset_stats();
+        assert_eq!(core.stats(), TrafficStats::default());
     }
-}
 
-/// Error when processing a received message (e.g. frame decode failure).
-#[derive(Debug, thiserror::Error)]
-pub enum OnMessageError {
-    #[error("decode: {0}")]
-    Decode(#[from] FrameDecodeError),
-}
+    #[test]
+    fn contribution_policy_answers_busy_outside_schedule_and_budget() {
+        let mut core = PeaPodCore::with_config(Config {
+            contribution: ContributionPolicy {
+                serve_windows: vec![(22 * 60, 6 * 60)],
+                daily_byte_budget: 25,
+            },
+            ..Config::default()
+        });
+        let core_pk = core.keypair.public_key().clone();
+        let peer = PeaPodCore::new();
+        let frame = peer.join_frame(&core_pk).unwrap();
+        core.on_message_received(peer.device_id(), &frame).unwrap();
+        let ask = |core: &mut PeaPodCore, start: u64| {
+            let request = wire::encode_frame(&Message::ChunkRequest {
+                transfer_id: [1; 16],
+                start,
+                end: start + 10,
+                url: Some("http://example.com/f".into()),
+                validator: None,
+                headers: Vec::new(),
+                deadline_ms: None,
+            })
+            .unwrap();
+            let (actions, _) = core
+                .on_message_received(peer.device_id(), &request)
+                .unwrap();
+            match &actions[..] {
+                [OutboundAction::FetchChunk { .. }] => "served",
+                _ if matches!(messages(&actions)[..], [(_, Message::Busy { .. })]) => "busy",
+                _ => "other",
+            }
+        };
+        let day = 20_000 * MINUTES_PER_DAY;
+        core.set_local_time(day + 12 * 60);
+        assert_eq!(ask(&mut core, 0), "busy", "outside the night window");
+        core.set_local_time(day + 23 * 60);
+        assert_eq!(ask(&mut core, 0), "served");
+        core.set_do_not_disturb(true);
+        assert_eq!(ask(&mut core, 10), "busy", "do not disturb");
+        core.set_do_not_disturb(false);
+        assert_eq!(ask(&mut core, 10), "served");
+        assert_eq!(ask(&mut core, 20), "busy", "daily budget spent");
+        core.set_local_time(day + MINUTES_PER_DAY + 60);
+        assert_eq!(ask(&mut core, 20), "served", "budget restarts the next day");
+    }
 
-impl Default for PeaPodCore {
-    fn default() -> Self {
-        Self::new()
+    #[test]
+    fn busy_reassigns_without_failure_and_rests_the_peer() {
+        let mut core = PeaPodCore::with_config(Config {
+            chunk_size: 10,
+            adaptive_chunk_size: false,
+            endgame_chunks: 0,
+            ..Config::default()
+        });
+        let peer = Keypair::generate();
+        core.on_peer_joined(peer.device_id(), peer.public_key());
+        let (transfer_id, chunk) =
+            match core.on_incoming_request("http://example.com/f", Some((0, 39))) {
+                Action::Accelerate {
+                    transfer_id,
+                    assignment,
+                    ..
+                } => (
+                    transfer_id,
+                    assignment
+                        .into_iter()
+                        .find(|(_, d)| *d == peer.device_id())
+                        .unwrap()
+                        .0,
+                ),
+                _ => panic!("expected Accelerate"),
+            };
+        let busy = wire::encode_frame(&Message::Busy {
+            transfer_id,
+            start: chunk.start,
+            end: chunk.end,
+        })
+        .unwrap();
+        core.on_message_received(peer.device_id(), &busy).unwrap();
+        let info = &core.peers()[0];
+        assert_eq!(info.metrics.chunks_failed, 0);
+        assert_eq!(info.busy_until, BUSY_BACKOFF_TICKS);
+        assert_eq!(
+            core.transfers[&transfer_id].peer_for(chunk),
+            Some(core.device_id())
+        );
+        assert!(!core.workers().any(|w| w == peer.device_id()));
+        core.tick_count = BUSY_BACKOFF_TICKS;
+        assert!(core.workers().any(|w| w == peer.device_id()));
     }
-}
 
-/// Error from `on_chunk_received`: unknown transfer or integrity check failed.
-#[derive(Debug, thiserror::Error)]
-pub enum ChunkError {
-    #[error("unknown transfer")]
-    UnknownTransfer,
-    #[error("integrity check failed")]
-    IntegrityFailed,
-}
+    #[test]
+    fn reciprocity_declines_freeloaders_until_they_contribute() {
+        let mut core = PeaPodCore::with_config(Config {
+            chunk_size: 10,
+            reciprocity: ReciprocityPolicy::Decline,
+            freeloader_grace_bytes: 10,
+            ..Config::default()
+        });
+        let peer = Keypair::generate();
+        core.on_peer_joined(peer.device_id(), peer.public_key());
+        let request = |start: u64| {
+            wire::encode_frame(&Message::ChunkRequest {
+                transfer_id: [1; 16],
+                start,
+                end: start + 10,
+                url: Some("http://example.com/f".into()),
+                validator: None,
+                headers: Vec::new(),
+                deadline_ms: None,
+            })
+            .unwrap()
+        };
+        let served = |actions: &[OutboundAction]| {
+            matches!(actions.first(), Some(OutboundAction::FetchChunk { .. }))
+        };
+        let (a, _) = core
+            .on_message_received(peer.device_id(), &request(0))
+            .unwrap();
+        assert!(served(&a), "within grace");
+        let (a, _) = core
+            .on_message_received(peer.device_id(), &request(10))
+            .unwrap();
+        assert!(!served(&a), "freeloader declined");
 
-/// Outcome of processing a received chunk: result and any outbound actions (e.g. reassign on failure).
-#[derive(Debug)]
-pub struct ChunkReceiveOutcome {
-    pub result: Result<Option<Vec<u8>>, ChunkError>,
-    pub actions: Vec<OutboundAction>,
-}
+        // The peer delivers a chunk of our transfer; its balance is even again.
+        let (transfer_id, chunk) =
+            match core.on_incoming_request("http://example.com/g", Some((0, 99))) {
+                Action::Accelerate {
+                    transfer_id,
+                    assignment,
+                    ..
+                } => (transfer_id, assignment[1].0),
+                _ => panic!("expected Accelerate"),
+            };
+        let payload = vec![3; 10];
+        let data = Message::ChunkData {
+            transfer_id,
+            start: chunk.start,
+            end: chunk.end,
+            hash: crate::integrity::hash_chunk(&payload),
+            payload,
+            compressed: false,
+            hash_algorithm: HashAlgorithm::Sha256,
+            attestation: None,
+        };
+        core.on_message_received(peer.device_id(), &wire::encode_frame(&data).unwrap())
+            .unwrap();
+        assert_eq!(
+            core.credit_ledger(),
+            vec![(
+                peer.device_id(),
+                PeerCredit {
+                    bytes_served: 10,
+                    bytes_received: 10
+                }
+            )]
+        );
+        let (a, _) = core
+            .on_message_received(peer.device_id(), &request(10))
+            .unwrap();
+        assert!(served(&a));
+    }
 
-/// Result of `on_incoming_request`: accelerate (with chunk assignment) or fall back to normal path.
-pub enum Action {
-    /// Core produced a chunk plan; host fetches self chunks via WAN and sends ChunkRequest to peers.
-    Accelerate {
-        transfer_id: [u8; 16],
-        total_length: u64,
-        assignment: Vec<(ChunkId, DeviceId)>,
-    },
-    /// Do not accelerate; host forwards the request normally.
-    Fallback,
-}
+    #[test]
+    fn local_constraints_are_broadcast_and_peer_constraints_stored() {
+        let mut core = PeaPodCore::new();
+        let a = Keypair::generate();
+        join_capable(&mut core, a.device_id(), a.public_key());
+        let sent = messages(&core.set_local_constraints(true, false, Some(1000)));
+        assert!(matches!(
+            sent.as_slice(),
+            [(
+                _,
+                Message::Constraints {
+                    battery_low: true,
+                    metered: false,
+                    max_contribution_bps: Some(1000)
+                }
+            )]
+        ));
+        // A peer joining later is told on the next tick, once.
+        let b = Keypair::generate();
+        join_capable(&mut core, b.device_id(), b.public_key());
+        let told = |actions: &[OutboundAction]| {
+            messages(actions)
+                .into_iter()
+                .filter(|(_, m)| matches!(m, Message::Constraints { .. }))
+                .map(|(p, _)| p)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(told(&core.tick()), vec![b.device_id()]);
+        assert!(told(&core.tick()).is_empty());
 
-/// Instruction for the host: send a message to a peer (e.g. ChunkRequest, Heartbeat, Leave).
-#[derive(Debug)]
-pub enum OutboundAction {
-    /// Send the given bytes to the peer over the local transport (host encrypts if required).
-    SendMessage(DeviceId, Vec<u8>),
-}
+        let frame = wire::encode_frame(&Message::Constraints {
+            battery_low: false,
+            metered: true,
+            max_contribution_bps: None,
+        })
+        .unwrap();
+        core.on_message_received(a.device_id(), &frame).unwrap();
+        assert!(core.peers()[0].metrics.constraints.metered);
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::chunk::split_into_chunks;
-    use crate::integrity;
+    #[test]
+    fn update_config_keeps_active_chunk_plan() {
+        let mut core = PeaPodCore::with_config(Config {
+            chunk_size: 10,
+            ..Config::default()
+        });
+        let peer = Keypair::generate();
+        core.on_peer_joined(peer.device_id(), peer.public_key());
+        let transfer_id = match core.on_incoming_request("http://example.com/a", Some((0, 99))) {
+            Action::Accelerate { transfer_id, .. } => transfer_id,
+            _ => panic!("expected Accelerate"),
+        };
+        core.update_config(Config {
+            chunk_size: 50,
+            ..Config::default()
+        });
+        assert_eq!(core.config().chunk_size, 50);
+        assert_eq!(
+            core.transfer_progress(transfer_id).unwrap().chunks_total,
+            10
+        );
+        match core.on_incoming_request("http://example.com/b", Some((0, 99))) {
+            Action::Accelerate { transfer_id, .. } => {
+                assert_eq!(core.transfer_progress(transfer_id).unwrap().chunks_total, 2)
+            }
+            _ => panic!("expected Accelerate"),
+        }
+    }
 
     #[test]
-    fn integration_request_then_receive_chunks() {
-        let kp = Keypair::generate();
-        let mut core = PeaPodCore::with_keypair(kp);
-        let peer_id = Keypair::generate().device_id();
-        core.on_peer_joined(peer_id, &Keypair::generate().public_key().clone());
+    fn streaming_segments_then_remainder_on_completion() {
+        let mut core = PeaPodCore::with_config(Config {
+            chunk_size: 10,
+            ..Config::default()
+        });
+        let peer = Keypair::generate();
+        core.on_peer_joined(peer.device_id(), peer.public_key());
+        let transfer_id = match core.on_incoming_request("http://example.com/f", Some((0, 29))) {
+            Action::Accelerate { transfer_id, .. } => transfer_id,
+            _ => panic!("expected Accelerate"),
+        };
+        let body: Vec<u8> = (0..30u8).collect();
+        let feed = |core: &mut PeaPodCore, start: usize| {
+            let p = body[start..start + 10].to_vec();
+            let hash = integrity::hash_chunk(&p);
+            core.on_chunk_received(transfer_id, start as u64, start as u64 + 10, hash, p)
+                .unwrap()
+        };
+        assert!(feed(&mut core, 10).is_none());
+        assert!(core.take_segment(transfer_id).is_none());
+        assert!(feed(&mut core, 0).is_none());
+        let seg = core.take_segment(transfer_id).unwrap();
+        assert_eq!((seg.offset, seg.bytes.as_slice()), (0, &body[..20]));
+        assert_eq!(feed(&mut core, 20).unwrap(), body[20..].to_vec());
+    }
 
-        let range = (0u64, 99u64);
-        let total = 100u64;
-        let action = core.on_incoming_request("http://example.com/file", Some(range));
-        let transfer_id = match &action {
-            Action::Accelerate {
-                transfer_id,
-                total_length,
-                assignment: _,
-            } => {
-                assert_eq!(*total_length, total);
-                *transfer_id
-            }
-            Action::Fallback => panic!("expected Accelerate"),
+    #[test]
+    fn streamed_transfer_with_manifest_completes() {
+        let mut core = PeaPodCore::with_config(Config {
+            chunk_size: 10,
+            ..Config::default()
+        });
+        let peer = Keypair::generate();
+        core.on_peer_joined(peer.device_id(), peer.public_key());
+        let transfer_id = match core.on_incoming_request("http://example.com/f", Some((0, 29))) {
+            Action::Accelerate { transfer_id, .. } => transfer_id,
+            _ => panic!("expected Accelerate"),
+        };
+        let body: Vec<u8> = (0..30u8).collect();
+        assert!(core.set_transfer_manifest(transfer_id, Manifest::from_body(&body, 16)));
+        let feed = |core: &mut PeaPodCore, start: usize| {
+            let p = body[start..start + 10].to_vec();
+            let hash = integrity::hash_chunk(&p);
+            core.on_chunk_received(transfer_id, start as u64, start as u64 + 10, hash, p)
         };
+        assert!(feed(&mut core, 0).unwrap().is_none());
+        assert_eq!(core.take_segment(transfer_id).unwrap().bytes, body[..10]);
+        assert!(feed(&mut core, 10).unwrap().is_none());
+        // Only the remainder is held at completion: not compared against the root.
+        assert_eq!(feed(&mut core, 20).unwrap().unwrap(), body[10..].to_vec());
+        assert!(!core
+            .drain_events()
+            .iter()
+            .any(|e| matches!(e, CoreEvent::Fallback { .. })));
+    }
 
-        let chunk_ids = split_into_chunks(transfer_id, total, crate::chunk::DEFAULT_CHUNK_SIZE);
-        for &chunk_id in &chunk_ids {
-            let payload: Vec<u8> = (chunk_id.start..chunk_id.end).map(|j| j as u8).collect();
-            let hash = integrity::hash_chunk(&payload);
-            let r =
-                core.on_chunk_received(transfer_id, chunk_id.start, chunk_id.end, hash, payload);
-            if let Ok(Some(bytes)) = r {
-                assert_eq!(bytes.len(), 100);
-                for (j, &b) in bytes.iter().enumerate() {
-                    assert_eq!(b, j as u8);
+    #[test]
+    fn memory_budget_defers_requests_until_host_takes_data() {
+        let mut core = PeaPodCore::with_config(Config {
+            chunk_size: 10,
+            max_transfer_buffer_bytes: 20,
+            ..Config::default()
+        });
+        let peer = Keypair::generate();
+        core.on_peer_joined(peer.device_id(), peer.public_key());
+        let (transfer_id, assignment) =
+            match core.on_incoming_request("http://example.com/f", Some((0, 99))) {
+                Action::Accelerate {
+                    transfer_id,
+                    assignment,
+                    ..
+                } => (transfer_id, assignment),
+                _ => panic!("expected Accelerate"),
+            };
+        assert_eq!(
+            assignment.len(),
+            2,
+            "only the budgeted chunks are issued up front"
+        );
+        assert!(core.transfer_progress(transfer_id).unwrap().backpressured);
+        assert!(core.drain_events().contains(&CoreEvent::Backpressure {
+            transfer_id,
+            buffered_bytes: 0
+        }));
+
+        let payload = vec![0u8; 10];
+        let hash = integrity::hash_chunk(&payload);
+        core.on_chunk_received(transfer_id, 0, 10, hash, payload)
+            .unwrap();
+        assert!(
+            chunk_requests(&core.tick()).is_empty(),
+            "buffered chunk still counts"
+        );
+        assert_eq!(core.take_segment(transfer_id).unwrap().bytes.len(), 10);
+        let self_id = core.device_id();
+        let released = requested(&core.tick(), self_id);
+        assert_eq!(released.len(), 1);
+        assert_eq!(released[0].1.start, 20);
+    }
+
+    #[test]
+    fn held_back_self_chunks_are_fetched_by_the_host() {
+        let mut core = PeaPodCore::with_config(Config {
+            chunk_size: 10,
+            max_transfer_buffer_bytes: 20,
+            ..Config::default()
+        });
+        let self_id = core.device_id();
+        let peer = Keypair::generate();
+        core.on_peer_joined(peer.device_id(), peer.public_key());
+        let (transfer_id, assignment) =
+            match core.on_incoming_request("http://example.com/f", Some((0, 79))) {
+                Action::Accelerate {
+                    transfer_id,
+                    assignment,
+                    ..
+                } => (transfer_id, assignment),
+                _ => panic!("expected Accelerate"),
+            };
+        assert_eq!(assignment.len(), 2);
+        let mut chunks: Vec<ChunkId> = assignment.into_iter().map(|(c, _)| c).collect();
+
+        // Released chunks of this device come out as fetches for it, never as messages to itself.
+        let (mut fetched, mut delivered) = (0, None);
+        while let Some(c) = chunks.pop() {
+            let payload = vec![c.start as u8; 10];
+            let mut actions = core.on_chunk_fetched_for_peer(self_id, c, payload);
+            core.take_segment(transfer_id);
+            actions.extend(core.tick());
+            assert!(messages(&actions).iter().all(|(p, _)| *p != self_id));
+            for action in &actions {
+                match action {
+                    OutboundAction::FetchChunk {
+                        peer,
+                        chunk_id,
+                        url,
+                        ..
+                    } => {
+                        assert_eq!((*peer, url.as_str()), (self_id, "http://example.com/f"));
+                        fetched += 1;
+                        chunks.push(*chunk_id);
+                    }
+                    OutboundAction::TransferComplete { transfer_id: t, .. } => delivered = Some(*t),
+                    _ => {}
+                }
+            }
+            for (_, m) in chunk_requests(&actions) {
+                if let Message::ChunkRequest { start, end, .. } = m {
+                    chunks.push(ChunkId {
+                        transfer_id,
+                        start,
+                        end,
+                    });
                 }
-                return;
             }
         }
-        panic!("transfer should complete after receiving all chunks");
+        assert!(fetched > 0);
+        assert_eq!(delivered, Some(transfer_id));
     }
 }
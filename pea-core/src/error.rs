@@ -0,0 +1,187 @@
+//! Errors returned by the core's public API.
+//!
+//! Every fallible [`PeaPodCore`](crate::PeaPodCore) method returns [`CoreError`], which wraps the
+//! error of the failing step together with the transfer or peer it concerns. The free functions
+//! in [`wire`](crate::wire) and [`identity`](crate::identity) keep their specific errors, which
+//! convert into `CoreError` with `?`.
+
+use crate::identity::{DeviceId, WireCryptoError};
+use crate::wire::{FrameDecodeError, FrameEncodeError};
+
+/// Any error from the core, with the transfer or peer it concerns.
+#[derive(Debug, thiserror::Error)]
+pub enum CoreError {
+    /// Processing a chunk of `transfer_id` failed.
+    #[error("transfer {}: {source}", hex(transfer_id))]
+    Chunk {
+        transfer_id: [u8; 16],
+        source: ChunkError,
+    },
+    /// A message from `peer` was rejected.
+    #[error("peer {peer}: {source}")]
+    Message {
+        peer: DeviceId,
+        source: OnMessageError,
+    },
+    /// The identity `peer` presented in the transport handshake was refused.
+    #[error("peer {peer}: {source}")]
+    Handshake {
+        peer: DeviceId,
+        source: HandshakeError,
+    },
+    #[error("snapshot: {0}")]
+    Snapshot(#[from] SnapshotError),
+    #[error("frame encode: {0}")]
+    FrameEncode(#[from] FrameEncodeError),
+    #[error("frame decode: {0}")]
+    FrameDecode(#[from] FrameDecodeError),
+    #[error("wire crypto: {0}")]
+    Crypto(#[from] WireCryptoError),
+}
+
+impl CoreError {
+    /// Transfer the error concerns, if any.
+    pub fn transfer_id(&self) -> Option<[u8; 16]> {
+        match self {
+            CoreError::Chunk { transfer_id, .. } => Some(*transfer_id),
+            _ => None,
+        }
+    }
+
+    /// Peer the error concerns, if any.
+    pub fn peer(&self) -> Option<DeviceId> {
+        match self {
+            CoreError::Message { peer, .. } | CoreError::Handshake { peer, .. } => Some(*peer),
+            _ => None,
+        }
+    }
+
+    /// The chunk error, if processing a chunk failed.
+    pub fn chunk_error(&self) -> Option<&ChunkError> {
+        match self {
+            CoreError::Chunk { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Error when processing a received message (e.g. frame decode failure).
+#[derive(Debug, thiserror::Error)]
+pub enum OnMessageError {
+    #[error("decode: {0}")]
+    Decode(#[from] FrameDecodeError),
+    /// JoinRequest or JoinAccept whose device ID, public key or signature did not match, from a
+    /// revoked device, with a key other than the one pinned for the device, or with a name record
+    /// that does not verify.
+    #[error("join rejected: identity mismatch")]
+    JoinRejected,
+    /// Leave from a device other than the sender, or whose signature did not match.
+    #[error("leave rejected: identity mismatch")]
+    LeaveRejected,
+    /// PairConfirm from an unknown device or for a different pairing code.
+    #[error("pairing confirmation rejected")]
+    PairingRejected,
+    /// RotateKey from an unknown device, to a revoked one, or with proofs that do not verify.
+    #[error("key rotation rejected")]
+    RotationRejected,
+    /// Revoke whose signature does not verify, or issued by a device that is neither paired nor
+    /// the revoked one.
+    #[error("revocation rejected")]
+    RevocationRejected,
+    /// PodKey from a device that is not paired, or that does not unwrap.
+    #[error("pod key rejected")]
+    PodKeyRejected,
+    /// Hello whose version range does not overlap this build's.
+    #[error("no common protocol version (peer speaks {min}..={max})")]
+    IncompatibleVersion { min: u8, max: u8 },
+}
+
+/// Transport handshake failed ([`Handshake::read`](crate::handshake::Handshake::read)), or the
+/// identity a peer presented was refused by
+/// [`PeaPodCore::check_handshake`](crate::PeaPodCore::check_handshake).
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum HandshakeError {
+    /// The peer speaks another handshake version (1: the unauthenticated key swap).
+    #[error("unsupported handshake version {0}")]
+    Version(u8),
+    /// A handshake message of the wrong size, or read after the handshake finished.
+    #[error("malformed handshake message")]
+    Malformed,
+    /// The peer could not prove it holds the secret key for the identity it sent, or a message
+    /// was altered in flight.
+    #[error("handshake authentication failed")]
+    Authentication,
+    /// The device ID is not derived from the public key.
+    #[error("device id does not match public key")]
+    IdentityMismatch,
+    /// A known device presented a different key than the one pinned for it.
+    #[error("public key differs from pinned key")]
+    KeyChanged,
+    /// The host revoked this device.
+    #[error("device revoked")]
+    Revoked,
+}
+
+/// Error restoring a [`PeaPodCore::snapshot`](crate::PeaPodCore::snapshot).
+#[derive(Debug, thiserror::Error)]
+pub enum SnapshotError {
+    #[error("decode: {0}")]
+    Decode(#[from] bincode::Error),
+    /// Snapshot written by an incompatible version of the core.
+    #[error("unsupported snapshot version {0}")]
+    Version(u8),
+}
+
+/// Error from `on_chunk_received`: unknown transfer, chunk integrity failure or whole-body root
+/// or origin digest mismatch.
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum ChunkError {
+    #[error("unknown transfer")]
+    UnknownTransfer,
+    #[error("integrity check failed")]
+    IntegrityFailed,
+    /// The reassembled body does not match the transfer's manifest; the transfer was aborted.
+    #[error("transfer root mismatch")]
+    RootMismatch,
+    /// The reassembled body does not match the digest the origin published; the transfer was
+    /// aborted.
+    #[error("origin digest mismatch")]
+    DigestMismatch,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn errors_carry_their_transfer_or_peer() {
+        let peer = DeviceId::from_bytes([0xab; 16]);
+        let err = CoreError::Handshake {
+            peer,
+            source: HandshakeError::KeyChanged,
+        };
+        assert_eq!(err.peer(), Some(peer));
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "peer {}: public key differs from pinned key",
+                "ab".repeat(16)
+            )
+        );
+        let err = CoreError::Chunk {
+            transfer_id: [1; 16],
+            source: ChunkError::IntegrityFailed,
+        };
+        assert_eq!(
+            (err.transfer_id(), err.chunk_error()),
+            (Some([1; 16]), Some(&ChunkError::IntegrityFailed))
+        );
+        assert!(std::error::Error::source(&err).is_some());
+        let err: CoreError = FrameDecodeError::TooLarge.into();
+        assert!(err.peer().is_none() && err.transfer_id().is_none());
+    }
+}
@@ -4,9 +4,13 @@
 use std::ffi::c_void;
 use std::os::raw::c_int;
 use std::slice;
+use std::sync::Mutex;
 
-use crate::identity::{decrypt_wire, encrypt_wire, DeviceId, PublicKey};
-use crate::protocol::{Message, PROTOCOL_VERSION};
+use zeroize::Zeroizing;
+
+use crate::handshake::{Handshake, HandshakeStep, Session};
+use crate::identity::{DeviceId, PublicKey, RekeyPolicy, SessionCipher};
+use crate::protocol::{is_supported_version, Message, PROTOCOL_VERSION};
 use crate::wire::decode_frame;
 use crate::{Action, PeaPodCore};
 
@@ -130,7 +134,7 @@ pub extern "C" fn pea_core_decode_discovery_frame(
             public_key,
             listen_port,
         } => {
-            if *protocol_version != PROTOCOL_VERSION {
+            if !is_supported_version(*protocol_version) {
                 return -1;
             }
             unsafe {
@@ -144,103 +148,217 @@ pub extern "C" fn pea_core_decode_discovery_frame(
     }
 }
 
-const HANDSHAKE_SIZE: usize = 1 + 16 + 32;
+/// A handshake in progress for an FFI host; holds the session once the peer is authenticated.
+struct FfiHandshake {
+    handshake: Handshake,
+    session: Option<Session>,
+}
+
+/// Frame encryption for one connection. Sealing and opening may run on different threads.
+type FfiSessionCipher = Mutex<SessionCipher>;
 
-/// Fill out_buf with handshake bytes (49: version + device_id + public_key). Returns 0 on success, -1 on error.
+/// Start a handshake on a connection this device opened. Writes the first message to out_buf (its
+/// length to out_len); send it, then drive the handshake with `pea_core_handshake_read`. Returns
+/// an opaque handshake handle, or null on error (e.g. out_buf too small).
 #[no_mangle]
-pub extern "C" fn pea_core_handshake_bytes(
+pub extern "C" fn pea_core_handshake_initiate(
+    out_buf: *mut u8,
+    out_buf_len: usize,
+    out_len: *mut usize,
+) -> *mut c_void {
+    if out_buf.is_null() || out_len.is_null() {
+        return std::ptr::null_mut();
+    }
+    let (handshake, msg1) = Handshake::initiate();
+    if msg1.len() > out_buf_len {
+        return std::ptr::null_mut();
+    }
+    unsafe {
+        out_buf.copy_from_nonoverlapping(msg1.as_ptr(), msg1.len());
+        *out_len = msg1.len();
+    }
+    let hs = FfiHandshake {
+        handshake,
+        session: None,
+    };
+    Box::into_raw(Box::new(hs)) as *mut c_void
+}
+
+/// Start a handshake on a connection this device accepted; the initiator speaks first. Returns an
+/// opaque handshake handle.
+#[no_mangle]
+pub extern "C" fn pea_core_handshake_respond() -> *mut c_void {
+    let hs = FfiHandshake {
+        handshake: Handshake::respond(),
+        session: None,
+    };
+    Box::into_raw(Box::new(hs)) as *mut c_void
+}
+
+/// Bytes the next `pea_core_handshake_read` expects; 0 once finished, -1 if hs is null.
+#[no_mangle]
+pub extern "C" fn pea_core_handshake_next_len(hs: *mut c_void) -> c_int {
+    if hs.is_null() {
+        return -1;
+    }
+    let hs = unsafe { &*(hs as *const FfiHandshake) };
+    hs.handshake.next_len() as c_int
+}
+
+/// Process the peer's next handshake message (exactly `pea_core_handshake_next_len` bytes). Writes
+/// the reply to send, if any, to out_buf and sets *out_done to 1 once the peer is authenticated and
+/// accepted by the core (see `PeaPodCore::check_handshake`). Returns reply bytes written (0 if
+/// none), or -1 on error, after which the connection should be closed.
+#[no_mangle]
+pub extern "C" fn pea_core_handshake_read(
     h: *mut c_void,
+    hs: *mut c_void,
+    msg: *const u8,
+    msg_len: usize,
     out_buf: *mut u8,
     out_buf_len: usize,
+    out_done: *mut u8,
 ) -> c_int {
-    if h.is_null() || out_buf.is_null() || out_buf_len < HANDSHAKE_SIZE {
+    if h.is_null() || hs.is_null() || msg.is_null() || out_buf.is_null() || out_done.is_null() {
         return -1;
     }
     let core = unsafe { &*(h as *const PeaPodCore) };
-    let bytes = core.handshake_bytes();
+    let hs = unsafe { &mut *(hs as *mut FfiHandshake) };
+    let msg = unsafe { slice::from_raw_parts(msg, msg_len) };
+    let (reply, session) = match hs.handshake.read(core.device_key(), msg) {
+        Ok(HandshakeStep::Reply(reply)) => (Some(reply), None),
+        Ok(HandshakeStep::Done { reply, session }) => (reply, Some(session)),
+        Err(_) => return -1,
+    };
+    if let Some(session) = &session {
+        if core
+            .check_handshake(session.peer_id, &session.peer_public)
+            .is_err()
+        {
+            return -1;
+        }
+    }
+    let reply = reply.unwrap_or_default();
+    if reply.len() > out_buf_len {
+        return -1;
+    }
     unsafe {
-        out_buf.copy_from_nonoverlapping(bytes.as_ptr(), HANDSHAKE_SIZE);
+        out_buf.copy_from_nonoverlapping(reply.as_ptr(), reply.len());
+        *out_done = u8::from(session.is_some());
     }
-    0
+    hs.session = session;
+    reply.len() as c_int
 }
 
-/// Derive session key for a peer. Fills out_session_key_32 (32 bytes). Returns 0 on success, -1 on error.
+/// The authenticated peer of a finished handshake. Fills device_id (16) and public_key (32).
+/// Returns 0 on success, -1 if the handshake is not done.
 #[no_mangle]
-pub extern "C" fn pea_core_session_key(
-    h: *mut c_void,
-    peer_public_key_32: *const u8,
-    out_session_key_32: *mut u8,
+pub extern "C" fn pea_core_handshake_peer(
+    hs: *mut c_void,
+    out_device_id_16: *mut u8,
+    out_public_key_32: *mut u8,
 ) -> c_int {
-    if h.is_null() || peer_public_key_32.is_null() || out_session_key_32.is_null() {
+    if hs.is_null() || out_device_id_16.is_null() || out_public_key_32.is_null() {
         return -1;
     }
-    let core = unsafe { &*(h as *const PeaPodCore) };
-    let pk = unsafe { slice::from_raw_parts(peer_public_key_32, 32) };
-    let mut arr = [0u8; 32];
-    arr.copy_from_slice(pk);
-    let peer_public = PublicKey::from_bytes(arr);
-    let key = core.session_key(&peer_public);
+    let hs = unsafe { &*(hs as *const FfiHandshake) };
+    let Some(session) = &hs.session else {
+        return -1;
+    };
     unsafe {
-        out_session_key_32.copy_from_nonoverlapping(key.as_ptr(), 32);
+        out_device_id_16.copy_from_nonoverlapping(session.peer_id.as_bytes().as_ptr(), 16);
+        out_public_key_32.copy_from_nonoverlapping(session.peer_public.as_bytes().as_ptr(), 32);
     }
     0
 }
 
-/// Encrypt plaintext for wire. Output is ciphertext (plain_len + 16 for tag). Returns bytes written, or -1 on error.
+/// Destroy a handshake handle (and the session keys it holds). No-op if hs is null.
+#[no_mangle]
+pub extern "C" fn pea_core_handshake_destroy(hs: *mut c_void) {
+    if hs.is_null() {
+        return;
+    }
+    let _ = unsafe { Box::from_raw(hs as *mut FfiHandshake) };
+}
+
+/// Frame encryption for a finished handshake (see `SessionCipher`), with the default rekey policy.
+/// now_ms is the host's monotonic clock, as for `pea_core_tick_at`. Returns an opaque cipher
+/// handle, or null if the handshake is not done.
+#[no_mangle]
+pub extern "C" fn pea_core_session_cipher(hs: *mut c_void, now_ms: u64) -> *mut c_void {
+    if hs.is_null() {
+        return std::ptr::null_mut();
+    }
+    let hs = unsafe { &*(hs as *const FfiHandshake) };
+    let Some(session) = &hs.session else {
+        return std::ptr::null_mut();
+    };
+    let cipher: FfiSessionCipher =
+        Mutex::new(SessionCipher::new(session, RekeyPolicy::default(), now_ms));
+    Box::into_raw(Box::new(cipher)) as *mut c_void
+}
+
+/// Encrypt one frame for the peer. Writes what to send on the stream to out_buf: one or two
+/// (length u32 LE ‖ ciphertext) records, a Rekey first when the send key is due. Returns bytes
+/// written, or -1 on error (e.g. out_buf too small; plain_len + 64 is enough with a Rekey).
 #[no_mangle]
-pub extern "C" fn pea_core_encrypt_wire(
-    session_key_32: *const u8,
-    nonce: u64,
+pub extern "C" fn pea_core_session_seal(
+    cipher: *mut c_void,
     plain: *const u8,
     plain_len: usize,
+    now_ms: u64,
     out_buf: *mut u8,
     out_buf_len: usize,
 ) -> c_int {
-    if session_key_32.is_null() || plain.is_null() || out_buf.is_null() {
+    if cipher.is_null() || plain.is_null() || out_buf.is_null() {
         return -1;
     }
-    let key = unsafe { slice::from_raw_parts(session_key_32, 32) };
-    if key.len() != 32 {
+    let cipher = unsafe { &*(cipher as *const FfiSessionCipher) };
+    let plain = unsafe { slice::from_raw_parts(plain, plain_len) };
+    let Ok(frames) = cipher.lock().map(|mut c| c.seal(plain, now_ms)) else {
         return -1;
-    }
-    let mut key_arr = [0u8; 32];
-    key_arr.copy_from_slice(key);
-    let plain_slice = unsafe { slice::from_raw_parts(plain, plain_len) };
-    let cipher = match encrypt_wire(&key_arr, nonce, plain_slice) {
-        Ok(c) => c,
-        Err(_) => return -1,
     };
-    if cipher.len() > out_buf_len {
+    let Ok(frames) = frames else {
+        return -1;
+    };
+    let need: usize = frames.iter().map(|f| 4 + f.len()).sum();
+    if need > out_buf_len {
         return -1;
     }
-    unsafe {
-        out_buf.copy_from_nonoverlapping(cipher.as_ptr(), cipher.len());
+    let buf = unsafe { slice::from_raw_parts_mut(out_buf, out_buf_len) };
+    let mut off = 0;
+    for frame in frames {
+        buf[off..off + 4].copy_from_slice(&(frame.len() as u32).to_le_bytes());
+        off += 4;
+        buf[off..off + frame.len()].copy_from_slice(&frame);
+        off += frame.len();
     }
-    cipher.len() as c_int
+    off as c_int
 }
 
-/// Decrypt ciphertext from wire. Output is plaintext (cipher_len - 16). Returns bytes written, or -1 on error.
+/// Decrypt one frame from the peer (the ciphertext after its length prefix). Writes the frame for
+/// `pea_core_on_message_received` to out_buf (at most frame_len bytes). Returns bytes written, 0 for
+/// a Rekey (nothing to pass on), or -1 on error, after which the connection should be closed.
 #[no_mangle]
-pub extern "C" fn pea_core_decrypt_wire(
-    session_key_32: *const u8,
-    nonce: u64,
-    cipher: *const u8,
-    cipher_len: usize,
+pub extern "C" fn pea_core_session_open(
+    cipher: *mut c_void,
+    frame: *const u8,
+    frame_len: usize,
+    now_ms: u64,
     out_buf: *mut u8,
     out_buf_len: usize,
 ) -> c_int {
-    if session_key_32.is_null() || cipher.is_null() || out_buf.is_null() {
+    if cipher.is_null() || frame.is_null() || out_buf.is_null() {
         return -1;
     }
-    let key = unsafe { slice::from_raw_parts(session_key_32, 32) };
-    if key.len() != 32 {
+    let cipher = unsafe { &*(cipher as *const FfiSessionCipher) };
+    let frame = unsafe { slice::from_raw_parts(frame, frame_len) };
+    let Ok(opened) = cipher.lock().map(|mut c| c.open(frame, now_ms)) else {
         return -1;
-    }
-    let mut key_arr = [0u8; 32];
-    key_arr.copy_from_slice(key);
-    let cipher_slice = unsafe { slice::from_raw_parts(cipher, cipher_len) };
-    let plain = match decrypt_wire(&key_arr, nonce, cipher_slice) {
-        Ok(p) => p,
+    };
+    let plain = match opened {
+        Ok(Some(plain)) => Zeroizing::new(plain),
+        Ok(None) => return 0,
         Err(_) => return -1,
     };
     if plain.len() > out_buf_len {
@@ -252,9 +370,21 @@ pub extern "C" fn pea_core_decrypt_wire(
     plain.len() as c_int
 }
 
+/// Destroy a session cipher handle. No-op if cipher is null.
+#[no_mangle]
+pub extern "C" fn pea_core_session_cipher_destroy(cipher: *mut c_void) {
+    if cipher.is_null() {
+        return;
+    }
+    let _ = unsafe { Box::from_raw(cipher as *mut FfiSessionCipher) };
+}
+
 /// On incoming request. url_len is byte length of url (UTF-8). range_end > range_start for a valid range; else treated as no range.
 /// out_buf when Accelerate: 16 transfer_id, 8 total_length (LE), 4 num (LE), then num*(16 device_id, 8 start LE, 8 end LE).
-/// Returns: 0 = Fallback, 1 = Accelerate (out_buf filled), -1 = error (e.g. out_buf too small).
+/// out_buf when Cached: 8 body length (LE), then the body.
+/// Returns: 0 = Fallback, 1 = Accelerate (out_buf filled), 2 = Cached (out_buf filled), -1 = error (e.g. out_buf too small).
+/// A request attached to an identical in-flight transfer is reported as Fallback (0): FFI hosts have
+/// no shared waiter for another request's body.
 #[no_mangle]
 pub extern "C" fn pea_core_on_request(
     h: *mut c_void,
@@ -281,7 +411,17 @@ pub extern "C" fn pea_core_on_request(
     };
     let action = core.on_incoming_request(url_str, range);
     match action {
-        Action::Fallback => 0,
+        Action::Fallback | Action::Attached { .. } => 0,
+        Action::Cached { body } => {
+            let need = 8 + body.len();
+            if out_buf.is_null() || out_buf_len < need {
+                return -1;
+            }
+            let buf = unsafe { slice::from_raw_parts_mut(out_buf, out_buf_len) };
+            buf[0..8].copy_from_slice(&(body.len() as u64).to_le_bytes());
+            buf[8..need].copy_from_slice(&body);
+            2
+        }
         Action::Accelerate {
             transfer_id,
             total_length,
@@ -353,6 +493,66 @@ pub extern "C" fn pea_core_peer_left(
     write_outbound_actions(&actions, out_buf, out_buf_len)
 }
 
+/// Report the WAN uplink of a peer (or this device): `uplink_len` bytes of an opaque identifier
+/// such as a hash of its public IP; `uplink_len` 0 means unknown. Workers on the same uplink split
+/// its share of chunks. Returns 0, or -1 on error.
+#[no_mangle]
+pub extern "C" fn pea_core_set_peer_uplink(
+    h: *mut c_void,
+    device_id_16: *const u8,
+    uplink: *const u8,
+    uplink_len: usize,
+) -> c_int {
+    if h.is_null() || device_id_16.is_null() || (uplink.is_null() && uplink_len > 0) {
+        return -1;
+    }
+    let core = unsafe { &mut *(h as *mut PeaPodCore) };
+    let mut id = [0u8; 16];
+    unsafe {
+        id.copy_from_slice(slice::from_raw_parts(device_id_16, 16));
+    }
+    let uplink = (uplink_len > 0).then(|| {
+        let bytes = unsafe { slice::from_raw_parts(uplink, uplink_len) };
+        String::from_utf8_lossy(bytes).into_owned()
+    });
+    core.set_peer_uplink(DeviceId::from_bytes(id), uplink);
+    0
+}
+
+/// Pause (`on` non-zero) or resume serving peers, e.g. while the device is under high load.
+/// Returns 0, or -1 on error.
+#[no_mangle]
+pub extern "C" fn pea_core_set_do_not_disturb(h: *mut c_void, on: u8) -> c_int {
+    if h.is_null() {
+        return -1;
+    }
+    let core = unsafe { &mut *(h as *mut PeaPodCore) };
+    core.set_do_not_disturb(on != 0);
+    0
+}
+
+/// Report the local time in minutes since the Unix epoch (user's time zone) for the contribution
+/// schedule and daily budget. Returns 0, or -1 on error.
+#[no_mangle]
+pub extern "C" fn pea_core_set_local_time(h: *mut c_void, local_minutes: u64) -> c_int {
+    if h.is_null() {
+        return -1;
+    }
+    let core = unsafe { &mut *(h as *mut PeaPodCore) };
+    core.set_local_time(local_minutes);
+    0
+}
+
+/// Peer and frame of a SendMessage action. FetchChunk, AbortToFallback, PartialFallback and
+/// TransferComplete actions are not exposed over the C ABI yet, so FFI hosts do not serve peers'
+/// ChunkRequests or see transfer deadlines.
+fn message_of(action: &crate::OutboundAction) -> Option<(&DeviceId, &Vec<u8>)> {
+    match action {
+        crate::OutboundAction::SendMessage(peer_id, bytes) => Some((peer_id, bytes)),
+        _ => None,
+    }
+}
+
 /// Serialize outbound actions to out_buf: 4 bytes count (LE), then each (16 peer_id, 4 len LE, payload).
 /// Returns number of bytes written, or -1 on error.
 fn write_outbound_actions(
@@ -363,19 +563,18 @@ fn write_outbound_actions(
     if out_buf.is_null() {
         return -1;
     }
+    let messages: Vec<(&DeviceId, &Vec<u8>)> = actions.iter().filter_map(message_of).collect();
     let mut need = 4;
-    for a in actions {
-        let crate::OutboundAction::SendMessage(_, ref bytes) = a;
+    for (_, bytes) in &messages {
         need += 16 + 4 + bytes.len();
     }
     if out_buf_len < need {
         return -1;
     }
     let buf = unsafe { slice::from_raw_parts_mut(out_buf, out_buf_len) };
-    buf[0..4].copy_from_slice(&(actions.len() as u32).to_le_bytes());
+    buf[0..4].copy_from_slice(&(messages.len() as u32).to_le_bytes());
     let mut off = 4;
-    for a in actions {
-        let crate::OutboundAction::SendMessage(peer_id, bytes) = a;
+    for (peer_id, bytes) in messages {
         buf[off..off + 16].copy_from_slice(peer_id.as_bytes());
         off += 16;
         let len = bytes.len() as u32;
@@ -415,8 +614,7 @@ pub extern "C" fn pea_core_on_message_received(
     };
     let body_len = completed.as_ref().map(|(_, b)| b.len()).unwrap_or(0);
     let mut need = 4 + body_len;
-    for a in &actions {
-        let crate::OutboundAction::SendMessage(_, ref bytes) = a;
+    for (_, bytes) in actions.iter().filter_map(message_of) {
         need += 16 + 4 + bytes.len();
     }
     if out_buf.is_null() || out_buf_len < need {
@@ -488,3 +686,179 @@ pub extern "C" fn pea_core_tick(h: *mut c_void, out_buf: *mut u8, out_buf_len: u
     }
     write_outbound_actions(&actions, out_buf, out_buf_len)
 }
+
+/// Leave the pod before exiting (see `PeaPodCore::shutdown`): writes serialized outbound actions
+/// (signed Leave messages, fallbacks) to out_buf. Returns bytes written, 0 if none, -1 on error.
+#[no_mangle]
+pub extern "C" fn pea_core_shutdown(h: *mut c_void, out_buf: *mut u8, out_buf_len: usize) -> c_int {
+    if h.is_null() {
+        return -1;
+    }
+    let core = unsafe { &mut *(h as *mut PeaPodCore) };
+    let actions = core.shutdown();
+    if actions.is_empty() {
+        return 0;
+    }
+    write_outbound_actions(&actions, out_buf, out_buf_len)
+}
+
+/// Tick from a monotonic clock in milliseconds (see `PeaPodCore::tick_at`). Same output as
+/// `pea_core_tick`.
+#[no_mangle]
+pub extern "C" fn pea_core_tick_at(
+    h: *mut c_void,
+    now_ms: u64,
+    out_buf: *mut u8,
+    out_buf_len: usize,
+) -> c_int {
+    if h.is_null() {
+        return -1;
+    }
+    let core = unsafe { &mut *(h as *mut PeaPodCore) };
+    let actions = core.tick_at(now_ms);
+    if actions.is_empty() {
+        return 0;
+    }
+    write_outbound_actions(&actions, out_buf, out_buf_len)
+}
+
+/// Current peers. Layout: 4 bytes count (LE), then each (16 device_id, 32 public_key, 8 last_seen tick LE).
+/// Returns bytes written, or -1 on error (null handle/buffer or buffer too small).
+#[no_mangle]
+pub extern "C" fn pea_core_peers(h: *mut c_void, out_buf: *mut u8, out_buf_len: usize) -> c_int {
+    if h.is_null() || out_buf.is_null() {
+        return -1;
+    }
+    let core = unsafe { &*(h as *const PeaPodCore) };
+    let peers = core.peers();
+    let need = 4 + peers.len() * 56;
+    if out_buf_len < need {
+        return -1;
+    }
+    let buf = unsafe { slice::from_raw_parts_mut(out_buf, out_buf_len) };
+    buf[0..4].copy_from_slice(&(peers.len() as u32).to_le_bytes());
+    for (i, p) in peers.iter().enumerate() {
+        let base = 4 + i * 56;
+        buf[base..base + 16].copy_from_slice(p.device_id.as_bytes());
+        buf[base + 16..base + 48].copy_from_slice(p.public_key.as_bytes());
+        buf[base + 48..base + 56].copy_from_slice(&p.last_seen.to_le_bytes());
+    }
+    need as c_int
+}
+
+/// Report local constraints (battery_low, metered: 0/1; max_contribution_bps: 0 = no cap). Writes the
+/// Constraints messages for peers to out_buf (same layout as write_outbound_actions). Returns bytes
+/// written, 0 if none, -1 on error.
+#[no_mangle]
+pub extern "C" fn pea_core_set_local_constraints(
+    h: *mut c_void,
+    battery_low: u8,
+    metered: u8,
+    max_contribution_bps: u64,
+    out_buf: *mut u8,
+    out_buf_len: usize,
+) -> c_int {
+    if h.is_null() {
+        return -1;
+    }
+    let core = unsafe { &mut *(h as *mut PeaPodCore) };
+    let cap = (max_contribution_bps > 0).then_some(max_contribution_bps);
+    let actions = core.set_local_constraints(battery_low != 0, metered != 0, cap);
+    if actions.is_empty() {
+        return 0;
+    }
+    write_outbound_actions(&actions, out_buf, out_buf_len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Run `pea_core_handshake_read` and return (reply, done).
+    fn read(core: *mut c_void, hs: *mut c_void, msg: &[u8]) -> Option<(Vec<u8>, bool)> {
+        let mut out = [0u8; 256];
+        let mut done = 0u8;
+        let n = pea_core_handshake_read(
+            core,
+            hs,
+            msg.as_ptr(),
+            msg.len(),
+            out.as_mut_ptr(),
+            out.len(),
+            &mut done,
+        );
+        (n >= 0).then(|| (out[..n as usize].to_vec(), done == 1))
+    }
+
+    #[test]
+    fn handshake_and_session_cipher_round_trip() {
+        let (a, b) = (pea_core_create(), pea_core_create());
+        let mut msg1 = [0u8; 64];
+        let mut len = 0;
+        let ha = pea_core_handshake_initiate(msg1.as_mut_ptr(), msg1.len(), &mut len);
+        let hb = pea_core_handshake_respond();
+        assert_eq!(pea_core_handshake_next_len(hb) as usize, len);
+        let (msg2, done) = read(b, hb, &msg1[..len]).unwrap();
+        assert!(!done);
+        assert!(pea_core_session_cipher(hb, 0).is_null());
+        let (msg3, done) = read(a, ha, &msg2).unwrap();
+        assert!(done);
+        let (reply, done) = read(b, hb, &msg3).unwrap();
+        assert!(done && reply.is_empty());
+        assert_eq!(pea_core_handshake_next_len(hb), 0);
+
+        let (mut id, mut pk) = ([0u8; 16], [0u8; 32]);
+        assert_eq!(
+            pea_core_handshake_peer(hb, id.as_mut_ptr(), pk.as_mut_ptr()),
+            0
+        );
+        let mut a_id = [0u8; 16];
+        pea_core_device_id(a, a_id.as_mut_ptr(), 16);
+        assert_eq!(id, a_id);
+
+        let (ca, cb) = (
+            pea_core_session_cipher(ha, 0),
+            pea_core_session_cipher(hb, 0),
+        );
+        pea_core_handshake_destroy(ha);
+        pea_core_handshake_destroy(hb);
+        let plain = b"frame";
+        let mut sealed = [0u8; 128];
+        let n = pea_core_session_seal(
+            ca,
+            plain.as_ptr(),
+            plain.len(),
+            0,
+            sealed.as_mut_ptr(),
+            sealed.len(),
+        );
+        assert!(n > 4);
+        let frame_len = u32::from_le_bytes(sealed[..4].try_into().unwrap()) as usize;
+        assert_eq!(4 + frame_len, n as usize);
+        let mut opened = [0u8; 128];
+        let m = pea_core_session_open(
+            cb,
+            sealed[4..].as_ptr(),
+            frame_len,
+            0,
+            opened.as_mut_ptr(),
+            opened.len(),
+        );
+        assert_eq!(&opened[..m as usize], plain);
+        // A replayed frame is refused.
+        let m = pea_core_session_open(
+            cb,
+            sealed[4..].as_ptr(),
+            frame_len,
+            0,
+            opened.as_mut_ptr(),
+            opened.len(),
+        );
+        assert_eq!(m, -1);
+
+        pea_core_session_cipher_destroy(ca);
+        pea_core_session_cipher_destroy(cb);
+        pea_core_destroy(a);
+        pea_core_destroy(b);
+    }
+}
@@ -0,0 +1,440 @@
+//! Mutually authenticated transport handshake, after the Noise XX pattern.
+//!
+//! Three messages: the initiator sends an ephemeral key; the responder answers with its own
+//! ephemeral key, its encrypted static identity and a proof that it holds the static secret; the
+//! initiator answers with its encrypted identity and proof. Each proof is keyed by a DH between
+//! the prover's static key and the other side's ephemeral key, so only the key's holder can make
+//! it and it cannot be replayed on another connection. The session keys (one per direction) are
+//! handed out only once the peer's proof checked out.
+//!
+//! Pure state machine: the host reads [`Handshake::next_len`] bytes from the stream, passes them
+//! to [`Handshake::read`] and writes whatever it returns. Afterwards a
+//! [`SessionCipher`](crate::identity::SessionCipher) encrypts the frames.
+
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::ChaCha20Poly1305;
+use rand::rngs::OsRng;
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use x25519_dalek::{PublicKey as X25519PublicKey, StaticSecret};
+use zeroize::Zeroize;
+
+use crate::error::HandshakeError;
+use crate::identity::{DeviceId, KeyAgreement, PublicKey};
+
+/// First byte of the first two messages. The original 49-byte key swap sent
+/// [`crate::PROTOCOL_VERSION`] (1) there, so older peers are told apart and refused.
+pub const HANDSHAKE_VERSION: u8 = 2;
+
+const PROTOCOL_NAME: &[u8] = b"peapod-handshake-v2";
+const PROOF: &[u8] = b"peapod-handshake-proof";
+const TAG: usize = 16;
+/// Encrypted device ID and static public key.
+const IDENTITY: usize = 16 + 32 + TAG;
+/// version ‖ ephemeral key.
+const MSG1_LEN: usize = 1 + 32;
+/// version ‖ ephemeral key ‖ identity ‖ proof.
+const MSG2_LEN: usize = 1 + 32 + IDENTITY + 32;
+/// identity ‖ proof.
+const MSG3_LEN: usize = IDENTITY + 32;
+
+/// Result of a completed handshake: the authenticated peer and the keys for each direction, for
+/// a [`SessionCipher`](crate::identity::SessionCipher).
+#[derive(Clone)]
+pub struct Session {
+    pub peer_id: DeviceId,
+    pub peer_public: PublicKey,
+    pub send_key: [u8; 32],
+    pub recv_key: [u8; 32],
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.send_key.zeroize();
+        self.recv_key.zeroize();
+    }
+}
+
+/// The session keys stay out of logs.
+impl std::fmt::Debug for Session {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Session")
+            .field("peer_id", &self.peer_id)
+            .field("peer_public", &self.peer_public)
+            .finish_non_exhaustive()
+    }
+}
+
+/// What to do after [`Handshake::read`].
+#[derive(Debug)]
+pub enum HandshakeStep {
+    /// Send these bytes, then read [`Handshake::next_len`] more.
+    Reply(Vec<u8>),
+    /// The peer is authenticated; send `reply` if any, then switch to encrypted frames.
+    Done {
+        reply: Option<Vec<u8>>,
+        session: Session,
+    },
+}
+
+enum State {
+    /// Initiator waiting for message 2.
+    SentEphemeral,
+    /// Responder waiting for message 1.
+    AwaitEphemeral,
+    /// Responder waiting for message 3.
+    SentIdentity,
+    Finished,
+}
+
+/// One side of a handshake in progress.
+pub struct Handshake {
+    state: State,
+    ephemeral: StaticSecret,
+    /// Hash of the transcript so far, authenticated by every proof.
+    transcript: [u8; 32],
+    /// Chaining key: hash of every DH result so far.
+    chaining: [u8; 32],
+}
+
+impl Handshake {
+    /// Start a handshake on a connection this device opened. Send the returned bytes first.
+    pub fn initiate() -> (Self, Vec<u8>) {
+        let mut hs = Self::new(State::SentEphemeral);
+        let msg1 = [&[HANDSHAKE_VERSION][..], hs.ephemeral_public().as_bytes()].concat();
+        hs.mix_transcript(&msg1);
+        (hs, msg1)
+    }
+
+    /// Handshake for a connection this device accepted; the initiator speaks first.
+    pub fn respond() -> Self {
+        Self::new(State::AwaitEphemeral)
+    }
+
+    fn new(state: State) -> Self {
+        let initial: [u8; 32] = Sha256::digest(PROTOCOL_NAME).into();
+        Self {
+            state,
+            ephemeral: StaticSecret::random_from_rng(OsRng),
+            transcript: initial,
+            chaining: initial,
+        }
+    }
+
+    /// Bytes the next [`Handshake::read`] expects; 0 once finished.
+    pub fn next_len(&self) -> usize {
+        match self.state {
+            State::AwaitEphemeral => MSG1_LEN,
+            State::SentEphemeral => MSG2_LEN,
+            State::SentIdentity => MSG3_LEN,
+            State::Finished => 0,
+        }
+    }
+
+    /// Process the peer's next message (exactly [`Handshake::next_len`] bytes). On error the
+    /// handshake is over and the connection should be closed.
+    pub fn read(
+        &mut self,
+        keypair: &(impl KeyAgreement + ?Sized),
+        msg: &[u8],
+    ) -> Result<HandshakeStep, HandshakeError> {
+        if msg.len() != self.next_len() || msg.is_empty() {
+            return Err(HandshakeError::Malformed);
+        }
+        match std::mem::replace(&mut self.state, State::Finished) {
+            State::AwaitEphemeral => self.read_ephemeral(keypair, msg),
+            State::SentEphemeral => self.read_responder_identity(keypair, msg),
+            State::SentIdentity => self.read_initiator_identity(msg),
+            State::Finished => Err(HandshakeError::Malformed),
+        }
+    }
+
+    /// Responder: message 1 in, message 2 out.
+    fn read_ephemeral(
+        &mut self,
+        keypair: &(impl KeyAgreement + ?Sized),
+        msg1: &[u8],
+    ) -> Result<HandshakeStep, HandshakeError> {
+        check_version(msg1[0])?;
+        self.mix_transcript(msg1);
+        let remote_ephemeral: [u8; 32] = msg1[1..].try_into().expect("length checked");
+        let mut msg2 = [&[HANDSHAKE_VERSION][..], self.ephemeral_public().as_bytes()].concat();
+        self.mix_transcript(&msg2);
+        self.mix_key(&self.ephemeral_dh(&remote_ephemeral));
+        let identity = self.seal_identity(keypair)?;
+        // es: our static key with their ephemeral.
+        self.mix_key(&keypair.shared_secret(&PublicKey::from_bytes(remote_ephemeral)));
+        let proof = self.proof();
+        self.mix_transcript(&proof);
+        msg2.extend_from_slice(&identity);
+        msg2.extend_from_slice(&proof);
+        self.state = State::SentIdentity;
+        Ok(HandshakeStep::Reply(msg2))
+    }
+
+    /// Initiator: message 2 in, message 3 out.
+    fn read_responder_identity(
+        &mut self,
+        keypair: &(impl KeyAgreement + ?Sized),
+        msg2: &[u8],
+    ) -> Result<HandshakeStep, HandshakeError> {
+        check_version(msg2[0])?;
+        let (head, rest) = msg2.split_at(1 + 32);
+        let (identity, proof) = rest.split_at(IDENTITY);
+        self.mix_transcript(head);
+        let remote_ephemeral: [u8; 32] = head[1..].try_into().expect("length checked");
+        self.mix_key(&self.ephemeral_dh(&remote_ephemeral));
+        let (peer_id, peer_public) = self.open_identity(identity)?;
+        self.mix_key(&self.ephemeral_dh(peer_public.as_bytes()));
+        self.check_proof(proof)?;
+        let identity = self.seal_identity(keypair)?;
+        // se: our static key with their ephemeral.
+        self.mix_key(&keypair.shared_secret(&PublicKey::from_bytes(remote_ephemeral)));
+        let proof = self.proof();
+        self.mix_transcript(&proof);
+        let (send_key, recv_key) = self.split();
+        Ok(HandshakeStep::Done {
+            reply: Some([identity, proof.to_vec()].concat()),
+            session: Session {
+                peer_id,
+                peer_public,
+                send_key,
+                recv_key,
+            },
+        })
+    }
+
+    /// Responder: message 3 in.
+    fn read_initiator_identity(&mut self, msg3: &[u8]) -> Result<HandshakeStep, HandshakeError> {
+        let (identity, proof) = msg3.split_at(IDENTITY);
+        let (peer_id, peer_public) = self.open_identity(identity)?;
+        self.mix_key(&self.ephemeral_dh(peer_public.as_bytes()));
+        self.check_proof(proof)?;
+        let (recv_key, send_key) = self.split();
+        Ok(HandshakeStep::Done {
+            reply: None,
+            session: Session {
+                peer_id,
+                peer_public,
+                send_key,
+                recv_key,
+            },
+        })
+    }
+
+    fn ephemeral_public(&self) -> PublicKey {
+        PublicKey::from_bytes(X25519PublicKey::from(&self.ephemeral).to_bytes())
+    }
+
+    fn ephemeral_dh(&self, remote: &[u8; 32]) -> [u8; 32] {
+        self.ephemeral
+            .diffie_hellman(&X25519PublicKey::from(*remote))
+            .to_bytes()
+    }
+
+    fn mix_transcript(&mut self, data: &[u8]) {
+        self.transcript = hash(&[&self.transcript, data]);
+    }
+
+    fn mix_key(&mut self, dh: &[u8; 32]) {
+        self.chaining = hash(&[&self.chaining, dh]);
+    }
+
+    fn cipher(&self) -> ChaCha20Poly1305 {
+        ChaCha20Poly1305::new_from_slice(&hash(&[&self.chaining, b"key"])).expect("32-byte key")
+    }
+
+    /// Our device ID and static key, encrypted under the current chaining key.
+    fn seal_identity(
+        &mut self,
+        keypair: &(impl KeyAgreement + ?Sized),
+    ) -> Result<Vec<u8>, HandshakeError> {
+        let plain = [
+            &keypair.device_id().as_bytes()[..],
+            keypair.public_key().as_bytes(),
+        ]
+        .concat();
+        let sealed = self
+            .cipher()
+            .encrypt(
+                (&[0u8; 12]).into(),
+                Payload {
+                    msg: &plain,
+                    aad: &self.transcript,
+                },
+            )
+            .map_err(|_| HandshakeError::Malformed)?;
+        self.mix_transcript(&sealed);
+        Ok(sealed)
+    }
+
+    fn open_identity(&mut self, sealed: &[u8]) -> Result<(DeviceId, PublicKey), HandshakeError> {
+        let plain = self
+            .cipher()
+            .decrypt(
+                (&[0u8; 12]).into(),
+                Payload {
+                    msg: sealed,
+                    aad: &self.transcript,
+                },
+            )
+            .map_err(|_| HandshakeError::Authentication)?;
+        self.mix_transcript(sealed);
+        let peer_id = DeviceId::from_bytes(plain[..16].try_into().expect("sealed length"));
+        let peer_public = PublicKey::from_bytes(plain[16..].try_into().expect("sealed length"));
+        if DeviceId::from_public_key(peer_public.as_bytes()) != peer_id {
+            return Err(HandshakeError::IdentityMismatch);
+        }
+        Ok((peer_id, peer_public))
+    }
+
+    /// Proof of the last static DH mixed in, over the whole transcript.
+    fn proof(&self) -> [u8; 32] {
+        hash(&[PROOF, &self.chaining, &self.transcript])
+    }
+
+    /// Compared in constant time, so a forger learns nothing from how long the check took.
+    fn check_proof(&mut self, proof: &[u8]) -> Result<(), HandshakeError> {
+        if !bool::from(self.proof()[..].ct_eq(proof)) {
+            return Err(HandshakeError::Authentication);
+        }
+        self.mix_transcript(proof);
+        Ok(())
+    }
+
+    /// (initiator-to-responder, responder-to-initiator) session keys.
+    fn split(&mut self) -> ([u8; 32], [u8; 32]) {
+        self.state = State::Finished;
+        (
+            hash(&[&self.chaining, b"initiator"]),
+            hash(&[&self.chaining, b"responder"]),
+        )
+    }
+}
+
+/// The ephemeral secret wipes itself; the chaining key is wiped here.
+impl Drop for Handshake {
+    fn drop(&mut self) {
+        self.chaining.zeroize();
+    }
+}
+
+fn check_version(version: u8) -> Result<(), HandshakeError> {
+    if version == HANDSHAKE_VERSION {
+        Ok(())
+    } else {
+        Err(HandshakeError::Version(version))
+    }
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::Keypair;
+
+    /// Run a handshake, letting `tamper` alter each message in flight (index 0..3).
+    fn run(
+        initiator: &Keypair,
+        responder: &Keypair,
+        mut tamper: impl FnMut(usize, &mut Vec<u8>),
+    ) -> (
+        Result<Session, HandshakeError>,
+        Result<Session, HandshakeError>,
+    ) {
+        let (mut i, mut msg1) = Handshake::initiate();
+        let mut r = Handshake::respond();
+        tamper(0, &mut msg1);
+        let mut msg2 = match r.read(responder, &msg1) {
+            Ok(HandshakeStep::Reply(m)) => m,
+            Ok(_) => panic!("expected message 2"),
+            Err(e) => return (Err(HandshakeError::Malformed), Err(e)),
+        };
+        tamper(1, &mut msg2);
+        let (mut msg3, initiator_session) = match i.read(initiator, &msg2) {
+            Ok(HandshakeStep::Done {
+                reply: Some(m),
+                session,
+            }) => (m, session),
+            Ok(_) => panic!("expected message 3"),
+            Err(e) => return (Err(e), Err(HandshakeError::Malformed)),
+        };
+        tamper(2, &mut msg3);
+        let responder_session = match r.read(responder, &msg3) {
+            Ok(HandshakeStep::Done {
+                reply: None,
+                session,
+            }) => Ok(session),
+            Ok(_) => panic!("expected completion"),
+            Err(e) => Err(e),
+        };
+        (Ok(initiator_session), responder_session)
+    }
+
+    #[test]
+    fn both_sides_authenticate_and_share_directional_keys() {
+        let (a, b) = (Keypair::generate(), Keypair::generate());
+        let (sa, sb) = run(&a, &b, |_, _| {});
+        let (sa, sb) = (sa.unwrap(), sb.unwrap());
+        assert_eq!(sa.peer_id, b.device_id());
+        assert_eq!(&sa.peer_public, b.public_key());
+        assert_eq!(sb.peer_id, a.device_id());
+        assert_eq!(sa.send_key, sb.recv_key);
+        assert_eq!(sa.recv_key, sb.send_key);
+        assert_ne!(sa.send_key, sa.recv_key);
+        let shown = format!("{sa:?}");
+        assert!(!shown.contains(&format!("{:?}", sa.send_key)));
+        assert!(!shown.contains(&format!("{:?}", sa.recv_key)));
+        // Fresh ephemeral keys: a second connection gets other keys.
+        let (again, _) = run(&a, &b, |_, _| {});
+        assert_ne!(again.unwrap().send_key, sa.send_key);
+    }
+
+    #[test]
+    fn tampering_or_old_peers_fail_the_handshake() {
+        let (a, b) = (Keypair::generate(), Keypair::generate());
+        // Legacy 49-byte handshake starts with PROTOCOL_VERSION.
+        let (_, r) = run(&a, &b, |n, m| {
+            if n == 0 {
+                m[0] = crate::PROTOCOL_VERSION;
+            }
+        });
+        assert_eq!(r.unwrap_err(), HandshakeError::Version(1));
+        for (index, byte) in [(1, 40), (1, MSG2_LEN - 1), (2, 3), (2, MSG3_LEN - 1)] {
+            let (i, r) = run(&a, &b, |n, m| {
+                if n == index {
+                    m[byte] ^= 1;
+                }
+            });
+            assert!(
+                i.is_err() || r.is_err(),
+                "message {index} byte {byte} accepted"
+            );
+        }
+        // A man in the middle relaying under its own keys cannot complete the handshake.
+        let (i, _) = run(&a, &b, |n, m| {
+            if n == 0 {
+                let (_, forged) = Handshake::initiate();
+                *m = forged;
+            }
+        });
+        assert_eq!(i.unwrap_err(), HandshakeError::Authentication);
+        let (i, _) = run(&a, &b, |n, m| {
+            if n == 1 {
+                let mut mitm = Handshake::respond();
+                let (_, msg1) = Handshake::initiate();
+                if let Ok(HandshakeStep::Reply(forged)) = mitm.read(&Keypair::generate(), &msg1) {
+                    *m = forged;
+                }
+            }
+        });
+        assert_eq!(i.unwrap_err(), HandshakeError::Authentication);
+    }
+}
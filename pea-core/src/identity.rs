@@ -1,11 +1,24 @@
-//! Device identity and crypto: keypairs, device ID, session keys, wire encryption.
+//! Device identity and crypto: keypairs, device ID, session keys, wire encryption, signatures.
+//!
+//! Secret key bytes, shared secrets and keys derived from them are handed out as [`Zeroizing`]
+//! and wiped when dropped, as are the keys held by ciphers and sessions, so they do not linger
+//! in freed memory.
 
-use chacha20poly1305::aead::{Aead, KeyInit};
+use argon2::Argon2;
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
 use chacha20poly1305::ChaCha20Poly1305;
+use ed25519_dalek::{Signer as _, SigningKey, Verifier, VerifyingKey};
 use rand::rngs::OsRng;
+use rand::RngCore;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
 use x25519_dalek::{PublicKey as X25519PublicKey, StaticSecret};
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::handshake::Session;
+use crate::protocol::Message;
+use crate::wire::{self, FrameEncodeError};
 
 /// Device public key (32 bytes, X25519). Serializable for beacon and handshake.
 #[derive(Clone, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
@@ -33,6 +46,159 @@ impl PublicKey {
     }
 }
 
+/// Ed25519 public key a device signs with (see [`Signer::signing_public_key`]). Unlike the
+/// X25519 proofs, its signatures convince any third party, not only the receiver.
+#[derive(Clone, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub struct SigningPublicKey(#[serde(with = "bytes_32")] [u8; 32]);
+
+/// Ed25519 signature (64 bytes) made by [`Signer::sign`] and its wrappers.
+#[derive(Clone, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub struct Signature(#[serde(with = "bytes_64")] [u8; 64]);
+
+mod bytes_64 {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    pub fn serialize<S: Serializer>(v: &[u8; 64], serializer: S) -> Result<S::Ok, S::Error> {
+        v.as_slice().serialize(serializer)
+    }
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<[u8; 64], D::Error> {
+        let buf: Vec<u8> = Deserialize::deserialize(d)?;
+        buf.try_into()
+            .map_err(|_| serde::de::Error::custom("expected 64 bytes"))
+    }
+}
+
+impl SigningPublicKey {
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+    /// Create a `SigningPublicKey` from a 32-byte array.
+    pub fn from_bytes(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+
+    /// Check a [`Signer::sign`] signature over `payload` in `domain`. False for a malformed key.
+    pub fn verify(&self, domain: &[u8], payload: &[u8], signature: &Signature) -> bool {
+        let Ok(key) = VerifyingKey::from_bytes(&self.0) else {
+            return false;
+        };
+        key.verify(
+            &signed_bytes(domain, payload),
+            &ed25519_dalek::Signature::from_bytes(&signature.0),
+        )
+        .is_ok()
+    }
+
+    /// Check a [`DeviceKey::identity_binding`]: the device ID must be derived from `public_key` and
+    /// the holder of this key must have signed both.
+    pub fn verify_binding(
+        &self,
+        device_id: &DeviceId,
+        public_key: &PublicKey,
+        signature: &Signature,
+    ) -> bool {
+        DeviceId::from_public_key(public_key.as_bytes()) == *device_id
+            && self.verify(BIND, &binding(device_id, public_key), signature)
+    }
+
+    /// Check a [`DeviceKey::sign_message`] signature.
+    pub fn verify_message(&self, msg: &Message, signature: &Signature) -> bool {
+        bincode::serialize(msg).is_ok_and(|payload| self.verify(MESSAGE, &payload, signature))
+    }
+
+    /// Check a [`DeviceKey::sign_chunk`] signature.
+    pub fn verify_chunk(
+        &self,
+        transfer_id: &[u8; 16],
+        (start, end): (u64, u64),
+        hash: &[u8; 32],
+        signature: &Signature,
+    ) -> bool {
+        self.verify(
+            CHUNK,
+            &chunk_statement(transfer_id, (start, end), hash),
+            signature,
+        )
+    }
+}
+
+impl Signature {
+    pub fn as_bytes(&self) -> &[u8; 64] {
+        &self.0
+    }
+    /// Create a `Signature` from a 64-byte array.
+    pub fn from_bytes(bytes: [u8; 64]) -> Self {
+        Self(bytes)
+    }
+}
+
+/// Longest device name, in bytes, a [`NameRecord`] may carry.
+pub const MAX_DEVICE_NAME_LEN: usize = 64;
+
+/// A device's human-readable name ("Ana's laptop"), signed with its signing key and bound to its
+/// device ID, so the name cannot be replayed under another key. Made by [`DeviceKey::name_record`].
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NameRecord {
+    pub name: String,
+    pub signing_key: SigningPublicKey,
+    /// [`DeviceKey::identity_binding`] of the named device.
+    pub binding: Signature,
+    /// Signature over the device ID and name.
+    pub signature: Signature,
+}
+
+impl NameRecord {
+    /// Whether the record names the device with `device_id` and `public_key`: the signing key is
+    /// bound to both and signed the name, which is non-empty, at most [`MAX_DEVICE_NAME_LEN`]
+    /// bytes and free of control characters.
+    pub fn verify(&self, device_id: &DeviceId, public_key: &PublicKey) -> bool {
+        valid_name(&self.name)
+            && self
+                .signing_key
+                .verify_binding(device_id, public_key, &self.binding)
+            && self.signing_key.verify(
+                NAME,
+                &name_statement(device_id, &self.name),
+                &self.signature,
+            )
+    }
+}
+
+/// A signed statement that device `revoked` must no longer be trusted, issued by the device with
+/// `issuer_key` (the revoked device itself when its key is compromised). Made by
+/// [`DeviceKey::revoke`]; it does not expire.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Revocation {
+    pub revoked: DeviceId,
+    pub issuer: DeviceId,
+    pub issuer_key: PublicKey,
+    pub signing_key: SigningPublicKey,
+    /// [`DeviceKey::identity_binding`] of the issuer.
+    pub binding: Signature,
+    /// Signature over the issuer and revoked device IDs.
+    pub signature: Signature,
+}
+
+impl Revocation {
+    /// Whether the record is authentic: `issuer` is derived from `issuer_key`, and the signing
+    /// key is bound to both and signed the revocation. Whether the issuer may revoke is up to the
+    /// receiver.
+    pub fn verify(&self) -> bool {
+        self.signing_key
+            .verify_binding(&self.issuer, &self.issuer_key, &self.binding)
+            && self.signing_key.verify(
+                REVOKE,
+                &revocation_statement(&self.issuer, &self.revoked),
+                &self.signature,
+            )
+    }
+}
+
+fn valid_name(name: &str) -> bool {
+    !name.trim().is_empty()
+        && name.len() <= MAX_DEVICE_NAME_LEN
+        && !name.chars().any(char::is_control)
+}
+
 /// Device ID: deterministic hash of public key. Used in discovery and peer list.
 #[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
 pub struct DeviceId(#[serde(with = "bytes_16")] [u8; 16]);
@@ -57,13 +223,33 @@ impl DeviceId {
     pub fn from_bytes(bytes: [u8; 16]) -> Self {
         Self(bytes)
     }
+    /// Parse the 32 hex digits `Display` writes (either case); `None` for anything else.
+    pub fn from_hex(s: &str) -> Option<Self> {
+        if s.len() != 32 || !s.is_ascii() {
+            return None;
+        }
+        let mut bytes = [0u8; 16];
+        for (i, b) in bytes.iter_mut().enumerate() {
+            *b = u8::from_str_radix(&s[2 * i..2 * i + 2], 16).ok()?;
+        }
+        Some(Self(bytes))
+    }
 }
 
-/// X25519 keypair. Keep secret key private; expose only public key and device ID.
+/// Lowercase hex, as shown in logs and error messages.
+impl std::fmt::Display for DeviceId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.iter().try_for_each(|b| write!(f, "{:02x}", b))
+    }
+}
+
+/// X25519 keypair. Keep secret key private; expose only public key and device ID. Both secret
+/// keys are wiped when it is dropped.
 pub struct Keypair {
     secret: StaticSecret,
     public: PublicKey,
     device_id: DeviceId,
+    signing: SigningKey,
 }
 
 impl DeviceId {
@@ -81,14 +267,87 @@ impl DeviceId {
 impl Keypair {
     /// Generate a new random keypair and derive device ID from public key.
     pub fn generate() -> Self {
-        let secret = StaticSecret::random_from_rng(OsRng);
+        Self::from_secret(StaticSecret::random_from_rng(OsRng))
+    }
+
+    /// Secret key bytes, for hosts that keep the identity in a protected keystore (OS keyring,
+    /// DPAPI, a file only the user can read). Anyone holding them can impersonate this device;
+    /// prefer [`Keypair::to_bytes_encrypted`] for plain files.
+    pub fn to_bytes(&self) -> Zeroizing<[u8; 32]> {
+        Zeroizing::new(self.secret.to_bytes())
+    }
+
+    /// Restore a keypair from [`Keypair::to_bytes`]; the device ID is the same as before. The
+    /// passed copy of the secret is wiped.
+    pub fn from_bytes(mut secret: [u8; 32]) -> Self {
+        let keypair = Self::from_secret(StaticSecret::from(secret));
+        secret.zeroize();
+        keypair
+    }
+
+    /// Secret key sealed under `passphrase`: Argon2id derives the key from the passphrase and a
+    /// random salt, and ChaCha20-Poly1305 encrypts the secret. Output: `PEAK`, format version,
+    /// 16-byte salt, 12-byte nonce, ciphertext.
+    pub fn to_bytes_encrypted(&self, passphrase: &[u8]) -> Result<Vec<u8>, KeystoreError> {
+        let mut header = [0u8; KEYSTORE_HEADER];
+        header[..4].copy_from_slice(KEYSTORE_MAGIC);
+        header[4] = KEYSTORE_VERSION;
+        let mut nonce = [0u8; 12];
+        OsRng.fill_bytes(&mut header[5..]);
+        OsRng.fill_bytes(&mut nonce);
+        let cipher = keystore_cipher(passphrase, &header[5..])?;
+        let sealed = cipher
+            .encrypt(
+                (&nonce).into(),
+                Payload {
+                    msg: self.to_bytes().as_slice(),
+                    aad: &header,
+                },
+            )
+            .map_err(|_| KeystoreError::Format)?;
+        Ok([&header[..], &nonce, &sealed].concat())
+    }
+
+    /// Restore a keypair from [`Keypair::to_bytes_encrypted`]. Fails with
+    /// [`KeystoreError::Passphrase`] for a wrong passphrase or altered data.
+    pub fn from_bytes_encrypted(bytes: &[u8], passphrase: &[u8]) -> Result<Self, KeystoreError> {
+        if bytes.len() != KEYSTORE_HEADER + 12 + 32 + 16 || &bytes[..4] != KEYSTORE_MAGIC {
+            return Err(KeystoreError::Format);
+        }
+        if bytes[4] != KEYSTORE_VERSION {
+            return Err(KeystoreError::Version(bytes[4]));
+        }
+        let (header, rest) = bytes.split_at(KEYSTORE_HEADER);
+        let (nonce, sealed) = rest.split_at(12);
+        let cipher = keystore_cipher(passphrase, &header[5..])?;
+        let plain = Zeroizing::new(
+            cipher
+                .decrypt(
+                    nonce.into(),
+                    Payload {
+                        msg: sealed,
+                        aad: header,
+                    },
+                )
+                .map_err(|_| KeystoreError::Passphrase)?,
+        );
+        let secret: [u8; 32] = plain
+            .as_slice()
+            .try_into()
+            .map_err(|_| KeystoreError::Format)?;
+        Ok(Self::from_bytes(secret))
+    }
+
+    fn from_secret(secret: StaticSecret) -> Self {
         let public_x = X25519PublicKey::from(&secret);
         let public = PublicKey(public_x.to_bytes());
         let device_id = DeviceId::from_public_key(public.as_bytes());
+        let signing = SigningKey::from_bytes(&sub_key(SIGNING, &secret));
         Self {
             secret,
             public,
             device_id,
+            signing,
         }
     }
 
@@ -99,21 +358,364 @@ impl Keypair {
     pub fn device_id(&self) -> DeviceId {
         self.device_id
     }
+}
+
+/// The X25519 half of a device key: its public key and Diffie-Hellman with it. [`Keypair`] holds
+/// the secret in process memory; a host can implement this over hardware (Android Keystore, a
+/// TPM, the Secure Enclave) so the secret never leaves it.
+pub trait KeyAgreement {
+    fn public_key(&self) -> &PublicKey;
 
     /// Shared secret with another device's public key. Used to derive session key.
-    pub fn shared_secret(&self, other_public: &PublicKey) -> [u8; 32] {
+    fn shared_secret(&self, other_public: &PublicKey) -> Zeroizing<[u8; 32]>;
+
+    fn device_id(&self) -> DeviceId {
+        DeviceId::from_public_key(self.public_key().as_bytes())
+    }
+
+    /// Key for data this device stores at rest (see [`crate::chunk::seal_chunk`]), so only the
+    /// holder of the secret key can read it. By default derived from the device's Diffie-Hellman
+    /// with its own public key.
+    fn storage_key(&self) -> Zeroizing<[u8; 32]> {
+        let mut hasher = Sha256::new();
+        hasher.update(STORAGE);
+        hasher.update(self.shared_secret(self.public_key()));
+        Zeroizing::new(hasher.finalize().into())
+    }
+}
+
+/// The Ed25519 half of a device key. Like [`KeyAgreement`], it may be backed by hardware.
+pub trait Signer {
+    /// Ed25519 key this device signs with.
+    fn signing_public_key(&self) -> SigningPublicKey;
+
+    /// Ed25519 signature over `message` as given.
+    fn sign_bytes(&self, message: &[u8]) -> Signature;
+
+    /// Sign `payload` under `domain`, so a signature for one kind of statement cannot be replayed
+    /// as another. Verify with [`SigningPublicKey::verify`].
+    fn sign(&self, domain: &[u8], payload: &[u8]) -> Signature {
+        self.sign_bytes(&signed_bytes(domain, payload))
+    }
+}
+
+/// A device identity as the core uses it: key agreement and signing, by whatever holds the keys.
+/// The protocol's proofs and signed records are built on those two operations, so every
+/// implementation produces the same bytes as [`Keypair`].
+pub trait DeviceKey: KeyAgreement + Signer + Send + Sync {
+    /// Binds the signing key to this device ID and X25519 public key; peers that learn the
+    /// signing key check it with [`SigningPublicKey::verify_binding`].
+    fn identity_binding(&self) -> Signature {
+        self.sign(BIND, &binding(&self.device_id(), self.public_key()))
+    }
+
+    /// Sign a protocol message (e.g. a Beacon or JoinRequest) as encoded on the wire.
+    fn sign_message(&self, msg: &Message) -> Result<Signature, FrameEncodeError> {
+        let payload = bincode::serialize(msg).map_err(FrameEncodeError::Encode)?;
+        Ok(self.sign(MESSAGE, &payload))
+    }
+
+    /// Non-repudiable counterpart of [`DeviceKey::attest_chunk`]: anyone holding this device's
+    /// signing key can check that it served `hash` for the range of the transfer.
+    fn sign_chunk(
+        &self,
+        transfer_id: &[u8; 16],
+        (start, end): (u64, u64),
+        hash: &[u8; 32],
+    ) -> Signature {
+        self.sign(CHUNK, &chunk_statement(transfer_id, (start, end), hash))
+    }
+
+    /// Signed record of this device's name for Beacons and Joins. Control characters are dropped
+    /// and the name is cut to [`MAX_DEVICE_NAME_LEN`] bytes; `None` when nothing is left.
+    fn name_record(&self, name: &str) -> Option<NameRecord> {
+        let mut name: String = name.trim().chars().filter(|c| !c.is_control()).collect();
+        let mut end = name.len().min(MAX_DEVICE_NAME_LEN);
+        while !name.is_char_boundary(end) {
+            end -= 1;
+        }
+        name.truncate(end);
+        if !valid_name(&name) {
+            return None;
+        }
+        Some(NameRecord {
+            signature: self.sign(NAME, &name_statement(&self.device_id(), &name)),
+            name,
+            signing_key: self.signing_public_key(),
+            binding: self.identity_binding(),
+        })
+    }
+
+    /// Signed revocation of `device_id` by this device, for [`Message::Revoke`].
+    fn revoke(&self, device_id: DeviceId) -> Revocation {
+        Revocation {
+            revoked: device_id,
+            issuer: self.device_id(),
+            issuer_key: self.public_key().clone(),
+            signing_key: self.signing_public_key(),
+            binding: self.identity_binding(),
+            signature: self.sign(REVOKE, &revocation_statement(&self.device_id(), &device_id)),
+        }
+    }
+
+    /// Proofs for a [`Message::RotateKey`] to the peer with `peer_public`, moving this device to
+    /// `new`: this (old) key vouches for the new device ID, and the new key proves its holder
+    /// is the device with this one's ID.
+    fn rotation_proofs(
+        &self,
+        new: &dyn DeviceKey,
+        peer_public: &PublicKey,
+    ) -> ([u8; 32], [u8; 32]) {
+        (
+            proof(ROTATE, &self.shared_secret(peer_public), &new.device_id()),
+            proof(ROTATE, &new.shared_secret(peer_public), &self.device_id()),
+        )
+    }
+
+    /// Verify a [`Message::RotateKey`] from the device with `old_public` moving to `new_public`.
+    fn verify_rotation(
+        &self,
+        old_public: &PublicKey,
+        new_public: &PublicKey,
+        old_proof: &[u8; 32],
+        new_proof: &[u8; 32],
+    ) -> bool {
+        let old_id = DeviceId::from_public_key(old_public.as_bytes());
+        let new_id = DeviceId::from_public_key(new_public.as_bytes());
+        old_id != new_id
+            && macs_match(
+                &proof(ROTATE, &self.shared_secret(old_public), &new_id),
+                old_proof,
+            )
+            && macs_match(
+                &proof(ROTATE, &self.shared_secret(new_public), &old_id),
+                new_proof,
+            )
+    }
+
+    /// Join MAC for the peer with `peer_public` (sent as the JoinRequest's `signature`): a
+    /// SHA-256 MAC binding this device ID to the X25519 shared secret, so only the holder of the
+    /// secret key (or the peer itself) can produce it. Unlike a signature, it convinces only
+    /// the peer.
+    fn join_signature(&self, peer_public: &PublicKey) -> [u8; 32] {
+        proof(JOIN, &self.shared_secret(peer_public), &self.device_id())
+    }
+
+    /// Verify a Join from a peer: its device ID must be derived from its public key and the MAC
+    /// in its `signature` field must match the one computed from our side of the key exchange,
+    /// compared in constant time.
+    fn verify_join(
+        &self,
+        device_id: &DeviceId,
+        public_key: &PublicKey,
+        signature: &[u8; 32],
+    ) -> bool {
+        if DeviceId::from_public_key(public_key.as_bytes()) != *device_id {
+            return false;
+        }
+        macs_match(
+            &proof(JOIN, &self.shared_secret(public_key), device_id),
+            signature,
+        )
+    }
+
+    /// Leave proof for the peer with `peer_public`, so a third device cannot remove this one from
+    /// the peer's pod.
+    fn leave_signature(&self, peer_public: &PublicKey) -> [u8; 32] {
+        proof(LEAVE, &self.shared_secret(peer_public), &self.device_id())
+    }
+
+    /// Verify a Leave from the peer with `public_key` (as stored when it joined).
+    fn verify_leave(&self, public_key: &PublicKey, signature: &[u8; 32]) -> bool {
+        let device_id = DeviceId::from_public_key(public_key.as_bytes());
+        macs_match(
+            &proof(LEAVE, &self.shared_secret(public_key), &device_id),
+            signature,
+        )
+    }
+
+    /// Attestation that this device served `hash` for the range `start..end` of `transfer_id` to the peer
+    /// with `peer_public`. Keyed by the pairwise shared secret like the join MAC: the peer
+    /// did not make it, so it pins the chunk on this device.
+    fn attest_chunk(
+        &self,
+        peer_public: &PublicKey,
+        transfer_id: &[u8; 16],
+        (start, end): (u64, u64),
+        hash: &[u8; 32],
+    ) -> [u8; 32] {
+        let shared = self.shared_secret(peer_public);
+        attestation(&shared, &self.device_id(), transfer_id, (start, end), hash)
+    }
+
+    /// Verify an attestation from the peer with `public_key` for a chunk it served this device.
+    fn verify_chunk_attestation(
+        &self,
+        public_key: &PublicKey,
+        transfer_id: &[u8; 16],
+        (start, end): (u64, u64),
+        hash: &[u8; 32],
+        signature: &[u8; 32],
+    ) -> bool {
+        let device_id = DeviceId::from_public_key(public_key.as_bytes());
+        let shared = self.shared_secret(public_key);
+        macs_match(
+            &attestation(&shared, &device_id, transfer_id, (start, end), hash),
+            signature,
+        )
+    }
+}
+
+impl<T: KeyAgreement + Signer + Send + Sync + ?Sized> DeviceKey for T {}
+
+impl KeyAgreement for Keypair {
+    fn public_key(&self) -> &PublicKey {
+        &self.public
+    }
+
+    fn shared_secret(&self, other_public: &PublicKey) -> Zeroizing<[u8; 32]> {
         let other = X25519PublicKey::from(other_public.0);
-        self.secret.diffie_hellman(&other).to_bytes()
+        Zeroizing::new(self.secret.diffie_hellman(&other).to_bytes())
+    }
+
+    fn device_id(&self) -> DeviceId {
+        self.device_id
+    }
+
+    /// Derived from the secret key directly, as before hardware keys were possible, so data
+    /// already sealed stays readable.
+    fn storage_key(&self) -> Zeroizing<[u8; 32]> {
+        sub_key(STORAGE, &self.secret)
     }
 }
 
+/// The signing key is derived from the X25519 secret, so a restored keypair (see
+/// [`Keypair::from_bytes`]) signs with the same key.
+impl Signer for Keypair {
+    fn signing_public_key(&self) -> SigningPublicKey {
+        SigningPublicKey(self.signing.verifying_key().to_bytes())
+    }
+
+    fn sign_bytes(&self, message: &[u8]) -> Signature {
+        Signature(self.signing.sign(message).to_bytes())
+    }
+}
+
+/// Format of [`Keypair::to_bytes_encrypted`]: magic, version, then the salt.
+const KEYSTORE_MAGIC: &[u8; 4] = b"PEAK";
+const KEYSTORE_VERSION: u8 = 1;
+const KEYSTORE_HEADER: usize = 4 + 1 + 16;
+
+/// Cipher for a sealed secret key, keyed by Argon2id (default parameters) over the passphrase.
+fn keystore_cipher(passphrase: &[u8], salt: &[u8]) -> Result<ChaCha20Poly1305, KeystoreError> {
+    let mut key = Zeroizing::new([0u8; 32]);
+    Argon2::default()
+        .hash_password_into(passphrase, salt, key.as_mut_slice())
+        .map_err(|_| KeystoreError::Kdf)?;
+    ChaCha20Poly1305::new_from_slice(key.as_slice()).map_err(|_| KeystoreError::Kdf)
+}
+
+/// Proof domains for [`DeviceKey::join_signature`] and [`DeviceKey::leave_signature`].
+const JOIN: &[u8] = b"peapod-join-v1";
+const LEAVE: &[u8] = b"peapod-leave-v1";
+/// Domain for [`KeyAgreement::storage_key`].
+const STORAGE: &[u8] = b"peapod-storage-v1";
+/// Domain for [`DeviceKey::attest_chunk`].
+const ATTEST: &[u8] = b"peapod-attest-v1";
+/// Domain for [`DeviceKey::rotation_proofs`].
+const ROTATE: &[u8] = b"peapod-rotate-v1";
+/// Domain deriving the Ed25519 seed from the X25519 secret.
+const SIGNING: &[u8] = b"peapod-signing-v1";
+/// Signature domains for [`DeviceKey::identity_binding`], [`DeviceKey::sign_message`] and
+/// [`DeviceKey::sign_chunk`].
+const BIND: &[u8] = b"peapod-bind-v1";
+const MESSAGE: &[u8] = b"peapod-message-v1";
+const CHUNK: &[u8] = b"peapod-chunk-v1";
+const NAME: &[u8] = b"peapod-name-v1";
+const REVOKE: &[u8] = b"peapod-revoke-v1";
+
+/// Key derived from the secret key for one purpose: SHA-256(domain ‖ secret).
+fn sub_key(domain: &[u8], secret: &StaticSecret) -> Zeroizing<[u8; 32]> {
+    let mut hasher = Sha256::new();
+    hasher.update(domain);
+    hasher.update(Zeroizing::new(secret.to_bytes()));
+    Zeroizing::new(hasher.finalize().into())
+}
+
+/// What [`Signer::sign`] signs: domain length (u32 big-endian), domain, payload.
+fn signed_bytes(domain: &[u8], payload: &[u8]) -> Vec<u8> {
+    [&(domain.len() as u32).to_be_bytes()[..], domain, payload].concat()
+}
+
+fn binding(device_id: &DeviceId, public_key: &PublicKey) -> Vec<u8> {
+    [&device_id.0[..], &public_key.0].concat()
+}
+
+/// Device ID ‖ name (UTF-8).
+fn name_statement(device_id: &DeviceId, name: &str) -> Vec<u8> {
+    let mut out = device_id.as_bytes().to_vec();
+    out.extend_from_slice(name.as_bytes());
+    out
+}
+
+fn revocation_statement(issuer: &DeviceId, revoked: &DeviceId) -> Vec<u8> {
+    let mut out = issuer.as_bytes().to_vec();
+    out.extend_from_slice(revoked.as_bytes());
+    out
+}
+
+/// Transfer ID ‖ start ‖ end ‖ hash, offsets big-endian.
+fn chunk_statement(transfer_id: &[u8; 16], (start, end): (u64, u64), hash: &[u8; 32]) -> Vec<u8> {
+    [
+        &transfer_id[..],
+        &start.to_be_bytes(),
+        &end.to_be_bytes(),
+        hash,
+    ]
+    .concat()
+}
+
+/// Compare two MACs in constant time, so a forger learns nothing from how long a check took.
+pub(crate) fn macs_match(expected: &[u8; 32], received: &[u8; 32]) -> bool {
+    expected.ct_eq(received).into()
+}
+
+/// Domain-separated proof binding `device_id` to a pairwise shared secret.
+fn proof(domain: &[u8], shared_secret: &[u8; 32], device_id: &DeviceId) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(domain);
+    hasher.update(shared_secret);
+    hasher.update(device_id.as_bytes());
+    hasher.finalize().into()
+}
+
+/// Chunk attestation: SHA-256(domain ‖ shared secret ‖ server device ID ‖ transfer ID ‖ start ‖
+/// end ‖ hash), offsets big-endian.
+fn attestation(
+    shared_secret: &[u8; 32],
+    server: &DeviceId,
+    transfer_id: &[u8; 16],
+    (start, end): (u64, u64),
+    hash: &[u8; 32],
+) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(ATTEST);
+    hasher.update(shared_secret);
+    hasher.update(server.as_bytes());
+    hasher.update(transfer_id);
+    hasher.update(start.to_be_bytes());
+    hasher.update(end.to_be_bytes());
+    hasher.update(hash);
+    hasher.finalize().into()
+}
+
 /// Derive a 32-byte session key from shared secret (e.g. for ChaCha20-Poly1305).
 /// Pairwise: each pair of devices has its own session key.
-pub fn derive_session_key(shared_secret: &[u8; 32]) -> [u8; 32] {
+pub fn derive_session_key(shared_secret: &[u8; 32]) -> Zeroizing<[u8; 32]> {
     let mut hasher = Sha256::new();
     hasher.update(b"peapod-session-v1");
     hasher.update(shared_secret);
-    hasher.finalize().into()
+    Zeroizing::new(hasher.finalize().into())
 }
 
 /// Wire encryption: ChaCha20-Poly1305. Nonce: 96-bit counter per direction; never reuse.
@@ -144,6 +746,231 @@ pub fn decrypt_wire(
         .map_err(|_| WireCryptoError::Decrypt)
 }
 
+/// Nonces this far behind the newest one a [`DirectionalCipher`] opened are refused.
+pub const REPLAY_WINDOW: u64 = 64;
+/// Ratchet domain for [`SessionCipher`] rekeys.
+const REKEY: &[u8] = b"peapod-rekey-v1";
+/// Frames under one key before a rekey is forced whatever the policy, far below nonce wrap-around.
+const MAX_FRAMES_PER_KEY: u64 = 1 << 32;
+/// Largest plaintext that can be a [`Message::Rekey`] frame; longer ones are not decoded.
+const REKEY_FRAME_MAX: usize = 16;
+
+/// One direction of a session: a key, the nonce counter when sealing, and a sliding window of
+/// opened nonces that refuses replays. Frames are the nonce (u64 little-endian) followed by the
+/// ChaCha20-Poly1305 ciphertext, so a transport may deliver them out of order within
+/// [`REPLAY_WINDOW`].
+pub struct DirectionalCipher {
+    key: [u8; 32],
+    next_nonce: u64,
+    /// Newest nonce opened; bit `i` of `seen` is nonce `highest - i`.
+    highest: Option<u64>,
+    seen: u64,
+}
+
+impl DirectionalCipher {
+    pub fn new(key: [u8; 32]) -> Self {
+        Self {
+            key,
+            next_nonce: 0,
+            highest: None,
+            seen: 0,
+        }
+    }
+
+    /// Encrypt `plain` under the next nonce.
+    pub fn seal(&mut self, plain: &[u8]) -> Result<Vec<u8>, WireCryptoError> {
+        let nonce = self.next_nonce;
+        self.next_nonce = nonce.checked_add(1).ok_or(WireCryptoError::Encrypt)?;
+        let sealed = encrypt_wire(&self.key, nonce, plain)?;
+        Ok([&nonce.to_le_bytes()[..], &sealed].concat())
+    }
+
+    /// Decrypt a frame from [`DirectionalCipher::seal`]. Fails with [`WireCryptoError::Replay`]
+    /// for a nonce already opened or older than the window, [`WireCryptoError::Decrypt`] for a
+    /// forged or altered frame.
+    pub fn open(&mut self, frame: &[u8]) -> Result<Vec<u8>, WireCryptoError> {
+        if frame.len() < 8 {
+            return Err(WireCryptoError::Decrypt);
+        }
+        let (nonce, ciphertext) = frame.split_at(8);
+        let nonce = u64::from_le_bytes(nonce.try_into().expect("8 bytes"));
+        if !self.fresh(nonce) {
+            return Err(WireCryptoError::Replay);
+        }
+        // Only authentic frames move the window.
+        let plain = decrypt_wire(&self.key, nonce, ciphertext)?;
+        self.mark(nonce);
+        Ok(plain)
+    }
+
+    /// Frames sealed under this key so far.
+    pub fn sealed(&self) -> u64 {
+        self.next_nonce
+    }
+
+    fn fresh(&self, nonce: u64) -> bool {
+        match self.highest {
+            None => true,
+            Some(h) if nonce > h => true,
+            Some(h) => h - nonce < REPLAY_WINDOW && self.seen & (1 << (h - nonce)) == 0,
+        }
+    }
+
+    fn mark(&mut self, nonce: u64) {
+        match self.highest {
+            Some(h) if nonce <= h => self.seen |= 1 << (h - nonce),
+            Some(h) => {
+                let shift = nonce - h;
+                self.seen = if shift < REPLAY_WINDOW {
+                    self.seen << shift
+                } else {
+                    0
+                } | 1;
+                self.highest = Some(nonce);
+            }
+            None => {
+                self.seen = 1;
+                self.highest = Some(nonce);
+            }
+        }
+    }
+
+    /// Move to the next key of the hash ratchet, forgetting this one (so it cannot be recovered
+    /// later), with fresh nonces.
+    fn ratchet(&mut self, generation: u32) {
+        *self = Self::new(hash_parts(&[REKEY, &self.key, &generation.to_be_bytes()]));
+    }
+}
+
+impl Drop for DirectionalCipher {
+    fn drop(&mut self) {
+        self.key.zeroize();
+    }
+}
+
+/// When a [`SessionCipher`] moves a direction to a fresh key: after `max_bytes` of plaintext or
+/// `max_ms` milliseconds under the current one, whichever comes first (0 disables either limit).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RekeyPolicy {
+    pub max_bytes: u64,
+    pub max_ms: u64,
+}
+
+impl Default for RekeyPolicy {
+    /// 1 GiB or 15 minutes.
+    fn default() -> Self {
+        Self {
+            max_bytes: 1 << 30,
+            max_ms: 15 * 60 * 1000,
+        }
+    }
+}
+
+/// A [`DirectionalCipher`] with its rekey bookkeeping.
+struct Direction {
+    cipher: DirectionalCipher,
+    generation: u32,
+    bytes: u64,
+    since_ms: u64,
+}
+
+impl Direction {
+    fn new(key: [u8; 32], now_ms: u64) -> Self {
+        Self {
+            cipher: DirectionalCipher::new(key),
+            generation: 0,
+            bytes: 0,
+            since_ms: now_ms,
+        }
+    }
+
+    fn ratchet(&mut self, now_ms: u64) {
+        self.generation += 1;
+        self.cipher.ratchet(self.generation);
+        self.bytes = 0;
+        self.since_ms = now_ms;
+    }
+}
+
+/// Frame encryption for an established [`Session`]: a [`DirectionalCipher`] per direction, so
+/// hosts keep no nonces. Before the send key exceeds its [`RekeyPolicy`] (or its nonce budget), a
+/// [`Message::Rekey`] goes out under the old key and later frames use the next one; the receiving
+/// side ratchets when it opens that frame.
+pub struct SessionCipher {
+    send: Direction,
+    recv: Direction,
+    policy: RekeyPolicy,
+}
+
+impl SessionCipher {
+    /// `now_ms` is the host's clock, as passed to [`crate::PeaPodCore::tick_at`].
+    pub fn new(session: &Session, policy: RekeyPolicy, now_ms: u64) -> Self {
+        Self {
+            send: Direction::new(session.send_key, now_ms),
+            recv: Direction::new(session.recv_key, now_ms),
+            policy,
+        }
+    }
+
+    /// Encrypt one frame. Returns the frames to send in order: a rekey frame first when the send
+    /// key is due, then the frame itself.
+    pub fn seal(&mut self, plain: &[u8], now_ms: u64) -> Result<Vec<Vec<u8>>, WireCryptoError> {
+        let mut out = Vec::with_capacity(1);
+        if self.rekey_due(plain.len() as u64, now_ms) {
+            let rekey = Message::Rekey {
+                generation: self.send.generation + 1,
+            };
+            let frame = wire::encode_frame(&rekey).map_err(|_| WireCryptoError::Encrypt)?;
+            out.push(self.send.cipher.seal(&frame)?);
+            self.send.ratchet(now_ms);
+        }
+        out.push(self.send.cipher.seal(plain)?);
+        self.send.bytes = self.send.bytes.saturating_add(plain.len() as u64);
+        Ok(out)
+    }
+
+    /// Decrypt one frame. `None` for a rekey frame, which only moves the receive key on.
+    pub fn open(&mut self, frame: &[u8], now_ms: u64) -> Result<Option<Vec<u8>>, WireCryptoError> {
+        let plain = self.recv.cipher.open(frame)?;
+        if plain.len() <= REKEY_FRAME_MAX {
+            if let Ok((Message::Rekey { generation }, _)) = wire::decode_frame(&plain) {
+                if generation != self.recv.generation + 1 {
+                    return Err(WireCryptoError::Decrypt);
+                }
+                self.recv.ratchet(now_ms);
+                return Ok(None);
+            }
+        }
+        self.recv.bytes = self.recv.bytes.saturating_add(plain.len() as u64);
+        Ok(Some(plain))
+    }
+
+    /// Rekeys of the (send, receive) direction so far.
+    pub fn generations(&self) -> (u32, u32) {
+        (self.send.generation, self.recv.generation)
+    }
+
+    fn rekey_due(&self, next_len: u64, now_ms: u64) -> bool {
+        let RekeyPolicy { max_bytes, max_ms } = self.policy;
+        let sealed = self.send.cipher.sealed();
+        // Nothing sent yet: a fresh key needs no rotation.
+        if sealed == 0 {
+            return false;
+        }
+        sealed + 2 >= MAX_FRAMES_PER_KEY
+            || (max_bytes > 0 && self.send.bytes.saturating_add(next_len) > max_bytes)
+            || (max_ms > 0 && now_ms.saturating_sub(self.send.since_ms) >= max_ms)
+    }
+}
+
+fn hash_parts(parts: &[&[u8]]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum WireCryptoError {
     #[error("invalid key")]
@@ -152,6 +979,22 @@ pub enum WireCryptoError {
     Encrypt,
     #[error("decryption failed")]
     Decrypt,
+    /// Nonce already used or outside the [`DirectionalCipher`] replay window.
+    #[error("replayed frame")]
+    Replay,
+}
+
+/// Error restoring a keypair from [`Keypair::from_bytes_encrypted`].
+#[derive(Debug, thiserror::Error)]
+pub enum KeystoreError {
+    #[error("not a PeaPod keystore")]
+    Format,
+    #[error("unsupported keystore version {0}")]
+    Version(u8),
+    #[error("wrong passphrase or corrupt keystore")]
+    Passphrase,
+    #[error("key derivation failed")]
+    Kdf,
 }
 
 #[cfg(test)]
@@ -174,6 +1017,232 @@ mod tests {
         assert_eq!(secret_a, secret_b);
     }
 
+    #[test]
+    fn keypair_round_trips_plain_and_encrypted() {
+        let kp = Keypair::generate();
+        let restored = Keypair::from_bytes(*kp.to_bytes());
+        assert_eq!(restored.device_id(), kp.device_id());
+        assert_eq!(restored.public_key(), kp.public_key());
+
+        let sealed = kp.to_bytes_encrypted(b"correct horse").unwrap();
+        assert!(!sealed.windows(32).any(|w| *w == *kp.to_bytes()));
+        let restored = Keypair::from_bytes_encrypted(&sealed, b"correct horse").unwrap();
+        assert_eq!(restored.device_id(), kp.device_id());
+        assert!(matches!(
+            Keypair::from_bytes_encrypted(&sealed, b"battery staple"),
+            Err(KeystoreError::Passphrase)
+        ));
+        let mut tampered = sealed.clone();
+        tampered[8] ^= 1;
+        assert!(matches!(
+            Keypair::from_bytes_encrypted(&tampered, b"correct horse"),
+            Err(KeystoreError::Passphrase)
+        ));
+        tampered = sealed;
+        tampered[4] = 9;
+        assert!(matches!(
+            Keypair::from_bytes_encrypted(&tampered, b"correct horse"),
+            Err(KeystoreError::Version(9))
+        ));
+        assert!(matches!(
+            Keypair::from_bytes_encrypted(kp.to_bytes().as_slice(), b"correct horse"),
+            Err(KeystoreError::Format)
+        ));
+    }
+
+    #[test]
+    fn signatures_verify_for_anyone_and_only_for_the_signed_statement() {
+        let kp = Keypair::generate();
+        let key = kp.signing_public_key();
+        assert_eq!(
+            Keypair::from_bytes(*kp.to_bytes()).signing_public_key(),
+            key
+        );
+        let other = Keypair::generate();
+
+        let binding = kp.identity_binding();
+        assert!(key.verify_binding(&kp.device_id(), kp.public_key(), &binding));
+        assert!(!key.verify_binding(&other.device_id(), other.public_key(), &binding));
+        assert!(!other.signing_public_key().verify_binding(
+            &kp.device_id(),
+            kp.public_key(),
+            &binding
+        ));
+
+        let beacon = |listen_port| Message::Beacon {
+            protocol_version: 1,
+            device_id: kp.device_id(),
+            public_key: kp.public_key().clone(),
+            listen_port,
+        };
+        let sig = kp.sign_message(&beacon(45679)).unwrap();
+        assert!(key.verify_message(&beacon(45679), &sig));
+        assert!(!key.verify_message(&beacon(1), &sig));
+
+        let sig = kp.sign_chunk(&[7; 16], (0, 10), &[1; 32]);
+        assert!(key.verify_chunk(&[7; 16], (0, 10), &[1; 32], &sig));
+        assert!(!key.verify_chunk(&[7; 16], (0, 10), &[2; 32], &sig));
+        assert!(!key.verify_chunk(&[7; 16], (10, 20), &[1; 32], &sig));
+        // Same bytes under another domain.
+        assert!(!key.verify(BIND, &chunk_statement(&[7; 16], (0, 10), &[1; 32]), &sig));
+    }
+
+    #[test]
+    fn directional_cipher_rejects_replays_and_stale_nonces() {
+        let mut tx = DirectionalCipher::new([3; 32]);
+        let mut rx = DirectionalCipher::new([3; 32]);
+        let frames: Vec<_> = (0..100u8).map(|n| tx.seal(&[n]).unwrap()).collect();
+        // Out of order within the window is fine; each frame opens once.
+        assert_eq!(rx.open(&frames[1]).unwrap(), vec![1]);
+        assert_eq!(rx.open(&frames[0]).unwrap(), vec![0]);
+        assert!(matches!(rx.open(&frames[1]), Err(WireCryptoError::Replay)));
+        assert_eq!(rx.open(&frames[80]).unwrap(), vec![80]);
+        assert_eq!(rx.open(&frames[17]).unwrap(), vec![17]);
+        assert!(matches!(rx.open(&frames[16]), Err(WireCryptoError::Replay)));
+        // A forged frame with a fresh nonce does not move the window.
+        let mut forged = frames[99].clone();
+        forged[9] ^= 1;
+        assert!(matches!(rx.open(&forged), Err(WireCryptoError::Decrypt)));
+        assert_eq!(rx.open(&frames[99]).unwrap(), vec![99]);
+        assert_eq!(rx.open(&frames[40]).unwrap(), vec![40]);
+    }
+
+    #[test]
+    fn session_ciphers_rekey_each_direction_by_bytes_and_time() {
+        let session = |send_key, recv_key| Session {
+            peer_id: DeviceId::from_bytes([0; 16]),
+            peer_public: PublicKey::from_bytes([0; 32]),
+            send_key,
+            recv_key,
+        };
+        let frame = |n: u8| {
+            wire::encode_frame(&Message::Heartbeat {
+                device_id: DeviceId::from_bytes([n; 16]),
+            })
+            .unwrap()
+        };
+        // Room for four frames per key.
+        let policy = RekeyPolicy {
+            max_bytes: 4 * frame(0).len() as u64,
+            max_ms: 1000,
+        };
+        let mut ca = SessionCipher::new(&session([1; 32], [2; 32]), policy, 0);
+        let mut cb = SessionCipher::new(&session([2; 32], [1; 32]), policy, 0);
+        let deliver = |ca: &mut SessionCipher, cb: &mut SessionCipher, n: u8, now_ms| {
+            let sealed = ca.seal(&frame(n), now_ms).unwrap();
+            let opened: Vec<_> = sealed
+                .iter()
+                .filter_map(|c| cb.open(c, now_ms).unwrap())
+                .collect();
+            assert_eq!(opened, vec![frame(n)]);
+            sealed
+        };
+        for n in 0..4 {
+            assert_eq!(deliver(&mut ca, &mut cb, n, 10).len(), 1);
+        }
+        let sealed = deliver(&mut ca, &mut cb, 4, 10);
+        assert_eq!(sealed.len(), 2, "rekey frame first");
+        assert_eq!(ca.generations(), (1, 0));
+        assert_eq!(cb.generations(), (0, 1));
+        // A frame replayed from the old key no longer opens.
+        assert!(cb.open(&sealed[0], 10).is_err());
+
+        // The other direction rotates on time alone.
+        deliver(&mut cb, &mut ca, 0, 10);
+        assert_eq!(deliver(&mut cb, &mut ca, 1, 999).len(), 1);
+        assert_eq!(deliver(&mut cb, &mut ca, 2, 1010).len(), 2);
+        assert_eq!(cb.generations(), (1, 1));
+
+        // A rekey that skips a generation is refused.
+        let rekey = wire::encode_frame(&Message::Rekey { generation: 9 }).unwrap();
+        let bogus = ca.send.cipher.seal(&rekey).unwrap();
+        assert!(cb.open(&bogus, 1010).is_err());
+    }
+
+    #[test]
+    fn join_signature_verifies_only_for_matching_identity() {
+        let a = Keypair::generate();
+        let b = Keypair::generate();
+        let mallory = Keypair::generate();
+        let sig = a.join_signature(b.public_key());
+        assert!(b.verify_join(&a.device_id(), a.public_key(), &sig));
+        // Device ID not derived from the public key.
+        assert!(!b.verify_join(&mallory.device_id(), a.public_key(), &sig));
+        // Mallory claims a's key but cannot produce a's signature.
+        let forged = mallory.join_signature(b.public_key());
+        assert!(!b.verify_join(&a.device_id(), a.public_key(), &forged));
+    }
+
+    #[test]
+    fn leave_signature_is_not_a_join_signature() {
+        let a = Keypair::generate();
+        let b = Keypair::generate();
+        let mallory = Keypair::generate();
+        assert!(b.verify_leave(a.public_key(), &a.leave_signature(b.public_key())));
+        assert!(!b.verify_leave(a.public_key(), &a.join_signature(b.public_key())));
+        assert!(!b.verify_leave(a.public_key(), &mallory.leave_signature(b.public_key())));
+    }
+
+    #[test]
+    fn dropped_ciphers_wipe_their_keys() {
+        let mut cipher = std::mem::ManuallyDrop::new(DirectionalCipher::new([7u8; 32]));
+        cipher.ratchet(1);
+        assert_ne!(cipher.key, [0u8; 32]);
+        // SAFETY: the cipher is not used again; only its (plain byte) key field is read back.
+        unsafe { std::ptr::drop_in_place(&mut *cipher) };
+        assert_eq!(cipher.key, [0u8; 32]);
+    }
+
+    /// Stands in for a hardware key: only the four required operations, delegated to a keypair
+    /// the core never sees.
+    struct HostKey(Keypair);
+
+    impl KeyAgreement for HostKey {
+        fn public_key(&self) -> &PublicKey {
+            self.0.public_key()
+        }
+
+        fn shared_secret(&self, other_public: &PublicKey) -> Zeroizing<[u8; 32]> {
+            KeyAgreement::shared_secret(&self.0, other_public)
+        }
+    }
+
+    impl Signer for HostKey {
+        fn signing_public_key(&self) -> SigningPublicKey {
+            Signer::signing_public_key(&self.0)
+        }
+
+        fn sign_bytes(&self, message: &[u8]) -> Signature {
+            self.0.sign_bytes(message)
+        }
+    }
+
+    #[test]
+    fn host_provided_keys_interoperate_with_keypairs() {
+        let host = HostKey(Keypair::generate());
+        let peer = Keypair::generate();
+        assert_eq!(KeyAgreement::device_id(&host), host.0.device_id());
+        assert_eq!(
+            host.shared_secret(peer.public_key()),
+            KeyAgreement::shared_secret(&peer, host.public_key())
+        );
+        let signing = host.signing_public_key();
+        assert!(signing.verify_binding(
+            &KeyAgreement::device_id(&host),
+            host.public_key(),
+            &host.identity_binding()
+        ));
+        let sig = host.join_signature(peer.public_key());
+        assert!(peer.verify_join(&host.0.device_id(), host.public_key(), &sig));
+        let sig = peer.join_signature(host.public_key());
+        assert!(host.verify_join(&peer.device_id(), peer.public_key(), &sig));
+        // The default storage key is stable and differs from the shared secret it is derived from.
+        assert_eq!(host.storage_key(), host.storage_key());
+        assert_ne!(host.storage_key(), host.shared_secret(host.public_key()));
+        let core: std::sync::Arc<dyn DeviceKey> = std::sync::Arc::new(host);
+        assert_eq!(core.signing_public_key(), signing);
+    }
+
     #[test]
     fn encrypt_decrypt_roundtrip() {
         use rand::RngCore;
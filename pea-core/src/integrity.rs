@@ -1,7 +1,13 @@
-//! Integrity: per-chunk hash (e.g. SHA-256), verify on receive.
+//! Integrity: per-chunk hash (SHA-256, or BLAKE3 between peers that support it), verify on receive.
 
+use std::collections::HashMap;
+
+use base64::prelude::{Engine, BASE64_STANDARD};
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 
+use crate::identity::DeviceId;
+
 /// Hash a chunk payload. Returns 32-byte digest.
 pub fn hash_chunk(payload: &[u8]) -> [u8; 32] {
     let mut hasher = Sha256::new();
@@ -14,6 +20,365 @@ pub fn verify_chunk(payload: &[u8], expected_hash: &[u8; 32]) -> bool {
     hash_chunk(payload) == *expected_hash
 }
 
+/// Hash function behind a chunk's hash in [`crate::protocol::Message::ChunkData`]. SHA-256 is what
+/// every peer understands; BLAKE3 costs a fraction of the CPU on phones and is only sent to peers
+/// advertising [`crate::protocol::FEATURE_BLAKE3`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HashAlgorithm {
+    #[default]
+    Sha256,
+    Blake3,
+}
+
+impl HashAlgorithm {
+    /// Hash a chunk payload with this algorithm. Returns 32-byte digest.
+    pub fn hash(self, payload: &[u8]) -> [u8; 32] {
+        match self {
+            HashAlgorithm::Sha256 => hash_chunk(payload),
+            HashAlgorithm::Blake3 => blake3::hash(payload).into(),
+        }
+    }
+
+    /// Verify chunk payload against an expected hash made with this algorithm.
+    pub fn verify(self, payload: &[u8], expected_hash: &[u8; 32]) -> bool {
+        self.hash(payload) == *expected_hash
+    }
+}
+
+/// Merkle root over leaf hashes: each parent is SHA-256(0x01 ‖ left ‖ right); an odd node is
+/// carried up unchanged. The root of no leaves is SHA-256 of the empty string.
+pub fn merkle_root(leaves: &[[u8; 32]]) -> [u8; 32] {
+    if leaves.is_empty() {
+        return hash_chunk(&[]);
+    }
+    let mut level = leaves.to_vec();
+    while level.len() > 1 {
+        level = merkle_level(&level);
+    }
+    level[0]
+}
+
+/// Parent of two nodes: SHA-256(0x01 ‖ left ‖ right).
+fn merkle_parent(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update([1u8]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+/// The level above `level`, carrying an odd last node up unchanged.
+fn merkle_level(level: &[[u8; 32]]) -> Vec<[u8; 32]> {
+    level
+        .chunks(2)
+        .map(|pair| match pair {
+            [left, right] => merkle_parent(left, right),
+            [single] => *single,
+            _ => unreachable!("chunks(2) yields one or two items"),
+        })
+        .collect()
+}
+
+/// Inclusion proof of one leaf in a [`merkle_root`] tree: the sibling hashes from the leaf up
+/// (levels where the node is carried up without a sibling contribute none). With a root learned
+/// from a trusted source, a chunk from a peer is checked against the root instead of against the
+/// hash the peer sent with it.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MerkleProof {
+    /// Position of the leaf.
+    pub index: usize,
+    /// Leaves in the tree.
+    pub leaf_count: usize,
+    pub siblings: Vec<[u8; 32]>,
+}
+
+impl MerkleProof {
+    /// Whether `leaf` (e.g. [`hash_chunk`] of a received payload) sits at `index` of the tree
+    /// with `root`.
+    pub fn verify(&self, leaf: &[u8; 32], root: &[u8; 32]) -> bool {
+        if self.index >= self.leaf_count {
+            return false;
+        }
+        let mut siblings = self.siblings.iter();
+        let (mut node, mut index, mut len) = (*leaf, self.index, self.leaf_count);
+        while len > 1 {
+            if index % 2 == 1 || index + 1 < len {
+                let Some(sibling) = siblings.next() else {
+                    return false;
+                };
+                node = match index % 2 {
+                    0 => merkle_parent(&node, sibling),
+                    _ => merkle_parent(sibling, &node),
+                };
+            }
+            index /= 2;
+            len = len.div_ceil(2);
+        }
+        siblings.next().is_none() && node == *root
+    }
+}
+
+/// Inclusion proof for `leaves[index]`, or None if `index` is out of range.
+pub fn merkle_proof(leaves: &[[u8; 32]], index: usize) -> Option<MerkleProof> {
+    if index >= leaves.len() {
+        return None;
+    }
+    let mut siblings = Vec::new();
+    let mut level = leaves.to_vec();
+    let mut i = index;
+    while level.len() > 1 {
+        if let Some(sibling) = level.get(i ^ 1) {
+            siblings.push(*sibling);
+        }
+        level = merkle_level(&level);
+        i /= 2;
+    }
+    Some(MerkleProof {
+        index,
+        leaf_count: leaves.len(),
+        siblings,
+    })
+}
+
+/// SHA-256 of each `block_size` block of a body given as consecutive `parts` (chunk payloads in
+/// order; part boundaries need not line up with blocks). The last block may be short.
+pub fn block_hashes<'a>(
+    parts: impl IntoIterator<Item = &'a [u8]>,
+    block_size: u64,
+) -> Vec<[u8; 32]> {
+    let block_size = block_size.max(1);
+    let mut out = Vec::new();
+    let mut hasher = Sha256::new();
+    let mut filled = 0u64;
+    for mut part in parts {
+        while !part.is_empty() {
+            let take = (block_size - filled).min(part.len() as u64) as usize;
+            hasher.update(&part[..take]);
+            filled += take as u64;
+            part = &part[take..];
+            if filled == block_size {
+                out.push(std::mem::take(&mut hasher).finalize().into());
+                filled = 0;
+            }
+        }
+    }
+    if filled > 0 {
+        out.push(hasher.finalize().into());
+    }
+    out
+}
+
+/// Expected block hashes of a whole body from a trusted source (an origin manifest, or a direct
+/// fetch made under a strong validator). Lets the requester reject a reassembled body whose chunks
+/// were each self-consistent but wrong.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub block_size: u64,
+    pub block_hashes: Vec<[u8; 32]>,
+}
+
+impl Manifest {
+    /// Manifest of a trusted copy of the body.
+    pub fn from_body(body: &[u8], block_size: u64) -> Self {
+        Self {
+            block_size,
+            block_hashes: block_hashes([body], block_size),
+        }
+    }
+
+    /// Merkle root over the block hashes.
+    pub fn root(&self) -> [u8; 32] {
+        merkle_root(&self.block_hashes)
+    }
+
+    /// Check a body (as consecutive parts) against the root. Returns the indexes of blocks that
+    /// differ (all of them if the block count differs), or an empty list if the root matches.
+    pub fn mismatched_blocks<'a>(&self, parts: impl IntoIterator<Item = &'a [u8]>) -> Vec<usize> {
+        let actual = block_hashes(parts, self.block_size);
+        if merkle_root(&actual) == self.root() {
+            return Vec::new();
+        }
+        if actual.len() != self.block_hashes.len() {
+            return (0..actual.len().max(self.block_hashes.len())).collect();
+        }
+        (0..actual.len())
+            .filter(|&i| actual[i] != self.block_hashes[i])
+            .collect()
+    }
+}
+
+/// Digest of a whole body published by the origin: a `Digest` (RFC 3230) or `Repr-Digest`
+/// (RFC 9530) header, `Content-MD5`, or a strong ETag that is the hex MD5 or SHA-256 of the body
+/// (as S3-style stores use). Checked against the reassembled body on completion.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OriginDigest {
+    Md5([u8; 16]),
+    Sha256([u8; 32]),
+}
+
+impl OriginDigest {
+    /// Digests in response headers given as (name, value). Unknown algorithms, weak or opaque
+    /// ETags and malformed values are skipped.
+    pub fn from_headers<'a>(headers: impl IntoIterator<Item = (&'a str, &'a str)>) -> Vec<Self> {
+        let mut digests = Vec::new();
+        for (name, value) in headers {
+            match name.to_ascii_lowercase().as_str() {
+                "digest" | "repr-digest" => {
+                    for item in value.split(',') {
+                        let Some((algorithm, encoded)) = item.split_once('=') else {
+                            continue;
+                        };
+                        // Repr-Digest wraps the value in colons (structured-field byte sequence).
+                        let encoded = encoded.trim().trim_matches(':');
+                        digests.extend(Self::from_base64(algorithm.trim(), encoded));
+                    }
+                }
+                "content-md5" => digests.extend(Self::from_base64("md5", value.trim())),
+                "etag" => digests.extend(Self::from_etag(value)),
+                _ => {}
+            }
+        }
+        digests
+    }
+
+    fn from_base64(algorithm: &str, encoded: &str) -> Option<Self> {
+        let bytes = BASE64_STANDARD.decode(encoded).ok()?;
+        match algorithm.to_ascii_lowercase().as_str() {
+            "md5" => bytes.try_into().ok().map(Self::Md5),
+            "sha-256" => bytes.try_into().ok().map(Self::Sha256),
+            _ => None,
+        }
+    }
+
+    fn from_etag(etag: &str) -> Option<Self> {
+        let etag = etag.trim();
+        if etag.starts_with("W/") {
+            return None;
+        }
+        let hex = etag.trim_matches('"');
+        if !hex.len().is_multiple_of(2) || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return None;
+        }
+        let bytes: Vec<u8> = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+            .collect::<Option<_>>()?;
+        match bytes.len() {
+            16 => bytes.try_into().ok().map(Self::Md5),
+            32 => bytes.try_into().ok().map(Self::Sha256),
+            _ => None,
+        }
+    }
+
+    /// Whether `body` has this digest.
+    pub fn matches(&self, body: &[u8]) -> bool {
+        match self {
+            Self::Md5(digest) => md5::Md5::digest(body).as_slice() == digest,
+            Self::Sha256(digest) => hash_chunk(body) == *digest,
+        }
+    }
+}
+
+/// Integrity record for one peer.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerTrust {
+    /// Chunks from this peer that failed hash verification.
+    pub integrity_failures: u32,
+    /// Peer reached the failure threshold and is excluded from chunk assignment.
+    pub isolated: bool,
+}
+
+/// Tracks integrity failures per peer and isolates peers that reach a threshold. Records survive
+/// the peer leaving, so an isolated peer stays isolated when it rejoins.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PeerTrustTracker {
+    threshold: u32,
+    peers: HashMap<DeviceId, PeerTrust>,
+}
+
+impl PeerTrustTracker {
+    /// Isolate peers after `threshold` integrity failures (0 disables isolation).
+    pub fn new(threshold: u32) -> Self {
+        Self {
+            threshold,
+            peers: HashMap::new(),
+        }
+    }
+
+    /// Change the threshold. Peers whose failures already reach a lowered threshold are isolated
+    /// now; returns them.
+    pub fn set_threshold(&mut self, threshold: u32) -> Vec<DeviceId> {
+        self.threshold = threshold;
+        if threshold == 0 {
+            return Vec::new();
+        }
+        self.peers
+            .iter_mut()
+            .filter(|(_, t)| !t.isolated && t.integrity_failures >= threshold)
+            .map(|(&peer, t)| {
+                t.isolated = true;
+                peer
+            })
+            .collect()
+    }
+
+    /// Record a failed chunk from `peer`. Returns true if this failure isolated the peer.
+    pub fn record_failure(&mut self, peer: DeviceId) -> bool {
+        let trust = self.peers.entry(peer).or_default();
+        trust.integrity_failures = trust.integrity_failures.saturating_add(1);
+        if !trust.isolated && self.threshold > 0 && trust.integrity_failures >= self.threshold {
+            trust.isolated = true;
+            return true;
+        }
+        false
+    }
+
+    /// Isolate `peer` regardless of the threshold (e.g. it was caught poisoning a chunk), counting
+    /// one more failure. Returns true if it was not isolated before.
+    pub fn isolate(&mut self, peer: DeviceId) -> bool {
+        let trust = self.peers.entry(peer).or_default();
+        trust.integrity_failures = trust.integrity_failures.saturating_add(1);
+        !std::mem::replace(&mut trust.isolated, true)
+    }
+
+    pub fn is_isolated(&self, peer: &DeviceId) -> bool {
+        self.peers.get(peer).is_some_and(|t| t.isolated)
+    }
+
+    /// Trust record for `peer`, or None if it never failed a chunk.
+    pub fn get(&self, peer: &DeviceId) -> Option<PeerTrust> {
+        self.peers.get(peer).copied()
+    }
+
+    /// Forget a peer's failures and lift its isolation (e.g. user marked it trusted again).
+    pub fn reset(&mut self, peer: &DeviceId) {
+        self.peers.remove(peer);
+    }
+
+    /// Every peer's record, for persisting.
+    pub fn records(&self) -> Vec<(DeviceId, PeerTrust)> {
+        self.peers.iter().map(|(&peer, &t)| (peer, t)).collect()
+    }
+
+    /// Merge a saved record for `peer`. With `replace` it overwrites the local one; otherwise the
+    /// more cautious wins (the larger failure count, isolated if either is). Returns true if this
+    /// isolated the peer.
+    pub fn merge(&mut self, peer: DeviceId, record: PeerTrust, replace: bool) -> bool {
+        let was_isolated = self.is_isolated(&peer);
+        let trust = self.peers.entry(peer).or_default();
+        if replace {
+            *trust = record;
+        } else {
+            trust.integrity_failures = trust.integrity_failures.max(record.integrity_failures);
+            trust.isolated |= record.isolated;
+        }
+        if *trust == PeerTrust::default() {
+            self.peers.remove(&peer);
+        }
+        !was_isolated && self.is_isolated(&peer)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -31,4 +396,78 @@ mod tests {
         let hash = hash_chunk(payload);
         assert!(!verify_chunk(b"tampered", &hash));
     }
+
+    #[test]
+    fn manifest_locates_tampered_block_across_chunk_boundaries() {
+        let body: Vec<u8> = (0..100).collect();
+        let manifest = Manifest::from_body(&body, 32);
+        assert_eq!(manifest.block_hashes.len(), 4);
+        let parts = [&body[..10], &body[10..70], &body[70..]];
+        assert!(manifest.mismatched_blocks(parts).is_empty());
+        let mut tampered = body.clone();
+        tampered[40] ^= 1;
+        let parts = [&tampered[..10], &tampered[10..70], &tampered[70..]];
+        assert_eq!(manifest.mismatched_blocks(parts), vec![1]);
+        assert_ne!(merkle_root(&manifest.block_hashes[..3]), manifest.root());
+    }
+
+    #[test]
+    fn merkle_proofs_verify_every_leaf_against_the_root() {
+        for n in 1..=9u8 {
+            let leaves: Vec<[u8; 32]> = (0..n).map(|i| hash_chunk(&[i])).collect();
+            let root = merkle_root(&leaves);
+            for (i, leaf) in leaves.iter().enumerate() {
+                let proof = merkle_proof(&leaves, i).unwrap();
+                assert!(proof.verify(leaf, &root), "leaf {i} of {n}");
+                assert!(!proof.verify(&hash_chunk(b"forged"), &root));
+                if n > 1 {
+                    let moved = MerkleProof {
+                        index: (i + 1) % n as usize,
+                        ..proof.clone()
+                    };
+                    assert!(!moved.verify(leaf, &root));
+                }
+            }
+            assert!(merkle_proof(&leaves, n as usize).is_none());
+        }
+    }
+
+    #[test]
+    fn origin_digests_parse_from_headers_and_check_bodies() {
+        let body = b"hello origin";
+        let sha = BASE64_STANDARD.encode(hash_chunk(body));
+        let md5 = md5::Md5::digest(body);
+        let md5_hex: String = md5.iter().map(|b| format!("{b:02x}")).collect();
+        let headers = [
+            ("Digest", format!("SHA-256={sha}, unixsum=30637")),
+            ("Repr-Digest", format!("sha-256=:{sha}:")),
+            ("Content-MD5", BASE64_STANDARD.encode(md5)),
+            ("ETag", format!("\"{md5_hex}\"")),
+            ("ETag", format!("W/\"{md5_hex}\"")),
+            ("ETag", "\"5d41-2\"".to_string()),
+        ];
+        let digests = OriginDigest::from_headers(headers.iter().map(|(n, v)| (*n, v.as_str())));
+        assert_eq!(digests.len(), 4);
+        assert!(digests.iter().all(|d| d.matches(body)));
+        assert!(digests.iter().all(|d| !d.matches(b"hello 0rigin")));
+    }
+
+    #[test]
+    fn trust_tracker_isolates_at_threshold() {
+        let peer = crate::identity::Keypair::generate().device_id();
+        let mut tracker = PeerTrustTracker::new(2);
+        assert!(!tracker.record_failure(peer));
+        assert!(!tracker.is_isolated(&peer));
+        assert!(tracker.record_failure(peer));
+        assert!(!tracker.record_failure(peer), "isolation reported once");
+        assert_eq!(
+            tracker.get(&peer),
+            Some(PeerTrust {
+                integrity_failures: 3,
+                isolated: true
+            })
+        );
+        tracker.reset(&peer);
+        assert!(!tracker.is_isolated(&peer));
+    }
 }
@@ -24,17 +24,47 @@ pub mod wire;
 #[allow(clippy::not_unsafe_ptr_arg_deref)]
 pub mod ffi;
 
-pub use chunk::ChunkId;
+pub use cache::{CacheKey, ChunkCache};
+pub use chunk::{
+    ChunkAttempts, ChunkAttestation, ChunkId, ChunkSink, ChunkTiming, Segment, SharedSink,
+};
 pub use core::{
-    Action, ChunkError, ChunkReceiveOutcome, Config, OnMessageError, OutboundAction, PeaPodCore,
-    PeerMetrics,
+    Action, ChunkReceiveOutcome, Config, Constraints, ContributionPolicy, CoreEvent, FetchFailure,
+    KeyRotation, Membership, OutboundAction, PeaPodCore, PeerCredit, PeerEviction, PeerInfo,
+    PeerMetrics, ReciprocityPolicy, SavingsEstimate, TimerIntervals, TrafficStats, TransferOutcome,
+    TransferProgress, TransferRecord, TrustMerge, TrustStore,
+};
+pub use error::{ChunkError, CoreError, HandshakeError, OnMessageError, SnapshotError};
+pub use handshake::{Handshake, HandshakeStep, Session, HANDSHAKE_VERSION};
+pub use identity::{
+    DeviceId, DeviceKey, DirectionalCipher, KeyAgreement, Keypair, KeystoreError, NameRecord,
+    PublicKey, RekeyPolicy, Revocation, SessionCipher, Signature, Signer, SigningPublicKey,
+    WireCryptoError, MAX_DEVICE_NAME_LEN,
+};
+pub use integrity::{HashAlgorithm, Manifest, MerkleProof, OriginDigest, PeerTrust};
+pub use pairing::{Fingerprint, PodKey, WrappedPodKey};
+pub use policy::{
+    ContentDecision, ContentFilter, Eligibility, EligibilityPolicy, PolicyRule, RequestInfo,
+    TransferRequest,
+};
+pub use protocol::{
+    is_supported_version, negotiate_version, Capabilities, Message, MessageType,
+    CANONICAL_PROTOCOL_VERSION, MAX_PROTOCOL_VERSION, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
+pub use scheduler::{AssignmentPlan, DefaultScheduler, Scheduler, Workers};
+pub use wire::{
+    decode_frame, encode_frame, encode_frame_as, encode_frame_with, peek_frame, test_vectors,
+    FrameDecodeError, FrameEncodeError, FrameHeader, FrameOptions, TestVector, WireEncoding,
 };
-pub use identity::{DeviceId, Keypair, PublicKey};
-pub use protocol::{Message, PROTOCOL_VERSION};
-pub use wire::{decode_frame, encode_frame, FrameDecodeError, FrameEncodeError};
+pub use zeroize::Zeroizing;
 
 // Stub modules for chunk manager, scheduler, integrity (full impl later).
+pub mod cache;
 pub mod chunk;
 pub mod core;
+pub mod error;
+pub mod handshake;
 pub mod integrity;
+pub mod pairing;
+pub mod policy;
 pub mod scheduler;
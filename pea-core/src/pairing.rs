@@ -0,0 +1,512 @@
+//! Pairing: a short authentication string both users compare before two devices trust each other.
+//!
+//! Both sides derive the same 6-digit code from the two public keys and a random nonce from each
+//! device. Each device commits to its nonce ([`nonce_commitment`]) and reveals it only after it
+//! holds the other's commitment, so nobody can choose a nonce, or a key, after seeing the other
+//! side's. An attacker in the middle runs a separate exchange with each device and cannot steer
+//! the two codes to match: they do with probability 10^-6 per attempt. Once a user confirms that
+//! the codes match, the device sends a confirmation bound to the X25519 shared secret and the
+//! code; a peer is paired when both confirmations are in.
+//!
+//! [`Fingerprint`] renders a key (or a pair of keys) the same way on every host, as hex groups,
+//! words or emoji, for users who compare keys out of band.
+//!
+//! Paired devices also share a [`PodKey`], agreed while pairing, that seals discovery frames.
+
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::ChaCha20Poly1305;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::identity::{self, DeviceId, KeyAgreement, PublicKey, WireCryptoError};
+use crate::protocol::Message;
+
+/// Number of decimal digits in a pairing code.
+pub const CODE_DIGITS: u32 = 6;
+
+/// Bytes of a fingerprint shown by the short renderings ([`Fingerprint::to_short_hex`],
+/// [`Fingerprint::to_words`], [`Fingerprint::to_emoji`]).
+pub const SHORT_FINGERPRINT_BYTES: usize = 6;
+
+/// Fresh random nonce for one pairing exchange. Never reveal one twice.
+pub fn pairing_nonce() -> [u8; 32] {
+    let mut nonce = [0u8; 32];
+    OsRng.fill_bytes(&mut nonce);
+    nonce
+}
+
+/// Commitment to the nonce of the device with `public_key`, sent before the nonce itself.
+pub fn nonce_commitment(public_key: &PublicKey, nonce: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(b"peapod-sas-commit-v1");
+    hasher.update(public_key.as_bytes());
+    hasher.update(nonce);
+    hasher.finalize().into()
+}
+
+/// Pairing code for two public keys and their devices' nonces (order does not matter), in
+/// `0..10^CODE_DIGITS`.
+pub fn pairing_code(a: &PublicKey, a_nonce: &[u8; 32], b: &PublicKey, b_nonce: &[u8; 32]) -> u32 {
+    let digest = Fingerprint::of_pair(a, a_nonce, b, b_nonce).0;
+    let n = u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]]);
+    n % 10u32.pow(CODE_DIGITS)
+}
+
+/// Number of replaced pod keys a device still opens discovery frames with, so pod members that
+/// missed a key change are still found (and then sent the current key).
+pub const POD_KEY_HISTORY: usize = 3;
+
+/// Canonical fingerprint of a public key, or of the two keys of a pairing. Hosts display one of
+/// its renderings rather than cutting keys or device IDs themselves; `Display` is the short hex.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Fingerprint([u8; 32]);
+
+impl Fingerprint {
+    /// Fingerprint of one device's key: SHA-256 over a domain and the key.
+    pub fn of(public_key: &PublicKey) -> Self {
+        let mut hasher = Sha256::new();
+        hasher.update(b"peapod-fingerprint-v1");
+        hasher.update(public_key.as_bytes());
+        Self(hasher.finalize().into())
+    }
+
+    /// Short authentication string for two keys and their devices' pairing nonces (order does
+    /// not matter); [`pairing_code`] is derived from it, so both devices show the same renderings.
+    pub fn of_pair(a: &PublicKey, a_nonce: &[u8; 32], b: &PublicKey, b_nonce: &[u8; 32]) -> Self {
+        let ((lo, lo_nonce), (hi, hi_nonce)) = if a.as_bytes() <= b.as_bytes() {
+            ((a, a_nonce), (b, b_nonce))
+        } else {
+            ((b, b_nonce), (a, a_nonce))
+        };
+        let mut hasher = Sha256::new();
+        hasher.update(b"peapod-sas-v2");
+        hasher.update(lo.as_bytes());
+        hasher.update(hi.as_bytes());
+        hasher.update(lo_nonce);
+        hasher.update(hi_nonce);
+        Self(hasher.finalize().into())
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    /// The whole fingerprint: 16 groups of four lowercase hex digits.
+    pub fn to_hex(&self) -> String {
+        hex_groups(&self.0)
+    }
+
+    /// The first [`SHORT_FINGERPRINT_BYTES`] bytes as hex groups, e.g. `"3f2a 9c01 77be"`.
+    pub fn to_short_hex(&self) -> String {
+        hex_groups(&self.0[..SHORT_FINGERPRINT_BYTES])
+    }
+
+    /// The first [`SHORT_FINGERPRINT_BYTES`] bytes as words, one per byte, e.g.
+    /// `"carpet boot ladder acorn fossil orbit"`.
+    pub fn to_words(&self) -> String {
+        self.0[..SHORT_FINGERPRINT_BYTES]
+            .iter()
+            .map(|&b| WORDS[usize::from(b)])
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// The first [`SHORT_FINGERPRINT_BYTES`] bytes as eight emoji, six bits each.
+    pub fn to_emoji(&self) -> String {
+        let bits = self.0[..SHORT_FINGERPRINT_BYTES]
+            .iter()
+            .fold(0u64, |acc, &b| acc << 8 | u64::from(b));
+        let count = SHORT_FINGERPRINT_BYTES * 8 / 6;
+        (0..count)
+            .map(|i| EMOJI[((bits >> (6 * (count - 1 - i))) & 0x3f) as usize])
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// Whether `shown` (typed or read back by a user) is one of this fingerprint's renderings.
+    /// Case, whitespace and `-`, `:`, `_`, `.` separators are ignored.
+    pub fn matches(&self, shown: &str) -> bool {
+        let shown = normalize(shown);
+        !shown.is_empty()
+            && [
+                self.to_hex(),
+                self.to_short_hex(),
+                self.to_words(),
+                self.to_emoji(),
+            ]
+            .iter()
+            .any(|r| normalize(r) == shown)
+    }
+}
+
+impl std::fmt::Display for Fingerprint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.to_short_hex())
+    }
+}
+
+fn hex_groups(bytes: &[u8]) -> String {
+    bytes
+        .chunks(2)
+        .map(|pair| pair.iter().map(|b| format!("{b:02x}")).collect::<String>())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Drops separators (and emoji variation selectors) and lowercases.
+fn normalize(s: &str) -> String {
+    s.chars()
+        .filter(|c| !c.is_whitespace() && !matches!(c, '-' | ':' | '_' | '.' | '\u{fe0f}'))
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+/// One word per byte value for [`Fingerprint::to_words`]. Never reorder: renderings must match
+/// across versions.
+const WORDS: [&str; 256] = [
+    "acid", "acorn", "actor", "adult", "agent", "alarm", "album", "alley", "amber", "angle",
+    "ankle", "apple", "april", "apron", "arena", "armor", "arrow", "atlas", "attic", "audio",
+    "autumn", "award", "bacon", "badge", "baker", "bamboo", "banana", "banjo", "barrel", "basil",
+    "basket", "beach", "beard", "beaver", "bell", "bench", "berry", "bike", "bird", "blade",
+    "boat", "bonus", "boot", "bottle", "bowl", "brain", "branch", "bread", "brick", "bridge",
+    "broom", "bubble", "bucket", "butter", "button", "cabin", "cactus", "camel", "camera",
+    "candle", "canoe", "canvas", "carbon", "carpet", "carrot", "castle", "cattle", "cave", "cedar",
+    "cement", "chair", "chalk", "cherry", "chess", "cider", "cinema", "circle", "clock", "cloud",
+    "clover", "coast", "cobra", "cocoa", "coffee", "comet", "copper", "coral", "cotton", "cougar",
+    "crab", "crane", "crayon", "crown", "cube", "dancer", "dawn", "deer", "delta", "denim",
+    "desert", "dinner", "donkey", "door", "dragon", "drum", "eagle", "earth", "echo", "elbow",
+    "ember", "engine", "falcon", "fence", "ferry", "fiddle", "finger", "flame", "flute", "forest",
+    "fossil", "fox", "frost", "galaxy", "garden", "garlic", "gecko", "ginger", "globe", "goat",
+    "gold", "grape", "gravel", "guitar", "hammer", "harbor", "harp", "hawk", "hazel", "helmet",
+    "hero", "hill", "honey", "hotel", "igloo", "island", "ivory", "jacket", "jaguar", "jelly",
+    "jewel", "jigsaw", "jungle", "kayak", "kettle", "kitten", "koala", "ladder", "lagoon", "lamp",
+    "laser", "lemon", "lentil", "lilac", "lion", "lizard", "lotus", "magnet", "mango", "maple",
+    "marble", "meadow", "melon", "meteor", "mint", "mirror", "monkey", "moose", "mosaic", "motor",
+    "muffin", "napkin", "nectar", "needle", "nest", "noodle", "oasis", "ocean", "olive", "onion",
+    "orange", "orbit", "orchid", "otter", "owl", "oyster", "paddle", "palace", "panda", "parrot",
+    "peach", "peanut", "pebble", "pencil", "pepper", "piano", "pigeon", "pillow", "pine", "planet",
+    "plum", "pocket", "pony", "potato", "puzzle", "quartz", "quilt", "rabbit", "radar", "radio",
+    "raven", "ribbon", "river", "robot", "rocket", "ruby", "saddle", "salmon", "sandal", "satin",
+    "scarf", "shadow", "shark", "shell", "silver", "sketch", "sled", "snail", "spider", "sponge",
+    "spruce", "squid", "statue", "storm", "sugar", "summit", "sunset", "swan", "tablet", "tango",
+    "tiger", "tulip", "wagon", "whale", "wolf", "yacht", "zebra",
+];
+
+/// One emoji per six bits for [`Fingerprint::to_emoji`]. Never reorder.
+const EMOJI: [&str; 64] = [
+    "🐶", "🐱", "🦁", "🐴", "🦄", "🐷", "🐘", "🐰", "🐼", "🐓", "🐧", "🐢", "🐟", "🐙", "🦋", "🌷",
+    "🌳", "🌵", "🍄", "🌏", "🌙", "🔥", "🍌", "🍎", "🍓", "🌽", "🍕", "🎂", "🍪", "🍩", "🎈", "🎁",
+    "🎸", "🎺", "🎻", "🥁", "🚲", "🚀", "🚂", "🚗", "⚽", "🏀", "🎲", "🔑", "🔔", "📌", "📎", "🔨",
+    "🎩", "👓", "👑", "🌂", "🎀", "💎", "🏆", "🐝", "🐞", "🦊", "🐸", "🐳", "🌻", "🍒", "🍋", "🍇",
+];
+
+/// Code as shown to users: two groups of three digits (e.g. `"042 917"`).
+pub fn format_code(code: u32) -> String {
+    format!("{:03} {:03}", code / 1000, code % 1000)
+}
+
+/// Confirmation that `keypair`'s user saw `code` for the peer with `peer_public`.
+pub fn confirmation(
+    keypair: &(impl KeyAgreement + ?Sized),
+    peer_public: &PublicKey,
+    code: u32,
+) -> [u8; 32] {
+    confirmation_mac(
+        &keypair.shared_secret(peer_public),
+        &keypair.device_id(),
+        code,
+    )
+}
+
+/// Verify a peer's confirmation: it must come from the holder of `peer_public`'s secret key and
+/// cover `code`, the one this device shows.
+pub fn verify_confirmation(
+    keypair: &(impl KeyAgreement + ?Sized),
+    peer_public: &PublicKey,
+    code: u32,
+    mac: &[u8; 32],
+) -> bool {
+    let peer_id = DeviceId::from_public_key(peer_public.as_bytes());
+    let expected = confirmation_mac(&keypair.shared_secret(peer_public), &peer_id, code);
+    identity::macs_match(&expected, mac)
+}
+
+fn confirmation_mac(shared_secret: &[u8; 32], sender: &DeviceId, code: u32) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(b"peapod-pair-confirm-v1");
+    hasher.update(shared_secret);
+    hasher.update(sender.as_bytes());
+    hasher.update(code.to_be_bytes());
+    hasher.finalize().into()
+}
+
+/// Secret shared by the paired devices of a pod. It seals Beacons and DiscoveryResponses
+/// ([`Message::SealedDiscovery`]) so a device outside the pod learns nothing by joining the
+/// multicast group. Two devices without a pod key derive one while pairing; otherwise the key
+/// that [supersedes](PodKey::supersedes) the other is kept and spreads to paired devices.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PodKey {
+    /// Raised each time the pod replaces its key (e.g. after a revocation).
+    pub epoch: u32,
+    key: [u8; 32],
+}
+
+impl PodKey {
+    /// Key two devices without one derive from their shared secret when pairing (epoch 0). Both
+    /// sides get the same key.
+    pub fn derive(keypair: &(impl KeyAgreement + ?Sized), peer_public: &PublicKey) -> Self {
+        let own = keypair.public_key();
+        let (lo, hi) = if own.as_bytes() <= peer_public.as_bytes() {
+            (own, peer_public)
+        } else {
+            (peer_public, own)
+        };
+        let mut hasher = Sha256::new();
+        hasher.update(b"peapod-pod-key-v1");
+        hasher.update(keypair.shared_secret(peer_public));
+        hasher.update(lo.as_bytes());
+        hasher.update(hi.as_bytes());
+        Self {
+            epoch: 0,
+            key: hasher.finalize().into(),
+        }
+    }
+
+    /// Fresh random key for `epoch`.
+    pub fn generate(epoch: u32) -> Self {
+        let mut key = [0u8; 32];
+        OsRng.fill_bytes(&mut key);
+        Self { epoch, key }
+    }
+
+    /// Public tag of the key, sent with sealed frames so receivers know which key to try.
+    pub fn id(&self) -> [u8; 8] {
+        let mut hasher = Sha256::new();
+        hasher.update(b"peapod-pod-key-id-v1");
+        hasher.update(self.key);
+        let digest: [u8; 32] = hasher.finalize().into();
+        digest[..8].try_into().unwrap()
+    }
+
+    /// Whether a device holding `other` should replace it with this key: a higher epoch wins,
+    /// and the lower id breaks ties, so every device settles on the same key.
+    pub fn supersedes(&self, other: &PodKey) -> bool {
+        (self.epoch, other.id()) > (other.epoch, self.id())
+    }
+
+    /// Wrap this key for the device with `peer_public`, under the two devices' shared secret.
+    pub fn wrap(
+        &self,
+        keypair: &(impl KeyAgreement + ?Sized),
+        peer_public: &PublicKey,
+    ) -> Result<WrappedPodKey, WireCryptoError> {
+        let mut nonce = [0u8; 12];
+        OsRng.fill_bytes(&mut nonce);
+        let ciphertext = wrap_cipher(keypair, peer_public)?
+            .encrypt(
+                (&nonce).into(),
+                Payload {
+                    msg: &self.key,
+                    aad: &self.epoch.to_be_bytes(),
+                },
+            )
+            .map_err(|_| WireCryptoError::Encrypt)?;
+        Ok(WrappedPodKey {
+            epoch: self.epoch,
+            nonce,
+            ciphertext,
+        })
+    }
+
+    /// Unwrap a key the device with `peer_public` wrapped for this device; `None` if it was
+    /// wrapped for someone else or altered.
+    pub fn unwrap_from(
+        wrapped: &WrappedPodKey,
+        keypair: &(impl KeyAgreement + ?Sized),
+        peer_public: &PublicKey,
+    ) -> Option<Self> {
+        let key = Zeroizing::new(
+            wrap_cipher(keypair, peer_public)
+                .ok()?
+                .decrypt(
+                    (&wrapped.nonce).into(),
+                    Payload {
+                        msg: &wrapped.ciphertext,
+                        aad: &wrapped.epoch.to_be_bytes(),
+                    },
+                )
+                .ok()?,
+        );
+        Some(Self {
+            epoch: wrapped.epoch,
+            key: key.as_slice().try_into().ok()?,
+        })
+    }
+
+    /// Seal a discovery message (Beacon or DiscoveryResponse) as a [`Message::SealedDiscovery`].
+    pub fn seal_discovery(&self, msg: &Message) -> Result<Message, WireCryptoError> {
+        let plain = bincode::serialize(msg).map_err(|_| WireCryptoError::Encrypt)?;
+        let key_id = self.id();
+        let mut nonce = [0u8; 12];
+        OsRng.fill_bytes(&mut nonce);
+        let ciphertext = self
+            .cipher()?
+            .encrypt(
+                (&nonce).into(),
+                Payload {
+                    msg: &plain,
+                    aad: &key_id,
+                },
+            )
+            .map_err(|_| WireCryptoError::Encrypt)?;
+        Ok(Message::SealedDiscovery {
+            key_id,
+            nonce,
+            ciphertext,
+        })
+    }
+
+    /// Open a [`Message::SealedDiscovery`] sealed with this key; `None` for another key, an
+    /// altered frame or anything but a Beacon, DiscoveryResponse or DiscoveryName inside.
+    pub fn open_discovery(&self, nonce: &[u8; 12], ciphertext: &[u8]) -> Option<Message> {
+        let key_id = self.id();
+        let plain = self
+            .cipher()
+            .ok()?
+            .decrypt(
+                nonce.into(),
+                Payload {
+                    msg: ciphertext,
+                    aad: &key_id,
+                },
+            )
+            .ok()?;
+        match bincode::deserialize(&plain).ok()? {
+            msg @ (Message::Beacon { .. }
+            | Message::DiscoveryResponse { .. }
+            | Message::DiscoveryName { .. }) => Some(msg),
+            _ => None,
+        }
+    }
+
+    fn cipher(&self) -> Result<ChaCha20Poly1305, WireCryptoError> {
+        ChaCha20Poly1305::new_from_slice(&self.key).map_err(|_| WireCryptoError::Key)
+    }
+}
+
+impl Drop for PodKey {
+    fn drop(&mut self) {
+        self.key.zeroize();
+    }
+}
+
+/// The key itself stays out of logs.
+impl std::fmt::Debug for PodKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PodKey")
+            .field("epoch", &self.epoch)
+            .field("id", &self.id())
+            .finish_non_exhaustive()
+    }
+}
+
+/// A [`PodKey`] encrypted for one paired device ([`PodKey::wrap`]), as sent in
+/// [`Message::PairConfirm`] and [`Message::PodKey`].
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WrappedPodKey {
+    pub epoch: u32,
+    pub nonce: [u8; 12],
+    pub ciphertext: Vec<u8>,
+}
+
+fn wrap_cipher(
+    keypair: &(impl KeyAgreement + ?Sized),
+    peer_public: &PublicKey,
+) -> Result<ChaCha20Poly1305, WireCryptoError> {
+    let mut hasher = Sha256::new();
+    hasher.update(b"peapod-pod-wrap-v1");
+    hasher.update(keypair.shared_secret(peer_public));
+    let key: Zeroizing<[u8; 32]> = Zeroizing::new(hasher.finalize().into());
+    ChaCha20Poly1305::new_from_slice(key.as_slice()).map_err(|_| WireCryptoError::Key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::Keypair;
+
+    #[test]
+    fn both_sides_show_the_same_code_and_accept_each_other() {
+        let a = Keypair::generate();
+        let b = Keypair::generate();
+        let (a_nonce, b_nonce) = (pairing_nonce(), pairing_nonce());
+        let code = pairing_code(a.public_key(), &a_nonce, b.public_key(), &b_nonce);
+        assert_eq!(
+            code,
+            pairing_code(b.public_key(), &b_nonce, a.public_key(), &a_nonce)
+        );
+        assert!(code < 1_000_000);
+        assert_eq!(format_code(42_917), "042 917");
+        // The nonces, not just the keys, decide the code; a commitment binds nonce and key.
+        assert_ne!(
+            Fingerprint::of_pair(a.public_key(), &a_nonce, b.public_key(), &b_nonce),
+            Fingerprint::of_pair(a.public_key(), &pairing_nonce(), b.public_key(), &b_nonce)
+        );
+        let commitment = nonce_commitment(a.public_key(), &a_nonce);
+        assert_ne!(commitment, nonce_commitment(b.public_key(), &a_nonce));
+        assert_ne!(commitment, nonce_commitment(a.public_key(), &b_nonce));
+
+        let from_a = confirmation(&a, b.public_key(), code);
+        assert!(verify_confirmation(&b, a.public_key(), code, &from_a));
+        // A confirmation is not valid for another code, in the other direction or from anyone
+        // else.
+        assert!(!verify_confirmation(&b, a.public_key(), code ^ 1, &from_a));
+        assert!(!verify_confirmation(&a, b.public_key(), code, &from_a));
+        let mallory = Keypair::generate();
+        assert!(!verify_confirmation(
+            &b,
+            a.public_key(),
+            code,
+            &confirmation(&mallory, b.public_key(), code)
+        ));
+    }
+
+    #[test]
+    fn fingerprints_render_canonically_and_match_any_rendering() {
+        let a = Keypair::generate();
+        let b = Keypair::generate();
+        let fp = Fingerprint::of(a.public_key());
+        assert_eq!(fp, Fingerprint::of(a.public_key()));
+        assert_ne!(fp, Fingerprint::of(b.public_key()));
+        let (a_nonce, b_nonce) = ([1; 32], [2; 32]);
+        assert_eq!(
+            Fingerprint::of_pair(a.public_key(), &a_nonce, b.public_key(), &b_nonce),
+            Fingerprint::of_pair(b.public_key(), &b_nonce, a.public_key(), &a_nonce)
+        );
+
+        // A fixed key pins the renderings: every host must show exactly these.
+        let fixed = Fingerprint([
+            0x3f, 0x2a, 0x9c, 0x01, 0x77, 0xbe, 0xff, 0x00, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        ]);
+        assert_eq!(fixed.to_short_hex(), "3f2a 9c01 77be");
+        assert_eq!(fixed.to_string(), "3f2a 9c01 77be");
+        assert_eq!(fixed.to_hex().split(' ').count(), 16);
+        assert!(fixed.to_hex().starts_with("3f2a 9c01 77be ff00 0000"));
+        assert_eq!(fixed.to_words(), "carpet boot ladder acorn fossil orbit");
+        assert_eq!(fixed.to_emoji(), "🌷 👑 🎲 🍪 🐶 🍎 🎈 🍋");
+
+        assert!(fp.matches(&fp.to_words().to_uppercase()));
+        assert!(fp.matches(&fp.to_short_hex().replace(' ', ":")));
+        assert!(fp.matches(&fp.to_hex().replace(' ', "")));
+        assert!(fp.matches(&fp.to_emoji()));
+        assert!(!fp.matches(&Fingerprint::of(b.public_key()).to_words()));
+        assert!(!fp.matches(&fp.to_short_hex()[..9]));
+        assert!(!fp.matches(" - "));
+        let distinct: std::collections::HashSet<_> = WORDS.iter().chain(EMOJI.iter()).collect();
+        assert_eq!(distinct.len(), 256 + 64);
+    }
+}
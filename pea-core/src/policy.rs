@@ -0,0 +1,292 @@
+//! Eligibility policy: which requests the pod may accelerate, and which rule sent a request to the
+//! normal path. Eligible requests then pass the host's [`ContentFilter`], if any.
+//!
+//! Rules are checked in a fixed order: method, never-share exclusions, denied domains, allowed
+//! domains, content length, content type. The first rule a request fails is reported. Values the
+//! host does not know yet (content length or type before the origin answered) pass.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+/// A request as seen by the host, for policy evaluation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RequestInfo<'a> {
+    /// HTTP method (only GET is accelerated).
+    pub method: &'a str,
+    /// Absolute URL.
+    pub url: &'a str,
+    /// Body length when known (e.g. from the client's Range or a Content-Length).
+    pub content_length: Option<u64>,
+    /// Content-Type when known.
+    pub content_type: Option<&'a str>,
+}
+
+/// Configurable eligibility rules. The default accelerates every GET.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EligibilityPolicy {
+    /// If non-empty, only these domains (and their subdomains) are accelerated.
+    pub allow_domains: Vec<String>,
+    /// Domains (and their subdomains) never accelerated.
+    pub deny_domains: Vec<String>,
+    /// Smallest body worth accelerating, in bytes (0: any size).
+    pub min_content_length: u64,
+    /// If non-empty, only these content types are accelerated; an entry ending in `/` matches a
+    /// whole family (e.g. `"video/"`).
+    pub content_types: Vec<String>,
+    /// Privacy exclusions: URLs containing any of these substrings are never shared with the pod
+    /// (not accelerated, so neither cached nor fetched by peers).
+    pub never_share: Vec<String>,
+}
+
+/// The rule that made a request ineligible.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PolicyRule {
+    /// Not a GET.
+    Method(String),
+    /// Matched a [`EligibilityPolicy::never_share`] entry.
+    NeverShare(String),
+    /// Matched a [`EligibilityPolicy::deny_domains`] entry.
+    DeniedDomain(String),
+    /// The host is not in [`EligibilityPolicy::allow_domains`] (or the URL has no host).
+    DomainNotAllowed,
+    /// Smaller than [`EligibilityPolicy::min_content_length`].
+    ContentTooShort { length: u64, min: u64 },
+    /// Not one of [`EligibilityPolicy::content_types`].
+    ContentType(String),
+}
+
+impl fmt::Display for PolicyRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PolicyRule::Method(m) => write!(f, "method {} is not accelerated", m),
+            PolicyRule::NeverShare(p) => write!(f, "never-share rule \"{}\"", p),
+            PolicyRule::DeniedDomain(d) => write!(f, "domain {} is denied", d),
+            PolicyRule::DomainNotAllowed => write!(f, "domain is not in the allow list"),
+            PolicyRule::ContentTooShort { length, min } => {
+                write!(f, "{} bytes is below the {} byte minimum", length, min)
+            }
+            PolicyRule::ContentType(t) => write!(f, "content type {} is not accelerated", t),
+        }
+    }
+}
+
+/// Result of evaluating a request against an [`EligibilityPolicy`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Eligibility {
+    /// The request may be accelerated.
+    Eligible,
+    /// The request goes the normal path because of this rule.
+    Ineligible(PolicyRule),
+}
+
+impl Eligibility {
+    /// Whether the request may be accelerated.
+    pub fn is_eligible(&self) -> bool {
+        matches!(self, Eligibility::Eligible)
+    }
+}
+
+impl EligibilityPolicy {
+    /// Evaluate every rule against `request`.
+    pub fn evaluate(&self, request: &RequestInfo) -> Eligibility {
+        if !request.method.eq_ignore_ascii_case("GET") {
+            return Eligibility::Ineligible(PolicyRule::Method(request.method.to_string()));
+        }
+        if let Some(rule) = self.url_rule(request.url) {
+            return Eligibility::Ineligible(rule);
+        }
+        if let Some(length) = request.content_length {
+            if length < self.min_content_length {
+                return Eligibility::Ineligible(PolicyRule::ContentTooShort {
+                    length,
+                    min: self.min_content_length,
+                });
+            }
+        }
+        if let Some(content_type) = request.content_type {
+            if !self.content_types.is_empty() && !self.content_type_allowed(content_type) {
+                return Eligibility::Ineligible(PolicyRule::ContentType(content_type.to_string()));
+            }
+        }
+        Eligibility::Eligible
+    }
+
+    /// Evaluate only the rules that depend on the URL (never-share and domain lists).
+    pub fn evaluate_url(&self, url: &str) -> Eligibility {
+        match self.url_rule(url) {
+            Some(rule) => Eligibility::Ineligible(rule),
+            None => Eligibility::Eligible,
+        }
+    }
+
+    fn url_rule(&self, url: &str) -> Option<PolicyRule> {
+        if let Some(p) = self.never_share.iter().find(|p| url.contains(p.as_str())) {
+            return Some(PolicyRule::NeverShare(p.clone()));
+        }
+        let host = host_of(url);
+        if let Some(d) = self
+            .deny_domains
+            .iter()
+            .find(|d| host.as_deref().is_some_and(|h| domain_matches(h, d)))
+        {
+            return Some(PolicyRule::DeniedDomain(d.clone()));
+        }
+        if !self.allow_domains.is_empty()
+            && !host
+                .as_deref()
+                .is_some_and(|h| self.allow_domains.iter().any(|d| domain_matches(h, d)))
+        {
+            return Some(PolicyRule::DomainNotAllowed);
+        }
+        None
+    }
+
+    fn content_type_allowed(&self, content_type: &str) -> bool {
+        let essence = content_type
+            .split(';')
+            .next()
+            .unwrap_or("")
+            .trim()
+            .to_ascii_lowercase();
+        self.content_types.iter().any(|t| {
+            let t = t.to_ascii_lowercase();
+            if t.ends_with('/') {
+                essence.starts_with(&t)
+            } else {
+                essence == t
+            }
+        })
+    }
+}
+
+/// An eligible request about to be accelerated, as shown to a [`ContentFilter`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TransferRequest {
+    /// Absolute URL.
+    pub url: String,
+    /// The client's inclusive byte range.
+    pub range: Option<(u64, u64)>,
+    /// Origin validator (ETag or Last-Modified) when known.
+    pub validator: Option<String>,
+}
+
+/// What a [`ContentFilter`] decided for a request.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ContentDecision {
+    /// Accelerate the request as is.
+    Allow,
+    /// Do not accelerate; the request takes the normal path.
+    Deny,
+    /// Accelerate this request instead (e.g. a rewritten URL or narrowed range). The eligibility
+    /// policy is checked again for the new URL.
+    Modify(TransferRequest),
+}
+
+/// Host hook run synchronously on every eligible request before chunks are assigned, for
+/// parental controls, corporate DLP and similar filters. Closures taking a [`TransferRequest`]
+/// implement it.
+pub trait ContentFilter: Send {
+    fn review(&mut self, request: &TransferRequest) -> ContentDecision;
+}
+
+impl<F> ContentFilter for F
+where
+    F: FnMut(&TransferRequest) -> ContentDecision + Send,
+{
+    fn review(&mut self, request: &TransferRequest) -> ContentDecision {
+        self(request)
+    }
+}
+
+/// Lowercase host of an absolute URL, without userinfo or port.
+fn host_of(url: &str) -> Option<String> {
+    let rest = url.split_once("://").map(|(_, r)| r)?;
+    let authority = rest.split(['/', '?', '#']).next().unwrap_or("");
+    let host_port = authority.rsplit('@').next().unwrap_or(authority);
+    let host = match host_port.rsplit_once(':') {
+        Some((h, port)) if port.chars().all(|c| c.is_ascii_digit()) => h,
+        _ => host_port,
+    };
+    (!host.is_empty()).then(|| host.to_ascii_lowercase())
+}
+
+/// Whether `host` is `domain` or one of its subdomains.
+fn domain_matches(host: &str, domain: &str) -> bool {
+    let domain = domain.trim_start_matches('.').to_ascii_lowercase();
+    host == domain
+        || host
+            .strip_suffix(domain.as_str())
+            .is_some_and(|prefix| prefix.ends_with('.'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get(url: &str) -> RequestInfo<'_> {
+        RequestInfo {
+            method: "GET",
+            url,
+            content_length: None,
+            content_type: None,
+        }
+    }
+
+    #[test]
+    fn reports_the_first_rule_a_request_fails() {
+        let policy = EligibilityPolicy {
+            allow_domains: vec!["example.com".into()],
+            deny_domains: vec!["private.example.com".into()],
+            min_content_length: 1000,
+            content_types: vec!["video/".into(), "application/zip".into()],
+            never_share: vec!["/account/".into()],
+        };
+        assert!(policy
+            .evaluate(&get("https://cdn.example.com:8443/f.zip"))
+            .is_eligible());
+        assert_eq!(
+            policy.evaluate(&RequestInfo {
+                method: "POST",
+                ..get("https://example.com/f")
+            }),
+            Eligibility::Ineligible(PolicyRule::Method("POST".into()))
+        );
+        assert_eq!(
+            policy.evaluate(&get("https://example.com/account/export")),
+            Eligibility::Ineligible(PolicyRule::NeverShare("/account/".into()))
+        );
+        assert_eq!(
+            policy.evaluate_url("http://user@Private.Example.com/f"),
+            Eligibility::Ineligible(PolicyRule::DeniedDomain("private.example.com".into()))
+        );
+        assert_eq!(
+            policy.evaluate(&get("http://notexample.com/f")),
+            Eligibility::Ineligible(PolicyRule::DomainNotAllowed)
+        );
+        assert_eq!(
+            policy.evaluate(&RequestInfo {
+                content_length: Some(10),
+                ..get("http://example.com/f")
+            }),
+            Eligibility::Ineligible(PolicyRule::ContentTooShort {
+                length: 10,
+                min: 1000
+            })
+        );
+        assert!(policy
+            .evaluate(&RequestInfo {
+                content_type: Some("Video/MP4; codecs=avc1"),
+                ..get("http://example.com/f")
+            })
+            .is_eligible());
+        assert_eq!(
+            policy.evaluate(&RequestInfo {
+                content_type: Some("text/html"),
+                ..get("http://example.com/f")
+            }),
+            Eligibility::Ineligible(PolicyRule::ContentType("text/html".into()))
+        );
+    }
+}
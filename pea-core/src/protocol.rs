@@ -2,12 +2,79 @@
 
 use serde::{Deserialize, Serialize};
 
-use crate::identity::{DeviceId, PublicKey};
+use crate::identity::{DeviceId, NameRecord, PublicKey, Revocation};
+use crate::integrity::HashAlgorithm;
+use crate::pairing::WrappedPodKey;
 
-/// Current protocol version. Used in beacon and handshake.
+/// Base protocol version. Used in beacon and handshake, and with a peer until a
+/// [`Message::Hello`] exchange settles on a higher one.
 pub const PROTOCOL_VERSION: u8 = 1;
+/// First protocol version whose frames use the canonical encoding
+/// ([`crate::wire::WireEncoding::Canonical`]) instead of bincode.
+pub const CANONICAL_PROTOCOL_VERSION: u8 = 2;
+/// Oldest protocol version this build speaks.
+pub const MIN_PROTOCOL_VERSION: u8 = 1;
+/// Newest protocol version this build speaks.
+pub const MAX_PROTOCOL_VERSION: u8 = CANONICAL_PROTOCOL_VERSION;
 
-/// All wire message types. Encoding is bincode; framing is length-prefix (see wire module).
+/// Whether this build can talk to a device announcing `version` in a Beacon or handshake.
+pub fn is_supported_version(version: u8) -> bool {
+    (MIN_PROTOCOL_VERSION..=MAX_PROTOCOL_VERSION).contains(&version)
+}
+
+/// Highest version in both `ours` and `theirs` (inclusive `(min, max)` ranges), or `None` if
+/// they do not overlap.
+pub fn negotiate_version(ours: (u8, u8), theirs: (u8, u8)) -> Option<u8> {
+    let version = ours.1.min(theirs.1);
+    (version >= ours.0.max(theirs.0)).then_some(version)
+}
+
+/// Feature bit: understands [`Message::Cancel`] (endgame duplicate requests).
+pub const FEATURE_CANCEL: u32 = 1 << 0;
+/// Feature bit: sends and honours [`Message::Constraints`].
+pub const FEATURE_CONSTRAINTS: u32 = 1 << 1;
+/// Feature bit: uploads ranges on a peer's behalf ([`Message::UploadOffer`], [`Message::UploadData`]).
+pub const FEATURE_UPLOAD: u32 = 1 << 2;
+/// Feature bit: understands [`Message::Busy`] (otherwise declined requests are Nacked).
+pub const FEATURE_BUSY: u32 = 1 << 3;
+/// Feature bit: accepts zstd-compressed [`Message::ChunkData`] payloads.
+pub const FEATURE_COMPRESSION: u32 = 1 << 4;
+/// Feature bit: verifies [`Message::ChunkData`] hashed with [`HashAlgorithm::Blake3`].
+pub const FEATURE_BLAKE3: u32 = 1 << 5;
+/// Feature bit: reads zstd-compressed frames ([`crate::wire::FLAG_COMPRESSED`]).
+pub const FEATURE_FRAME_COMPRESSION: u32 = 1 << 6;
+/// Features implemented by this build.
+pub const SUPPORTED_FEATURES: u32 = FEATURE_CANCEL
+    | FEATURE_CONSTRAINTS
+    | FEATURE_UPLOAD
+    | FEATURE_BUSY
+    | FEATURE_COMPRESSION
+    | FEATURE_BLAKE3
+    | FEATURE_FRAME_COMPRESSION;
+
+/// What a device can do, advertised in [`Message::Capabilities`] and [`Message::JoinRequest`] so
+/// peers stop treating it like everyone else.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Capabilities {
+    /// Largest chunk the device will fetch for the pod (0 = no limit).
+    pub max_chunk_size: u64,
+    /// Estimated WAN downlink in bytes per second (0 = unknown).
+    pub downlink_bps: u64,
+    /// Protocol feature bits (`FEATURE_*`).
+    pub features: u32,
+    /// Host platform, e.g. "linux", "windows", "android".
+    pub platform: String,
+}
+
+impl Capabilities {
+    /// Whether every bit in `feature` is advertised.
+    pub fn supports(&self, feature: u32) -> bool {
+        self.features & feature == feature
+    }
+}
+
+/// All wire message types. Encoding is bincode; framing is length, [`MessageType`] and flags (see
+/// wire module).
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Message {
     /// Discovery: advertise presence. Include device ID, public key, protocol version, optional listen address.
@@ -24,27 +91,62 @@ pub enum Message {
         public_key: PublicKey,
         listen_port: u16,
     },
-    /// Request to join pod or confirm membership.
-    Join { device_id: DeviceId },
-    /// Graceful leave.
-    Leave { device_id: DeviceId },
+    /// Request to join the receiver's pod. `signature` is a MAC keyed by the X25519 shared secret
+    /// (not a signature; see [`crate::identity::DeviceKey::join_signature`]) proving possession of
+    /// the secret key for `public_key`. The receiver answers with
+    /// [`Message::JoinAccept`] once it approves (or had already invited the sender). `name` is the
+    /// sender's signed name, if it has one.
+    JoinRequest {
+        device_id: DeviceId,
+        public_key: PublicKey,
+        signature: [u8; 32],
+        capabilities: Capabilities,
+        name: Option<NameRecord>,
+    },
+    /// Graceful leave. `signature` proves the sender is `device_id` (see
+    /// [`crate::identity::DeviceKey::leave_signature`]).
+    Leave {
+        device_id: DeviceId,
+        signature: [u8; 32],
+    },
     /// Liveness heartbeat.
     Heartbeat { device_id: DeviceId },
-    /// Request a chunk by transfer ID and range. Optional url so responder can fetch from WAN.
+    /// Request a chunk by transfer ID and range. Optional url so responder can fetch from WAN;
+    /// optional validator (ETag or Last-Modified) so it only answers from a cache of the same version;
+    /// headers of the client request (cookies, authorization) the responder replays on its WAN
+    /// fetch, sent only when the requester opts in; and the milliseconds the requester waits
+    /// before reassigning the chunk.
     ChunkRequest {
         transfer_id: [u8; 16],
         start: u64,
         end: u64,
         #[serde(default)]
         url: Option<String>,
+        #[serde(default)]
+        validator: Option<String>,
+        #[serde(default)]
+        headers: Vec<(String, String)>,
+        #[serde(default)]
+        deadline_ms: Option<u64>,
     },
-    /// Chunk payload: transfer ID, range, hash, data (or encrypted).
+    /// Chunk payload: transfer ID, range, hash, data (or encrypted). With `compressed` the payload
+    /// is zstd-compressed (only sent to peers advertising [`FEATURE_COMPRESSION`]); the hash
+    /// always covers the uncompressed bytes. `hash_algorithm` says how `hash` was made: SHA-256
+    /// unless the receiver advertised [`FEATURE_BLAKE3`]. `attestation` is the server's
+    /// [`crate::identity::DeviceKey::attest_chunk`] over transfer, range and hash, kept by the
+    /// receiver as evidence should the chunk later prove corrupt.
     ChunkData {
         transfer_id: [u8; 16],
         start: u64,
         end: u64,
         hash: [u8; 32],
         payload: Vec<u8>,
+        #[serde(default)]
+        compressed: bool,
+        #[serde(default)]
+        hash_algorithm: HashAlgorithm,
+        #[serde(default)]
+        attestation: Option<[u8; 32]>,
     },
     /// Chunk failed or peer left; trigger reassignment.
     Nack {
@@ -52,4 +154,248 @@ pub enum Message {
         start: u64,
         end: u64,
     },
+    /// Requester no longer needs the chunk (another copy arrived first); responder may drop it.
+    Cancel {
+        transfer_id: [u8; 16],
+        start: u64,
+        end: u64,
+    },
+    /// Sender's resource constraints changed; peers give it less (or capped) work.
+    Constraints {
+        battery_low: bool,
+        metered: bool,
+        max_contribution_bps: Option<u64>,
+    },
+    /// Answer to a [`Message::JoinRequest`]: the sender admitted the receiver. Same proof and
+    /// capabilities (and name) as the request.
+    JoinAccept {
+        device_id: DeviceId,
+        public_key: PublicKey,
+        signature: [u8; 32],
+        capabilities: Capabilities,
+        name: Option<NameRecord>,
+    },
+    /// The sender's user confirmed that both devices show the same pairing code
+    /// (see [`crate::pairing::confirmation`]), with the sender's pod key if it has one.
+    PairConfirm {
+        confirmation: [u8; 32],
+        #[serde(default)]
+        pod_key: Option<WrappedPodKey>,
+    },
+    /// Peer-assisted upload: the sender will hand the receiver ranges of a `total_length`-byte
+    /// body to upload to `url` on its behalf.
+    UploadOffer {
+        upload_id: [u8; 16],
+        url: String,
+        total_length: u64,
+    },
+    /// One range `[start, end)` of an offered upload; the receiver uploads it and answers with
+    /// [`Message::UploadAck`].
+    UploadData {
+        upload_id: [u8; 16],
+        start: u64,
+        end: u64,
+        hash: [u8; 32],
+        payload: Vec<u8>,
+    },
+    /// Whether the receiver uploaded the range (`ok: false` for a bad payload, an unknown upload
+    /// or a failed upload).
+    UploadAck {
+        upload_id: [u8; 16],
+        start: u64,
+        end: u64,
+        ok: bool,
+    },
+    /// The sender's contribution policy does not allow serving right now (schedule, do-not-disturb
+    /// or daily budget); the requester reassigns the chunk without counting it as a failure.
+    Busy {
+        transfer_id: [u8; 16],
+        start: u64,
+        end: u64,
+    },
+    /// Frames after this one use the sender's next session key, number `generation` (see
+    /// [`crate::identity::SessionCipher`]). Consumed by the session layer, never by the core.
+    Rekey { generation: u32 },
+    /// The sender is moving to a new long-term key. `old_proof` and `new_proof` come from
+    /// [`crate::identity::DeviceKey::rotation_proofs`]; the receiver carries the sender's pin,
+    /// pairing and trust over to the new device ID and retires the old one after a grace period.
+    RotateKey {
+        new_public_key: PublicKey,
+        old_proof: [u8; 32],
+        new_proof: [u8; 32],
+    },
+    /// A signed revocation of a device, shared across the pod. Receivers apply it if the issuer
+    /// is a device they paired with (or the revoked device itself) and pass it on.
+    Revoke { revocation: Revocation },
+    /// The pod's current [`crate::pairing::PodKey`], wrapped for the receiver. Sent only between
+    /// paired devices; a receiver keeps it if it supersedes its own and passes it on.
+    PodKey { key: WrappedPodKey },
+    /// A Beacon or DiscoveryResponse sealed with the pod key `key_id` (see
+    /// [`crate::pairing::PodKey::seal_discovery`]), so only pod members can read it.
+    SealedDiscovery {
+        key_id: [u8; 8],
+        nonce: [u8; 12],
+        ciphertext: Vec<u8>,
+    },
+    /// Protocol versions the sender speaks, sent when a connection comes up. Both sides then use
+    /// the highest version in both ranges ([`negotiate_version`]); peers that never send one stay
+    /// at [`PROTOCOL_VERSION`].
+    Hello { min_version: u8, max_version: u8 },
+    /// The sender's current [`Capabilities`], sent when a connection comes up and again when they
+    /// change. Receivers keep the latest per peer and check it before using an optional feature.
+    Capabilities { capabilities: Capabilities },
+    /// The sender's pod ID (its pod key's [`crate::pairing::PodKey::id`]), sent by paired devices
+    /// after their Beacon or DiscoveryResponse in the same datagram so devices of other pods can
+    /// ignore them. A separate frame, so builds that do not know it skip it.
+    DiscoveryPod { pod_id: [u8; 8] },
+    /// The sender's signed name ([`NameRecord`]), sent after its Beacon or DiscoveryResponse in the
+    /// same datagram, sealed like it. A separate frame for the same reason as
+    /// [`Message::DiscoveryPod`].
+    DiscoveryName { name: NameRecord },
+    /// Commitment to the sender's pairing nonce ([`crate::pairing::nonce_commitment`]), sent to an
+    /// unpaired peer when the connection comes up. The receiver answers with its own
+    /// [`Message::PairNonce`] only once it holds this commitment.
+    PairCommit { commitment: [u8; 32] },
+    /// The sender's pairing nonce, revealed after it received the receiver's
+    /// [`Message::PairCommit`]. Both nonces go into the pairing code.
+    PairNonce { nonce: [u8; 32] },
+}
+
+/// Type byte carried in each frame header ahead of the bincode payload (see [`crate::wire`]).
+/// Codes are fixed and never reused, so receivers can route or skip a frame without decoding it
+/// and reordering [`Message`] can no longer change what is on the wire unnoticed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum MessageType {
+    Beacon = 1,
+    DiscoveryResponse = 2,
+    JoinRequest = 3,
+    Leave = 4,
+    Heartbeat = 5,
+    ChunkRequest = 6,
+    ChunkData = 7,
+    Nack = 8,
+    Cancel = 9,
+    Constraints = 10,
+    JoinAccept = 11,
+    PairConfirm = 12,
+    UploadOffer = 13,
+    UploadData = 14,
+    UploadAck = 15,
+    Busy = 16,
+    Rekey = 17,
+    RotateKey = 18,
+    Revoke = 19,
+    PodKey = 20,
+    SealedDiscovery = 21,
+    Hello = 22,
+    Capabilities = 23,
+    DiscoveryPod = 24,
+    DiscoveryName = 25,
+    PairCommit = 26,
+    PairNonce = 27,
+}
+
+impl MessageType {
+    /// The type for a header byte, or `None` for one this build does not know.
+    pub fn from_u8(code: u8) -> Option<Self> {
+        Some(match code {
+            1 => MessageType::Beacon,
+            2 => MessageType::DiscoveryResponse,
+            3 => MessageType::JoinRequest,
+            4 => MessageType::Leave,
+            5 => MessageType::Heartbeat,
+            6 => MessageType::ChunkRequest,
+            7 => MessageType::ChunkData,
+            8 => MessageType::Nack,
+            9 => MessageType::Cancel,
+            10 => MessageType::Constraints,
+            11 => MessageType::JoinAccept,
+            12 => MessageType::PairConfirm,
+            13 => MessageType::UploadOffer,
+            14 => MessageType::UploadData,
+            15 => MessageType::UploadAck,
+            16 => MessageType::Busy,
+            17 => MessageType::Rekey,
+            18 => MessageType::RotateKey,
+            19 => MessageType::Revoke,
+            20 => MessageType::PodKey,
+            21 => MessageType::SealedDiscovery,
+            22 => MessageType::Hello,
+            23 => MessageType::Capabilities,
+            24 => MessageType::DiscoveryPod,
+            25 => MessageType::DiscoveryName,
+            26 => MessageType::PairCommit,
+            27 => MessageType::PairNonce,
+            _ => return None,
+        })
+    }
+
+    /// The variant's name, as in [`Message`].
+    pub fn name(self) -> &'static str {
+        match self {
+            MessageType::Beacon => "Beacon",
+            MessageType::DiscoveryResponse => "DiscoveryResponse",
+            MessageType::JoinRequest => "JoinRequest",
+            MessageType::Leave => "Leave",
+            MessageType::Heartbeat => "Heartbeat",
+            MessageType::ChunkRequest => "ChunkRequest",
+            MessageType::ChunkData => "ChunkData",
+            MessageType::Nack => "Nack",
+            MessageType::Cancel => "Cancel",
+            MessageType::Constraints => "Constraints",
+            MessageType::JoinAccept => "JoinAccept",
+            MessageType::PairConfirm => "PairConfirm",
+            MessageType::UploadOffer => "UploadOffer",
+            MessageType::UploadData => "UploadData",
+            MessageType::UploadAck => "UploadAck",
+            MessageType::Busy => "Busy",
+            MessageType::Rekey => "Rekey",
+            MessageType::RotateKey => "RotateKey",
+            MessageType::Revoke => "Revoke",
+            MessageType::PodKey => "PodKey",
+            MessageType::SealedDiscovery => "SealedDiscovery",
+            MessageType::Hello => "Hello",
+            MessageType::Capabilities => "Capabilities",
+            MessageType::DiscoveryPod => "DiscoveryPod",
+            MessageType::DiscoveryName => "DiscoveryName",
+            MessageType::PairCommit => "PairCommit",
+            MessageType::PairNonce => "PairNonce",
+        }
+    }
+}
+
+impl Message {
+    /// The type byte this message is framed with.
+    pub fn message_type(&self) -> MessageType {
+        match self {
+            Message::Beacon { .. } => MessageType::Beacon,
+            Message::DiscoveryResponse { .. } => MessageType::DiscoveryResponse,
+            Message::JoinRequest { .. } => MessageType::JoinRequest,
+            Message::Leave { .. } => MessageType::Leave,
+            Message::Heartbeat { .. } => MessageType::Heartbeat,
+            Message::ChunkRequest { .. } => MessageType::ChunkRequest,
+            Message::ChunkData { .. } => MessageType::ChunkData,
+            Message::Nack { .. } => MessageType::Nack,
+            Message::Cancel { .. } => MessageType::Cancel,
+            Message::Constraints { .. } => MessageType::Constraints,
+            Message::JoinAccept { .. } => MessageType::JoinAccept,
+            Message::PairConfirm { .. } => MessageType::PairConfirm,
+            Message::UploadOffer { .. } => MessageType::UploadOffer,
+            Message::UploadData { .. } => MessageType::UploadData,
+            Message::UploadAck { .. } => MessageType::UploadAck,
+            Message::Busy { .. } => MessageType::Busy,
+            Message::Rekey { .. } => MessageType::Rekey,
+            Message::RotateKey { .. } => MessageType::RotateKey,
+            Message::Revoke { .. } => MessageType::Revoke,
+            Message::PodKey { .. } => MessageType::PodKey,
+            Message::SealedDiscovery { .. } => MessageType::SealedDiscovery,
+            Message::Hello { .. } => MessageType::Hello,
+            Message::Capabilities { .. } => MessageType::Capabilities,
+            Message::DiscoveryPod { .. } => MessageType::DiscoveryPod,
+            Message::DiscoveryName { .. } => MessageType::DiscoveryName,
+            Message::PairCommit { .. } => MessageType::PairCommit,
+            Message::PairNonce { .. } => MessageType::PairNonce,
+        }
+    }
 }
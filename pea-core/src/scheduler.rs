@@ -1,10 +1,144 @@
 //! Distributed scheduler: assign chunks to peers; reassign when peer leaves.
+//!
+//! [`PeaPodCore`](crate::PeaPodCore) places chunks through a [`Scheduler`]; [`DefaultScheduler`]
+//! wraps the free functions below and alternatives can be injected with
+//! [`PeaPodCore::with_scheduler`](crate::PeaPodCore::with_scheduler).
 
 use std::collections::HashMap;
 
+use serde::{Deserialize, Serialize};
+
 use crate::chunk::ChunkId;
 use crate::identity::DeviceId;
 
+/// The workers a [`Scheduler`] chooses from. All slices are in the same order (this device first).
+#[derive(Clone, Copy, Debug)]
+pub struct Workers<'a> {
+    pub ids: &'a [DeviceId],
+    /// Metrics per worker (bandwidth falls back to the measured delivery rate, then the advertised
+    /// downlink).
+    pub metrics: &'a [PeerMetrics],
+    /// WAN uplink per worker (`None`: unknown).
+    pub uplinks: &'a [Option<&'a str>],
+    /// Bytes requested from or queued for each worker across all transfers.
+    pub outstanding_bytes: &'a [u64],
+}
+
+/// Chunk placement policy. New transfers are planned by the core's sized-chunk planner
+/// ([`plan_sized_chunks`]) instead of [`Scheduler::assign`] when [`Scheduler::chunk_sizes`] returns
+/// sizes, or with [`Config::adaptive_chunk_size`](crate::Config::adaptive_chunk_size) when their
+/// workers have measured delivery rates.
+pub trait Scheduler: Send {
+    /// Assign the chunks of a new transfer or upload, or chunks orphaned by a peer that left.
+    /// Chunks left out of the result stay unassigned.
+    fn assign(&mut self, chunk_ids: &[ChunkId], workers: &Workers) -> Vec<(ChunkId, DeviceId)>;
+
+    /// New worker for one chunk taken off its peer (Nack, timeout, integrity failure, Busy);
+    /// `workers` excludes that peer. `None` leaves the chunk where it is.
+    fn reassign(&mut self, chunk_id: ChunkId, workers: &Workers) -> Option<DeviceId>;
+
+    /// A chunk from `peer` verified (`ok`) or failed; [`PeerMetrics`] counters are already updated.
+    fn on_result(&mut self, _peer: DeviceId, _ok: bool) {}
+
+    /// Called on each tick with [`Config::work_stealing`](crate::Config::work_stealing): `queued`
+    /// holds every chunk still waiting for room in its peer's window, with that peer; `workers`
+    /// are the peers (not this device). Returns new peers for chunks that should move; they are
+    /// requested from them as their windows allow.
+    fn rebalance(
+        &mut self,
+        _queued: &[(ChunkId, DeviceId)],
+        _workers: &Workers,
+    ) -> Vec<(ChunkId, DeviceId)> {
+        Vec::new()
+    }
+
+    /// Chunk size per worker (same order as `workers.ids`) for a new transfer, so faster workers
+    /// get bigger chunks. `None` (the default) leaves sizing to the core.
+    fn chunk_sizes(&mut self, _workers: &Workers) -> Option<Vec<u64>> {
+        None
+    }
+}
+
+/// The built-in policy: [`assign_by_backlog`] for new chunks, regrouped by [`contiguous_runs`]
+/// with the lowest-latency worker first, and [`least_loaded_worker`] for reassignment.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DefaultScheduler;
+
+impl Scheduler for DefaultScheduler {
+    fn assign(&mut self, chunk_ids: &[ChunkId], workers: &Workers) -> Vec<(ChunkId, DeviceId)> {
+        let mut assignment = assign_by_backlog(chunk_ids, workers);
+        let lead = lowest_latency_worker(workers.ids, workers.metrics);
+        contiguous_runs(&mut assignment, lead);
+        lead_with_low_latency(&mut assignment, workers.ids, workers.metrics);
+        assignment
+    }
+
+    fn reassign(&mut self, chunk_id: ChunkId, workers: &Workers) -> Option<DeviceId> {
+        let len = chunk_id.end.saturating_sub(chunk_id.start);
+        least_loaded_worker(workers.ids, workers.metrics, workers.outstanding_bytes, len)
+    }
+
+    fn rebalance(
+        &mut self,
+        queued: &[(ChunkId, DeviceId)],
+        workers: &Workers,
+    ) -> Vec<(ChunkId, DeviceId)> {
+        steal_work(queued, workers)
+    }
+}
+
+/// Outcome of a dry run of a [`Scheduler`] over a snapshot of workers (see [`plan`]).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AssignmentPlan {
+    pub assignment: Vec<(ChunkId, DeviceId)>,
+    /// Bytes assigned to each worker (same order as [`Workers::ids`]).
+    pub bytes_per_worker: Vec<u64>,
+    /// Chunks the strategy left unassigned.
+    pub unassigned: usize,
+    /// When the last worker would finish its assigned and already outstanding bytes at its
+    /// [`contribution_rate`], in milliseconds; `None` if a worker with work has no known rate.
+    pub estimated_ms: Option<u64>,
+}
+
+/// Run `strategy` over `chunk_ids` and `workers` without touching any core state, so hosts, tests
+/// and tuning tools can compare strategies on real metric snapshots (see
+/// [`PeaPodCore::dry_run`](crate::PeaPodCore::dry_run)). A strategy that keeps state sees the call
+/// like any other; pass a fresh one to leave a live scheduler alone.
+pub fn plan(
+    chunk_ids: &[ChunkId],
+    workers: &Workers,
+    strategy: &mut dyn Scheduler,
+) -> AssignmentPlan {
+    let assignment = strategy.assign(chunk_ids, workers);
+    let mut bytes_per_worker = vec![0u64; workers.ids.len()];
+    for (c, p) in &assignment {
+        if let Some(i) = workers.ids.iter().position(|w| w == p) {
+            bytes_per_worker[i] += c.end.saturating_sub(c.start);
+        }
+    }
+    let mut estimated_ms = Some(0u64);
+    for (i, &bytes) in bytes_per_worker.iter().enumerate() {
+        if bytes == 0 {
+            continue;
+        }
+        let backlog = bytes + workers.outstanding_bytes.get(i).copied().unwrap_or(0);
+        let rate = workers.metrics.get(i).map_or(0, contribution_rate);
+        estimated_ms = match (estimated_ms, rate) {
+            (Some(ms), rate) if rate > 0 => {
+                let own = (u128::from(backlog) * 1000).div_ceil(u128::from(rate));
+                Some(ms.max(own.min(u128::from(u64::MAX)) as u64))
+            }
+            _ => None,
+        };
+    }
+    AssignmentPlan {
+        unassigned: chunk_ids.len().saturating_sub(assignment.len()),
+        assignment,
+        bytes_per_worker,
+        estimated_ms,
+    }
+}
+
 /// Assign each chunk to a peer (round-robin over peers). Returns (ChunkId, DeviceId) for each chunk.
 /// If peers is empty, returns empty. Does not include "self" in assignment; host treats missing peer as self.
 pub fn assign_chunks_to_peers(
@@ -68,6 +202,492 @@ pub fn assign_chunks_to_peers_weighted(
     out
 }
 
+/// Optional per-peer metrics for scheduler weighting. Serializable (missing fields default) so
+/// hosts can persist them across runs (see
+/// [`PeaPodCore::known_metrics`](crate::PeaPodCore::known_metrics)).
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PeerMetrics {
+    /// Estimated bandwidth in bytes per second, an EWMA of reported throughput; higher gives more
+    /// chunks.
+    pub bandwidth_bytes_per_sec: Option<u64>,
+    /// Round-trip time in milliseconds; the lowest-latency worker gets the first chunks of a
+    /// transfer (see [`lead_with_low_latency`]).
+    pub latency_ms: Option<u32>,
+    /// Chunks from this peer that verified (maintained by the core).
+    pub chunks_verified: u64,
+    /// Chunks from this peer that failed integrity, were Nacked or timed out (maintained by the core).
+    pub chunks_failed: u64,
+    /// Resource constraints the device advertised (maintained by the core).
+    pub constraints: Constraints,
+    /// Delivery rate measured from ChunkRequest to verified chunk, in bytes per tick (hosts tick
+    /// once per second); smoothed over recent chunks (maintained by the core).
+    pub delivery_rate_bps: Option<u64>,
+    /// Tick of the latest failure, moved on as `chunks_failed` decays (maintained by the core; see
+    /// [`Config::failure_half_life_ticks`](crate::Config::failure_half_life_ticks)).
+    pub last_failure_tick: Option<u64>,
+}
+
+/// Resource constraints a device advertises so the pod avoids giving it heavy work.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Constraints {
+    pub battery_low: bool,
+    /// WAN traffic is metered (e.g. mobile data).
+    pub metered: bool,
+    /// Upper bound on bytes per second the device is willing to fetch for the pod.
+    pub max_contribution_bps: Option<u64>,
+}
+
+impl Constraints {
+    /// Low battery or metered connection: the device gets a fraction of the work.
+    pub fn is_constrained(&self) -> bool {
+        self.battery_low || self.metered
+    }
+}
+
+/// Share of work a battery-low or metered device gets relative to an otherwise equal device.
+pub const CONSTRAINED_WEIGHT_DIVISOR: u64 = 8;
+
+/// Failures after which a peer that fails more often than it succeeds stops receiving work.
+pub const FAILURE_EXCLUSION_THRESHOLD: u64 = 3;
+
+impl PeerMetrics {
+    /// Scheduling weight: bandwidth (default 1, capped by `max_contribution_bps`) scaled by the
+    /// chunk success rate and divided by [`CONSTRAINED_WEIGHT_DIVISOR`] for constrained devices; 0
+    /// for a peer with at least [`FAILURE_EXCLUSION_THRESHOLD`] failures and more failures than
+    /// successes.
+    pub fn weight(&self) -> u64 {
+        let mut bandwidth = self.bandwidth_bytes_per_sec.unwrap_or(1).max(1);
+        if let Some(cap) = self.constraints.max_contribution_bps {
+            bandwidth = bandwidth.min(cap.max(1));
+        }
+        if self.chunks_failed >= FAILURE_EXCLUSION_THRESHOLD
+            && self.chunks_failed > self.chunks_verified
+        {
+            return 0;
+        }
+        let base = if self.constraints.is_constrained() {
+            bandwidth
+        } else {
+            bandwidth.saturating_mul(CONSTRAINED_WEIGHT_DIVISOR)
+        };
+        if self.chunks_failed == 0 {
+            return base;
+        }
+        let attempts = self.chunks_verified + self.chunks_failed + 1;
+        (base.saturating_mul(self.chunks_verified + 1) / attempts).max(1)
+    }
+}
+
+/// Assign chunks using per-worker metrics (same order as workers). Peers excluded by
+/// [`PeerMetrics::weight`] get nothing unless every worker is excluded; with equal weights this is
+/// round-robin, otherwise chunks are split in proportion to weight with the best workers first.
+pub fn assign_chunks_with_metrics(
+    chunk_ids: &[ChunkId],
+    workers: &[DeviceId],
+    metrics: &[PeerMetrics],
+) -> Vec<(ChunkId, DeviceId)> {
+    if metrics.len() != workers.len() {
+        return assign_chunks_to_peers(chunk_ids, workers);
+    }
+    let weights: Vec<u64> = metrics.iter().map(PeerMetrics::weight).collect();
+    assign_by_weight(chunk_ids, workers, &weights)
+}
+
+/// Like [`assign_chunks_with_metrics`], but workers reporting the same uplink (`uplinks`, same
+/// order as workers; `None` for unknown) share one WAN link: each gets its weight divided by the
+/// number of workers on that uplink, so chunks spread across distinct uplinks first.
+pub fn assign_chunks_with_uplinks(
+    chunk_ids: &[ChunkId],
+    workers: &[DeviceId],
+    metrics: &[PeerMetrics],
+    uplinks: &[Option<&str>],
+) -> Vec<(ChunkId, DeviceId)> {
+    if metrics.len() != workers.len() || uplinks.len() != workers.len() {
+        return assign_chunks_with_metrics(chunk_ids, workers, metrics);
+    }
+    assign_by_weight(chunk_ids, workers, &uplink_weights(metrics, uplinks))
+}
+
+/// [`PeerMetrics::weight`] per worker, divided by the number of workers on the same uplink.
+fn uplink_weights(metrics: &[PeerMetrics], uplinks: &[Option<&str>]) -> Vec<u64> {
+    metrics
+        .iter()
+        .zip(uplinks)
+        .map(|(m, uplink)| {
+            let w = m.weight();
+            let sharing = uplink.map_or(1, |u| uplinks.iter().filter(|o| **o == Some(u)).count());
+            match w {
+                0 => 0,
+                w => (w / sharing as u64).max(1),
+            }
+        })
+        .collect()
+}
+
+/// Assign chunks one at a time (by offset) to the worker whose backlog, its
+/// [`Workers::outstanding_bytes`] plus the bytes assigned so far, would be smallest relative to its
+/// weight after taking the chunk; ties go to the higher weight, then the earlier worker. Weights
+/// follow [`assign_chunks_with_uplinks`] (excluded workers get nothing unless all are excluded), so
+/// workers already busy with other transfers get less, and chunks of different sizes balance by
+/// bytes rather than by count.
+pub fn assign_by_backlog(chunk_ids: &[ChunkId], workers: &Workers) -> Vec<(ChunkId, DeviceId)> {
+    let n = workers.ids.len();
+    if workers.metrics.len() != n || workers.uplinks.len() != n {
+        return assign_chunks_to_peers(chunk_ids, workers.ids);
+    }
+    let mut weights = uplink_weights(workers.metrics, workers.uplinks);
+    if weights.iter().all(|&w| w == 0) {
+        weights.iter_mut().for_each(|w| *w = 1);
+    }
+    let mut backlog: Vec<u64> = if workers.outstanding_bytes.len() == n {
+        workers.outstanding_bytes.to_vec()
+    } else {
+        vec![0; n]
+    };
+    let mut order: Vec<ChunkId> = chunk_ids.to_vec();
+    order.sort_by_key(|c| c.start);
+    let mut out = Vec::with_capacity(order.len());
+    for chunk_id in order {
+        let len = chunk_id.end.saturating_sub(chunk_id.start);
+        // Smallest (backlog + len) / weight, compared without division.
+        let Some(best) = (0..n).filter(|&i| weights[i] > 0).min_by(|&a, &b| {
+            let load_a = u128::from(backlog[a] + len) * u128::from(weights[b]);
+            let load_b = u128::from(backlog[b] + len) * u128::from(weights[a]);
+            load_a.cmp(&load_b).then(weights[b].cmp(&weights[a]))
+        }) else {
+            break;
+        };
+        backlog[best] += len;
+        out.push((chunk_id, workers.ids[best]));
+    }
+    out
+}
+
+/// Bytes per second a worker can be expected to add to a transfer: its bandwidth, capped by
+/// `max_contribution_bps` and divided by [`CONSTRAINED_WEIGHT_DIVISOR`] when constrained; 0 for an
+/// unknown bandwidth or a peer excluded by [`PeerMetrics::weight`].
+pub fn contribution_rate(metrics: &PeerMetrics) -> u64 {
+    if metrics.weight() == 0 {
+        return 0;
+    }
+    let mut rate = metrics.bandwidth_bytes_per_sec.unwrap_or(0);
+    if let Some(cap) = metrics.constraints.max_contribution_bps {
+        rate = rate.min(cap);
+    }
+    if metrics.constraints.is_constrained() {
+        rate /= CONSTRAINED_WEIGHT_DIVISOR;
+    }
+    rate
+}
+
+/// Combined rate of a pod (same order for `metrics` and `uplinks`): workers on the same uplink
+/// share it, so only the fastest of them counts; workers with an unknown uplink count separately.
+pub fn pod_rate(metrics: &[PeerMetrics], uplinks: &[Option<&str>]) -> u64 {
+    let mut per_uplink: HashMap<&str, u64> = HashMap::new();
+    let mut total = 0u64;
+    for (m, uplink) in metrics.iter().zip(uplinks) {
+        let rate = contribution_rate(m);
+        match uplink {
+            Some(u) => {
+                let best = per_uplink.entry(u).or_default();
+                *best = (*best).max(rate);
+            }
+            None => total = total.saturating_add(rate),
+        }
+    }
+    per_uplink
+        .values()
+        .fold(total, |sum, &r| sum.saturating_add(r))
+}
+
+/// Assign by per-worker weights (0 excludes, unless every worker is excluded): round-robin when
+/// equal, otherwise in proportion to weight with the heaviest workers first.
+fn assign_by_weight(
+    chunk_ids: &[ChunkId],
+    workers: &[DeviceId],
+    weights: &[u64],
+) -> Vec<(ChunkId, DeviceId)> {
+    let mut ranked: Vec<(DeviceId, u64)> = workers
+        .iter()
+        .zip(weights)
+        .map(|(&p, &w)| (p, w))
+        .filter(|&(_, w)| w > 0)
+        .collect();
+    if ranked.is_empty() {
+        return assign_chunks_to_peers(chunk_ids, workers);
+    }
+    if ranked.iter().all(|&(_, w)| w == ranked[0].1) {
+        let peers: Vec<DeviceId> = ranked.iter().map(|&(p, _)| p).collect();
+        return assign_chunks_to_peers(chunk_ids, &peers);
+    }
+    ranked.sort_by_key(|&(_, w)| std::cmp::Reverse(w));
+    let (peers, weights): (Vec<DeviceId>, Vec<u64>) = ranked.into_iter().unzip();
+    assign_chunks_to_peers_weighted(chunk_ids, &peers, Some(&weights))
+}
+
+/// Chunks at the start of a transfer that go to its lowest-latency worker, to cut time to first
+/// byte.
+pub const LOW_LATENCY_LEAD_CHUNKS: usize = 2;
+
+/// Move the first [`LOW_LATENCY_LEAD_CHUNKS`] chunks (by offset) of `assignment` to the worker
+/// with the lowest known [`PeerMetrics::latency_ms`] (ties to the higher weight), trading them for
+/// that worker's last chunks so every worker keeps its chunk count and bulk ranges stay with
+/// high-throughput workers. Excluded workers are never picked; without known latencies nothing
+/// changes.
+pub fn lead_with_low_latency(
+    assignment: &mut [(ChunkId, DeviceId)],
+    workers: &[DeviceId],
+    metrics: &[PeerMetrics],
+) {
+    let Some(best) = lowest_latency_worker(workers, metrics) else {
+        return;
+    };
+    let mut order: Vec<usize> = (0..assignment.len()).collect();
+    order.sort_by_key(|&i| assignment[i].0.start);
+    for lead in 0..LOW_LATENCY_LEAD_CHUNKS.min(order.len()) {
+        let i = order[lead];
+        if assignment[i].1 == best {
+            continue;
+        }
+        let Some(&j) = order[lead + 1..]
+            .iter()
+            .rev()
+            .find(|&&j| assignment[j].1 == best)
+        else {
+            break;
+        };
+        assignment[j].1 = assignment[i].1;
+        assignment[i].1 = best;
+    }
+}
+
+/// The worker with the lowest known [`PeerMetrics::latency_ms`] (ties to the higher weight),
+/// excluding workers whose weight is 0; `None` without known latencies.
+pub fn lowest_latency_worker(workers: &[DeviceId], metrics: &[PeerMetrics]) -> Option<DeviceId> {
+    workers
+        .iter()
+        .zip(metrics)
+        .filter_map(|(&p, m)| Some((p, m.latency_ms?, m.weight())))
+        .filter(|&(_, _, w)| w > 0)
+        .min_by(|a, b| a.1.cmp(&b.1).then(b.2.cmp(&a.2)))
+        .map(|(p, _, _)| p)
+}
+
+/// Regroup `assignment` so each worker holds one contiguous run of chunks instead of interleaved
+/// ones: a serving peer can then fetch its run over one keep-alive connection to the origin.
+/// Chunk boundaries stay as they are; each worker keeps about the bytes it was assigned. Runs
+/// follow the order in which workers first appear by offset, with `lead` (if assigned) first.
+pub fn contiguous_runs(assignment: &mut [(ChunkId, DeviceId)], lead: Option<DeviceId>) {
+    assignment.sort_by_key(|(c, _)| c.start);
+    let mut order: Vec<(DeviceId, u64)> = Vec::new();
+    for &(c, p) in assignment.iter() {
+        let len = c.end.saturating_sub(c.start);
+        match order.iter_mut().find(|(o, _)| *o == p) {
+            Some((_, bytes)) => *bytes += len,
+            None => order.push((p, len)),
+        }
+    }
+    if let Some(i) = lead.and_then(|l| order.iter().position(|&(p, _)| p == l)) {
+        let first = order.remove(i);
+        order.insert(0, first);
+    }
+    let (mut run, mut filled) = (0, 0u64);
+    for (c, p) in assignment.iter_mut() {
+        let len = c.end.saturating_sub(c.start);
+        // Move on once this chunk would overshoot the run's bytes by more than it falls short.
+        while run + 1 < order.len() && 2 * filled + len > 2 * order[run].1 {
+            run += 1;
+            filled = 0;
+        }
+        *p = order[run].0;
+        filled += len;
+    }
+}
+
+/// Work stealing: each idle worker (nothing outstanding, weight above 0) takes queued chunks from
+/// the end of the most loaded worker's queue, as long as that leaves the thief less loaded
+/// relative to its [`PeerMetrics::weight`] than the worker it took from. Loads are
+/// [`Workers::outstanding_bytes`] over weight. Returns the moved chunks with their new worker.
+pub fn steal_work(queued: &[(ChunkId, DeviceId)], workers: &Workers) -> Vec<(ChunkId, DeviceId)> {
+    let n = workers.ids.len();
+    if workers.metrics.len() != n || workers.outstanding_bytes.len() != n {
+        return Vec::new();
+    }
+    let weights: Vec<u64> = workers.metrics.iter().map(PeerMetrics::weight).collect();
+    let mut load = workers.outstanding_bytes.to_vec();
+    // Each worker's queue, last chunk (by offset) at the end.
+    let mut queues: Vec<Vec<ChunkId>> = vec![Vec::new(); n];
+    for &(c, p) in queued {
+        if let Some(i) = workers.ids.iter().position(|&w| w == p) {
+            queues[i].push(c);
+        }
+    }
+    queues.iter_mut().for_each(|q| q.sort_by_key(|c| c.start));
+    let idle: Vec<usize> = (0..n).filter(|&i| load[i] == 0 && weights[i] > 0).collect();
+    let mut moves = Vec::new();
+    for thief in idle {
+        loop {
+            // Most loaded donor with something queued, compared as load / weight.
+            let donor = (0..n)
+                .filter(|&i| i != thief && !queues[i].is_empty())
+                .max_by(|&a, &b| {
+                    let load_a = u128::from(load[a]) * u128::from(weights[b].max(1));
+                    let load_b = u128::from(load[b]) * u128::from(weights[a].max(1));
+                    load_a.cmp(&load_b)
+                });
+            let Some(donor) = donor else {
+                break;
+            };
+            let Some(&chunk_id) = queues[donor].last() else {
+                break;
+            };
+            let len = chunk_id.end.saturating_sub(chunk_id.start);
+            // Move only while the thief stays below the donor: (thief + len) / wt < donor / wd.
+            let thief_after = u128::from(load[thief] + len) * u128::from(weights[donor].max(1));
+            let donor_now = u128::from(load[donor]) * u128::from(weights[thief]);
+            if weights[donor] > 0 && thief_after >= donor_now {
+                break;
+            }
+            queues[donor].pop();
+            load[donor] = load[donor].saturating_sub(len);
+            load[thief] += len;
+            moves.push((chunk_id, workers.ids[thief]));
+        }
+    }
+    moves
+}
+
+/// Worker for one reassigned chunk of `chunk_len` bytes: the one with the fewest outstanding bytes
+/// (`outstanding_bytes`, same order as workers) relative to its [`PeerMetrics::weight`]; ties go to
+/// the higher weight, then the earlier worker. Excluded peers are only chosen when every worker is
+/// excluded.
+pub fn least_loaded_worker(
+    workers: &[DeviceId],
+    metrics: &[PeerMetrics],
+    outstanding_bytes: &[u64],
+    chunk_len: u64,
+) -> Option<DeviceId> {
+    if metrics.len() != workers.len() || outstanding_bytes.len() != workers.len() {
+        return workers.first().copied();
+    }
+    let candidates: Vec<(DeviceId, u64, u64)> = workers
+        .iter()
+        .zip(metrics)
+        .zip(outstanding_bytes)
+        .map(|((&p, m), &n)| (p, m.weight(), n))
+        .collect();
+    let eligible: Vec<(DeviceId, u64, u64)> = if candidates.iter().any(|&(_, w, _)| w > 0) {
+        candidates.into_iter().filter(|&(_, w, _)| w > 0).collect()
+    } else {
+        candidates.into_iter().map(|(p, _, n)| (p, 1, n)).collect()
+    };
+    // Load after taking the chunk, compared as (n + len) / w without dividing.
+    eligible
+        .iter()
+        .min_by(|a, b| {
+            let load_a = u128::from(a.2 + chunk_len) * u128::from(b.1);
+            let load_b = u128::from(b.2 + chunk_len) * u128::from(a.1);
+            load_a.cmp(&load_b).then(b.1.cmp(&a.1))
+        })
+        .map(|&(p, _, _)| p)
+}
+
+/// Seconds of work an adaptive chunk aims for at the worker's measured rate.
+pub const ADAPTIVE_CHUNK_TARGET_SECS: u64 = 2;
+
+/// Preferred chunk size for a worker delivering `rate` bytes per second: about `target_secs` of
+/// work, clamped to `[min, max]`.
+pub fn adaptive_chunk_size(rate: u64, target_secs: u64, min: u64, max: u64) -> u64 {
+    rate.saturating_mul(target_secs).clamp(min, max.max(min))
+}
+
+/// Split `[0, total_length)` into chunks sized per worker (`sizes`, same order as `workers`) and
+/// assign them. Each next chunk goes to the worker that would finish its share soonest for its
+/// weight, so faster workers get both more and larger chunks. Weights follow
+/// [`assign_chunks_with_metrics`] (excluded workers get nothing unless all are excluded). Each
+/// worker's chunks are then laid out as one contiguous run, the lowest-latency worker's first
+/// (see [`contiguous_runs`]).
+pub fn plan_sized_chunks(
+    transfer_id: [u8; 16],
+    total_length: u64,
+    workers: &[DeviceId],
+    metrics: &[PeerMetrics],
+    sizes: &[u64],
+) -> Vec<(ChunkId, DeviceId)> {
+    let mut candidates: Vec<(DeviceId, u64, u64)> = workers
+        .iter()
+        .zip(metrics)
+        .zip(sizes)
+        .map(|((&p, m), &size)| (p, m.weight(), size.max(1)))
+        .filter(|&(_, w, _)| w > 0)
+        .collect();
+    if candidates.is_empty() {
+        candidates = workers
+            .iter()
+            .zip(sizes)
+            .map(|(&p, &size)| (p, 1, size.max(1)))
+            .collect();
+    }
+    let mut assigned = vec![0u64; candidates.len()];
+    let mut out = Vec::new();
+    let mut offset = 0;
+    while offset < total_length {
+        // Smallest (assigned + size) / weight, compared without division.
+        let finish = |i: usize| {
+            let (_, w, size) = candidates[i];
+            (u128::from(assigned[i] + size), u128::from(w))
+        };
+        let Some(best) = (0..candidates.len()).min_by(|&a, &b| {
+            let ((na, wa), (nb, wb)) = (finish(a), finish(b));
+            (na * wb).cmp(&(nb * wa))
+        }) else {
+            break;
+        };
+        let (peer, _, size) = candidates[best];
+        let end = offset.saturating_add(size).min(total_length);
+        out.push((
+            ChunkId {
+                transfer_id,
+                start: offset,
+                end,
+            },
+            peer,
+        ));
+        assigned[best] += end - offset;
+        offset = end;
+    }
+    // Lay the chunks out again run by run, keeping each one's size.
+    let lead = lowest_latency_worker(workers, metrics);
+    let mut order: Vec<DeviceId> = Vec::new();
+    for &(_, p) in &out {
+        if !order.contains(&p) {
+            order.push(p);
+        }
+    }
+    if let Some(i) = lead.and_then(|l| order.iter().position(|&p| p == l)) {
+        let first = order.remove(i);
+        order.insert(0, first);
+    }
+    let mut offset = 0;
+    let mut runs = Vec::with_capacity(out.len());
+    for peer in order {
+        for &(c, _) in out.iter().filter(|(_, p)| *p == peer) {
+            let end = offset + (c.end - c.start);
+            runs.push((
+                ChunkId {
+                    transfer_id,
+                    start: offset,
+                    end,
+                },
+                peer,
+            ));
+            offset = end;
+        }
+    }
+    runs
+}
+
 /// Reassign chunks that were assigned to `peer_left` to the remaining peers.
 /// Returns only the new assignments for chunks that were previously assigned to peer_left.
 pub fn reassign_after_peer_left(
@@ -100,6 +720,41 @@ mod tests {
     use super::*;
     use crate::identity::Keypair;
 
+    #[test]
+    fn low_latency_worker_leads_without_changing_shares() {
+        let (near, far) = (
+            Keypair::generate().device_id(),
+            Keypair::generate().device_id(),
+        );
+        let chunk = |i: u64| ChunkId {
+            transfer_id: [0; 16],
+            start: i * 10,
+            end: i * 10 + 10,
+        };
+        let mut assignment = vec![
+            (chunk(0), far),
+            (chunk(1), far),
+            (chunk(2), far),
+            (chunk(3), near),
+            (chunk(4), near),
+        ];
+        let metrics = |latency_ms| PeerMetrics {
+            latency_ms,
+            ..PeerMetrics::default()
+        };
+        let unknown = [metrics(None), metrics(None)];
+        let before = assignment.clone();
+        lead_with_low_latency(&mut assignment, &[far, near], &unknown);
+        assert_eq!(assignment, before);
+        lead_with_low_latency(
+            &mut assignment,
+            &[far, near],
+            &[metrics(Some(40)), metrics(Some(2))],
+        );
+        let owners: Vec<DeviceId> = assignment.iter().map(|&(_, p)| p).collect();
+        assert_eq!(owners, vec![near, near, far, far, far]);
+    }
+
     #[test]
     fn assign_zero_peers_empty() {
         let chunks = vec![ChunkId {
@@ -161,6 +816,170 @@ mod tests {
         assert_eq!(out[2].1, a.device_id());
     }
 
+    #[test]
+    fn workers_sharing_an_uplink_split_its_share() {
+        let chunks: Vec<ChunkId> = (0..8)
+            .map(|i| ChunkId {
+                transfer_id: [0; 16],
+                start: i * 10,
+                end: (i + 1) * 10,
+            })
+            .collect();
+        let (a, b, c) = (
+            Keypair::generate().device_id(),
+            Keypair::generate().device_id(),
+            Keypair::generate().device_id(),
+        );
+        let metrics = vec![PeerMetrics::default(); 3];
+        let out = assign_chunks_with_uplinks(
+            &chunks,
+            &[a, b, c],
+            &metrics,
+            &[Some("home"), Some("home"), Some("mobile")],
+        );
+        let count = |id: DeviceId| out.iter().filter(|(_, d)| *d == id).count();
+        assert_eq!((count(a), count(b), count(c)), (2, 2, 4));
+        // Unknown uplinks are assumed distinct.
+        let out = assign_chunks_with_uplinks(&chunks, &[a, b, c], &metrics, &[None, None, None]);
+        assert_eq!(
+            out,
+            assign_chunks_with_metrics(&chunks, &[a, b, c], &metrics)
+        );
+    }
+
+    #[test]
+    fn least_loaded_worker_spreads_by_outstanding_bytes_and_weight() {
+        let (a, b, c) = (
+            Keypair::generate().device_id(),
+            Keypair::generate().device_id(),
+            Keypair::generate().device_id(),
+        );
+        let workers = [a, b, c];
+        let even = vec![PeerMetrics::default(); 3];
+        assert_eq!(least_loaded_worker(&workers, &even, &[2, 0, 1], 1), Some(b));
+        assert_eq!(least_loaded_worker(&workers, &even, &[1, 1, 1], 1), Some(a));
+        // A peer twice as fast may hold twice as many chunks.
+        let mut fast = even.clone();
+        fast[0].bandwidth_bytes_per_sec = Some(2);
+        assert_eq!(least_loaded_worker(&workers, &fast, &[1, 1, 1], 1), Some(a));
+        // Excluded peers are skipped however idle they are.
+        let mut failing = even.clone();
+        failing[1].chunks_failed = FAILURE_EXCLUSION_THRESHOLD;
+        assert_eq!(
+            least_loaded_worker(&workers, &failing, &[3, 0, 2], 1),
+            Some(c)
+        );
+        // One large chunk outweighs several small ones.
+        assert_eq!(
+            least_loaded_worker(&workers, &even, &[4_000, 300, 1_000], 100),
+            Some(b)
+        );
+    }
+
+    #[test]
+    fn backlog_assignment_balances_bytes_across_chunk_sizes() {
+        let (a, b) = (
+            Keypair::generate().device_id(),
+            Keypair::generate().device_id(),
+        );
+        let chunk = |start, end| ChunkId {
+            transfer_id: [0; 16],
+            start,
+            end,
+        };
+        // One 400-byte chunk, then four of 100 bytes: a takes the large one, b the small ones.
+        let chunks = [
+            chunk(0, 400),
+            chunk(400, 500),
+            chunk(500, 600),
+            chunk(600, 700),
+            chunk(700, 800),
+        ];
+        let metrics = vec![PeerMetrics::default(); 2];
+        let workers = Workers {
+            ids: &[a, b],
+            metrics: &metrics,
+            uplinks: &[None, None],
+            outstanding_bytes: &[0, 0],
+        };
+        let out = assign_by_backlog(&chunks, &workers);
+        let bytes = |peer| -> u64 {
+            out.iter()
+                .filter(|(_, p)| *p == peer)
+                .map(|(c, _)| c.end - c.start)
+                .sum()
+        };
+        assert_eq!((bytes(a), bytes(b)), (400, 400));
+        // A worker already holding bytes from another transfer gets less of this one.
+        let workers = Workers {
+            outstanding_bytes: &[0, 200],
+            ..workers
+        };
+        let out = assign_by_backlog(&chunks[1..], &workers);
+        let count = |id| out.iter().filter(|(_, p)| *p == id).count();
+        assert_eq!((count(a), count(b)), (3, 1));
+    }
+
+    #[test]
+    fn default_scheduler_assigns_contiguous_runs() {
+        let (a, b, c) = (
+            Keypair::generate().device_id(),
+            Keypair::generate().device_id(),
+            Keypair::generate().device_id(),
+        );
+        let chunks: Vec<ChunkId> = (0..9)
+            .map(|i| ChunkId {
+                transfer_id: [0; 16],
+                start: i * 10,
+                end: (i + 1) * 10,
+            })
+            .collect();
+        let mut metrics = vec![PeerMetrics::default(); 3];
+        metrics[2].latency_ms = Some(5);
+        let workers = Workers {
+            ids: &[a, b, c],
+            metrics: &metrics,
+            uplinks: &[None, None, None],
+            outstanding_bytes: &[0, 0, 0],
+        };
+        let mut out = DefaultScheduler.assign(&chunks, &workers);
+        out.sort_by_key(|(c, _)| c.start);
+        let peers: Vec<DeviceId> = out.iter().map(|&(_, p)| p).collect();
+        // Three runs of three; the lowest-latency worker's run comes first.
+        assert_eq!(peers, [c, c, c, a, a, a, b, b, b]);
+    }
+
+    #[test]
+    fn sized_plan_gives_fast_worker_larger_and_more_chunks() {
+        let fast = Keypair::generate().device_id();
+        let slow = Keypair::generate().device_id();
+        let metrics = |bw| PeerMetrics {
+            bandwidth_bytes_per_sec: Some(bw),
+            ..PeerMetrics::default()
+        };
+        let plan = plan_sized_chunks(
+            [0; 16],
+            1000,
+            &[fast, slow],
+            &[metrics(400), metrics(100)],
+            &[200, 50],
+        );
+        let bytes = |peer| -> u64 {
+            plan.iter()
+                .filter(|(_, p)| *p == peer)
+                .map(|(c, _)| c.end - c.start)
+                .sum()
+        };
+        assert_eq!(bytes(fast) + bytes(slow), 1000);
+        assert_eq!((bytes(fast), bytes(slow)), (800, 200));
+        assert!(plan.windows(2).all(|w| w[0].0.end == w[1].0.start));
+        // Each worker's chunks form one run.
+        let switches = plan.windows(2).filter(|w| w[0].1 != w[1].1).count();
+        assert_eq!(switches, 1);
+        assert_eq!(adaptive_chunk_size(1_000_000, 2, 128, 4096), 4096);
+        assert_eq!(adaptive_chunk_size(10, 2, 128, 4096), 128);
+    }
+
     #[test]
     fn assign_weighted() {
         let a = Keypair::generate();
@@ -181,6 +1000,65 @@ mod tests {
         assert!(b_count > a_count, "weighted: b should get more chunks");
     }
 
+    #[test]
+    fn metrics_exclude_failing_peer_and_prefer_reliable_one() {
+        let a = Keypair::generate();
+        let b = Keypair::generate();
+        let chunks: Vec<ChunkId> = (0..4)
+            .map(|i| ChunkId {
+                transfer_id: [0; 16],
+                start: i * 100,
+                end: (i + 1) * 100,
+            })
+            .collect();
+        let peers = vec![a.device_id(), b.device_id()];
+        let failing = PeerMetrics {
+            chunks_failed: FAILURE_EXCLUSION_THRESHOLD,
+            ..PeerMetrics::default()
+        };
+        let out = assign_chunks_with_metrics(&chunks, &peers, &[failing, PeerMetrics::default()]);
+        assert!(out.iter().all(|(_, p)| *p == b.device_id()));
+        let flaky = PeerMetrics {
+            chunks_verified: 1,
+            chunks_failed: 1,
+            bandwidth_bytes_per_sec: Some(100),
+            ..PeerMetrics::default()
+        };
+        let reliable = PeerMetrics {
+            bandwidth_bytes_per_sec: Some(100),
+            ..PeerMetrics::default()
+        };
+        let single = assign_chunks_with_metrics(&chunks[..1], &peers, &[flaky, reliable]);
+        assert_eq!(single[0].1, b.device_id());
+    }
+
+    #[test]
+    fn constrained_peer_gets_less_work() {
+        let a = Keypair::generate();
+        let b = Keypair::generate();
+        let chunks: Vec<ChunkId> = (0..9)
+            .map(|i| ChunkId {
+                transfer_id: [0; 16],
+                start: i * 100,
+                end: (i + 1) * 100,
+            })
+            .collect();
+        let metered = PeerMetrics {
+            constraints: Constraints {
+                metered: true,
+                ..Constraints::default()
+            },
+            ..PeerMetrics::default()
+        };
+        let out = assign_chunks_with_metrics(
+            &chunks,
+            &[a.device_id(), b.device_id()],
+            &[metered, PeerMetrics::default()],
+        );
+        let a_count = out.iter().filter(|(_, p)| *p == a.device_id()).count();
+        assert_eq!(a_count, 1);
+    }
+
     #[test]
     fn reassign_after_leave() {
         let a = Keypair::generate();
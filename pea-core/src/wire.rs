@@ -1,66 +1,426 @@
-//! Framing: length-prefix (4 bytes LE) + bincode payload.
+//! Framing: length (4 bytes LE) + message type (1 byte) + flags (1 byte) + payload.
+//!
+//! The type byte is a fixed [`MessageType`] code, so a receiver can read a frame's kind with
+//! [`peek_frame`] and skip types it does not know without touching the payload. The payload is
+//! bincode, or the canonical encoding ([postcard](https://postcard.jamesmunns.com/wire-format))
+//! when the [`FLAG_CANONICAL`] flag is set; [`test_vectors`] pins the bytes for other languages.
 
-use crate::protocol::Message;
+use crate::chunk;
+use crate::identity::{DeviceKey, Keypair};
+use crate::integrity::HashAlgorithm;
+use crate::pairing::WrappedPodKey;
+use crate::protocol::{Capabilities, Message, MessageType, CANONICAL_PROTOCOL_VERSION};
 
 const LEN_SIZE: usize = 4;
+/// Length, type and flags.
+const HEADER_SIZE: usize = LEN_SIZE + 2;
 const MAX_FRAME_LEN: u32 = 16 * 1024 * 1024; // 16 MiB
+/// Flag bit: the payload uses [`WireEncoding::Canonical`].
+pub const FLAG_CANONICAL: u8 = 1 << 0;
+/// Flag bit: a CRC-32 (IEEE, 4 bytes LE) of the payload comes before it, checked before decoding.
+pub const FLAG_CHECKSUM: u8 = 1 << 1;
+/// Flag bit: the payload is zstd-compressed (after the checksum, which covers the compressed
+/// bytes). Only sent to peers advertising [`crate::protocol::FEATURE_FRAME_COMPRESSION`].
+pub const FLAG_COMPRESSED: u8 = 1 << 2;
+/// Flag bits this build understands; frames carrying others are skipped.
+const KNOWN_FLAGS: u8 = FLAG_CANONICAL | FLAG_CHECKSUM | FLAG_COMPRESSED;
+const CHECKSUM_SIZE: usize = 4;
 
-/// Encode a message into a single frame: 4 bytes LE length + bincode payload.
+/// How a frame's payload is encoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WireEncoding {
+    /// bincode 1 with its default options: whatever the Rust crate does (protocol version 1).
+    #[default]
+    Bincode,
+    /// postcard 1: varint integers and length-prefixed sequences, specified byte for byte so
+    /// other languages can match it (protocol version [`CANONICAL_PROTOCOL_VERSION`] and later).
+    Canonical,
+}
+
+impl WireEncoding {
+    /// The encoding to send to a peer speaking `version`.
+    pub fn for_protocol_version(version: u8) -> Self {
+        if version >= CANONICAL_PROTOCOL_VERSION {
+            WireEncoding::Canonical
+        } else {
+            WireEncoding::Bincode
+        }
+    }
+}
+
+/// Encode a message into a single frame: 4 bytes LE payload length, type byte, flags, then the
+/// bincode payload.
 pub fn encode_frame(msg: &Message) -> Result<Vec<u8>, FrameEncodeError> {
-    let payload = bincode::serialize(msg).map_err(FrameEncodeError::Encode)?;
-    let len = payload.len() as u32;
-    if len > MAX_FRAME_LEN {
+    encode_frame_as(msg, WireEncoding::Bincode)
+}
+
+/// Like [`encode_frame`], with the payload in `encoding`. Receivers tell the two apart by the
+/// [`FLAG_CANONICAL`] flag, so [`decode_frame`] reads either.
+pub fn encode_frame_as(msg: &Message, encoding: WireEncoding) -> Result<Vec<u8>, FrameEncodeError> {
+    encode_frame_with(
+        msg,
+        FrameOptions {
+            encoding,
+            ..FrameOptions::default()
+        },
+    )
+}
+
+/// How [`encode_frame_with`] writes a frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FrameOptions {
+    pub encoding: WireEncoding,
+    /// Add a CRC-32 of the payload ([`FLAG_CHECKSUM`]), for links that are not authenticated:
+    /// corruption then fails with [`FrameDecodeError::BadChecksum`] instead of decoding as garbage.
+    pub checksum: bool,
+    /// zstd-compress the payload when that saves at least an eighth of it ([`FLAG_COMPRESSED`]).
+    pub compress: bool,
+}
+
+/// Encode a message into a frame as `options` say.
+pub fn encode_frame_with(
+    msg: &Message,
+    options: FrameOptions,
+) -> Result<Vec<u8>, FrameEncodeError> {
+    let (payload, mut flags) = match options.encoding {
+        WireEncoding::Bincode => (bincode::serialize(msg)?, 0),
+        WireEncoding::Canonical => (postcard::to_allocvec(msg)?, FLAG_CANONICAL),
+    };
+    let packed = options
+        .compress
+        .then(|| chunk::compress_payload(&payload))
+        .flatten();
+    if packed.is_some() {
+        flags |= FLAG_COMPRESSED;
+    }
+    let payload = packed.unwrap_or(payload);
+    let checksum = options.checksum.then(|| crc32fast::hash(&payload));
+    if checksum.is_some() {
+        flags |= FLAG_CHECKSUM;
+    }
+    let body_len = payload.len() + checksum.map_or(0, |_| CHECKSUM_SIZE);
+    if body_len > MAX_FRAME_LEN as usize {
         return Err(FrameEncodeError::TooLarge);
     }
-    let mut out = Vec::with_capacity(LEN_SIZE + payload.len());
-    out.extend_from_slice(&len.to_le_bytes());
+    let mut out = Vec::with_capacity(HEADER_SIZE + body_len);
+    out.extend_from_slice(&(body_len as u32).to_le_bytes());
+    out.push(msg.message_type() as u8);
+    out.push(flags);
+    if let Some(crc) = checksum {
+        out.extend_from_slice(&crc.to_le_bytes());
+    }
     out.extend_from_slice(&payload);
     Ok(out)
 }
 
-/// Error encoding a message into a frame (bincode or size limit).
+/// Error encoding a message into a frame (bincode, postcard or size limit).
 #[derive(Debug, thiserror::Error)]
 pub enum FrameEncodeError {
     #[error("encode error: {0}")]
     Encode(#[from] bincode::Error),
+    #[error("canonical encode error: {0}")]
+    Canonical(#[from] postcard::Error),
     #[error("frame too large")]
     TooLarge,
 }
 
+/// A frame's header, read without decoding its payload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameHeader {
+    /// Raw type byte; see [`FrameHeader::message_type`].
+    pub kind: u8,
+    pub flags: u8,
+    /// Bytes after the header: the checksum, if flagged, then the payload.
+    pub payload_len: usize,
+}
+
+impl FrameHeader {
+    /// The message type, or `None` if this build does not know the code.
+    pub fn message_type(&self) -> Option<MessageType> {
+        MessageType::from_u8(self.kind)
+    }
+
+    /// Whole frame size, header included.
+    pub fn frame_len(&self) -> usize {
+        HEADER_SIZE + self.payload_len
+    }
+}
+
+/// Read the header at the front of `bytes` (only the header need be present).
+pub fn peek_frame(bytes: &[u8]) -> Result<FrameHeader, FrameDecodeError> {
+    if bytes.len() < HEADER_SIZE {
+        return Err(FrameDecodeError::NeedMore);
+    }
+    let len = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    if len > MAX_FRAME_LEN {
+        return Err(FrameDecodeError::TooLarge);
+    }
+    Ok(FrameHeader {
+        kind: bytes[LEN_SIZE],
+        flags: bytes[LEN_SIZE + 1],
+        payload_len: len as usize,
+    })
+}
+
 /// Decode one frame from the front of `bytes`. Returns the message and the number of bytes consumed.
 /// Call with partial buffer; returns error if not enough bytes (caller should try again after more data).
+/// A whole frame of an unknown type, or with unknown flags, fails with [`FrameDecodeError::Skipped`]
+/// carrying its length, so the caller can drop it and read on.
 pub fn decode_frame(bytes: &[u8]) -> Result<(Message, usize), FrameDecodeError> {
-    if bytes.len() < LEN_SIZE {
+    let header = peek_frame(bytes)?;
+    let end = header.frame_len();
+    if bytes.len() < end {
         return Err(FrameDecodeError::NeedMore);
     }
-    let len = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize;
-    if len > MAX_FRAME_LEN as usize {
-        return Err(FrameDecodeError::TooLarge);
+    let kind = match header.message_type() {
+        Some(kind) if header.flags & !KNOWN_FLAGS == 0 => kind,
+        _ => return Err(FrameDecodeError::Skipped { header }),
+    };
+    let mut payload = &bytes[HEADER_SIZE..end];
+    if header.flags & FLAG_CHECKSUM != 0 {
+        if payload.len() < CHECKSUM_SIZE {
+            return Err(FrameDecodeError::BadChecksum);
+        }
+        let (crc, rest) = payload.split_at(CHECKSUM_SIZE);
+        if crc32fast::hash(rest).to_le_bytes() != crc {
+            return Err(FrameDecodeError::BadChecksum);
+        }
+        payload = rest;
     }
-    if bytes.len() < LEN_SIZE + len {
-        return Err(FrameDecodeError::NeedMore);
+    let unpacked;
+    if header.flags & FLAG_COMPRESSED != 0 {
+        unpacked = chunk::decompress_payload(payload, MAX_FRAME_LEN as u64)
+            .ok_or(FrameDecodeError::Decompress)?;
+        payload = &unpacked;
     }
-    let msg: Message =
-        bincode::deserialize(&bytes[LEN_SIZE..LEN_SIZE + len]).map_err(FrameDecodeError::Decode)?;
-    Ok((msg, LEN_SIZE + len))
+    let msg: Message = if header.flags & FLAG_CANONICAL != 0 {
+        // One message, nothing after it: the canonical form has exactly one encoding.
+        match postcard::take_from_bytes(payload)? {
+            (msg, []) => msg,
+            _ => {
+                return Err(FrameDecodeError::Canonical(
+                    postcard::Error::DeserializeBadEncoding,
+                ))
+            }
+        }
+    } else {
+        bincode::deserialize(payload)?
+    };
+    if msg.message_type() != kind {
+        return Err(FrameDecodeError::TypeMismatch);
+    }
+    Ok((msg, end))
 }
 
-/// Error decoding a frame (need more bytes, too large, or bincode failure).
+/// Error decoding a frame (need more bytes, too large, skipped, bad checksum, or decode failure).
 #[derive(Debug, thiserror::Error)]
 pub enum FrameDecodeError {
     #[error("need more bytes")]
     NeedMore,
     #[error("frame too large")]
     TooLarge,
+    /// A well-formed frame this build does not understand; `header.frame_len()` bytes to skip.
+    #[error("skipped frame of type {} with flags {:#04x}", header.kind, header.flags)]
+    Skipped { header: FrameHeader },
+    /// The payload does not match the frame's CRC-32 ([`FLAG_CHECKSUM`]).
+    #[error("frame checksum mismatch")]
+    BadChecksum,
+    /// A compressed payload that is malformed or inflates past the frame size limit.
+    #[error("frame decompression failed")]
+    Decompress,
+    /// The payload decoded as a different message than the header's type byte.
+    #[error("payload does not match frame type")]
+    TypeMismatch,
     #[error("decode error: {0}")]
     Decode(#[from] bincode::Error),
+    #[error("canonical decode error: {0}")]
+    Canonical(#[from] postcard::Error),
+}
+
+/// A message with its frame in each encoding, for checking another implementation (Kotlin,
+/// Swift, C) against this one. Keys come from fixed seeds, so the bytes never change.
+#[derive(Debug, Clone)]
+pub struct TestVector {
+    /// The message type's name.
+    pub name: &'static str,
+    pub message: Message,
+    /// [`encode_frame_as`] with [`WireEncoding::Canonical`].
+    pub canonical: Vec<u8>,
+    /// [`encode_frame`] (bincode).
+    pub bincode: Vec<u8>,
+}
+
+/// One vector per message type, in [`MessageType`] order, covering present and absent optional
+/// fields, strings, byte payloads and nested records.
+pub fn test_vectors() -> Vec<TestVector> {
+    let a = Keypair::from_bytes([1; 32]);
+    let b = Keypair::from_bytes([2; 32]);
+    let c = Keypair::from_bytes([3; 32]);
+    let capabilities = Capabilities {
+        max_chunk_size: 1 << 20,
+        downlink_bps: 12_500_000,
+        features: 0b11_1111,
+        platform: "linux".into(),
+    };
+    let transfer_id = [0x11; 16];
+    let (old_proof, new_proof) = a.rotation_proofs(&c, b.public_key());
+    let messages = [
+        Message::Beacon {
+            protocol_version: 1,
+            device_id: a.device_id(),
+            public_key: a.public_key().clone(),
+            listen_port: 45678,
+        },
+        Message::DiscoveryResponse {
+            protocol_version: 1,
+            device_id: b.device_id(),
+            public_key: b.public_key().clone(),
+            listen_port: 45679,
+        },
+        Message::JoinRequest {
+            device_id: a.device_id(),
+            public_key: a.public_key().clone(),
+            signature: a.join_signature(b.public_key()),
+            capabilities: capabilities.clone(),
+            name: a.name_record("kitchen"),
+        },
+        Message::Leave {
+            device_id: a.device_id(),
+            signature: a.leave_signature(b.public_key()),
+        },
+        Message::Heartbeat {
+            device_id: a.device_id(),
+        },
+        Message::ChunkRequest {
+            transfer_id,
+            start: 0,
+            end: 65536,
+            url: Some("https://example.com/file.bin".into()),
+            validator: Some("\"v1\"".into()),
+            headers: vec![("Cookie".into(), "a=1".into())],
+            deadline_ms: Some(5000),
+        },
+        Message::ChunkData {
+            transfer_id,
+            start: 0,
+            end: 5,
+            hash: crate::integrity::hash_chunk(b"hello"),
+            payload: b"hello".to_vec(),
+            compressed: false,
+            hash_algorithm: HashAlgorithm::Sha256,
+            attestation: Some([0x22; 32]),
+        },
+        Message::Nack {
+            transfer_id,
+            start: 65536,
+            end: 131072,
+        },
+        Message::Cancel {
+            transfer_id,
+            start: 131072,
+            end: 196608,
+        },
+        Message::Constraints {
+            battery_low: true,
+            metered: false,
+            max_contribution_bps: Some(1_000_000),
+        },
+        Message::JoinAccept {
+            device_id: b.device_id(),
+            public_key: b.public_key().clone(),
+            signature: b.join_signature(a.public_key()),
+            capabilities,
+            name: None,
+        },
+        Message::PairConfirm {
+            confirmation: [0x33; 32],
+            pod_key: Some(WrappedPodKey {
+                epoch: 2,
+                nonce: [0x44; 12],
+                ciphertext: vec![0x55; 48],
+            }),
+        },
+        Message::UploadOffer {
+            upload_id: [0x66; 16],
+            url: "https://example.com/upload".into(),
+            total_length: 300,
+        },
+        Message::UploadData {
+            upload_id: [0x66; 16],
+            start: 0,
+            end: 3,
+            hash: crate::integrity::hash_chunk(b"abc"),
+            payload: b"abc".to_vec(),
+        },
+        Message::UploadAck {
+            upload_id: [0x66; 16],
+            start: 0,
+            end: 3,
+            ok: true,
+        },
+        Message::Busy {
+            transfer_id,
+            start: 0,
+            end: 65536,
+        },
+        Message::Rekey { generation: 300 },
+        Message::RotateKey {
+            new_public_key: c.public_key().clone(),
+            old_proof,
+            new_proof,
+        },
+        Message::Revoke {
+            revocation: a.revoke(b.device_id()),
+        },
+        Message::PodKey {
+            key: WrappedPodKey {
+                epoch: 0,
+                nonce: [0x77; 12],
+                ciphertext: vec![0x88; 48],
+            },
+        },
+        Message::SealedDiscovery {
+            key_id: [0x99; 8],
+            nonce: [0xaa; 12],
+            ciphertext: vec![0xbb; 24],
+        },
+        Message::Hello {
+            min_version: 1,
+            max_version: 2,
+        },
+        Message::Capabilities {
+            capabilities: Capabilities {
+                max_chunk_size: 0,
+                downlink_bps: 0,
+                features: 1 << 31,
+                platform: "android".into(),
+            },
+        },
+        Message::DiscoveryPod { pod_id: [0x42; 8] },
+        Message::DiscoveryName {
+            name: a.name_record("kitchen").expect("valid name"),
+        },
+        Message::PairCommit {
+            commitment: crate::pairing::nonce_commitment(a.public_key(), &[0x33; 32]),
+        },
+        Message::PairNonce { nonce: [0x33; 32] },
+    ];
+    messages
+        .into_iter()
+        .map(|message| TestVector {
+            name: message.message_type().name(),
+            canonical: encode_frame_as(&message, WireEncoding::Canonical).expect("vector encodes"),
+            bincode: encode_frame(&message).expect("vector encodes"),
+            message,
+        })
+        .collect()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::identity::Keypair;
-    use crate::protocol::PROTOCOL_VERSION;
+    use crate::identity::{DeviceId, DeviceKey, Keypair, NameRecord, PublicKey};
+    use crate::protocol::{MessageType, PROTOCOL_VERSION};
 
     fn sample_beacon() -> Message {
         let kp = Keypair::generate();
@@ -110,6 +470,238 @@ mod tests {
             decode_frame(&frame[..super::LEN_SIZE]),
             Err(FrameDecodeError::NeedMore)
         ));
+        assert!(matches!(
+            decode_frame(&frame[..frame.len() - 1]),
+            Err(FrameDecodeError::NeedMore)
+        ));
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    #[test]
+    fn test_vectors_round_trip_in_both_encodings() {
+        let vectors = test_vectors();
+        assert_eq!(vectors.len(), 27);
+        for (code, v) in (1u8..).zip(&vectors) {
+            assert_eq!(v.message.message_type() as u8, code, "{}", v.name);
+            for frame in [&v.canonical, &v.bincode] {
+                let (msg, n) = decode_frame(frame).unwrap();
+                assert_eq!(n, frame.len());
+                assert_eq!(msg.message_type() as u8, code);
+                assert_eq!(
+                    encode_frame_as(&msg, WireEncoding::Canonical).unwrap(),
+                    v.canonical
+                );
+            }
+            assert_eq!(v.canonical[LEN_SIZE + 1], FLAG_CANONICAL);
+            assert_eq!(v.bincode[LEN_SIZE + 1], 0);
+        }
+    }
+
+    #[test]
+    fn canonical_encoding_is_pinned() {
+        let heartbeat = &test_vectors()[4];
+        assert_eq!(
+            hex(&heartbeat.canonical),
+            "12000000050104101a92f23852dc908d97316a3b13578281"
+        );
+        // Vectors are only ever appended: the ones before a new message type keep their hash, so
+        // a change to an existing layout shows up here instead of being pinned over.
+        let vectors = test_vectors();
+        let hash_of = |vectors: &[TestVector]| {
+            let all: Vec<u8> = vectors.iter().flat_map(|v| v.canonical.clone()).collect();
+            hex(&crate::integrity::hash_chunk(&all))
+        };
+        // The Beacon keeps the four fields of the original discovery format; the name and pod ID
+        // follow it in frames of their own.
+        assert_eq!(hex(&vectors[0].canonical), "3700000001010001101a92f23852dc908d97316a3b1357828120a4e09292b651c278b9772c569f5fa9bb13d906b46ab68c9df9dc2b4409f8a209eee402");
+        assert_eq!(
+            hash_of(&vectors[..23]),
+            "eaa57240a75815b72cdd20189f4df4d3a6aa42d28440a975ab9a3c552a8035eb"
+        );
+        assert_eq!(
+            hex(&vectors[23].canonical),
+            "090000001801174242424242424242"
+        );
+        assert_eq!(
+            hash_of(&vectors[24..25]),
+            "00298e4332e47e3f23914f6e64c79bcea1995c3bf7c86b6e885490281903e188"
+        );
+        assert_eq!(
+            hash_of(&vectors[25..]),
+            "a20c0e97bb00913e086501459f8a364a6b96791d21f7640ae3ab9eb7d5f368ad"
+        );
+
+        // Trailing bytes inside a canonical payload are refused, not ignored.
+        let mut padded = heartbeat.canonical.clone();
+        padded.push(0);
+        padded[0] += 1;
+        assert!(matches!(
+            decode_frame(&padded),
+            Err(FrameDecodeError::Canonical(_))
+        ));
+    }
+
+    /// Beacon and DiscoveryResponse as builds that carried the name inline, before
+    /// [`Message::DiscoveryName`], lay them out.
+    #[derive(serde::Serialize)]
+    enum OldDiscovery {
+        Beacon {
+            protocol_version: u8,
+            device_id: DeviceId,
+            public_key: PublicKey,
+            listen_port: u16,
+            name: Option<NameRecord>,
+        },
+        DiscoveryResponse {
+            protocol_version: u8,
+            device_id: DeviceId,
+            public_key: PublicKey,
+            listen_port: u16,
+            name: Option<NameRecord>,
+        },
+    }
+
+    #[test]
+    fn discovery_frames_from_older_builds_decode() {
+        let kp = Keypair::from_bytes([1; 32]);
+        let old = [
+            OldDiscovery::Beacon {
+                protocol_version: 1,
+                device_id: kp.device_id(),
+                public_key: kp.public_key().clone(),
+                listen_port: 45678,
+                name: kp.name_record("kitchen"),
+            },
+            OldDiscovery::DiscoveryResponse {
+                protocol_version: 1,
+                device_id: kp.device_id(),
+                public_key: kp.public_key().clone(),
+                listen_port: 45678,
+                name: None,
+            },
+        ];
+        // Discovery frames are bincode, which reads the fields it knows and ignores the name.
+        for (code, msg) in (1u8..).zip(&old) {
+            let payload = bincode::serialize(msg).unwrap();
+            let mut frame = (payload.len() as u32).to_le_bytes().to_vec();
+            frame.extend([code, 0]);
+            frame.extend(payload);
+            let (decoded, n) = decode_frame(&frame).unwrap();
+            assert_eq!(n, frame.len());
+            assert!(matches!(
+                decoded,
+                Message::Beacon { device_id, listen_port: 45678, .. }
+                | Message::DiscoveryResponse { device_id, listen_port: 45678, .. }
+                    if device_id == kp.device_id()
+            ));
+        }
+    }
+
+    #[test]
+    fn checksummed_frames_catch_corruption_before_decoding() {
+        let msg = sample_beacon();
+        for encoding in [WireEncoding::Bincode, WireEncoding::Canonical] {
+            let options = FrameOptions {
+                encoding,
+                checksum: true,
+                compress: false,
+            };
+            let frame = encode_frame_with(&msg, options).unwrap();
+            assert_eq!(peek_frame(&frame).unwrap().frame_len(), frame.len());
+            let (decoded, n) = decode_frame(&frame).unwrap();
+            assert_eq!(n, frame.len());
+            assert!(matches!(decoded, Message::Beacon { .. }));
+            for at in [
+                HEADER_SIZE,
+                HEADER_SIZE + CHECKSUM_SIZE + 20,
+                frame.len() - 1,
+            ] {
+                let mut bad = frame.clone();
+                bad[at] ^= 0x01;
+                assert!(matches!(
+                    decode_frame(&bad),
+                    Err(FrameDecodeError::BadChecksum)
+                ));
+            }
+        }
+        // Too short to hold the checksum.
+        let mut frame = vec![2, 0, 0, 0, MessageType::Heartbeat as u8, FLAG_CHECKSUM];
+        frame.extend_from_slice(&[0, 0]);
+        assert!(matches!(
+            decode_frame(&frame),
+            Err(FrameDecodeError::BadChecksum)
+        ));
+    }
+
+    #[test]
+    fn compressed_frames_inflate_transparently() {
+        let msg = Message::UploadOffer {
+            upload_id: [1; 16],
+            url: format!("https://example.com/{}", "a/".repeat(500)),
+            total_length: 10,
+        };
+        let plain = encode_frame(&msg).unwrap();
+        let options = FrameOptions {
+            checksum: true,
+            compress: true,
+            ..FrameOptions::default()
+        };
+        let packed = encode_frame_with(&msg, options).unwrap();
+        assert_eq!(packed[LEN_SIZE + 1], FLAG_CHECKSUM | FLAG_COMPRESSED);
+        assert!(packed.len() < plain.len() / 4);
+        match decode_frame(&packed).unwrap() {
+            (Message::UploadOffer { url, .. }, n) => {
+                assert_eq!(n, packed.len());
+                assert_eq!(url.len(), 1020);
+            }
+            other => panic!("expected UploadOffer, got {other:?}"),
+        }
+        // Small or incompressible payloads are left alone.
+        let heartbeat = encode_frame_with(&test_vectors()[4].message, options).unwrap();
+        assert_eq!(heartbeat[LEN_SIZE + 1], FLAG_CHECKSUM);
+        // Garbage flagged as compressed is an error, not a panic.
+        let mut bad = vec![4, 0, 0, 0, MessageType::Heartbeat as u8, FLAG_COMPRESSED];
+        bad.extend_from_slice(&[1, 2, 3, 4]);
+        assert!(matches!(
+            decode_frame(&bad),
+            Err(FrameDecodeError::Decompress)
+        ));
+    }
+
+    #[test]
+    fn unknown_types_and_flags_are_skipped_whole() {
+        let beacon = encode_frame(&sample_beacon()).unwrap();
+        let header = peek_frame(&beacon).unwrap();
+        assert_eq!(header.message_type(), Some(MessageType::Beacon));
+        assert_eq!(header.frame_len(), beacon.len());
+
+        let mut unknown = beacon.clone();
+        unknown[LEN_SIZE] = 0xff;
+        let mut flagged = beacon.clone();
+        flagged[LEN_SIZE + 1] = 0x80;
+        for frame in [unknown, flagged] {
+            let mut buf = frame.clone();
+            buf.extend_from_slice(&beacon);
+            match decode_frame(&buf) {
+                Err(FrameDecodeError::Skipped { header }) => {
+                    assert_eq!(header.frame_len(), frame.len());
+                    let (msg, _) = decode_frame(&buf[header.frame_len()..]).unwrap();
+                    assert!(matches!(msg, Message::Beacon { .. }));
+                }
+                other => panic!("expected Skipped, got {other:?}"),
+            }
+        }
+
+        // A known type byte that disagrees with the payload is an error, not a different message.
+        let mut relabelled = beacon;
+        relabelled[LEN_SIZE] = MessageType::Heartbeat as u8;
+        assert!(matches!(
+            decode_frame(&relabelled),
+            Err(FrameDecodeError::TypeMismatch)
+        ));
     }
 
     #[test]
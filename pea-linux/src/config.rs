@@ -4,7 +4,9 @@ use serde::Deserialize;
 use std::path::PathBuf;
 
 /// Daemon configuration. File: ~/.config/peapod/config.toml or /etc/peapod/config.toml.
-/// Env overrides: PEAPOD_PROXY_PORT, PEAPOD_DISCOVERY_PORT, PEAPOD_TRANSPORT_PORT.
+/// Env overrides: PEAPOD_PROXY_PORT, PEAPOD_DISCOVERY_PORT, PEAPOD_TRANSPORT_PORT,
+/// PEAPOD_IDENTITY_FILE.
+/// Core tuning fields are optional; unset fields use pea-core defaults. They are re-read on SIGHUP.
 #[derive(Debug, Clone, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct Config {
@@ -17,6 +19,111 @@ pub struct Config {
     /// Local transport TCP port (default 45679).
     #[serde(default = "default_transport_port")]
     pub transport_port: u16,
+    /// Device keypair file (default `$XDG_STATE_HOME/peapod/identity`). Read at startup only.
+    #[serde(default)]
+    pub identity_file: Option<PathBuf>,
+    /// Name peers show for this device (signed; at most 64 bytes). Read at startup only.
+    #[serde(default)]
+    pub device_name: Option<String>,
+    /// Chunk size in bytes for new transfers.
+    #[serde(default)]
+    pub chunk_size: Option<u64>,
+    /// Seconds (ticks) before an outstanding chunk request is reassigned.
+    #[serde(default)]
+    pub chunk_timeout_secs: Option<u64>,
+    /// Times a timed-out chunk is retried before its transfer falls back (0: unlimited).
+    #[serde(default)]
+    pub chunk_retries: Option<u32>,
+    /// Seconds (ticks) before the first retry of a timed-out chunk, doubled per retry.
+    #[serde(default)]
+    pub retry_backoff_secs: Option<u64>,
+    /// Percent of a transfer's chunks that may fail before it falls back to the origin.
+    #[serde(default)]
+    pub fallback_failure_percent: Option<u64>,
+    /// Seconds (ticks) between heartbeats to peers.
+    #[serde(default)]
+    pub heartbeat_interval_secs: Option<u64>,
+    /// Seconds (ticks) without a heartbeat before a peer is treated as left.
+    #[serde(default)]
+    pub heartbeat_timeout_secs: Option<u64>,
+    /// Maximum number of peers in the pod.
+    #[serde(default)]
+    pub max_peers: Option<usize>,
+    /// Bytes of verified chunks kept in memory to answer repeated requests (0 disables).
+    #[serde(default)]
+    pub chunk_cache_bytes: Option<u64>,
+    /// Seconds (ticks) without progress before an abandoned transfer or upload is dropped.
+    #[serde(default)]
+    pub stale_transfer_secs: Option<u64>,
+    /// Seconds (ticks) an accelerated transfer may take before the proxy falls back to a direct
+    /// fetch; time paused does not count. Omitted or 0: no deadline.
+    #[serde(default)]
+    pub transfer_deadline_secs: Option<u64>,
+    /// Percent of each accelerated download this device fetches itself (0–100); the pod fetches
+    /// the rest. Omitted: this device is weighted like any peer.
+    #[serde(default)]
+    pub self_share_percent: Option<u8>,
+    /// Always fetch the first chunk of an accelerated download here (fast start).
+    #[serde(default)]
+    pub self_first_chunk: Option<bool>,
+    /// Send the client's Cookie and Authorization headers with chunk requests, so peers can fetch
+    /// downloads that need a login.
+    #[serde(default)]
+    pub forward_request_headers: Option<bool>,
+    /// Compress chunks served to peers when that pays off (text-heavy downloads).
+    #[serde(default)]
+    pub compress_chunks: Option<bool>,
+    /// Compress whole chunk frames for peers that read compressed frames.
+    #[serde(default)]
+    pub compress_frames: Option<bool>,
+    /// Hash chunks for peers with BLAKE3 when they support it (cheaper than SHA-256).
+    #[serde(default)]
+    pub blake3_hashes: Option<bool>,
+    /// Largest download (bytes) to accelerate; bigger ones go direct. 0 means no cap.
+    #[serde(default)]
+    pub max_transfer_bytes: Option<u64>,
+    /// Re-fetch chunks that fail verification from two other sources to tell poisoning from corruption.
+    #[serde(default)]
+    pub dispute_quorum: Option<bool>,
+    /// Only join peers whose pairing code was confirmed (see `pairing_codes`).
+    #[serde(default)]
+    pub require_pairing: Option<bool>,
+    /// Seal beacons with the pod key once this device is paired.
+    #[serde(default)]
+    pub confidential_discovery: Option<bool>,
+    /// Also accept beacons from devices of other pods on the LAN.
+    #[serde(default)]
+    pub open_pod: Option<bool>,
+    /// Pairing codes the user confirmed match on both devices, each with the peer it was logged
+    /// for; applied once, on the next SIGHUP that finds that peer with that code.
+    #[serde(default)]
+    pub pairing_codes: Vec<PairingCode>,
+    /// Device IDs (hex, as logged) of lost devices to revoke on every paired device; applied on
+    /// SIGHUP.
+    #[serde(default)]
+    pub revoke_devices: Vec<String>,
+    /// Which requests are accelerated (`[eligibility]` table: `allow_domains`, `deny_domains`,
+    /// `min_content_length`, `content_types`, `never_share`).
+    #[serde(default)]
+    pub eligibility: Option<pea_core::EligibilityPolicy>,
+    /// When peers are served (`[contribution]` table: `serve_windows` as `[start, end]` minutes
+    /// since local midnight, `daily_byte_budget`).
+    #[serde(default)]
+    pub contribution: Option<pea_core::ContributionPolicy>,
+    /// Offset of local time from UTC in minutes, for `serve_windows` and the daily budget
+    /// (default 0; read at startup).
+    #[serde(default)]
+    pub utc_offset_minutes: Option<i32>,
+}
+
+/// A confirmed pairing code (`{ peer = "...", code = "042 917" }` in `pairing_codes`).
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq, Hash)]
+#[serde(deny_unknown_fields)]
+pub struct PairingCode {
+    /// The peer's fingerprint as logged with the code (words, or its hex).
+    pub peer: String,
+    /// The code as logged, e.g. "042 917".
+    pub code: String,
 }
 
 fn default_proxy_port() -> u16 {
@@ -35,7 +142,129 @@ impl Default for Config {
             proxy_port: default_proxy_port(),
             discovery_port: default_discovery_port(),
             transport_port: default_transport_port(),
+            identity_file: None,
+            device_name: None,
+            chunk_size: None,
+            chunk_timeout_secs: None,
+            chunk_retries: None,
+            retry_backoff_secs: None,
+            fallback_failure_percent: None,
+            heartbeat_interval_secs: None,
+            heartbeat_timeout_secs: None,
+            max_peers: None,
+            chunk_cache_bytes: None,
+            stale_transfer_secs: None,
+            transfer_deadline_secs: None,
+            self_share_percent: None,
+            self_first_chunk: None,
+            forward_request_headers: None,
+            compress_chunks: None,
+            compress_frames: None,
+            blake3_hashes: None,
+            max_transfer_bytes: None,
+            dispute_quorum: None,
+            require_pairing: None,
+            confidential_discovery: None,
+            open_pod: None,
+            pairing_codes: Vec::new(),
+            revoke_devices: Vec::new(),
+            eligibility: None,
+            contribution: None,
+            utc_offset_minutes: None,
+        }
+    }
+}
+
+impl Config {
+    /// Where the device keypair is kept: `identity_file`, else `$XDG_STATE_HOME/peapod/identity`
+    /// (`~/.local/state/peapod/identity`). `None` if neither variable is set.
+    pub fn identity_path(&self) -> Option<PathBuf> {
+        if let Some(p) = &self.identity_file {
+            return Some(p.clone());
+        }
+        let state = std::env::var_os("XDG_STATE_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|h| PathBuf::from(h).join(".local/state")))?;
+        Some(state.join("peapod/identity"))
+    }
+
+    /// pea-core config from the optional tuning fields (the transport ticks the core once per second).
+    pub fn core_config(&self) -> pea_core::Config {
+        let mut c = pea_core::Config::default();
+        if let Some(v) = self.chunk_size {
+            c.chunk_size = v;
+        }
+        if let Some(v) = self.chunk_timeout_secs {
+            c.chunk_timeout_ticks = v;
+        }
+        if let Some(v) = self.chunk_retries {
+            c.chunk_retries = v;
+        }
+        if let Some(v) = self.retry_backoff_secs {
+            c.retry_backoff_ticks = v;
+        }
+        if let Some(v) = self.fallback_failure_percent {
+            c.fallback_failure_percent = v;
+        }
+        if let Some(v) = self.heartbeat_interval_secs {
+            c.heartbeat_interval_ticks = v;
+        }
+        if let Some(v) = self.heartbeat_timeout_secs {
+            c.heartbeat_timeout_ticks = v;
+        }
+        if let Some(v) = self.max_peers {
+            c.max_peers = v;
         }
+        if let Some(v) = self.chunk_cache_bytes {
+            c.chunk_cache_bytes = v;
+        }
+        if let Some(v) = self.stale_transfer_secs {
+            c.stale_transfer_ticks = v;
+        }
+        if let Some(v) = self.transfer_deadline_secs {
+            c.transfer_deadline_ticks = v;
+        }
+        if let Some(v) = self.self_share_percent {
+            c.self_share_percent = Some(v);
+        }
+        if let Some(v) = self.self_first_chunk {
+            c.self_first_chunk = v;
+        }
+        if let Some(v) = self.forward_request_headers {
+            c.forward_request_headers = v;
+        }
+        if let Some(v) = self.compress_chunks {
+            c.compress_chunks = v;
+        }
+        if let Some(v) = self.compress_frames {
+            c.compress_frames = v;
+        }
+        if let Some(v) = self.blake3_hashes {
+            c.blake3_hashes = v;
+        }
+        if let Some(v) = self.max_transfer_bytes {
+            c.max_transfer_bytes = v;
+        }
+        if let Some(v) = self.dispute_quorum {
+            c.dispute_quorum = v;
+        }
+        if let Some(v) = self.require_pairing {
+            c.require_pairing = v;
+        }
+        if let Some(v) = self.confidential_discovery {
+            c.confidential_discovery = v;
+        }
+        if let Some(v) = self.open_pod {
+            c.open_pod = v;
+        }
+        c.device_name = self.device_name.clone();
+        if let Some(v) = &self.eligibility {
+            c.eligibility = v.clone();
+        }
+        if let Some(v) = &self.contribution {
+            c.contribution = v.clone();
+        }
+        c
     }
 }
 
@@ -57,6 +286,9 @@ pub fn load() -> Config {
             c.transport_port = p;
         }
     }
+    if let Some(p) = std::env::var_os("PEAPOD_IDENTITY_FILE") {
+        c.identity_file = Some(PathBuf::from(p));
+    }
     c
 }
 
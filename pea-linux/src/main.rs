@@ -2,6 +2,7 @@
 
 mod config;
 mod discovery;
+mod identity;
 mod proxy;
 mod transport;
 
@@ -26,7 +27,9 @@ fn print_help() {
     println!("    Discovery   UDP 45678        (LAN multicast 239.255.60.60)");
     println!("    Transport   TCP 45679        (encrypted peer-to-peer)");
     println!();
-    println!("    Stop with Ctrl+C or SIGTERM.");
+    println!("    Stop with Ctrl+C or SIGTERM. SIGHUP reloads core tuning from the config file.");
+    println!("    SIGUSR1 logs traffic statistics (data saved, estimated speedup).");
+    println!("    SIGUSR2 toggles do-not-disturb (stop serving peers).");
     println!();
     println!("CONFIGURATION:");
     println!("    Config file (optional, first found wins):");
@@ -37,11 +40,19 @@ fn print_help() {
     println!("      proxy_port = 3128");
     println!("      discovery_port = 45678");
     println!("      transport_port = 45679");
+    println!("      chunk_size = 262144            # optional core tuning (reloaded on SIGHUP)");
+    println!("      chunk_timeout_secs = 10");
+    println!("      heartbeat_timeout_secs = 5");
+    println!("      max_peers = 64");
     println!();
     println!("ENVIRONMENT VARIABLES (override config file):");
     println!("    PEAPOD_PROXY_PORT       Proxy listen port (default: 3128)");
     println!("    PEAPOD_DISCOVERY_PORT   Discovery UDP port (default: 45678)");
     println!("    PEAPOD_TRANSPORT_PORT   Transport TCP port (default: 45679)");
+    println!(
+        "    PEAPOD_IDENTITY_FILE    Device keypair file (default: ~/.local/state/peapod/identity)"
+    );
+    println!("    PEAPOD_IDENTITY_PASSPHRASE  Encrypt the keypair file with this passphrase");
     println!();
     println!("SYSTEMD:");
     println!("    systemctl --user enable peapod    Enable auto-start on login");
@@ -72,13 +83,23 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
     }
 
-    let _ = pea_core::Config::default();
     let cfg = config::load();
 
-    let keypair = std::sync::Arc::new(pea_core::Keypair::generate());
-    let core = std::sync::Arc::new(tokio::sync::Mutex::new(
-        pea_core::PeaPodCore::with_keypair_arc(keypair.clone()),
-    ));
+    let keypair = match cfg.identity_path() {
+        Some(path) => identity::load_or_create(&path)
+            .map_err(|e| format!("failed to load device identity {}: {}", path.display(), e))?,
+        None => {
+            eprintln!("pea-linux: warning: no HOME or identity_file; using a new device identity");
+            pea_core::Keypair::generate()
+        }
+    };
+    let keypair = std::sync::Arc::new(keypair);
+    let mut pea =
+        pea_core::PeaPodCore::with_keypair_arc_and_config(keypair.clone(), cfg.core_config());
+    let mut capabilities = pea.capabilities().clone();
+    capabilities.platform = std::env::consts::OS.to_string();
+    pea.set_capabilities(capabilities);
+    let core = std::sync::Arc::new(tokio::sync::Mutex::new(pea));
 
     let bind: std::net::SocketAddr = format!("127.0.0.1:{}", cfg.proxy_port).parse()?;
     let (connect_tx, connect_rx) = tokio::sync::mpsc::unbounded_channel();
@@ -112,23 +133,169 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         let core_trans = core.clone();
         let keypair_trans = keypair.clone();
         let transport_port = cfg.transport_port;
+        let senders_trans = peer_senders.clone();
+        let waiters_trans = transfer_waiters.clone();
         tokio::spawn(async move {
             let _ = transport::run_transport(
                 core_trans,
                 keypair_trans,
                 transport_port,
                 connect_rx,
-                peer_senders,
-                transfer_waiters,
+                senders_trans,
+                waiters_trans,
             )
             .await;
         });
-        shutdown_signal().await
+        #[cfg(unix)]
+        tokio::spawn(report_stats_on_sigusr1(core.clone()));
+        #[cfg(unix)]
+        tokio::spawn(toggle_do_not_disturb_on_sigusr2(core.clone()));
+        tokio::spawn(report_local_time(
+            core.clone(),
+            cfg.utc_offset_minutes.unwrap_or(0),
+        ));
+        #[cfg(unix)]
+        tokio::spawn(reload_on_sighup(
+            core.clone(),
+            peer_senders.clone(),
+            transfer_waiters.clone(),
+        ));
+        shutdown_signal().await?;
+        leave_pod(&core, &peer_senders, &transfer_waiters).await;
+        Ok::<(), Box<dyn std::error::Error>>(())
     })?;
     Ok(())
 }
 
-/// Wait for Ctrl+C or SIGTERM (Unix). On shutdown, runtime and tasks exit; systemd may restart if configured.
+/// Say goodbye to the pod: send the core's signed Leave messages so peers reassign our chunks at
+/// once, then give the peer writers a moment to flush.
+async fn leave_pod(
+    core: &std::sync::Arc<tokio::sync::Mutex<pea_core::PeaPodCore>>,
+    peer_senders: &transport::PeerSenders,
+    transfer_waiters: &transport::TransferWaiters,
+) {
+    let actions = core.lock().await.shutdown();
+    transport::dispatch_actions(actions, core, peer_senders, transfer_waiters).await;
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+}
+
+/// On SIGHUP, re-read the config file and apply core tuning to the live core, then confirm any
+/// listed pairing codes whose peer is waiting with that code. A code that confirmed a pairing is
+/// not applied again, even if it stays in the file. Ports are not rebound.
+#[cfg(unix)]
+async fn reload_on_sighup(
+    core: std::sync::Arc<tokio::sync::Mutex<pea_core::PeaPodCore>>,
+    peer_senders: transport::PeerSenders,
+    transfer_waiters: transport::TransferWaiters,
+) {
+    use tokio::signal::unix::{signal, SignalKind};
+    let Ok(mut sighup) = signal(SignalKind::hangup()) else {
+        return;
+    };
+    let mut used_codes = std::collections::HashSet::new();
+    while sighup.recv().await.is_some() {
+        let cfg = config::load();
+        let actions = {
+            let mut c = core.lock().await;
+            let mut actions = c.update_config(cfg.core_config());
+            for entry in &cfg.pairing_codes {
+                if used_codes.contains(entry) {
+                    continue;
+                }
+                let confirmed = c.confirm_pairing_code(&entry.peer, &entry.code);
+                if !confirmed.is_empty() {
+                    eprintln!(
+                        "pea-linux: confirmed pairing code {} for {}; it can be removed from pairing_codes",
+                        entry.code, entry.peer
+                    );
+                    used_codes.insert(entry.clone());
+                    actions.extend(confirmed);
+                }
+            }
+            for id in &cfg.revoke_devices {
+                match pea_core::DeviceId::from_hex(id.trim()) {
+                    Some(peer_id) => actions.extend(c.revoke_device(peer_id)),
+                    None => eprintln!("pea-linux: revoke_devices: not a device ID: {}", id),
+                }
+            }
+            actions
+        };
+        transport::dispatch_actions(actions, &core, &peer_senders, &transfer_waiters).await;
+        eprintln!("pea-linux: reloaded config");
+    }
+}
+
+/// On SIGUSR1, log the core's traffic statistics.
+#[cfg(unix)]
+async fn report_stats_on_sigusr1(core: std::sync::Arc<tokio::sync::Mutex<pea_core::PeaPodCore>>) {
+    use tokio::signal::unix::{signal, SignalKind};
+    let Ok(mut sigusr1) = signal(SignalKind::user_defined1()) else {
+        return;
+    };
+    while sigusr1.recv().await.is_some() {
+        let stats = core.lock().await.stats();
+        eprintln!("pea-linux: {}", format_stats(&stats));
+    }
+}
+
+/// On SIGUSR2, pause or resume serving peers (e.g. while gaming); own downloads are unaffected.
+#[cfg(unix)]
+async fn toggle_do_not_disturb_on_sigusr2(
+    core: std::sync::Arc<tokio::sync::Mutex<pea_core::PeaPodCore>>,
+) {
+    use tokio::signal::unix::{signal, SignalKind};
+    let Ok(mut sigusr2) = signal(SignalKind::user_defined2()) else {
+        return;
+    };
+    let mut on = false;
+    while sigusr2.recv().await.is_some() {
+        on = !on;
+        core.lock().await.set_do_not_disturb(on);
+        eprintln!(
+            "pea-linux: do not disturb {}",
+            if on { "on: not serving peers" } else { "off" }
+        );
+    }
+}
+
+/// Report local time (UTC shifted by `utc_offset_minutes`) to the core once a minute for the
+/// contribution schedule and daily budget.
+async fn report_local_time(
+    core: std::sync::Arc<tokio::sync::Mutex<pea_core::PeaPodCore>>,
+    utc_offset_minutes: i32,
+) {
+    loop {
+        let utc_minutes = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs() / 60);
+        let local = utc_minutes.saturating_add_signed(i64::from(utc_offset_minutes));
+        core.lock().await.set_local_time(local);
+        tokio::time::sleep(std::time::Duration::from_secs(60)).await;
+    }
+}
+
+/// One-line summary: bytes fetched locally, from peers and from the cache, served, speedup.
+fn format_stats(stats: &pea_core::TrafficStats) -> String {
+    const MB: f64 = 1024.0 * 1024.0;
+    let from_peers: u64 = stats.bytes_from_peers.values().sum();
+    let served: u64 = stats.bytes_served.values().sum();
+    let speedup = match stats.speedup() {
+        Some(x) => format!("{:.1}x", x),
+        None => "n/a".to_string(),
+    };
+    format!(
+        "fetched {:.1} MB locally, {:.1} MB from {} peer(s), {:.1} MB from cache; saved {:.1} MB; served {:.1} MB; speedup {}",
+        stats.bytes_fetched_locally as f64 / MB,
+        from_peers as f64 / MB,
+        stats.bytes_from_peers.len(),
+        stats.bytes_from_cache as f64 / MB,
+        stats.bytes_saved() as f64 / MB,
+        served as f64 / MB,
+        speedup
+    )
+}
+
+/// Wait for Ctrl+C or SIGTERM (Unix). On shutdown, the pod is left and runtime and tasks exit; systemd may restart if configured.
 async fn shutdown_signal() -> Result<(), Box<dyn std::error::Error>> {
     #[cfg(unix)]
     {
@@ -3,11 +3,10 @@
 use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use pea_core::chunk::chunk_request_message;
 use pea_core::wire::encode_frame;
-use pea_core::{Action, ChunkId, PeaPodCore};
+use pea_core::{Action, ChunkId, Eligibility, PeaPodCore, PolicyRule, RequestInfo};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::{mpsc, Mutex};
@@ -38,11 +37,6 @@ pub async fn run_proxy(
     }
 }
 
-/// Check if this request is eligible for acceleration: GET with optional Range.
-fn is_eligible(method: &[u8], _path: &[u8]) -> bool {
-    method.eq_ignore_ascii_case(b"GET")
-}
-
 /// Parsed request data: method, path, host, range.
 type ParsedRequest = (Vec<u8>, Vec<u8>, Option<String>, Option<(u64, u64)>);
 
@@ -70,6 +64,25 @@ fn parse_request(buf: &[u8]) -> Option<ParsedRequest> {
     Some((method, path, host, range))
 }
 
+/// Client request headers a peer needs to fetch the origin as the client would.
+fn forwardable_headers(buf: &[u8]) -> Vec<(String, String)> {
+    let mut headers = [httparse::EMPTY_HEADER; 32];
+    let mut req = httparse::Request::new(&mut headers);
+    if req.parse(buf).is_err() {
+        return Vec::new();
+    }
+    req.headers
+        .iter()
+        .filter(|h| {
+            h.name.eq_ignore_ascii_case("Cookie") || h.name.eq_ignore_ascii_case("Authorization")
+        })
+        .map(|h| {
+            let value = String::from_utf8_lossy(h.value).trim().to_string();
+            (h.name.to_string(), value)
+        })
+        .collect()
+}
+
 /// Parse "bytes=start-end" or "bytes=start-".
 fn parse_range_header(s: &str) -> Option<(u64, u64)> {
     let s = s.trim().strip_prefix("bytes=")?;
@@ -118,10 +131,6 @@ async fn handle_client(
         None => return forward_raw(&mut client, buf).await,
     };
 
-    if !is_eligible(&method, &path) {
-        return forward_raw(&mut client, buf).await;
-    }
-
     let path_str = String::from_utf8_lossy(&path);
     let url = if path_str.starts_with("http://") || path_str.starts_with("https://") {
         path_str.to_string()
@@ -129,14 +138,66 @@ async fn handle_client(
         format!("http://{}{}", host, path_str)
     };
 
+    let method = String::from_utf8_lossy(&method);
+    let eligibility = core.lock().await.evaluate_request(&RequestInfo {
+        method: &method,
+        url: &url,
+        content_length: range.map(|(s, e)| e - s + 1),
+        content_type: None,
+    });
+    match eligibility {
+        Eligibility::Eligible => {}
+        Eligibility::Ineligible(PolicyRule::Method(_)) => {
+            return forward_raw(&mut client, buf).await
+        }
+        Eligibility::Ineligible(rule) => {
+            eprintln!("pea-linux: not accelerating {}: {}", url, rule);
+            return forward_raw(&mut client, buf).await;
+        }
+    }
+
     let range_opt = range;
-    let action = {
+    let (action, attached) = {
         let mut c = core.lock().await;
-        c.on_incoming_request(&url, range_opt)
+        let action = c.on_incoming_request(&url, range_opt);
+        if let Action::Accelerate { transfer_id, .. } = &action {
+            // The core only sends them to peers with `forward_request_headers`.
+            c.set_transfer_headers(*transfer_id, forwardable_headers(buf));
+        }
+        // Register for an attached transfer before releasing the core so it cannot complete unseen.
+        let attached = match &action {
+            Action::Attached { transfer_id, .. } => {
+                Some(transport::wait_for_transfer(&transfer_waiters, *transfer_id).await)
+            }
+            _ => None,
+        };
+        (action, attached)
     };
 
     match action {
         Action::Fallback => forward_raw(&mut client, buf).await,
+        Action::Cached { body } => {
+            let headers = response_head(range_opt, body.len());
+            client.write_all(headers.as_bytes()).await?;
+            client.write_all(&body).await?;
+            client.flush().await
+        }
+        Action::Attached { transfer_id, .. } => match attached {
+            Some(rx) => {
+                await_transfer(
+                    &mut client,
+                    rx,
+                    transfer_id,
+                    range_opt,
+                    buf,
+                    &core,
+                    &peer_senders,
+                    &transfer_waiters,
+                )
+                .await
+            }
+            None => forward_raw(&mut client, buf).await,
+        },
         Action::Accelerate {
             transfer_id,
             total_length,
@@ -149,6 +210,8 @@ async fn handle_client(
                 total_length,
                 assignment,
                 &url,
+                range_opt,
+                buf,
                 peer_senders,
                 transfer_waiters,
             )
@@ -157,6 +220,23 @@ async fn handle_client(
     }
 }
 
+/// Status line and headers for an accelerated body: 200, or 206 with Content-Range when the
+/// client asked for `range` (the body is exactly that range; the full length is not known here).
+fn response_head(range: Option<(u64, u64)>, len: usize) -> String {
+    match range {
+        Some((start, _)) if len > 0 => format!(
+            "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {}-{}/*\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            start,
+            start + len as u64 - 1,
+            len
+        ),
+        _ => format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            len
+        ),
+    }
+}
+
 /// Tunnel CONNECT: connect to host:port, 200 to client, then bidirectional copy.
 async fn tunnel_connect(client: &mut TcpStream, buf: &[u8]) -> std::io::Result<()> {
     let mut headers = [httparse::EMPTY_HEADER; 8];
@@ -221,6 +301,7 @@ async fn forward_raw(client: &mut TcpStream, request: &[u8]) -> std::io::Result<
 }
 
 /// Execute accelerate path: fetch self chunks via HTTP, request peer chunks over transport; wait for reassembled body and send response.
+/// If the core aborts the transfer (deadline), `request` is forwarded to the origin instead.
 #[allow(clippy::too_many_arguments)]
 async fn accelerate_response(
     stream: &mut TcpStream,
@@ -229,15 +310,13 @@ async fn accelerate_response(
     _total_length: u64,
     assignment: Vec<(ChunkId, pea_core::DeviceId)>,
     url: &str,
+    range: Option<(u64, u64)>,
+    request: &[u8],
     peer_senders: Arc<Mutex<HashMap<pea_core::DeviceId, mpsc::UnboundedSender<Vec<u8>>>>>,
     transfer_waiters: transport::TransferWaiters,
 ) -> std::io::Result<()> {
     let self_id = core.lock().await.device_id();
-    let (tx, rx) = tokio::sync::oneshot::channel();
-    {
-        let mut w = transfer_waiters.lock().await;
-        w.insert(transfer_id, tx);
-    }
+    let rx = transport::wait_for_transfer(&transfer_waiters, transfer_id).await;
 
     let http_client = reqwest::Client::builder()
         .timeout(Duration::from_secs(30))
@@ -248,6 +327,7 @@ async fn accelerate_response(
         if *peer_id == self_id {
             let end_inclusive = chunk_id.end.saturating_sub(1);
             let range_header = format!("bytes={}-{}", chunk_id.start, end_inclusive);
+            let started = Instant::now();
             let resp = http_client
                 .get(url)
                 .header("Range", range_header)
@@ -258,22 +338,24 @@ async fn accelerate_response(
             let payload = bytes.to_vec();
             let hash = pea_core::integrity::hash_chunk(&payload);
             let mut c = core.lock().await;
-            if let Ok(Some(full_body)) =
-                c.on_chunk_received(transfer_id, chunk_id.start, chunk_id.end, hash, payload)
-            {
-                let _ = transfer_waiters.lock().await.remove(&transfer_id);
-                let len = full_body.len();
-                let status = "HTTP/1.1 200 OK\r\n";
-                let headers = format!("Content-Length: {}\r\nConnection: close\r\n\r\n", len);
-                stream.write_all(status.as_bytes()).await?;
-                stream.write_all(headers.as_bytes()).await?;
-                stream.write_all(&full_body).await?;
-                stream.flush().await?;
-                return Ok(());
+            c.report_self_wan_throughput(payload.len() as u64, transport::elapsed_ms(started));
+            let outcome = c.on_chunk_received_with_actions(
+                transfer_id,
+                chunk_id.start,
+                chunk_id.end,
+                hash,
+                payload,
+            );
+            drop(c);
+            transport::dispatch_actions(outcome.actions, &core, &peer_senders, &transfer_waiters)
+                .await;
+            if let Ok(Some(full_body)) = outcome.result {
+                transport::complete_transfer(&transfer_waiters, transfer_id, full_body).await;
+                break;
             }
         } else {
-            let msg = chunk_request_message(*chunk_id, Some(url.to_string()));
-            if let Ok(frame) = encode_frame(&msg) {
+            let msg = core.lock().await.chunk_request_for(*chunk_id);
+            if let Some(Ok(frame)) = msg.as_ref().map(encode_frame) {
                 let senders = peer_senders.lock().await;
                 if let Some(tx) = senders.get(peer_id) {
                     let _ = tx.send(frame);
@@ -282,21 +364,49 @@ async fn accelerate_response(
         }
     }
 
+    await_transfer(
+        stream,
+        rx,
+        transfer_id,
+        range,
+        request,
+        &core,
+        &peer_senders,
+        &transfer_waiters,
+    )
+    .await
+}
+
+/// Wait for the body of `transfer_id` and send it to the client. If the core aborts the transfer
+/// (deadline passed) or it takes longer than 30 s, `request` is forwarded to the origin instead.
+#[allow(clippy::too_many_arguments)]
+async fn await_transfer(
+    stream: &mut TcpStream,
+    rx: tokio::sync::oneshot::Receiver<Vec<u8>>,
+    transfer_id: [u8; 16],
+    range: Option<(u64, u64)>,
+    request: &[u8],
+    core: &Arc<Mutex<PeaPodCore>>,
+    peer_senders: &transport::PeerSenders,
+    transfer_waiters: &transport::TransferWaiters,
+) -> std::io::Result<()> {
     match tokio::time::timeout(Duration::from_secs(30), rx).await {
         Ok(Ok(full_body)) => {
-            let _ = transfer_waiters.lock().await.remove(&transfer_id);
-            let len = full_body.len();
-            let status = "HTTP/1.1 200 OK\r\n";
-            let headers = format!("Content-Length: {}\r\nConnection: close\r\n\r\n", len);
-            stream.write_all(status.as_bytes()).await?;
+            let headers = response_head(range, full_body.len());
             stream.write_all(headers.as_bytes()).await?;
             stream.write_all(&full_body).await?;
             stream.flush().await?;
             Ok(())
         }
-        _ => {
+        // Core aborted the transfer (deadline passed): finish the request directly.
+        Ok(Err(_)) => forward_raw(stream, request).await,
+        // Too slow: stop the transfer (other requests waiting on it go direct too) and finish this
+        // one directly.
+        Err(_) => {
             let _ = transfer_waiters.lock().await.remove(&transfer_id);
-            Ok(())
+            let actions = core.lock().await.abandon_transfer(transfer_id);
+            transport::dispatch_actions(actions, core, peer_senders, transfer_waiters).await;
+            forward_raw(stream, request).await
         }
     }
 }
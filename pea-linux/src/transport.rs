@@ -1,41 +1,318 @@
 //! Local transport: TCP server (incoming), TCP client (outbound to discovered peers), handshake + encrypted frames.
 
 use std::collections::HashMap;
+use std::future::Future;
 use std::net::SocketAddr;
-use std::sync::Arc;
-use std::time::Duration;
+use std::pin::Pin;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
 
-use pea_core::identity::{derive_session_key, PublicKey};
-use pea_core::wire::{decode_frame, encode_frame};
-use pea_core::{DeviceId, Keypair, Message, OutboundAction, PeaPodCore, PROTOCOL_VERSION};
+use pea_core::{
+    ChunkId, DeviceId, FetchFailure, Handshake, HandshakeStep, Keypair, Membership, OutboundAction,
+    PeaPodCore, RekeyPolicy, Segment, Session, SessionCipher,
+};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::{mpsc, Mutex};
 
-const HANDSHAKE_SIZE: usize = 1 + 16 + 32; // version + device_id + public_key
 const LEN_SIZE: usize = 4;
 const MAX_FRAME_LEN: u32 = 16 * 1024 * 1024;
 
-async fn fetch_range(url: &str, start: u64, end: u64) -> std::io::Result<Vec<u8>> {
+/// HTTP client shared by WAN fetches and uploads, so consecutive ranges of the same origin (the
+/// scheduler hands each peer a contiguous run) reuse one keep-alive connection.
+fn wan_client() -> &'static reqwest::Client {
+    static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+    CLIENT.get_or_init(|| {
+        reqwest::Client::builder()
+            .timeout(Duration::from_secs(30))
+            .build()
+            .unwrap_or_default()
+    })
+}
+
+async fn fetch_range(
+    url: &str,
+    start: u64,
+    end: u64,
+    headers: &[(String, String)],
+) -> std::io::Result<Vec<u8>> {
     let end_inclusive = end.saturating_sub(1);
-    let client = reqwest::Client::builder()
-        .timeout(Duration::from_secs(30))
-        .build()
-        .map_err(std::io::Error::other)?;
+    let client = wan_client();
     let range_header = format!("bytes={}-{}", start, end_inclusive);
+    let mut req = client.get(url).header("Range", range_header);
+    for (name, value) in headers {
+        req = req.header(name.as_str(), value.as_str());
+    }
+    let resp = req.send().await.map_err(std::io::Error::other)?;
+    let bytes = resp.bytes().await.map_err(std::io::Error::other)?;
+    Ok(bytes.to_vec())
+}
+
+/// Upload `payload` as bytes `start..start + len` of a `total_length`-byte body with a ranged PUT.
+async fn put_range(
+    url: &str,
+    start: u64,
+    total_length: u64,
+    payload: Vec<u8>,
+) -> std::io::Result<()> {
+    let end_inclusive = (start + payload.len() as u64).saturating_sub(1);
+    let client = wan_client();
+    let content_range = format!("bytes {}-{}/{}", start, end_inclusive, total_length);
     let resp = client
-        .get(url)
-        .header("Range", range_header)
+        .put(url)
+        .header("Content-Range", content_range)
+        .body(payload)
         .send()
         .await
         .map_err(std::io::Error::other)?;
-    let bytes = resp.bytes().await.map_err(std::io::Error::other)?;
-    Ok(bytes.to_vec())
+    if !resp.status().is_success() {
+        return Err(std::io::Error::other(format!("status {}", resp.status())));
+    }
+    Ok(())
+}
+
+/// Shared: per-peer writer channels (frames are encrypted by the connection's writer task).
+pub type PeerSenders = Arc<Mutex<HashMap<DeviceId, mpsc::UnboundedSender<Vec<u8>>>>>;
+
+/// Carry out core actions: messages go to the peer's writer; `FetchChunk` is served from WAN and
+/// `UploadChunk` uploaded in the background; `AbortToFallback` drops the proxy's waiter so it
+/// fetches the request directly, `PartialFallback` fetches only the bytes peers did not deliver,
+/// and `TransferComplete` hands a body finished by a shared chunk to its waiters.
+pub async fn dispatch_actions(
+    actions: Vec<OutboundAction>,
+    core: &Arc<Mutex<PeaPodCore>>,
+    peer_senders: &PeerSenders,
+    transfer_waiters: &TransferWaiters,
+) {
+    let senders = peer_senders.lock().await;
+    let mut aborted = Vec::new();
+    let mut completed = Vec::new();
+    for action in actions {
+        match action {
+            OutboundAction::SendMessage(peer, bytes) => {
+                if let Some(tx) = senders.get(&peer) {
+                    let _ = tx.send(bytes);
+                }
+            }
+            OutboundAction::FetchChunk {
+                peer,
+                chunk_id,
+                url,
+                headers,
+                deadline_ms,
+            } => {
+                tokio::spawn(serve_chunk(
+                    core.clone(),
+                    peer_senders.clone(),
+                    transfer_waiters.clone(),
+                    peer,
+                    chunk_id,
+                    url,
+                    headers,
+                    deadline_ms,
+                ));
+            }
+            OutboundAction::AbortToFallback(transfer_id) => aborted.push(transfer_id),
+            OutboundAction::TransferComplete { transfer_id, body } => {
+                completed.push((transfer_id, body))
+            }
+            OutboundAction::PartialFallback {
+                transfer_id,
+                url,
+                range,
+                verified,
+            } => {
+                tokio::spawn(finish_partial(
+                    transfer_waiters.clone(),
+                    transfer_id,
+                    url,
+                    range,
+                    verified,
+                ));
+            }
+            OutboundAction::UploadChunk {
+                peer,
+                chunk_id,
+                url,
+                total_length,
+                payload,
+            } => {
+                tokio::spawn(upload_chunk(
+                    core.clone(),
+                    peer_senders.clone(),
+                    peer,
+                    chunk_id,
+                    url,
+                    total_length,
+                    payload,
+                ));
+            }
+        }
+    }
+    drop(senders);
+    for (transfer_id, body) in completed {
+        complete_transfer(transfer_waiters, transfer_id, body).await;
+    }
+    if !aborted.is_empty() {
+        let mut waiters = transfer_waiters.lock().await;
+        for transfer_id in aborted {
+            waiters.remove(&transfer_id);
+        }
+    }
+}
+
+/// [`dispatch_actions`] for the tasks it spawns, boxed so their future type is not recursive.
+fn dispatch_boxed<'a>(
+    actions: Vec<OutboundAction>,
+    core: &'a Arc<Mutex<PeaPodCore>>,
+    peer_senders: &'a PeerSenders,
+    transfer_waiters: &'a TransferWaiters,
+) -> Pin<Box<dyn Future<Output = ()> + Send + 'a>> {
+    Box::pin(dispatch_actions(
+        actions,
+        core,
+        peer_senders,
+        transfer_waiters,
+    ))
+}
+
+/// Milliseconds since `started`, for throughput reports to the core.
+pub fn elapsed_ms(started: Instant) -> u64 {
+    u64::try_from(started.elapsed().as_millis()).unwrap_or(u64::MAX)
+}
+
+/// Fetch a chunk for a peer (with the headers it forwarded, giving up at its deadline) and hand
+/// the bytes to the core, which answers every peer waiting on the range with ChunkData (or with a
+/// Nack on failure). Chunks fetched for this device go to its own transfer, which may complete.
+#[allow(clippy::too_many_arguments)]
+async fn serve_chunk(
+    core: Arc<Mutex<PeaPodCore>>,
+    peer_senders: PeerSenders,
+    transfer_waiters: TransferWaiters,
+    peer: DeviceId,
+    chunk_id: ChunkId,
+    url: String,
+    headers: Vec<(String, String)>,
+    deadline_ms: Option<u64>,
+) {
+    let started = Instant::now();
+    let fetch = fetch_range(&url, chunk_id.start, chunk_id.end, &headers);
+    // Past the requester's deadline the chunk has been reassigned; stop fetching it.
+    let result = match deadline_ms {
+        Some(ms) => tokio::time::timeout(Duration::from_millis(ms), fetch)
+            .await
+            .unwrap_or_else(|e| Err(std::io::Error::other(e))),
+        None => fetch.await,
+    };
+    let actions = {
+        let mut core = core.lock().await;
+        match result {
+            Ok(body) => {
+                core.report_self_wan_throughput(body.len() as u64, elapsed_ms(started));
+                core.on_chunk_fetched_for_peer(peer, chunk_id, body)
+            }
+            Err(_) => core.on_chunk_fetch_failed(peer, chunk_id, FetchFailure::Network),
+        }
+    };
+    dispatch_boxed(actions, &core, &peer_senders, &transfer_waiters).await;
+}
+
+/// Fetch the gaps between the verified segments of a transfer the core gave up on and hand the
+/// whole body to its waiters. If a fetch fails, the waiters are dropped and the proxy forwards
+/// the request instead.
+async fn finish_partial(
+    transfer_waiters: TransferWaiters,
+    transfer_id: [u8; 16],
+    url: String,
+    range: (u64, u64),
+    verified: Vec<Segment>,
+) {
+    let (start, end) = range;
+    let mut body = Vec::with_capacity(end.saturating_sub(start) as usize);
+    // An empty segment at the end fetches the tail after the last verified one.
+    let tail = Segment {
+        offset: end.saturating_sub(start),
+        bytes: Vec::new(),
+    };
+    for segment in verified.into_iter().chain(std::iter::once(tail)) {
+        let (have, next) = (start + body.len() as u64, start + segment.offset);
+        if next > have {
+            match fetch_range(&url, have, next, &[]).await {
+                Ok(bytes) if bytes.len() as u64 == next - have => body.extend(bytes),
+                _ => {
+                    transfer_waiters.lock().await.remove(&transfer_id);
+                    return;
+                }
+            }
+        }
+        body.extend(segment.bytes);
+    }
+    complete_transfer(&transfer_waiters, transfer_id, body).await;
+}
+
+/// Upload a range for a peer (or this device) and report the result to the core, which answers
+/// the peer with an UploadAck.
+async fn upload_chunk(
+    core: Arc<Mutex<PeaPodCore>>,
+    peer_senders: PeerSenders,
+    peer: DeviceId,
+    chunk_id: ChunkId,
+    url: String,
+    total_length: u64,
+    payload: Vec<u8>,
+) {
+    let ok = put_range(&url, chunk_id.start, total_length, payload)
+        .await
+        .is_ok();
+    let actions = core.lock().await.on_upload_chunk_done(peer, chunk_id, ok);
+    send_frames(&peer_senders, actions).await;
+}
+
+/// Send the `SendMessage` actions among `actions` to their peers.
+async fn send_frames(peer_senders: &PeerSenders, actions: Vec<OutboundAction>) {
+    let frames = actions.into_iter().filter_map(|action| match action {
+        OutboundAction::SendMessage(to_peer, bytes) => Some((to_peer, bytes)),
+        _ => None,
+    });
+    let senders = peer_senders.lock().await;
+    for (to_peer, bytes) in frames {
+        if let Some(tx) = senders.get(&to_peer) {
+            let _ = tx.send(bytes);
+        }
+    }
 }
 
 /// Shared: when a transfer completes (reassembled body ready), transport sends it here so the proxy can respond.
+/// Several client requests can wait on one transfer (identical requests are coalesced by the core).
 pub type TransferWaiters =
-    Arc<Mutex<std::collections::HashMap<[u8; 16], tokio::sync::oneshot::Sender<Vec<u8>>>>>;
+    Arc<Mutex<std::collections::HashMap<[u8; 16], Vec<tokio::sync::oneshot::Sender<Vec<u8>>>>>>;
+
+/// Register a waiter for the body of `transfer_id`.
+pub async fn wait_for_transfer(
+    transfer_waiters: &TransferWaiters,
+    transfer_id: [u8; 16],
+) -> tokio::sync::oneshot::Receiver<Vec<u8>> {
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    transfer_waiters
+        .lock()
+        .await
+        .entry(transfer_id)
+        .or_default()
+        .push(tx);
+    rx
+}
+
+/// Hand a completed transfer's body to every request waiting on it.
+pub async fn complete_transfer(
+    transfer_waiters: &TransferWaiters,
+    transfer_id: [u8; 16],
+    body: Vec<u8>,
+) {
+    let waiting = transfer_waiters.lock().await.remove(&transfer_id);
+    for tx in waiting.into_iter().flatten() {
+        let _ = tx.send(body.clone());
+    }
+}
 
 /// Run transport: listen for incoming TCP, accept connections; connect outbound when peer is pushed to `connect_rx`.
 /// `peer_senders` is shared with the proxy so it can send ChunkRequests. `transfer_waiters`: proxy registers (transfer_id, tx); transport sends body on tx when transfer completes.
@@ -51,17 +328,15 @@ pub async fn run_transport(
 
     let tick_core = core.clone();
     let tick_senders = peer_senders.clone();
+    let tick_waiters = transfer_waiters.clone();
     tokio::spawn(async move {
+        let clock = std::time::Instant::now();
         loop {
-            tokio::time::sleep(std::time::Duration::from_secs(1)).await;
-            let actions = tick_core.lock().await.tick();
-            let senders = tick_senders.lock().await;
-            for action in actions {
-                let OutboundAction::SendMessage(peer, bytes) = action;
-                if let Some(tx) = senders.get(&peer) {
-                    let _ = tx.send(bytes);
-                }
-            }
+            let tick_ms = tick_core.lock().await.timer_intervals().tick_ms;
+            tokio::time::sleep(std::time::Duration::from_millis(tick_ms)).await;
+            let now_ms = u64::try_from(clock.elapsed().as_millis()).unwrap_or(u64::MAX);
+            let actions = tick_core.lock().await.tick_at(now_ms);
+            dispatch_actions(actions, &tick_core, &tick_senders, &tick_waiters).await;
         }
     });
 
@@ -76,10 +351,11 @@ pub async fn run_transport(
             let senders = accept_senders.clone();
             let waiters = accept_waiters.clone();
             tokio::spawn(async move {
-                if let Ok((peer_id, session_key)) =
-                    handshake_accept(&mut stream, keypair.as_ref()).await
-                {
-                    run_connection(stream, peer_id, session_key, core, senders, waiters).await;
+                match handshake(&mut stream, keypair.as_ref(), false).await {
+                    Ok(session) => {
+                        run_connection(stream, session, None, core, senders, waiters).await
+                    }
+                    Err(e) => eprintln!("pea-linux: handshake failed: {}", e),
                 }
             });
         }
@@ -92,10 +368,13 @@ pub async fn run_transport(
         let waiters = transfer_waiters.clone();
         tokio::spawn(async move {
             if let Ok(mut stream) = TcpStream::connect(addr).await {
-                if let Ok((peer_id, session_key)) =
-                    handshake_connect(&mut stream, keypair.as_ref()).await
-                {
-                    run_connection(stream, peer_id, session_key, core, senders, waiters).await;
+                let started = Instant::now();
+                match handshake(&mut stream, keypair.as_ref(), true).await {
+                    Ok(session) => {
+                        let rtt_ms = u32::try_from(elapsed_ms(started)).ok();
+                        run_connection(stream, session, rtt_ms, core, senders, waiters).await;
+                    }
+                    Err(e) => eprintln!("pea-linux: handshake with {} failed: {}", addr, e),
                 }
             }
         });
@@ -103,100 +382,93 @@ pub async fn run_transport(
     Ok(())
 }
 
-async fn handshake_accept(
+/// Run the authenticated handshake on a new connection (`initiator` if this device opened it):
+/// both sides prove they hold the keys of the identities they present before any frame is
+/// exchanged. Fails for peers on another handshake version or that cannot prove their identity.
+async fn handshake(
     stream: &mut TcpStream,
     keypair: &Keypair,
-) -> std::io::Result<(DeviceId, [u8; 32])> {
-    let mut buf = [0u8; HANDSHAKE_SIZE];
-    let (mut r, mut w) = stream.split();
-    r.read_exact(&mut buf).await?;
-    let version = buf[0];
-    if version != PROTOCOL_VERSION {
-        return Err(std::io::Error::new(
-            std::io::ErrorKind::InvalidData,
-            "unsupported protocol version",
-        ));
-    }
-    let mut device_id = [0u8; 16];
-    device_id.copy_from_slice(&buf[1..17]);
-    let mut public_key = [0u8; 32];
-    public_key.copy_from_slice(&buf[17..49]);
-    let peer_id = DeviceId::from_bytes(device_id);
-    let peer_public = PublicKey::from_bytes(public_key);
-
-    let secret = keypair.shared_secret(&peer_public);
-    let session_key = derive_session_key(&secret);
-
-    let out = handshake_bytes(keypair);
-    w.write_all(&out).await?;
-    w.flush().await?;
-    Ok((peer_id, session_key))
-}
-
-async fn handshake_connect(
-    stream: &mut TcpStream,
-    keypair: &Keypair,
-) -> std::io::Result<(DeviceId, [u8; 32])> {
-    let (mut r, mut w) = stream.split();
-    let out = handshake_bytes(keypair);
-    w.write_all(&out).await?;
-    w.flush().await?;
-    let mut buf = [0u8; HANDSHAKE_SIZE];
-    r.read_exact(&mut buf).await?;
-    if buf[0] != PROTOCOL_VERSION {
-        return Err(std::io::Error::new(
-            std::io::ErrorKind::InvalidData,
-            "unsupported protocol version",
-        ));
+    initiator: bool,
+) -> std::io::Result<Session> {
+    let mut hs = if initiator {
+        let (hs, msg1) = Handshake::initiate();
+        stream.write_all(&msg1).await?;
+        stream.flush().await?;
+        hs
+    } else {
+        Handshake::respond()
+    };
+    loop {
+        let mut buf = vec![0u8; hs.next_len()];
+        stream.read_exact(&mut buf).await?;
+        let step = hs
+            .read(keypair, &buf)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        let (reply, session) = match step {
+            HandshakeStep::Reply(out) => (Some(out), None),
+            HandshakeStep::Done { reply, session } => (reply, Some(session)),
+        };
+        if let Some(out) = reply {
+            stream.write_all(&out).await?;
+            stream.flush().await?;
+        }
+        if let Some(session) = session {
+            return Ok(session);
+        }
     }
-    let mut device_id = [0u8; 16];
-    device_id.copy_from_slice(&buf[1..17]);
-    let mut public_key = [0u8; 32];
-    public_key.copy_from_slice(&buf[17..49]);
-    let peer_id = DeviceId::from_bytes(device_id);
-    let peer_public = PublicKey::from_bytes(public_key);
-    let secret = keypair.shared_secret(&peer_public);
-    let session_key = derive_session_key(&secret);
-    Ok((peer_id, session_key))
-}
-
-fn handshake_bytes(keypair: &Keypair) -> [u8; HANDSHAKE_SIZE] {
-    let mut out = [0u8; HANDSHAKE_SIZE];
-    out[0] = PROTOCOL_VERSION;
-    out[1..17].copy_from_slice(keypair.device_id().as_bytes());
-    out[17..49].copy_from_slice(keypair.public_key().as_bytes());
-    out
 }
 
+/// Serve one authenticated connection: `session` is the handshake's peer and keys. Refuses
+/// identities the core rejects (forged, revoked or with a changed key), starts the join exchange,
+/// then feeds decrypted frames to the core. `handshake_rtt_ms` (timed by the connecting side) is
+/// reported to the core once the peer has joined.
 async fn run_connection(
     stream: TcpStream,
-    peer_id: DeviceId,
-    session_key: [u8; 32],
+    session: Session,
+    mut handshake_rtt_ms: Option<u32>,
     core: Arc<Mutex<PeaPodCore>>,
     peer_senders: Arc<Mutex<HashMap<DeviceId, mpsc::UnboundedSender<Vec<u8>>>>>,
     transfer_waiters: TransferWaiters,
 ) {
+    let (peer_id, peer_public) = (session.peer_id, session.peer_public.clone());
+    if let Err(e) = core.lock().await.check_handshake(peer_id, &peer_public) {
+        eprintln!("pea-linux: refusing {}", e);
+        return;
+    }
     let (tx, mut rx) = mpsc::unbounded_channel::<Vec<u8>>();
     {
         let mut senders = peer_senders.lock().await;
         senders.insert(peer_id, tx);
     }
+    let join = core.lock().await.on_peer_connected(peer_id, &peer_public);
+    dispatch_actions(join, &core, &peer_senders, &transfer_waiters).await;
     let (mut reader, mut writer) = stream.into_split();
-    let writer_key = session_key;
+    let mut shown_code = None;
+    // Both directions rekey on their own (by bytes or time); the lock is never held across I/O.
+    let clock = Instant::now();
+    let session_cipher = Arc::new(std::sync::Mutex::new(SessionCipher::new(
+        &session,
+        RekeyPolicy::default(),
+        0,
+    )));
+    let writer_cipher = session_cipher.clone();
     let writer_senders = peer_senders.clone();
     tokio::spawn(async move {
-        let mut write_nonce: u64 = 0;
         while let Some(plain) = rx.recv().await {
-            if let Ok(cipher) = pea_core::identity::encrypt_wire(&writer_key, write_nonce, &plain) {
-                write_nonce = write_nonce.saturating_add(1);
-                let len = cipher.len() as u32;
-                let _ = writer.write_all(&len.to_le_bytes()).await;
-                let _ = writer.write_all(&cipher).await;
+            let sealed = writer_cipher
+                .lock()
+                .ok()
+                .map(|mut c| c.seal(&plain, elapsed_ms(clock)));
+            if let Some(Ok(frames)) = sealed {
+                for cipher in frames {
+                    let len = cipher.len() as u32;
+                    let _ = writer.write_all(&len.to_le_bytes()).await;
+                    let _ = writer.write_all(&cipher).await;
+                }
                 let _ = writer.flush().await;
             }
         }
     });
-    let mut read_nonce: u64 = 0;
     loop {
         let mut len_buf = [0u8; LEN_SIZE];
         if reader.read_exact(&mut len_buf).await.is_err() {
@@ -210,53 +482,30 @@ async fn run_connection(
         if reader.read_exact(&mut cipher).await.is_err() {
             break;
         }
-        let plain = match pea_core::identity::decrypt_wire(&session_key, read_nonce, &cipher) {
-            Ok(p) => p,
-            Err(_) => break,
+        let opened = session_cipher
+            .lock()
+            .ok()
+            .map(|mut c| c.open(&cipher, elapsed_ms(clock)));
+        let plain = match opened {
+            Some(Ok(Some(p))) => p,
+            Some(Ok(None)) => continue,
+            _ => break,
         };
-        read_nonce = read_nonce.saturating_add(1);
-        if let Ok((
-            Message::ChunkRequest {
-                transfer_id,
-                start,
-                end,
-                url: Some(ref url),
-            },
-            _,
-        )) = decode_frame(&plain)
-        {
-            if let Ok(body) = fetch_range(url, start, end).await {
-                let hash = pea_core::integrity::hash_chunk(&body);
-                let chunk_data = Message::ChunkData {
-                    transfer_id,
-                    start,
-                    end,
-                    hash,
-                    payload: body,
-                };
-                if let Ok(frame) = encode_frame(&chunk_data) {
-                    let senders = writer_senders.lock().await;
-                    if let Some(tx) = senders.get(&peer_id) {
-                        let _ = tx.send(frame);
-                    }
-                }
-            }
-            continue;
-        }
-        let mut c = core.lock().await;
-        if let Ok((actions, completed)) = c.on_message_received(peer_id, &plain) {
-            for action in actions {
-                let OutboundAction::SendMessage(to_peer, bytes) = action;
-                let senders = writer_senders.lock().await;
-                if let Some(tx) = senders.get(&to_peer) {
-                    let _ = tx.send(bytes);
+        let result = {
+            let mut c = core.lock().await;
+            let result = c.on_message_received(peer_id, &plain);
+            show_pairing_code(&c, peer_id, &mut shown_code);
+            if c.membership(peer_id) == Some(Membership::Joined) {
+                if let Some(rtt_ms) = handshake_rtt_ms.take() {
+                    c.report_peer_rtt(peer_id, rtt_ms);
                 }
             }
+            result
+        };
+        if let Ok((actions, completed)) = result {
+            dispatch_actions(actions, &core, &writer_senders, &transfer_waiters).await;
             if let Some((tid, body)) = completed {
-                let mut w = transfer_waiters.lock().await;
-                if let Some(tx) = w.remove(&tid) {
-                    let _ = tx.send(body);
-                }
+                complete_transfer(&transfer_waiters, tid, body).await;
             }
         }
     }
@@ -266,3 +515,27 @@ async fn run_connection(
     let mut c = core.lock().await;
     c.on_peer_left(peer_id);
 }
+
+/// Log the pairing code for an unpaired peer once both devices revealed their nonces, and again
+/// if a new exchange changes it, so the user can compare it with the other device.
+fn show_pairing_code(c: &PeaPodCore, peer_id: DeviceId, shown: &mut Option<String>) {
+    if !c.config().require_pairing || c.is_paired(peer_id) {
+        return;
+    }
+    let (Some(code), Some(fingerprint)) = (c.pairing_code(peer_id), c.peer_fingerprint(peer_id))
+    else {
+        return;
+    };
+    if shown.as_ref() == Some(&code) {
+        return;
+    }
+    eprintln!(
+        "pea-linux: pairing code for peer {} ({}): {} (if the other device shows the same code, add {{ peer = \"{}\", code = \"{}\" }} to pairing_codes in config.toml and reload)",
+        peer_id,
+        fingerprint.to_words(),
+        code,
+        fingerprint.to_words(),
+        code
+    );
+    *shown = Some(code);
+}
@@ -1,14 +1,11 @@
-//! LAN discovery: UDP multicast beacon, parse beacons/responses, maintain peer list, call core on_peer_joined/on_peer_left.
+//! LAN discovery: UDP multicast beacon, parse beacons/responses, maintain peer list, call core on_peer_discovered/on_peer_left.
 
 use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
-use pea_core::{
-    wire::{decode_frame, encode_frame},
-    DeviceId, Keypair, PeaPodCore, PROTOCOL_VERSION,
-};
+use pea_core::{is_supported_version, DeviceId, Keypair, PeaPodCore};
 use pea_core::{Message, PublicKey};
 use tokio::net::UdpSocket;
 use tokio::sync::Mutex;
@@ -50,9 +47,10 @@ pub async fn run_discovery(
     let core_recv = core.clone();
     let keypair_recv = keypair.clone();
     let connect_tx_recv = connect_tx.clone();
+    let core_beacon = core.clone();
 
     let beacon_task =
-        tokio::spawn(async move { beacon_loop(send_socket, keypair, listen_port).await });
+        tokio::spawn(async move { beacon_loop(send_socket, core_beacon, listen_port).await });
     let recv_task = tokio::spawn(async move {
         recv_loop(
             recv_socket,
@@ -83,28 +81,22 @@ async fn make_multicast_socket() -> std::io::Result<UdpSocket> {
     Ok(sock)
 }
 
+/// Beacons come from the core, which seals them once the device shares a pod key.
 async fn beacon_loop(
     socket: Arc<UdpSocket>,
-    keypair: Arc<Keypair>,
+    core: Arc<Mutex<PeaPodCore>>,
     listen_port: u16,
 ) -> std::io::Result<()> {
-    let device_id = keypair.device_id();
-    let public_key = keypair.public_key().clone();
-    let beacon = Message::Beacon {
-        protocol_version: PROTOCOL_VERSION,
-        device_id,
-        public_key,
-        listen_port,
-    };
-    let frame = encode_frame(&beacon)
-        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
     let dest: SocketAddr = format!("{}:{}", MULTICAST_GROUP, DISCOVERY_PORT)
         .parse()
         .map_err(|e: std::net::AddrParseError| {
             std::io::Error::new(std::io::ErrorKind::InvalidInput, e)
         })?;
     loop {
-        let _ = socket.send_to(&frame, dest).await;
+        let frame = core.lock().await.beacon_frame(listen_port);
+        if let Ok(frame) = frame {
+            let _ = socket.send_to(&frame, dest).await;
+        }
         tokio::time::sleep(BEACON_INTERVAL).await;
     }
 }
@@ -118,20 +110,13 @@ async fn recv_loop(
 ) -> std::io::Result<()> {
     let mut buf = vec![0u8; 65536];
     let my_id = keypair.device_id();
-    let my_public = keypair.public_key().clone();
-    let response_frame = encode_frame(&Message::DiscoveryResponse {
-        protocol_version: PROTOCOL_VERSION,
-        device_id: my_id,
-        public_key: my_public,
-        listen_port: LOCAL_TRANSPORT_PORT,
-    })
-    .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
-
     loop {
         match socket.recv_from(&mut buf).await {
             Ok((n, from)) => {
                 let buf = &buf[..n];
-                if let Ok((msg, _)) = decode_frame(buf) {
+                // Sealed frames from another pod do not open and are skipped.
+                let opened = core.lock().await.open_discovery_frame(buf);
+                if let Some((msg, name)) = opened {
                     match &msg {
                         Message::Beacon {
                             protocol_version,
@@ -139,7 +124,7 @@ async fn recv_loop(
                             public_key,
                             listen_port,
                         } => {
-                            if *protocol_version != PROTOCOL_VERSION {
+                            if !is_supported_version(*protocol_version) {
                                 continue;
                             }
                             if *device_id == my_id {
@@ -160,12 +145,20 @@ async fn recv_loop(
                             };
                             if is_new {
                                 let mut c = core.lock().await;
-                                c.on_peer_joined(*device_id, public_key);
+                                c.on_peer_discovered(*device_id, public_key);
+                                if let Some(record) = &name {
+                                    c.on_peer_named(*device_id, public_key, record);
+                                }
                                 let addr = SocketAddr::new(from.ip(), *listen_port);
                                 let _ = connect_tx.send((*device_id, addr));
                             }
-                            let to = from;
-                            let _ = socket.send_to(&response_frame, to).await;
+                            let response = core
+                                .lock()
+                                .await
+                                .discovery_response_frame(LOCAL_TRANSPORT_PORT);
+                            if let Ok(frame) = response {
+                                let _ = socket.send_to(&frame, from).await;
+                            }
                         }
                         Message::DiscoveryResponse {
                             protocol_version,
@@ -173,7 +166,7 @@ async fn recv_loop(
                             public_key,
                             listen_port,
                         } => {
-                            if *protocol_version != PROTOCOL_VERSION {
+                            if !is_supported_version(*protocol_version) {
                                 continue;
                             }
                             if *device_id == my_id {
@@ -194,7 +187,10 @@ async fn recv_loop(
                             };
                             if is_new {
                                 let mut c = core.lock().await;
-                                c.on_peer_joined(*device_id, public_key);
+                                c.on_peer_discovered(*device_id, public_key);
+                                if let Some(record) = &name {
+                                    c.on_peer_named(*device_id, public_key, record);
+                                }
                                 let addr = SocketAddr::new(from.ip(), *listen_port);
                                 let _ = connect_tx.send((*device_id, addr));
                             }
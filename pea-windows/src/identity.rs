@@ -0,0 +1,65 @@
+//! Persistent device identity: the keypair is kept on disk so the device ID survives restarts.
+
+use pea_core::{Keypair, Zeroizing};
+use std::io::{Error, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+/// Env var holding the passphrase the keypair file is encrypted with; unset keeps it plain.
+const PASSPHRASE_VAR: &str = "PEAPOD_IDENTITY_PASSPHRASE";
+
+/// `%APPDATA%\PeaPod\identity`, next to the proxy backup; `None` if APPDATA is not set.
+pub fn identity_path() -> Option<PathBuf> {
+    std::env::var_os("APPDATA").map(|p| PathBuf::from(p).join("PeaPod").join("identity"))
+}
+
+/// Load the keypair from `path`, or generate one and save it there (mode 0600 on Unix; the
+/// per-user profile ACLs on Windows). An unreadable or
+/// undecryptable file is an error rather than a reason to replace the identity.
+pub fn load_or_create(path: &Path) -> std::io::Result<Keypair> {
+    let passphrase = std::env::var(PASSPHRASE_VAR).ok();
+    match std::fs::read(path).map(Zeroizing::new) {
+        Ok(bytes) => decode(&bytes, passphrase.as_deref()),
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            let keypair = Keypair::generate();
+            let bytes = Zeroizing::new(match &passphrase {
+                Some(p) => keypair
+                    .to_bytes_encrypted(p.as_bytes())
+                    .map_err(Error::other)?,
+                None => keypair.to_bytes().to_vec(),
+            });
+            save(path, &bytes)?;
+            Ok(keypair)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+fn decode(bytes: &[u8], passphrase: Option<&str>) -> std::io::Result<Keypair> {
+    match passphrase {
+        Some(p) => Keypair::from_bytes_encrypted(bytes, p.as_bytes())
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e)),
+        None => {
+            let secret: [u8; 32] = bytes.try_into().map_err(|_| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("not a plain keypair (encrypted? set {})", PASSPHRASE_VAR),
+                )
+            })?;
+            Ok(Keypair::from_bytes(secret))
+        }
+    }
+}
+
+fn save(path: &Path, bytes: &[u8]) -> std::io::Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)?.write_all(bytes)
+}
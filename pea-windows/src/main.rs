@@ -3,6 +3,7 @@
 
 #[allow(dead_code)]
 mod discovery;
+mod identity;
 mod proxy;
 #[allow(dead_code)]
 mod transport;
@@ -80,12 +81,24 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         use windows::Win32::Foundation::BOOL;
         let _ = BOOL(1);
     }
-    let _ = pea_core::Config::default();
 
-    let keypair = std::sync::Arc::new(pea_core::Keypair::generate());
-    let core = std::sync::Arc::new(tokio::sync::Mutex::new(
-        pea_core::PeaPodCore::with_keypair_arc(keypair.clone()),
-    ));
+    let keypair = match identity::identity_path() {
+        Some(path) => identity::load_or_create(&path)
+            .map_err(|e| format!("failed to load device identity {}: {}", path.display(), e))?,
+        None => pea_core::Keypair::generate(),
+    };
+    let keypair = std::sync::Arc::new(keypair);
+    let mut pea = pea_core::PeaPodCore::with_keypair_arc_and_config(
+        keypair.clone(),
+        pea_core::Config {
+            device_name: std::env::var("COMPUTERNAME").ok(),
+            ..pea_core::Config::default()
+        },
+    );
+    let mut capabilities = pea.capabilities().clone();
+    capabilities.platform = std::env::consts::OS.to_string();
+    pea.set_capabilities(capabilities);
+    let core = std::sync::Arc::new(tokio::sync::Mutex::new(pea));
     let bind: std::net::SocketAddr = proxy::DEFAULT_PROXY_ADDR.parse()?;
 
     #[cfg(windows)]
@@ -123,20 +136,26 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             let tray_hwnd_raw_updater = tray_hwnd_raw;
             let proxy_enabled_updater = proxy_enabled.clone();
             let peer_senders_updater = peer_senders.clone();
+            let core_updater = core.clone();
             tokio::spawn(async move {
                 loop {
                     tokio::time::sleep(std::time::Duration::from_secs(2)).await;
                     let enabled = proxy_enabled_updater.load(std::sync::atomic::Ordering::Relaxed);
                     let senders = peer_senders_updater.lock().await;
                     let peer_count = senders.len() as u32;
-                    let peer_ids: Vec<[u8; 16]> = senders.keys().map(|d| *d.as_bytes()).collect();
+                    let peer_ids: Vec<pea_core::DeviceId> = senders.keys().copied().collect();
                     drop(senders);
                     let autostart_enabled = autostart::is_autostart_enabled().unwrap_or(false);
+                    let c = core_updater.lock().await;
+                    let peers = peer_ids.iter().map(|id| tray::peer_label(&c, *id)).collect();
+                    let stats = c.stats();
+                    drop(c);
                     let _ = state_tx_updater.send(tray::TrayStateUpdate {
                         enabled,
                         peer_count,
-                        peer_ids,
+                        peers,
                         autostart_enabled,
+                        stats,
                     });
                     let hwnd = HWND(tray_hwnd_raw_updater as *mut _);
                     let _ = PostMessageW(
@@ -153,8 +172,9 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             let _ = state_tx.send(tray::TrayStateUpdate {
                 enabled: true,
                 peer_count: 0,
-                peer_ids: vec![],
+                peers: vec![],
                 autostart_enabled,
+                stats: pea_core::TrafficStats::default(),
             });
             let _ = PostMessageW(
                 tray_hwnd,
@@ -177,13 +197,15 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             });
             let core_trans = core.clone();
             let keypair_trans = keypair.clone();
+            let senders_trans = peer_senders.clone();
+            let waiters_trans = transfer_waiters.clone();
             tokio::spawn(async move {
                 let _ = transport::run_transport(
                     core_trans,
                     keypair_trans,
                     connect_rx,
-                    peer_senders,
-                    transfer_waiters,
+                    senders_trans,
+                    waiters_trans,
                 )
                 .await;
             });
@@ -205,16 +227,21 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                             }
                             tray::TrayCommand::OpenSettings => {
                                 let senders = peer_senders.lock().await;
-                                let peer_ids: Vec<[u8; 16]> = senders.keys().map(|d| *d.as_bytes()).collect();
+                                let peer_ids: Vec<pea_core::DeviceId> = senders.keys().copied().collect();
                                 let peer_count = peer_ids.len() as u32;
                                 let enabled = proxy_enabled.load(std::sync::atomic::Ordering::Relaxed);
                                 let autostart_enabled = autostart::is_autostart_enabled().unwrap_or(false);
                                 drop(senders);
+                                let c = core.lock().await;
+                                let peers = peer_ids.iter().map(|id| tray::peer_label(&c, *id)).collect();
+                                let stats = c.stats();
+                                drop(c);
                                 let _ = state_tx.send(tray::TrayStateUpdate {
                                     enabled,
                                     peer_count,
-                                    peer_ids,
+                                    peers,
                                     autostart_enabled,
+                                    stats,
                                 });
                                 let _ = PostMessageW(
                                     tray_hwnd,
@@ -234,15 +261,20 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                         // Update tooltip immediately after Enable/Disable/SetAutostart
                         let enabled = proxy_enabled.load(std::sync::atomic::Ordering::Relaxed);
                         let senders = peer_senders.lock().await;
-                        let peer_ids: Vec<[u8; 16]> = senders.keys().map(|d| *d.as_bytes()).collect();
+                        let peer_ids: Vec<pea_core::DeviceId> = senders.keys().copied().collect();
                         let peer_count = senders.len() as u32;
                         let autostart_enabled = autostart::is_autostart_enabled().unwrap_or(false);
                         drop(senders);
+                        let c = core.lock().await;
+                        let peers = peer_ids.iter().map(|id| tray::peer_label(&c, *id)).collect();
+                        let stats = c.stats();
+                        drop(c);
                         let _ = state_tx.send(tray::TrayStateUpdate {
                             enabled,
                             peer_count,
-                            peer_ids,
+                            peers,
                             autostart_enabled,
+                            stats,
                         });
                         let _ = PostMessageW(
                             tray_hwnd,
@@ -256,6 +288,10 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             }
             proxy_enabled.store(false, std::sync::atomic::Ordering::Relaxed);
             let _ = system_proxy::restore_system_proxy();
+            // Tell the pod we are leaving so peers reassign our chunks instead of timing us out.
+            let actions = core.lock().await.shutdown();
+            transport::dispatch_actions(actions, &core, &peer_senders, &transfer_waiters).await;
+            tokio::time::sleep(std::time::Duration::from_millis(200)).await;
         }
         #[cfg(not(windows))]
         {
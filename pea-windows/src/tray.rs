@@ -1,5 +1,5 @@
 //! System tray icon and menu (Enable / Disable / Exit). Sends commands to main via channel.
-//! Tooltip shows state (enabled/disabled) and "Pod: N devices"; main sends TrayStateUpdate and posts WM_TRAY_UPDATE_STATE.
+//! Tooltip shows state (enabled/disabled), "Pod: N devices" and data saved; main sends TrayStateUpdate and posts WM_TRAY_UPDATE_STATE.
 
 #![cfg(windows)]
 
@@ -27,15 +27,29 @@ pub enum TrayCommand {
     Exit,
 }
 
-/// State for tooltip and settings: enabled/disabled, peer count, peer device IDs, and autostart.
+/// State for tooltip and settings: enabled/disabled, peer count, peer labels, and autostart.
 #[derive(Clone, Debug)]
 pub struct TrayStateUpdate {
     pub enabled: bool,
     pub peer_count: u32,
-    /// Device IDs of current peers (first 16 bytes each); used by settings window to list pod members.
-    pub peer_ids: Vec<[u8; 16]>,
+    /// Current peers as [`peer_label`]s; used by settings window to list pod members.
+    pub peers: Vec<String>,
     /// Start PeaPod when I sign in (§7.2).
     pub autostart_enabled: bool,
+    /// Traffic counters from the core, for "data saved / accelerated".
+    pub stats: pea_core::TrafficStats,
+}
+
+/// Settings-window line for a peer: its verified name, if any, and its key fingerprint, rendered
+/// the same way as on every other host.
+pub fn peer_label(core: &pea_core::PeaPodCore, peer_id: pea_core::DeviceId) -> String {
+    let fingerprint = core
+        .peer_fingerprint(peer_id)
+        .map_or_else(|| peer_id.to_string(), |f| f.to_string());
+    match core.peer_name(peer_id) {
+        Some(name) => format!("{} ({})", name, fingerprint),
+        None => fingerprint,
+    }
 }
 
 const WM_TRAYICON: u32 = WM_USER + 1;
@@ -62,7 +76,7 @@ const BM_GETCHECK: u32 = 0x00F0;
 
 static CMD_TX: AtomicPtr<()> = AtomicPtr::new(null_mut());
 static STATE_RX: Mutex<Option<UnboundedReceiver<TrayStateUpdate>>> = Mutex::new(None);
-/// Latest state (including peer_ids) for the settings window to read.
+/// Latest state (including peers) for the settings window to read.
 static LATEST_STATE: Mutex<Option<TrayStateUpdate>> = Mutex::new(None);
 static mut NID_PTR: *mut NOTIFYICONDATAW = null_mut();
 // SAFETY: Only accessed from the tray/UI thread.
@@ -128,10 +142,16 @@ unsafe extern "system" fn wnd_proc(
                     if let Ok(mut latest_guard) = LATEST_STATE.lock() {
                         *latest_guard = Some(s.clone());
                     }
+                    let speedup = match s.stats.speedup() {
+                        Some(x) => format!(" ({:.1}x)", x),
+                        None => String::new(),
+                    };
                     let tip = format!(
-                        "PeaPod – {}\r\nPod: {} device(s)",
+                        "PeaPod – {}\r\nPod: {} device(s)\r\nSaved: {:.1} MB{}",
                         if s.enabled { "enabled" } else { "disabled" },
-                        s.peer_count
+                        s.peer_count,
+                        s.stats.bytes_saved() as f64 / (1024.0 * 1024.0),
+                        speedup
                     );
                     let tip_wide: Vec<u16> = tip.encode_utf16().chain(std::iter::once(0)).collect();
                     let len = tip_wide.len().min(128);
@@ -202,9 +222,8 @@ unsafe fn refresh_settings_peer_list() {
     let _ = SendMessageW(list, LB_RESETCONTENT, WPARAM(0), LPARAM(0));
     if let Ok(guard) = LATEST_STATE.lock() {
         if let Some(ref s) = *guard {
-            for id in &s.peer_ids {
-                let hex = format!("{:02x}{:02x}{:02x}{:02x}...", id[0], id[1], id[2], id[3]);
-                let wide: Vec<u16> = hex.encode_utf16().chain(std::iter::once(0)).collect();
+            for label in &s.peers {
+                let wide: Vec<u16> = label.encode_utf16().chain(std::iter::once(0)).collect();
                 let _ = SendMessageW(
                     list,
                     LB_ADDSTRING,